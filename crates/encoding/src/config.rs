@@ -1,14 +1,12 @@
 // Copyright 2023 The Vello authors
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
-use crate::{math::PatternData, clip::PatternRange};
-
 use super::{
     BinHeader, Clip, ClipBbox, ClipBic, ClipElement, Cubic, DrawBbox, DrawMonoid, Layout, Path,
-    PathBbox, PathMonoid, PathSegment, Tile,
+    PathBbox, PathMonoid, PathSegment, Tile, Transform,
 };
 use bytemuck::{Pod, Zeroable};
-use std::mem;
+use std::{fmt, mem};
 
 const TILE_WIDTH: u32 = 16;
 const TILE_HEIGHT: u32 = 16;
@@ -20,6 +18,18 @@ const PATH_COARSE_WG: u32 = 256;
 const CLIP_REDUCE_WG: u32 = 256;
 const CLIP_PATTERN_WG: u32 = 256;
 
+// Must match the values in shader/shared/ptcl.wgsl.
+const PTCL_INITIAL_ALLOC: u32 = 64;
+
+/// Bitflags for each stage that can fail allocation.
+///
+/// These must be kept in sync with the values in shader/shared/bump.wgsl.
+pub const STAGE_BINNING: u32 = 0x1;
+pub const STAGE_TILE_ALLOC: u32 = 0x2;
+pub const STAGE_PATH_COARSE: u32 = 0x4;
+pub const STAGE_COARSE: u32 = 0x8;
+pub const STAGE_PATTERN: u32 = 0x10;
+
 /// Counters for tracking dynamic allocation on the GPU.
 ///
 /// This must be kept in sync with the struct in shader/shared/bump.wgsl
@@ -35,8 +45,32 @@ pub struct BumpAllocators {
     pub segments: u32,
     pub blend: u32,
     pub pattern_cubic: u32,
+    /// Draw objects that the pattern stage expands the pattern instances to.
+    pub pattern_draw: u32,
+    /// Draw info of the expanded draw objects, in `u32`s.
+    pub pattern_info: u32,
+}
+
+/// Error returned by [`BumpSizes::grow`] when stages of a render failed
+/// although none of the bump allocated buffers overflowed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AllocationFailed {
+    /// The stages that failed, as a combination of the `STAGE_*` flags.
+    pub stages: u32,
 }
 
+impl fmt::Display for AllocationFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "render stages {:#x} failed without overflowing their buffers",
+            self.stages
+        )
+    }
+}
+
+impl std::error::Error for AllocationFailed {}
+
 /// Uniform render configuration data used by all GPU stages.
 ///
 /// This data structure must be kept in sync with the definition in
@@ -64,6 +98,40 @@ pub struct ConfigUniform {
     pub segments_size: u32,
     /// Size of per-tile command list buffer allocation (in u32s).
     pub ptcl_size: u32,
+    /// Index in the cubic buffer where pattern instances start, following the
+    /// cubics of the scene.
+    pub pattern_cubics_base: u32,
+    /// Size of the pattern instance allocation (in Cubics).
+    pub pattern_cubics_size: u32,
+    /// Start of the slots of the expanded draw objects in the scene, with the
+    /// path, draw data offset and color of each (in u32s).
+    pub pattern_slot_base: u32,
+    /// Index of the transform of the first expanded draw object.
+    pub pattern_transform_base: u32,
+    /// Index of the path of the first expanded draw object, following the
+    /// paths of the scene.
+    pub pattern_path_base: u32,
+    /// Color space of the values written to the target, as a [`ColorSpace`].
+    pub target_color_space: u32,
+    /// Color space in which colors are blended and composited, as a [`ColorSpace`].
+    pub blend_color_space: u32,
+}
+
+/// Color space of the pixels written to the render target, or in which colors
+/// are blended.
+///
+/// The values must be kept in sync with shader/shared/config.wgsl.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[repr(u32)]
+pub enum ColorSpace {
+    /// sRGB encoded values, as used by scene colors and images and expected by `Rgba8Unorm`
+    /// and `Bgra8Unorm` targets. Blending in this space matches most 2D renderers.
+    #[default]
+    Srgb = 0,
+    /// Linear light values with sRGB primaries, as expected by `Rgba16Float` targets for HDR
+    /// compositing and by `*Srgb` texture formats, which encode on write. Blending in this
+    /// space is gamma correct.
+    LinearSrgb = 1,
 }
 
 /// CPU side setup and configuration.
@@ -79,14 +147,64 @@ pub struct RenderConfig {
 
 impl RenderConfig {
     pub fn new(layout: &Layout, width: u32, height: u32, base_color: &peniko::Color) -> Self {
+        let bump_sizes = BumpSizes::estimate(layout, width, height);
+        Self::with_bump_sizes(layout, width, height, base_color, &bump_sizes)
+    }
+
+    /// Creates a new configuration with the given sizes for the bump allocated
+    /// buffers.
+    ///
+    /// When the layout has pattern instances, the draw objects that the stages
+    /// after the pattern stage see are the ones it expands them to, which are
+    /// written to the scene after the packed encoding.
+    pub fn with_bump_sizes(
+        layout: &Layout,
+        width: u32,
+        height: u32,
+        base_color: &peniko::Color,
+        bump_sizes: &BumpSizes,
+    ) -> Self {
+        let packed = *layout;
+        let mut layout = packed;
+        let mut pattern_slot_base = 0;
+        let mut pattern_transform_base = 0;
+        let mut scene = packed.scene_size();
+        if packed.n_pattern_instances != 0 {
+            // The expanded draw tags are followed by a slot of 3 words and a
+            // transform for each draw object.
+            let n_draws = bump_sizes.draws.len();
+            let end = packed.scene_size() / 4;
+            layout.draw_tag_base = end;
+            layout.n_draw_objects = n_draws;
+            layout.n_paths = packed.n_paths.saturating_add(n_draws);
+            layout.n_clips = if packed.n_clips > 0 { n_draws } else { 0 };
+            layout.bin_data_start = bump_sizes.info.len();
+            pattern_slot_base = end.saturating_add(n_draws);
+            let slots_end = pattern_slot_base.saturating_add(n_draws.saturating_mul(3));
+            pattern_transform_base = (slots_end - packed.transform_base).div_ceil(6);
+            scene = pattern_transform_base
+                .saturating_add(n_draws)
+                .saturating_mul(6)
+                .saturating_add(packed.transform_base)
+                .saturating_mul(4);
+        }
+        let layout = &layout;
         let new_width = next_multiple_of(width, TILE_WIDTH);
         let new_height = next_multiple_of(height, TILE_HEIGHT);
         let width_in_tiles = new_width / TILE_WIDTH;
         let height_in_tiles = new_height / TILE_HEIGHT;
         let n_path_tags = layout.path_tags_size();
-        let workgroup_counts =
-            WorkgroupCounts::new(layout, width_in_tiles, height_in_tiles, n_path_tags);
-        let buffer_sizes = BufferSizes::new(layout, &workgroup_counts, n_path_tags);
+        let n_cubics = n_path_tags.saturating_add(bump_sizes.cubics.len());
+        let workgroup_counts = WorkgroupCounts::new(
+            layout,
+            width_in_tiles,
+            height_in_tiles,
+            n_path_tags,
+            n_cubics,
+        );
+        let mut buffer_sizes =
+            BufferSizes::with_bump_sizes(layout, &workgroup_counts, n_path_tags, bump_sizes);
+        buffer_sizes.scene = BufferSize::from_size_in_bytes(scene);
         Self {
             gpu: ConfigUniform {
                 width_in_tiles,
@@ -98,6 +216,13 @@ impl RenderConfig {
                 tiles_size: buffer_sizes.tiles.len(),
                 segments_size: buffer_sizes.segments.len(),
                 ptcl_size: buffer_sizes.ptcl.len(),
+                pattern_cubics_base: n_path_tags,
+                pattern_cubics_size: bump_sizes.cubics.len(),
+                pattern_slot_base,
+                pattern_transform_base,
+                pattern_path_base: packed.n_paths,
+                target_color_space: ColorSpace::Srgb as u32,
+                blend_color_space: ColorSpace::Srgb as u32,
                 layout: *layout,
             },
             workgroup_counts,
@@ -123,6 +248,8 @@ pub struct WorkgroupCounts {
     pub draw_leaf: WorkgroupSize,
     pub clip_reduce: WorkgroupSize,
     pub clip_leaf: WorkgroupSize,
+    pub pattern_reduce: WorkgroupSize,
+    pub pattern_leaf: WorkgroupSize,
     pub pattern: WorkgroupSize,
     pub binning: WorkgroupSize,
     pub tile_alloc: WorkgroupSize,
@@ -133,16 +260,19 @@ pub struct WorkgroupCounts {
 }
 
 impl WorkgroupCounts {
+    /// Computes the workgroup counts. `n_cubics` is the size of the cubic
+    /// buffer, including the space for pattern instances.
     pub fn new(
         layout: &Layout,
         width_in_tiles: u32,
         height_in_tiles: u32,
         n_path_tags: u32,
+        n_cubics: u32,
     ) -> Self {
         let n_paths = layout.n_paths;
         let n_draw_objects = layout.n_draw_objects;
         let n_clips = layout.n_clips;
-        let n_patterns = layout.n_patterns;
+        let n_pattern_instances = layout.n_pattern_instances;
         let path_tag_padded = align_up(n_path_tags, 4 * PATH_REDUCE_WG);
         let path_tag_wgs = path_tag_padded / (4 * PATH_REDUCE_WG);
         let use_large_path_scan = path_tag_wgs > PATH_REDUCE_WG;
@@ -151,27 +281,37 @@ impl WorkgroupCounts {
         } else {
             path_tag_wgs
         };
-        let draw_object_wgs = (n_draw_objects + PATH_BBOX_WG - 1) / PATH_BBOX_WG;
-        let path_coarse_wgs = (n_path_tags + PATH_COARSE_WG - 1) / PATH_COARSE_WG;
+        let draw_object_wgs = n_draw_objects.div_ceil(PATH_BBOX_WG);
+        let path_seg_wgs = n_path_tags.div_ceil(PATH_COARSE_WG);
+        // Path coarse also rasterizes the pattern instances that follow the scene cubics.
+        let path_coarse_wgs = n_cubics.div_ceil(PATH_COARSE_WG);
         let clip_reduce_wgs = n_clips.saturating_sub(1) / CLIP_REDUCE_WG;
-        let clip_wgs = (n_clips + CLIP_REDUCE_WG - 1) / CLIP_REDUCE_WG;
-        let pattern_wgs = (n_patterns + CLIP_PATTERN_WG - 1) / CLIP_PATTERN_WG;
-        let path_wgs = (n_paths + PATH_BBOX_WG - 1) / PATH_BBOX_WG;
-        let width_in_bins = (width_in_tiles + 15) / 16;
-        let height_in_bins = (height_in_tiles + 15) / 16;
+        let clip_wgs = n_clips.div_ceil(CLIP_REDUCE_WG);
+        let instance_wgs = n_pattern_instances.div_ceil(CLIP_PATTERN_WG);
+        // The pattern stage writes every expanded draw object.
+        let pattern_wgs = if n_pattern_instances != 0 {
+            draw_object_wgs
+        } else {
+            0
+        };
+        let path_wgs = n_paths.div_ceil(PATH_BBOX_WG);
+        let width_in_bins = width_in_tiles.div_ceil(16);
+        let height_in_bins = height_in_tiles.div_ceil(16);
         Self {
             use_large_path_scan,
             path_reduce: (path_tag_wgs, 1, 1),
             path_reduce2: (PATH_REDUCE_WG, 1, 1),
             path_scan1: (reduced_size / PATH_REDUCE_WG, 1, 1),
             path_scan: (path_tag_wgs, 1, 1),
-            bbox_clear: (draw_object_wgs, 1, 1),
-            path_seg: (path_coarse_wgs, 1, 1),
+            bbox_clear: (path_wgs, 1, 1),
+            path_seg: (path_seg_wgs, 1, 1),
             draw_reduce: (draw_object_wgs, 1, 1),
             draw_leaf: (draw_object_wgs, 1, 1),
             clip_reduce: (clip_reduce_wgs, 1, 1),
             clip_leaf: (clip_wgs, 1, 1),
-            pattern:(pattern_wgs,1,1),
+            pattern_reduce: (instance_wgs, 1, 1),
+            pattern_leaf: (instance_wgs, 1, 1),
+            pattern: (pattern_wgs, 1, 1),
             binning: (draw_object_wgs, 1, 1),
             tile_alloc: (path_wgs, 1, 1),
             path_coarse: (path_coarse_wgs, 1, 1),
@@ -246,7 +386,6 @@ pub struct BufferSizes {
     pub draw_monoids: BufferSize<DrawMonoid>,
     pub info: BufferSize<u32>,
     pub clip_inps: BufferSize<Clip>,
-    pub pattern_inps: BufferSize<PatternRange>,
     pub clip_els: BufferSize<ClipElement>,
     pub clip_bics: BufferSize<ClipBic>,
     pub clip_bboxes: BufferSize<ClipBbox>,
@@ -254,6 +393,11 @@ pub struct BufferSizes {
     pub bump_alloc: BufferSize<BumpAllocators>,
     pub bin_headers: BufferSize<BinHeader>,
     pub paths: BufferSize<Path>,
+    /// The packed scene, followed by the draw objects that pattern instances
+    /// expand to.
+    pub scene: BufferSize<u32>,
+    pub pattern_reduced: BufferSize<[u32; 2]>,
+    pub pattern_offsets: BufferSize<[u32; 2]>,
     // Bump allocated buffers
     pub bin_data: BufferSize<u32>,
     pub tiles: BufferSize<Tile>,
@@ -262,11 +406,33 @@ pub struct BufferSizes {
 }
 
 impl BufferSizes {
+    /// Computes buffer sizes, estimating the sizes of the bump allocated buffers
+    /// from the layout and the target size.
+    ///
+    /// The draw objects that pattern instances expand to, and the scene buffer
+    /// they are written to, are only sized by [`RenderConfig`], which should be
+    /// used when the encoding has them.
     pub fn new(layout: &Layout, workgroups: &WorkgroupCounts, n_path_tags: u32) -> Self {
+        let (width_in_tiles, height_in_tiles, _) = workgroups.fine;
+        let bump_sizes = BumpSizes::estimate(
+            layout,
+            width_in_tiles * TILE_WIDTH,
+            height_in_tiles * TILE_HEIGHT,
+        );
+        Self::with_bump_sizes(layout, workgroups, n_path_tags, &bump_sizes)
+    }
+
+    /// Computes buffer sizes, using the given sizes for the bump allocated
+    /// buffers.
+    pub fn with_bump_sizes(
+        layout: &Layout,
+        workgroups: &WorkgroupCounts,
+        n_path_tags: u32,
+        bump_sizes: &BumpSizes,
+    ) -> Self {
         let n_paths = layout.n_paths;
         let n_draw_objects = layout.n_draw_objects;
         let n_clips = layout.n_clips;
-        let n_patterns = layout.n_patterns;
         let path_tag_wgs = workgroups.path_reduce.0;
         let reduced_size = if workgroups.use_large_path_scan {
             align_up(path_tag_wgs, PATH_REDUCE_WG)
@@ -278,29 +444,33 @@ impl BufferSizes {
         let path_reduced_scan = BufferSize::new(path_tag_wgs);
         let path_monoids = BufferSize::new(path_tag_wgs * PATH_REDUCE_WG);
         let path_bboxes = BufferSize::new(n_paths);
-        let cubics = BufferSize::new(n_path_tags);
+        let cubics = BufferSize::new(n_path_tags.saturating_add(bump_sizes.cubics.len()));
         let draw_object_wgs = workgroups.draw_reduce.0;
         let draw_reduced = BufferSize::new(draw_object_wgs);
         let draw_monoids = BufferSize::new(n_draw_objects);
         let info = BufferSize::new(layout.bin_data_start);
         let clip_inps = BufferSize::new(n_clips);
-        let pattern_inps = BufferSize::new(n_patterns);
         let clip_els = BufferSize::new(n_clips);
         let clip_bics = BufferSize::new(n_clips / CLIP_REDUCE_WG);
         let clip_bboxes = BufferSize::new(n_clips);
-        let draw_bboxes = BufferSize::new(n_paths);
+        // Binning writes the bounding boxes by path, and pattern commands at the end
+        // of the scene index one past the last path. The paths of the draw objects
+        // expanded from pattern instances follow those of the scene.
+        let n_path_ixs = n_paths.max(n_draw_objects);
+        let draw_bboxes = BufferSize::new(n_path_ixs);
         let bump_alloc = BufferSize::new(1);
         let bin_headers = BufferSize::new(draw_object_wgs * 256);
-        let n_paths_aligned = align_up(n_paths, 256);
+        let n_paths_aligned = align_up(n_path_ixs, 256);
         let paths = BufferSize::new(n_paths_aligned);
-
-        // The following buffer sizes have been hand picked to accommodate the vello test scenes as
-        // well as paris-30k. These should instead get derived from the scene layout using
-        // reasonable heuristics.
-        let bin_data = BufferSize::new(1 << 18);
-        let tiles = BufferSize::new(1 << 21);
-        let segments = BufferSize::new(1 << 21);
-        let ptcl = BufferSize::new(1 << 23);
+        // Resized by `RenderConfig` when pattern instances are expanded.
+        let scene = BufferSize::from_size_in_bytes(layout.scene_size());
+        // The offsets of the instances are followed by the totals.
+        let pattern_reduced = BufferSize::new(workgroups.pattern_reduce.0);
+        let pattern_offsets = BufferSize::new(layout.n_pattern_instances.saturating_add(1));
+        let bin_data = BufferSize::new(layout.bin_data_start + bump_sizes.binning.len());
+        let tiles = bump_sizes.tiles;
+        let segments = bump_sizes.segments;
+        let ptcl = bump_sizes.ptcl;
         Self {
             path_reduced,
             path_reduced2,
@@ -312,7 +482,6 @@ impl BufferSizes {
             draw_monoids,
             info,
             clip_inps,
-            pattern_inps,
             clip_els,
             clip_bics,
             clip_bboxes,
@@ -320,6 +489,9 @@ impl BufferSizes {
             bump_alloc,
             bin_headers,
             paths,
+            scene,
+            pattern_reduced,
+            pattern_offsets,
             bin_data,
             tiles,
             segments,
@@ -328,6 +500,211 @@ impl BufferSizes {
     }
 }
 
+/// Sizes of the buffers that are bump allocated by the pipeline.
+///
+/// The space these need is only known after the pipeline has run. The sizes
+/// are first estimated from the scene with [`BumpSizes::estimate`]. If the
+/// counters in [`BumpAllocators`] show that a render overflowed, the sizes can
+/// be grown with [`BumpSizes::grow`] and the render run again.
+#[derive(Copy, Clone, Debug)]
+pub struct BumpSizes {
+    /// Binning allocation, excluding the draw info at the start of the buffer.
+    pub binning: BufferSize<u32>,
+    pub tiles: BufferSize<Tile>,
+    pub segments: BufferSize<PathSegment>,
+    pub ptcl: BufferSize<u32>,
+    /// Pattern instances, stored in the cubic buffer after the cubics of the scene.
+    pub cubics: BufferSize<Cubic>,
+    /// Draw objects that the pattern stage expands the pattern instances to.
+    ///
+    /// Each takes space in the scene buffer and in every buffer that holds a
+    /// value per draw object or path, see [`PATTERN_DRAW_SIZE`].
+    pub draws: BufferSize<u32>,
+    /// Draw info of the expanded draw objects, at the start of the binning
+    /// buffer.
+    pub info: BufferSize<u32>,
+}
+
+/// Size in bytes of a draw object that a pattern instance expands to.
+///
+/// It has a draw tag, a slot with its path, draw data offset and color, and a
+/// transform in the scene buffer, and a path with a value in each of the
+/// buffers that hold one per draw object or path.
+pub const PATTERN_DRAW_SIZE: u32 = (4
+    + 3 * 4
+    + mem::size_of::<Transform>()
+    + mem::size_of::<PathBbox>()
+    + mem::size_of::<DrawMonoid>()
+    + mem::size_of::<DrawBbox>()
+    + mem::size_of::<Path>()
+    + mem::size_of::<Clip>()
+    + mem::size_of::<ClipElement>()
+    + mem::size_of::<ClipBbox>()
+    + mem::size_of::<BinHeader>()) as u32;
+
+impl Default for BumpSizes {
+    fn default() -> Self {
+        Self {
+            binning: BufferSize::new(0),
+            tiles: BufferSize::new(0),
+            segments: BufferSize::new(0),
+            ptcl: BufferSize::new(0),
+            cubics: BufferSize::new(0),
+            draws: BufferSize::new(0),
+            info: BufferSize::new(0),
+        }
+    }
+}
+
+impl BumpSizes {
+    /// Estimates the sizes needed to render a scene with the given layout into
+    /// a target of `width` by `height` pixels.
+    ///
+    /// The space for pattern instances is estimated from the packed encoding,
+    /// before they are expanded: the number of cells is only known once the
+    /// pattern stage has run, so scenes with many cells grow the buffers.
+    ///
+    /// This is a heuristic: the estimate is meant to fit typical scenes without
+    /// wasting memory on small ones, and may still be exceeded by scenes with
+    /// many large overlapping paths.
+    pub fn estimate(layout: &Layout, width: u32, height: u32) -> Self {
+        let width_in_tiles = width.div_ceil(TILE_WIDTH);
+        let height_in_tiles = height.div_ceil(TILE_HEIGHT);
+        let n_tiles = width_in_tiles * height_in_tiles;
+        let n_bins = width_in_tiles.div_ceil(16) * height_in_tiles.div_ceil(16);
+        let n_paths = layout.n_paths;
+        let n_path_tags = layout.path_tags_size();
+        // Each cubic of the scene is assumed to be copied once by the pattern stage.
+        let has_instances = layout.n_pattern_instances != 0;
+        let n_pattern_cubics = if has_instances { n_path_tags } else { 0 };
+        let n_cubics = n_path_tags.saturating_add(n_pattern_cubics);
+        // Each draw object is binned at most once per bin.
+        let binning = layout.n_draw_objects.saturating_mul(n_bins);
+        // A path allocates a tile for each tile of its bounding box, which is bounded by the
+        // viewport. Most paths are small, so only a few are assumed to cover the viewport.
+        let tiles = n_paths.saturating_mul(n_tiles).min(
+            n_paths
+                .saturating_mul(16)
+                .saturating_add(n_tiles.saturating_mul(4)),
+        );
+        // Cubics are flattened into a few lines, each crossing a couple of tiles.
+        let segments = n_cubics.saturating_mul(8);
+        // Every tile has an initial allocation, and commands that don't fit spill into the
+        // dynamic part of the buffer.
+        let ptcl = n_tiles
+            .saturating_mul(PTCL_INITIAL_ALLOC)
+            .saturating_add(tiles.saturating_mul(8));
+        // Cap estimates at the default storage buffer binding limit of wgpu. Scenes that need
+        // more than this will overflow and grow the buffers if the device allows it.
+        const MAX_SIZE_IN_BYTES: u32 = 128 << 20;
+        fn capped<T>(len: u32) -> BufferSize<T> {
+            let max_len = MAX_SIZE_IN_BYTES / mem::size_of::<T>() as u32;
+            BufferSize::new(len.min(max_len))
+        }
+        fn size<T>(len: u32) -> BufferSize<T> {
+            capped(len.max(1).checked_next_power_of_two().unwrap_or(u32::MAX))
+        }
+        Self {
+            binning: size(binning),
+            tiles: size(tiles),
+            segments: size(segments),
+            ptcl: size(ptcl),
+            cubics: capped(n_pattern_cubics),
+            draws: if has_instances {
+                let max_draws = MAX_SIZE_IN_BYTES / PATTERN_DRAW_SIZE;
+                BufferSize::new(size::<u32>(layout.n_draw_objects).len().min(max_draws))
+            } else {
+                BufferSize::new(0)
+            },
+            info: if has_instances {
+                size(layout.bin_data_start)
+            } else {
+                BufferSize::new(0)
+            },
+        }
+    }
+
+    /// Returns the larger of each of the sizes in `self` and `other`.
+    pub fn max(&self, other: &Self) -> Self {
+        fn max<T>(a: BufferSize<T>, b: BufferSize<T>) -> BufferSize<T> {
+            BufferSize::new(a.len.max(b.len))
+        }
+        Self {
+            binning: max(self.binning, other.binning),
+            tiles: max(self.tiles, other.tiles),
+            segments: max(self.segments, other.segments),
+            ptcl: max(self.ptcl, other.ptcl),
+            cubics: max(self.cubics, other.cubics),
+            draws: max(self.draws, other.draws),
+            info: max(self.info, other.info),
+        }
+    }
+
+    /// Grows the sizes to fit the allocations recorded in `bump` by a render
+    /// using `config`.
+    ///
+    /// Returns `true` if any of the buffers was too small. Stages that follow a
+    /// failed stage don't run, so a render may need several rounds of growth
+    /// before it succeeds.
+    ///
+    /// Fails with [`AllocationFailed`] if a stage reported a failure but none
+    /// of the buffers was too small, as growing them wouldn't fix the render.
+    pub fn grow(
+        &mut self,
+        config: &ConfigUniform,
+        bump: &BumpAllocators,
+    ) -> Result<bool, AllocationFailed> {
+        fn grow_to<T>(size: &mut BufferSize<T>, required: u32) -> bool {
+            if required > size.len {
+                *size = BufferSize::new(required.checked_next_power_of_two().unwrap_or(u32::MAX));
+                true
+            } else {
+                false
+            }
+        }
+        let ptcl_dyn_start = config.width_in_tiles * config.height_in_tiles * PTCL_INITIAL_ALLOC;
+        // Segment offsets start at 1, as 0 is reserved for failed allocations.
+        let mut grew = grow_to(&mut self.binning, bump.binning);
+        grew |= grow_to(&mut self.tiles, bump.tile);
+        grew |= grow_to(&mut self.segments, bump.segments.saturating_add(1));
+        grew |= grow_to(&mut self.ptcl, ptcl_dyn_start.saturating_add(bump.ptcl));
+        grew |= grow_to(&mut self.cubics, bump.pattern_cubic);
+        grew |= grow_to(&mut self.draws, bump.pattern_draw);
+        grew |= grow_to(&mut self.info, bump.pattern_info);
+        if !grew && bump.failed != 0 {
+            return Err(AllocationFailed {
+                stages: bump.failed,
+            });
+        }
+        Ok(grew)
+    }
+
+    /// Returns the total size in bytes of the bump allocated buffers.
+    pub fn size_in_bytes(&self) -> u64 {
+        self.buffer_sizes_in_bytes().iter().sum()
+    }
+
+    /// Returns the size in bytes of the largest bump allocated buffer.
+    pub fn max_buffer_size_in_bytes(&self) -> u64 {
+        self.buffer_sizes_in_bytes().into_iter().max().unwrap_or(0)
+    }
+
+    fn buffer_sizes_in_bytes(&self) -> [u64; 7] {
+        fn bytes<T>(size: BufferSize<T>) -> u64 {
+            size.len() as u64 * mem::size_of::<T>() as u64
+        }
+        [
+            bytes(self.binning),
+            bytes(self.tiles),
+            bytes(self.segments),
+            bytes(self.ptcl),
+            bytes(self.cubics),
+            self.draws.len() as u64 * PATTERN_DRAW_SIZE as u64,
+            bytes(self.info),
+        ]
+    }
+}
+
 const fn align_up(len: u32, alignment: u32) -> u32 {
     len + (len.wrapping_neg() & (alignment - 1))
 }
@@ -338,3 +715,134 @@ const fn next_multiple_of(val: u32, rhs: u32) -> u32 {
         r => val + (rhs - r),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use peniko::kurbo::{BezPath, Rect};
+
+    use super::*;
+    use crate::{instance_patterns, resolve_solid_paths_only, DrawColor, Encoding, PatternData};
+
+    #[test]
+    fn estimate_is_capped() {
+        for n_pattern_instances in [0, 1] {
+            let layout = Layout {
+                n_draw_objects: 1 << 24,
+                n_paths: 1 << 24,
+                path_data_base: 1 << 24,
+                bin_data_start: 1 << 24,
+                n_pattern_instances,
+                ..Default::default()
+            };
+            let sizes = BumpSizes::estimate(&layout, 8192, 8192);
+            assert_eq!(sizes.max_buffer_size_in_bytes(), 128 << 20);
+        }
+    }
+
+    #[test]
+    fn grow_fails_without_overflow() {
+        let config = ConfigUniform::default();
+        let mut sizes = BumpSizes {
+            cubics: BufferSize::new(16),
+            draws: BufferSize::new(16),
+            ..Default::default()
+        };
+        let overflow = BumpAllocators {
+            failed: STAGE_PATTERN,
+            pattern_cubic: 20,
+            pattern_draw: 40,
+            ..Default::default()
+        };
+        assert_eq!(sizes.grow(&config, &overflow), Ok(true));
+        assert_eq!(sizes.cubics.len(), 32);
+        assert_eq!(sizes.draws.len(), 64);
+        // Growing again can't fix a stage that failed with buffers that fit.
+        assert_eq!(
+            sizes.grow(&config, &overflow),
+            Err(AllocationFailed {
+                stages: STAGE_PATTERN
+            })
+        );
+        assert_eq!(sizes.grow(&config, &BumpAllocators::default()), Ok(false));
+    }
+
+    #[test]
+    fn expanded_layout_of_instances() {
+        let mut encoding = Encoding::new();
+        let grid = PatternData {
+            transform: Transform::IDENTITY,
+            x_step: [32.0, 0.0],
+            y_step: [0.0, 32.0],
+            extend: PatternData::EXTEND_REPEAT,
+            tiling: PatternData::TILING_GRID,
+            ..bytemuck::Zeroable::zeroed()
+        };
+        encoding.encode_begin_pattern(grid, std::iter::empty(), std::iter::empty());
+        // Two paths, filling part of each cell.
+        encoding.encode_shape(&Rect::new(4.0, 4.0, 12.0, 12.0), true);
+        encoding.encode_color(DrawColor::default());
+        let mut triangle = BezPath::new();
+        triangle.move_to((4.0, 16.0));
+        triangle.line_to((12.0, 16.0));
+        triangle.line_to((8.0, 24.0));
+        encoding.encode_shape(&triangle, true);
+        encoding.encode_color(DrawColor::default());
+        encoding.encode_end_pattern();
+        let target = [0.0, 0.0, 128.0, 64.0];
+        let instanced = instance_patterns(&encoding, target).unwrap();
+        let mut packed = vec![];
+        let layout = resolve_solid_paths_only(&instanced, &mut packed);
+        // The pattern has a single instance, whatever its number of cells.
+        assert_eq!(layout.n_template_paths, 2);
+        assert_eq!(layout.n_pattern_instances, 1);
+        assert_eq!(packed.len(), layout.scene_size() as usize);
+        let instance = layout.pattern_instances(&packed)[0];
+        let n_cells = grid.cells(target, [4.0, 4.0, 12.0, 24.0]).len() as u64;
+        assert_eq!(instance.n_cells(&instanced.pattern_data), n_cells);
+
+        // The stages after the pattern stage see the expanded draw objects,
+        // written after the packed scene.
+        let mut bump_sizes = BumpSizes::estimate(&layout, 128, 64);
+        let bump = BumpAllocators {
+            failed: STAGE_PATTERN,
+            pattern_draw: n_cells as u32 * 2,
+            ..Default::default()
+        };
+        let config = RenderConfig::new(&layout, 128, 64, &peniko::Color::BLACK);
+        assert_eq!(bump_sizes.grow(&config.gpu, &bump), Ok(true));
+        let config =
+            RenderConfig::with_bump_sizes(&layout, 128, 64, &peniko::Color::BLACK, &bump_sizes);
+        let n_draws = bump_sizes.draws.len();
+        let expanded = &config.gpu.layout;
+        assert!(n_draws >= n_cells as u32 * 2);
+        assert_eq!(expanded.n_draw_objects, n_draws);
+        assert_eq!(expanded.n_paths, layout.n_paths + n_draws);
+        assert_eq!(config.gpu.pattern_path_base, layout.n_paths);
+        assert_eq!(expanded.draw_tag_base, layout.scene_size() / 4);
+        assert_eq!(
+            config.gpu.pattern_slot_base,
+            expanded.draw_tag_base + n_draws
+        );
+        let transforms = expanded.transform_base + config.gpu.pattern_transform_base * 6;
+        assert!(transforms >= config.gpu.pattern_slot_base + n_draws * 3);
+        assert_eq!(
+            config.buffer_sizes.scene.size_in_bytes(),
+            (transforms + n_draws * 6) * 4
+        );
+        assert!(config.buffer_sizes.paths.len() >= expanded.n_paths);
+
+        // Cells outside of an enclosing clip aren't drawn.
+        let mut clipped = Encoding::new();
+        let clip = [8.0, 8.0, 40.0, 40.0];
+        clipped.encode_shape(&Rect::new(8.0, 8.0, 40.0, 40.0), true);
+        clipped.encode_begin_clip(Default::default(), 1.0);
+        clipped.append(&encoding, &None);
+        clipped.encode_end_clip();
+        let instanced = instance_patterns(&clipped, target).unwrap();
+        let instance = instanced.pattern_instances[1];
+        assert_eq!(instance.bbox, clip);
+        let n_clipped_cells = grid.cells(clip, [4.0, 4.0, 12.0, 24.0]).len() as u64;
+        assert!(n_clipped_cells < n_cells);
+        assert_eq!(instance.n_cells(&instanced.pattern_data), n_clipped_cells);
+    }
+}
@@ -2,7 +2,7 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
 use bytemuck::{Pod, Zeroable};
-use peniko::{BlendMode, Color};
+use peniko::{BlendMode, Color, Extend};
 
 use super::Monoid;
 
@@ -24,8 +24,14 @@ impl DrawTag {
     /// Radial gradient fill. 0b 1010 0 111 0 0   /668
     pub const RADIAL_GRADIENT: Self = Self(0x29c);
 
-    /// Image fill.           0b 1001 0 010 0 0   /584
-    pub const IMAGE: Self = Self(0x248);
+    /// Sweep gradient fill.  0b 1001 0 101 0 0   /596
+    pub const SWEEP_GRADIENT: Self = Self(0x254);
+
+    /// Image fill.           0b 1010 0 011 0 0   /652
+    pub const IMAGE: Self = Self(0x28c);
+
+    /// Blurred rounded rectangle. 0b 1011 0 101 0 0   /724
+    pub const BLUR_RECT: Self = Self(0x2d4);
 
     /// Begin layer/clip.     0b        010 0 1   /9
     pub const BEGIN_CLIP: Self = Self(0x9);
@@ -70,6 +76,21 @@ impl DrawColor {
             rgba: color.to_premul_u32(),
         }
     }
+
+    /// Returns the draw data of `color` with its alpha scaled by the alpha of
+    /// `self`.
+    ///
+    /// This is how the colors of pattern cells replace the colors of the
+    /// contents. The pattern stage computes the same in `recolor`, with the
+    /// same integer rounding.
+    pub fn recolor(self, color: Color) -> Self {
+        let mul = |a: u32, b: u32| (a * b + 127) / 255;
+        let a = mul(color.a as u32, self.rgba & 0xff);
+        let [r, g, b] = [color.r, color.g, color.b].map(|c| mul(c as u32, a));
+        Self {
+            rgba: (r << 24) | (g << 16) | (b << 8) | a,
+        }
+    }
 }
 
 /// Draw data for a linear gradient.
@@ -100,14 +121,119 @@ pub struct DrawRadialGradient {
     pub r1: f32,
 }
 
+/// Draw data for a sweep gradient.
+#[derive(Clone, Copy, Debug, Default, Zeroable, Pod)]
+#[repr(C)]
+pub struct DrawSweepGradient {
+    /// Ramp index.
+    pub index: u32,
+    /// Center point.
+    pub p0: [f32; 2],
+    /// Start angle in radians, clockwise from the positive x axis.
+    pub t0: f32,
+    /// End angle in radians.
+    pub t1: f32,
+}
+
 /// Draw data for an image.
 #[derive(Clone, Copy, Debug, Default, Zeroable, Pod)]
 #[repr(C)]
 pub struct DrawImage {
-    /// Packed atlas coordinates.
+    /// Packed atlas coordinates, with the page in the top 6 bits followed by
+    /// 13 bits each for x and y.
     pub xy: u32,
     /// Packed image dimensions.
     pub width_height: u32,
+    /// Packed sampling parameters, with the alpha multiplier in the top 8 bits
+    /// followed by 2 bits each for the quality and the extend modes in y and x.
+    /// Bit 6 is [`SAMPLING_LAYER`](Self::SAMPLING_LAYER).
+    pub sampling: u32,
+}
+
+impl DrawImage {
+    /// Sampling flag for an image that is read from the rendered layers of
+    /// layer effects rather than the image atlas.
+    pub const SAMPLING_LAYER: u32 = 1 << 6;
+
+    /// Packs the sampling parameters and alpha multiplier of an image brush.
+    pub fn pack_sampling(sampling: ImageSampling, alpha: f32) -> u32 {
+        let alpha = (alpha.clamp(0.0, 1.0) * 255.0).round() as u32;
+        (alpha << 24)
+            | ((sampling.quality as u32) << 4)
+            | ((sampling.y_extend as u32) << 2)
+            | sampling.x_extend as u32
+    }
+}
+
+/// Filter used to sample an image.
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Default, Debug)]
+#[repr(u32)]
+pub enum ImageQuality {
+    /// The nearest pixel is used, which keeps pixel art sharp when scaled up.
+    Nearest = 0,
+    /// The four nearest pixels are interpolated linearly.
+    #[default]
+    Bilinear = 1,
+    /// The sixteen nearest pixels are interpolated with a cubic filter, which
+    /// is smoother when scaling up.
+    Bicubic = 2,
+}
+
+/// Sampling parameters of an image brush.
+#[derive(Copy, Clone, PartialEq, Default, Debug)]
+pub struct ImageSampling {
+    /// Extend mode in the x direction of the image.
+    pub x_extend: Extend,
+    /// Extend mode in the y direction of the image.
+    pub y_extend: Extend,
+    /// Filter used to sample the image.
+    pub quality: ImageQuality,
+}
+
+impl ImageSampling {
+    /// Creates sampling parameters with the given extend mode in both
+    /// directions and bilinear filtering.
+    pub fn new(extend: Extend) -> Self {
+        Self {
+            x_extend: extend,
+            y_extend: extend,
+            quality: ImageQuality::Bilinear,
+        }
+    }
+
+    /// Builder method for setting the extend mode in the x direction.
+    pub fn with_x_extend(mut self, extend: Extend) -> Self {
+        self.x_extend = extend;
+        self
+    }
+
+    /// Builder method for setting the extend mode in the y direction.
+    pub fn with_y_extend(mut self, extend: Extend) -> Self {
+        self.y_extend = extend;
+        self
+    }
+
+    /// Builder method for setting the filter.
+    pub fn with_quality(mut self, quality: ImageQuality) -> Self {
+        self.quality = quality;
+        self
+    }
+}
+
+/// Draw data for a blurred rounded rectangle.
+#[derive(Clone, Copy, Debug, Default, Zeroable, Pod)]
+#[repr(C)]
+pub struct DrawBlurRoundedRect {
+    /// Solid color brush.
+    pub color: DrawColor,
+    /// Width of the rectangle.
+    pub width: f32,
+    /// Height of the rectangle.
+    pub height: f32,
+    /// Radius of the corners.
+    pub radius: f32,
+    /// Standard deviation of the gaussian filter.
+    pub std_dev: f32,
 }
 
 /// Draw data for a clip or layer.
@@ -149,23 +275,28 @@ pub struct DrawMonoid {
 impl Monoid for DrawMonoid {
     type SourceValue = DrawTag;
 
+    /// Mirrors `map_draw_tag` in drawtag.wgsl. Pattern tags don't count as
+    /// paths, and the high bit of `pattern_ix` marks a pattern tag.
     fn new(tag: DrawTag) -> Self {
+        let pattern_bit = (tag.0 >> 10) & 1;
         Self {
-            path_ix: (tag != DrawTag::NOP) as u32,
+            path_ix: (tag != DrawTag::NOP) as u32 & !pattern_bit,
             clip_ix: tag.0 & 1,
             scene_offset: (tag.0 >> 2) & 0x7,
             info_offset: (tag.0 >> 6) & 0xf,
-            pattern_ix: (tag.0 >> 10) & 1,
+            pattern_ix: pattern_bit << 31 | pattern_bit,
         }
     }
 
+    /// Mirrors `combine_draw_monoid` in drawtag.wgsl.
     fn combine(&self, other: &Self) -> Self {
         Self {
             path_ix: self.path_ix + other.path_ix,
             clip_ix: self.clip_ix + other.clip_ix,
             scene_offset: self.scene_offset + other.scene_offset,
             info_offset: self.info_offset + other.info_offset,
-            pattern_ix: self.pattern_ix + other. pattern_ix,
+            pattern_ix: (self.pattern_ix.wrapping_add(other.pattern_ix) & 0x7fff_ffff)
+                | (self.pattern_ix & 0x8000_0000),
         }
     }
 }
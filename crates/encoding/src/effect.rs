@@ -0,0 +1,63 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use bytemuck::{Pod, Zeroable};
+
+/// Effect applied to the contents of a layer before it is composited.
+///
+/// The contents are clipped to the shape of the layer and then filtered, so the
+/// result extends beyond the shape by the reach of the blur and the offset of a
+/// shadow. Lengths are in the coordinate space of the shape of the layer.
+#[derive(Copy, Clone, Debug, PartialEq, Pod, Zeroable)]
+#[repr(C)]
+pub struct EffectData {
+    /// Index of the begin clip draw tag of the layer.
+    pub draw_tag_ix: u32,
+    /// Kind of the effect, one of the `KIND_*` constants.
+    pub kind: u32,
+    /// Standard deviation of the gaussian blur.
+    pub std_dev: f32,
+    /// Offset of the shadow.
+    pub offset: [f32; 2],
+    /// Packed premultiplied color of the shadow, as in [`DrawColor`].
+    ///
+    /// [`DrawColor`]: crate::DrawColor
+    pub color: u32,
+}
+
+impl EffectData {
+    /// The contents are blurred.
+    pub const KIND_BLUR: u32 = 0;
+    /// A blurred and offset copy of the alpha of the contents is drawn below
+    /// them in the shadow color.
+    pub const KIND_DROP_SHADOW: u32 = 1;
+}
+
+/// Uniform data for a pass of the blur of a layer with an effect.
+///
+/// This data structure must be kept in sync with the definition in
+/// shaders/blur.wgsl.
+#[derive(Copy, Clone, Debug, Default, Pod, Zeroable)]
+#[repr(C)]
+pub struct BlurConfig {
+    /// Width of the layer in pixels.
+    pub width: u32,
+    /// Height of the layer in pixels.
+    pub height: u32,
+    /// Horizontal location of the layer in the layer image.
+    pub x: u32,
+    /// Vertical location of the layer in the layer image.
+    pub y: u32,
+    /// Zero for the first, horizontal pass and one for the second, vertical pass.
+    pub vertical: u32,
+    /// Kind of the effect, one of the `KIND_*` constants of [`EffectData`].
+    pub kind: u32,
+    /// Standard deviation of the gaussian blur in pixels.
+    pub std_dev: f32,
+    /// Packed premultiplied color of the shadow.
+    pub color: u32,
+    /// Offset of the shadow, rounded to whole pixels.
+    pub offset: [i32; 2],
+    /// Pads the struct to the 16 byte alignment of uniforms.
+    pub _padding: [u32; 2],
+}
@@ -0,0 +1,329 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Splitting of layers with effects from an encoding.
+
+use guillotiere::{size2, AtlasAllocator};
+use peniko::kurbo::Rect;
+use peniko::{Extend, Mix};
+
+use super::pattern::{draw_objects, DrawObject, Expander};
+use super::{
+    expand_patterns, DrawBeginClip, DrawImage, DrawTag, EffectData, Encoding, ImageQuality,
+    ImageSampling, PathTag, PatternError, Transform,
+};
+
+const DEFAULT_LAYER_IMAGE_SIZE: i32 = 1024;
+/// Maximum size of the layer image. Locations in image draws are packed into
+/// 13 bits each.
+const MAX_LAYER_IMAGE_SIZE: i32 = 8192;
+
+/// Layers with effects, split from an encoding by [`split_effects`].
+#[derive(Default)]
+pub struct EffectLayers {
+    /// Width of the image that the layers are rendered to.
+    pub width: u32,
+    /// Height of the image that the layers are rendered to.
+    pub height: u32,
+    /// Layers in the order they must be rendered, with nested layers ahead of
+    /// the layers that contain them.
+    pub layers: Vec<EffectLayer>,
+    /// Number of layers that don't fit in the layer image, which are drawn
+    /// without their effect.
+    pub dropped: u32,
+}
+
+/// A layer rendered to a region of the layer image, with its effect applied.
+pub struct EffectLayer {
+    /// Contents of the layer, translated so that the top left corner of the
+    /// region is at the origin.
+    pub encoding: Encoding,
+    /// Horizontal location of the region in the layer image.
+    pub x: u32,
+    /// Vertical location of the region in the layer image.
+    pub y: u32,
+    /// Width of the region.
+    pub width: u32,
+    /// Height of the region.
+    pub height: u32,
+    /// Effect of the layer, with lengths in pixels.
+    pub effect: EffectData,
+}
+
+/// Splits the layers with effects from a scene encoding.
+///
+/// The contents of each layer with an effect are moved to an encoding of their
+/// own, to be rendered to a region of the layer image and filtered there. In
+/// the returned encoding, the layer is replaced by a draw of that region, in a
+/// layer with the blend mode and alpha of the original. Nested layers are
+/// rendered first, so that their regions can be drawn by the layers that
+/// contain them. Patterns are expanded, as the regions are drawn as images.
+///
+/// `viewport` bounds the drawing area. Regions only cover the parts of layers
+/// that can affect it. Layers that don't fit in the layer image, which is at
+/// most 8192 pixels on a side, are drawn without their effect and counted in
+/// [`EffectLayers::dropped`].
+///
+/// Fails if the patterns can't be expanded, see [`expand_patterns`].
+pub fn split_effects(
+    encoding: &Encoding,
+    viewport: [f32; 4],
+) -> Result<(Encoding, EffectLayers), PatternError> {
+    let expanded;
+    let encoding = if encoding.n_patterns != 0 {
+        expanded = expand_patterns(encoding, viewport)?;
+        &expanded
+    } else {
+        encoding
+    };
+    let mut allocator =
+        AtlasAllocator::new(size2(DEFAULT_LAYER_IMAGE_SIZE, DEFAULT_LAYER_IMAGE_SIZE));
+    let mut layers = vec![];
+    let mut frames = vec![Frame {
+        expander: Expander::new(encoding),
+        origin: [0.0; 2],
+        instance: None,
+        clip_stack: vec![viewport],
+        layer: None,
+    }];
+    // Number of open clips of a layer that is skipped because it is empty.
+    let mut skipped_clips = 0;
+    let mut dropped = 0;
+    for mut object in draw_objects(encoding)? {
+        // Effects are applied here, and not carried over to the split encodings.
+        let effect = object.effect.take();
+        if skipped_clips > 0 {
+            match object.tag {
+                DrawTag::BEGIN_CLIP => skipped_clips += 1,
+                DrawTag::END_CLIP => skipped_clips -= 1,
+                _ => {}
+            }
+            continue;
+        }
+        let frame = frames.last_mut().unwrap();
+        let visible = *frame.clip_stack.last().unwrap();
+        match object.tag {
+            DrawTag::BEGIN_CLIP => {
+                if let Some(ix) = effect {
+                    let effect = effect_in_pixels(encoding, &object, encoding.effects[ix]);
+                    // The blur reaches three standard deviations, and the shadow is offset.
+                    let pad = (3.0 * effect.std_dev).ceil()
+                        + effect.offset[0].abs().max(effect.offset[1].abs()).ceil();
+                    let region =
+                        round_out(intersect(inflate(object.bbox, pad), inflate(visible, pad)));
+                    let (width, height) = (region[2] - region[0], region[3] - region[1]);
+                    if width <= 0.0 || height <= 0.0 {
+                        skipped_clips = 1;
+                        continue;
+                    }
+                    if let Some(location) = allocate(&mut allocator, width as i32, height as i32) {
+                        let begin_clip = encoding.draw_data[object.draw_data.clone()].to_vec();
+                        let mut frame = Frame {
+                            expander: Expander::new(encoding),
+                            origin: [region[0], region[1]],
+                            instance: Some(Transform {
+                                translation: [-region[0], -region[1]],
+                                ..Transform::IDENTITY
+                            }),
+                            clip_stack: vec![region, intersect(region, object.bbox)],
+                            layer: Some(PendingLayer {
+                                location,
+                                region,
+                                effect,
+                                begin_clip,
+                            }),
+                        };
+                        // The shape of the layer clips its contents, while its blend mode
+                        // and alpha apply where the region is drawn.
+                        frame.append(&object);
+                        let draw_data = &mut frame.expander.expanded.draw_data;
+                        let clip_start = draw_data.len() - std::mem::size_of::<DrawBeginClip>();
+                        draw_data[clip_start..].copy_from_slice(bytemuck::bytes_of(
+                            &DrawBeginClip::new(Mix::Clip.into(), 1.0),
+                        ));
+                        frames.push(frame);
+                        continue;
+                    }
+                    dropped += 1;
+                }
+                frame.clip_stack.push(intersect(visible, object.bbox));
+                frame.append(&object);
+            }
+            DrawTag::END_CLIP => {
+                if frame.layer.is_some() && frame.clip_stack.len() == 2 {
+                    frame.append(&object);
+                    let frame = frames.pop().unwrap();
+                    frame.finish(frames.last_mut().unwrap(), &mut layers);
+                    continue;
+                }
+                if frame.clip_stack.len() > 1 {
+                    frame.clip_stack.pop();
+                }
+                frame.append(&object);
+            }
+            _ => frame.append(&object),
+        }
+    }
+    // Layers that are still open are closed when they are rendered.
+    while frames.len() > 1 {
+        let mut frame = frames.pop().unwrap();
+        frame.expander.expanded.n_open_clips = frame.clip_stack.len() as u32 - 1;
+        frame.finish(frames.last_mut().unwrap(), &mut layers);
+    }
+    let root = frames.pop().unwrap();
+    let mut encoding = root.expander.expanded;
+    encoding.n_open_clips = root.clip_stack.len() as u32 - 1;
+    let size = allocator.size();
+    let layers = EffectLayers {
+        width: size.width as u32,
+        height: size.height as u32,
+        layers,
+        dropped,
+    };
+    Ok((encoding, layers))
+}
+
+/// Encoding in progress, for the scene or for the contents of a layer.
+struct Frame<'a> {
+    expander: Expander<'a>,
+    /// Top left corner of the region of the layer in scene coordinates.
+    origin: [f32; 2],
+    /// Translation from scene coordinates to those of the frame.
+    instance: Option<Transform>,
+    /// Bounds of the enclosing clips in scene coordinates, starting with the
+    /// region of the layer.
+    clip_stack: Vec<[f32; 4]>,
+    layer: Option<PendingLayer>,
+}
+
+/// A layer with an effect whose contents are being split.
+struct PendingLayer {
+    /// Location of the region in the layer image.
+    location: [u32; 2],
+    /// Region of the layer in scene coordinates.
+    region: [f32; 4],
+    effect: EffectData,
+    /// Draw data of the begin clip command of the layer.
+    begin_clip: Vec<u8>,
+}
+
+impl Frame<'_> {
+    /// Appends a draw object in the coordinates of the frame.
+    fn append(&mut self, object: &DrawObject) {
+        self.expander.append(object, self.instance, None);
+    }
+
+    /// Adds the layer of the frame to `layers`, and draws its region in `parent`.
+    fn finish(self, parent: &mut Frame, layers: &mut Vec<EffectLayer>) {
+        let layer = self.layer.unwrap();
+        let [x, y] = layer.location;
+        let region = layer.region;
+        let (width, height) = (
+            (region[2] - region[0]) as u32,
+            (region[3] - region[1]) as u32,
+        );
+        layers.push(EffectLayer {
+            encoding: self.expander.expanded,
+            x,
+            y,
+            width,
+            height,
+            effect: layer.effect,
+        });
+        let encoding = &mut parent.expander.expanded;
+        let rect = Rect::new(
+            (region[0] - parent.origin[0]) as f64,
+            (region[1] - parent.origin[1]) as f64,
+            (region[2] - parent.origin[0]) as f64,
+            (region[3] - parent.origin[1]) as f64,
+        );
+        encoding.encode_transform(Transform::IDENTITY);
+        encoding.encode_linewidth(-1.0);
+        encoding.encode_shape(&rect, true);
+        encoding.draw_tags.push(DrawTag::BEGIN_CLIP);
+        encoding.draw_data.extend_from_slice(&layer.begin_clip);
+        encoding.n_clips += 1;
+        encoding.n_open_clips += 1;
+        encoding.encode_shape(&rect, true);
+        let brush_transform = Transform {
+            translation: [rect.x0 as f32, rect.y0 as f32],
+            ..Transform::IDENTITY
+        };
+        if encoding.encode_transform(brush_transform) {
+            encoding.swap_last_path_tags();
+        }
+        let sampling = ImageSampling::new(Extend::Pad).with_quality(ImageQuality::Nearest);
+        encoding.draw_tags.push(DrawTag::IMAGE);
+        encoding
+            .draw_data
+            .extend_from_slice(bytemuck::bytes_of(&DrawImage {
+                xy: (x << 13) | y,
+                width_height: (width << 16) | height,
+                sampling: DrawImage::pack_sampling(sampling, 1.0) | DrawImage::SAMPLING_LAYER,
+            }));
+        encoding.encode_end_clip();
+    }
+}
+
+/// Converts the lengths of an effect to pixels, with the transform of the
+/// shape of its layer.
+fn effect_in_pixels(encoding: &Encoding, object: &DrawObject, effect: EffectData) -> EffectData {
+    // The transform of the shape is encoded ahead of it, within the object.
+    let n_transforms = encoding.path_tags[object.path_tags.clone()]
+        .iter()
+        .filter(|tag| **tag == PathTag::TRANSFORM)
+        .count();
+    let transform = match (object.transform, n_transforms) {
+        (ix, 0) => ix,
+        (Some(ix), n) => Some(ix + n),
+        (None, n) => Some(n - 1),
+    }
+    .map_or(Transform::IDENTITY, |ix| encoding.transforms[ix]);
+    let m = transform.matrix;
+    let scale = (m[0] * m[3] - m[1] * m[2]).abs().sqrt();
+    let [x, y] = effect.offset;
+    EffectData {
+        std_dev: effect.std_dev.abs() * scale,
+        offset: [m[0] * x + m[2] * y, m[1] * x + m[3] * y],
+        ..effect
+    }
+}
+
+/// Allocates a region of the layer image, growing it as needed.
+fn allocate(allocator: &mut AtlasAllocator, width: i32, height: i32) -> Option<[u32; 2]> {
+    if width > MAX_LAYER_IMAGE_SIZE || height > MAX_LAYER_IMAGE_SIZE {
+        return None;
+    }
+    loop {
+        if let Some(alloc) = allocator.allocate(size2(width, height)) {
+            return Some([alloc.rectangle.min.x as u32, alloc.rectangle.min.y as u32]);
+        }
+        let new_size = allocator.size().width * 2;
+        if new_size > MAX_LAYER_IMAGE_SIZE {
+            return None;
+        }
+        allocator.grow(size2(new_size, new_size));
+    }
+}
+
+fn round_out(bbox: [f32; 4]) -> [f32; 4] {
+    [
+        bbox[0].floor(),
+        bbox[1].floor(),
+        bbox[2].ceil(),
+        bbox[3].ceil(),
+    ]
+}
+
+fn inflate(bbox: [f32; 4], pad: f32) -> [f32; 4] {
+    [bbox[0] - pad, bbox[1] - pad, bbox[2] + pad, bbox[3] + pad]
+}
+
+fn intersect(a: [f32; 4], b: [f32; 4]) -> [f32; 4] {
+    [
+        a[0].max(b[0]),
+        a[1].max(b[1]),
+        a[2].min(b[2]),
+        a[3].min(b[3]),
+    ]
+}
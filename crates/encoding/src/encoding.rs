@@ -1,17 +1,20 @@
 // Copyright 2022 The Vello authors
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
-use crate::math::PatternData;
+use crate::math::{PatternData, PatternInstance};
 
-use super::{DrawColor, DrawTag, PathEncoder, PathTag, Transform};
+use super::{DrawBlurRoundedRect, DrawColor, DrawTag, EffectData, PathEncoder, PathTag, Transform};
 
-use peniko::{kurbo::{Shape, Vec2}, BlendMode, BrushRef, Color};
+use peniko::{kurbo::Shape, BlendMode, BrushRef, Color, ColorStop};
 
 #[cfg(feature = "full")]
 use {
-    super::{DrawImage, DrawLinearGradient, DrawRadialGradient, Glyph, GlyphRun, Patch},
+    super::{
+        DrawImage, DrawLinearGradient, DrawRadialGradient, DrawSweepGradient, Glyph, GlyphRun,
+        ImageSampling, Patch,
+    },
     fello::NormalizedCoord,
-    peniko::{ColorStop, Extend, GradientKind, Image},
+    peniko::{Extend, GradientKind, Image},
 };
 
 /// Encoded data streams for a scene.
@@ -29,6 +32,18 @@ pub struct Encoding {
     pub transforms: Vec<Transform>,
     /// The pattern stream.
     pub pattern_data: Vec<PatternData>,
+    /// Color stops for per-cell pattern colors.
+    pub pattern_colors: Vec<ColorStop>,
+    /// Transforms of the cells of placed patterns, from the space of a cell to
+    /// pattern space.
+    pub pattern_placements: Vec<Transform>,
+    /// Copies of the draw objects of patterns, in the order of their draw
+    /// objects. Only encodings returned by [`instance_patterns`] have them.
+    ///
+    /// [`instance_patterns`]: crate::instance_patterns
+    pub pattern_instances: Vec<PatternInstance>,
+    /// Effects of layers, in the order of their begin clip draw tags.
+    pub effects: Vec<EffectData>,
     /// The line width stream.
     pub linewidths: Vec<f32>,
     /// Late bound resource data.
@@ -44,6 +59,9 @@ pub struct Encoding {
     pub n_open_clips: u32,
     /// Number of encoded pattern segments
     pub n_patterns: u32,
+    /// Number of paths at the start of the encoding that hold the geometry of
+    /// [`pattern_instances`](Self::pattern_instances) and aren't drawn.
+    pub n_template_paths: u32,
 }
 
 impl Encoding {
@@ -61,6 +79,10 @@ impl Encoding {
     pub fn reset(&mut self, is_fragment: bool) {
         self.transforms.clear();
         self.pattern_data.clear();
+        self.pattern_colors.clear();
+        self.pattern_placements.clear();
+        self.pattern_instances.clear();
+        self.effects.clear();
         self.path_tags.clear();
         self.path_data.clear();
         self.linewidths.clear();
@@ -70,6 +92,7 @@ impl Encoding {
         self.n_path_segments = 0;
         self.n_clips = 0;
         self.n_patterns = 0;
+        self.n_template_paths = 0;
         self.n_open_clips = 0;
         #[cfg(feature = "full")]
         self.resources.reset();
@@ -80,6 +103,12 @@ impl Encoding {
     }
 
     /// Appends another encoding to this one with an optional transform.
+    ///
+    /// The transform applies to the paths, glyph runs and pattern lattices of `other`,
+    /// except for the contents of patterns, which are in pattern space.
+    ///
+    /// Pattern instances aren't appended, as they refer to the paths of an
+    /// encoding that is rendered as is.
     pub fn append(&mut self, other: &Self, transform: &Option<Transform>) {
         #[cfg(feature = "full")]
         let glyph_runs_base = {
@@ -138,6 +167,12 @@ impl Encoding {
                 .extend_from_slice(&other.resources.color_stops);
             glyph_runs_base
         };
+        let draw_tags_base = self.draw_tags.len() as u32;
+        self.effects
+            .extend(other.effects.iter().map(|effect| EffectData {
+                draw_tag_ix: effect.draw_tag_ix + draw_tags_base,
+                ..*effect
+            }));
         self.path_tags.extend_from_slice(&other.path_tags);
         self.path_data.extend_from_slice(&other.path_data);
         self.draw_tags.extend_from_slice(&other.draw_tags);
@@ -147,17 +182,49 @@ impl Encoding {
         self.n_clips += other.n_clips;
         self.n_patterns += other.n_patterns;
         self.n_open_clips += other.n_open_clips;
+        let colors_base = self.pattern_colors.len() as u32;
+        let placements_base = self.pattern_placements.len() as u32;
+        let patterns_base = self.pattern_data.len();
+        self.pattern_data
+            .extend(other.pattern_data.iter().map(|pattern| PatternData {
+                placements: pattern.placements.map(|ix| ix + placements_base),
+                color_stops: pattern.color_stops.map(|ix| ix + colors_base),
+                ..*pattern
+            }));
         if let Some(transform) = *transform {
+            // The contents of patterns are in pattern space, which follows the lattice, so
+            // only the transforms outside of patterns and the outermost lattices change.
+            let spaces = if other.n_patterns != 0 {
+                other.pattern_spaces()
+            } else {
+                PatternSpaces::default()
+            };
             self.transforms
-                .extend(other.transforms.iter().map(|x| transform * *x));
+                .extend(other.transforms.iter().enumerate().map(|(ix, x)| {
+                    if spaces.transforms.get(ix) == Some(&true) {
+                        *x
+                    } else {
+                        transform * *x
+                    }
+                }));
+            for (ix, pattern) in self.pattern_data[patterns_base..].iter_mut().enumerate() {
+                if spaces.patterns.get(ix) != Some(&true) {
+                    pattern.transform = transform * pattern.transform;
+                }
+            }
             #[cfg(feature = "full")]
             for run in &mut self.resources.glyph_runs[glyph_runs_base..] {
-                run.transform = transform * run.transform;
+                let draw_tag_ix = run.stream_offsets.draw_tags - draw_tags_base as usize;
+                if spaces.draw_tags.get(draw_tag_ix) != Some(&true) {
+                    run.transform = transform * run.transform;
+                }
             }
         } else {
             self.transforms.extend_from_slice(&other.transforms);
         }
-        self.pattern_data.extend_from_slice(&other.pattern_data);
+        self.pattern_colors.extend_from_slice(&other.pattern_colors);
+        self.pattern_placements
+            .extend_from_slice(&other.pattern_placements);
         self.linewidths.extend_from_slice(&other.linewidths);
     }
 
@@ -174,6 +241,62 @@ impl Encoding {
         }
     }
 
+    /// Returns which transforms, patterns and draw objects are inside of a pattern, where
+    /// they are in the pattern space of the enclosing pattern.
+    ///
+    /// A transform belongs to the draw object whose path follows it.
+    fn pattern_spaces(&self) -> PatternSpaces {
+        // Glyph runs have no paths in the encoding, they are inserted when resolving.
+        #[cfg(feature = "full")]
+        let mut is_glyph_run = {
+            let mut glyph_runs = self
+                .resources
+                .glyph_runs
+                .iter()
+                .map(|run| run.stream_offsets.draw_tags)
+                .peekable();
+            move |ix| glyph_runs.next_if_eq(&ix).is_some()
+        };
+        #[cfg(not(feature = "full"))]
+        let is_glyph_run = |_| false;
+        let mut spaces = PatternSpaces::default();
+        // Whether each draw object with a path is inside of a pattern.
+        let mut paths = vec![];
+        let mut depth = 0u32;
+        for (ix, &tag) in self.draw_tags.iter().enumerate() {
+            spaces.draw_tags.push(depth > 0);
+            if is_glyph_run(ix) {
+                continue;
+            }
+            match tag {
+                DrawTag::BEGIN_PATTERN => {
+                    spaces.patterns.push(depth > 0);
+                    depth += 1;
+                }
+                DrawTag::END_PATTERN => depth = depth.saturating_sub(1),
+                DrawTag::NOP => {}
+                _ => paths.push(depth > 0),
+            }
+        }
+        // Scenes, unlike fragments, start with a transform that has no tag.
+        let n_transform_tags = self
+            .path_tags
+            .iter()
+            .filter(|tag| **tag == PathTag::TRANSFORM)
+            .count();
+        spaces.transforms = vec![false; self.transforms.len() - n_transform_tags];
+        let mut paths = paths.into_iter();
+        let mut in_pattern = paths.next().unwrap_or(false);
+        for &tag in &self.path_tags {
+            if tag == PathTag::TRANSFORM {
+                spaces.transforms.push(in_pattern);
+            } else if tag == PathTag::PATH {
+                in_pattern = paths.next().unwrap_or(false);
+            }
+        }
+        spaces
+    }
+
     /// Encodes a linewidth.
     pub fn encode_linewidth(&mut self, linewidth: f32) {
         if self.linewidths.last() != Some(&linewidth) {
@@ -263,8 +386,22 @@ impl Encoding {
                         gradient.extend,
                     );
                 }
-                GradientKind::Sweep { .. } => {
-                    todo!("sweep gradients aren't supported yet!")
+                GradientKind::Sweep {
+                    center,
+                    start_angle,
+                    end_angle,
+                } => {
+                    self.encode_sweep_gradient(
+                        DrawSweepGradient {
+                            index: 0,
+                            p0: point_to_f32(center),
+                            t0: start_angle,
+                            t1: end_angle,
+                        },
+                        gradient.stops.iter().copied(),
+                        alpha,
+                        gradient.extend,
+                    );
                 }
             },
             #[cfg(feature = "full")]
@@ -283,6 +420,31 @@ impl Encoding {
         self.draw_data.extend_from_slice(bytemuck::bytes_of(&color));
     }
 
+    /// Encodes a rounded rectangle convolved with a gaussian filter, centered at
+    /// the origin of the brush transform.
+    ///
+    /// The coverage is computed analytically, so this is much cheaper than
+    /// blurring a layer. The path should cover the rectangle expanded by about
+    /// three standard deviations.
+    pub fn encode_blurred_rounded_rect(
+        &mut self,
+        color: Color,
+        width: f32,
+        height: f32,
+        radius: f32,
+        std_dev: f32,
+    ) {
+        self.draw_tags.push(DrawTag::BLUR_RECT);
+        self.draw_data
+            .extend_from_slice(bytemuck::bytes_of(&DrawBlurRoundedRect {
+                color: DrawColor::new(color),
+                width,
+                height,
+                radius,
+                std_dev,
+            }));
+    }
+
     /// Encodes a linear gradient brush.
     #[cfg(feature = "full")]
     pub fn encode_linear_gradient(
@@ -328,11 +490,40 @@ impl Encoding {
         }
     }
 
-    /// Encodes an image brush.
+    /// Encodes a sweep gradient brush.
     #[cfg(feature = "full")]
-    pub fn encode_image(&mut self, image: &Image, _alpha: f32) {
-        // TODO: feed the alpha multiplier through the full pipeline for consistency
-        // with other brushes?
+    pub fn encode_sweep_gradient(
+        &mut self,
+        gradient: DrawSweepGradient,
+        color_stops: impl Iterator<Item = ColorStop>,
+        alpha: f32,
+        extend: Extend,
+    ) {
+        if gradient.t0 == gradient.t1 {
+            self.encode_color(DrawColor::new(Color::TRANSPARENT));
+            return;
+        }
+        match self.add_ramp(color_stops, alpha, extend) {
+            RampStops::Empty => self.encode_color(DrawColor::new(Color::TRANSPARENT)),
+            RampStops::One(color) => self.encode_color(DrawColor::new(color)),
+            _ => {
+                self.draw_tags.push(DrawTag::SWEEP_GRADIENT);
+                self.draw_data
+                    .extend_from_slice(bytemuck::bytes_of(&gradient));
+            }
+        }
+    }
+
+    /// Encodes an image brush, sampled bilinearly and extended in both
+    /// directions with the extend mode of the image.
+    #[cfg(feature = "full")]
+    pub fn encode_image(&mut self, image: &Image, alpha: f32) {
+        self.encode_image_sampled(image, alpha, ImageSampling::new(image.extend));
+    }
+
+    /// Encodes an image brush with the specified sampling parameters.
+    #[cfg(feature = "full")]
+    pub fn encode_image_sampled(&mut self, image: &Image, alpha: f32, sampling: ImageSampling) {
         self.resources.patches.push(Patch::Image {
             image: image.clone(),
             draw_data_offset: self.draw_data.len(),
@@ -342,22 +533,53 @@ impl Encoding {
             .extend_from_slice(bytemuck::bytes_of(&DrawImage {
                 xy: 0,
                 width_height: (image.width << 16) | (image.height & 0xFFFF),
+                sampling: DrawImage::pack_sampling(sampling, alpha),
             }));
     }
 
-    ///Encode a begin of pattern command.
-    /// start pivot offset from path boundary
-    pub fn encode_begin_pattern(&mut self, start: Vec2, box_scale:Vec2, rotation: f32){
+    /// Encodes a begin pattern command.
+    ///
+    /// The paths up to the matching end pattern command are repeated along the
+    /// lattice of `pattern`, across the bounds of the enclosing layer.
+    /// `color_stops` are the per-cell colors selected by `pattern.colors`, and
+    /// `placements` the transforms of the cells with `TILING_PLACEMENTS`.
+    /// With `OVERFLOW_CLIP`, the contents must start with the layer that clips
+    /// each cell.
+    pub fn encode_begin_pattern(
+        &mut self,
+        mut pattern: PatternData,
+        color_stops: impl Iterator<Item = ColorStop>,
+        placements: impl Iterator<Item = Transform>,
+    ) {
+        let start = self.pattern_colors.len() as u32;
+        self.pattern_colors.extend(color_stops);
+        pattern.color_stops = [start, self.pattern_colors.len() as u32];
+        let start = self.pattern_placements.len() as u32;
+        self.pattern_placements.extend(placements);
+        pattern.placements = [start, self.pattern_placements.len() as u32];
         self.draw_tags.push(DrawTag::BEGIN_PATTERN);
-        self.pattern_data.push(PatternData { start: [start.x as f32, start.y as f32], box_scale: [box_scale.x as f32, box_scale.y as f32], rotate: rotation });
+        self.pattern_data.push(pattern);
         self.n_patterns += 1;
+        self.encode_pattern_boundary();
     }
 
     ///Encode a end of pattern command.
     /// start pivot offset from path boundary
-    pub fn encode_end_pattern(&mut self){
+    pub fn encode_end_pattern(&mut self) {
         self.draw_tags.push(DrawTag::END_PATTERN);
         self.n_patterns += 1;
+        self.encode_pattern_boundary();
+    }
+
+    /// Encodes the current transform again, so that paths on either side of a pattern
+    /// boundary don't share a transform. The contents of a pattern are in pattern space,
+    /// and only the transforms outside of it change when the encoding is appended with a
+    /// transform.
+    fn encode_pattern_boundary(&mut self) {
+        if let Some(&transform) = self.transforms.last() {
+            self.path_tags.push(PathTag::TRANSFORM);
+            self.transforms.push(transform);
+        }
     }
 
     /// Encodes a begin clip command.
@@ -370,6 +592,17 @@ impl Encoding {
         self.n_open_clips += 1;
     }
 
+    /// Encodes an effect for the layer begun by the preceding begin clip
+    /// command.
+    ///
+    /// The effect is applied by splitting the layer from the encoding with
+    /// [`split_effects`](crate::split_effects) before it is rendered.
+    pub fn encode_layer_effect(&mut self, mut effect: EffectData) {
+        debug_assert!(self.draw_tags.last() == Some(&DrawTag::BEGIN_CLIP));
+        effect.draw_tag_ix = self.draw_tags.len() as u32 - 1;
+        self.effects.push(effect);
+    }
+
     /// Encodes an end clip command.
     pub fn encode_end_clip(&mut self) {
         if self.n_open_clips > 0 {
@@ -489,3 +722,132 @@ impl StreamOffsets {
         self.patterns += other.patterns;
     }
 }
+
+/// Parts of an encoding that are in the pattern space of an enclosing pattern, indexed
+/// like the streams of the encoding.
+#[derive(Default)]
+struct PatternSpaces {
+    transforms: Vec<bool>,
+    patterns: Vec<bool>,
+    draw_tags: Vec<bool>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use peniko::kurbo::{Affine, Rect};
+
+    fn fill_square(encoding: &mut Encoding, transform: Transform) {
+        encoding.encode_transform(transform);
+        encoding.encode_shape(&Rect::new(0.0, 0.0, 4.0, 4.0), true);
+        encoding.encode_color(DrawColor::new(Color::rgb8(0, 0, 255)));
+    }
+
+    fn pattern(transform: Affine, tiling: u32) -> PatternData {
+        PatternData {
+            transform: Transform::from_kurbo(&transform),
+            x_step: [8.0, 1.0],
+            y_step: [-2.0, 6.0],
+            extend: PatternData::EXTEND_REPEAT,
+            tiling,
+            offset_jitter: [1.5, 0.5],
+            ..bytemuck::Zeroable::zeroed()
+        }
+    }
+
+    fn stops(n: usize) -> impl Iterator<Item = ColorStop> {
+        (0..n).map(|i| ColorStop {
+            offset: i as f32,
+            color: Color::rgb8(i as u8, 0, 0),
+        })
+    }
+
+    fn placements(n: usize) -> impl Iterator<Item = Transform> {
+        (0..n).map(|i| Transform::from_kurbo(&Affine::translate((i as f64 * 10.0, 0.0))))
+    }
+
+    /// A fragment with a square outside of a pattern with per-cell colors and placements,
+    /// which contains a square and a nested pattern.
+    fn fragment() -> Encoding {
+        let mut fragment = Encoding::new();
+        fill_square(
+            &mut fragment,
+            Transform::from_kurbo(&Affine::translate((3.0, 4.0))),
+        );
+        let outer = PatternData {
+            colors: PatternData::COLORS_LIST,
+            ..pattern(Affine::rotate(0.5), PatternData::TILING_PLACEMENTS)
+        };
+        fragment.encode_begin_pattern(outer, stops(2), placements(3));
+        fill_square(&mut fragment, Transform::from_kurbo(&Affine::scale(0.5)));
+        let inner = pattern(Affine::skew(0.25, 0.0), PatternData::TILING_GRID);
+        fragment.encode_begin_pattern(inner, stops(0), placements(0));
+        fill_square(&mut fragment, Transform::IDENTITY);
+        fragment.encode_end_pattern();
+        fragment.encode_end_pattern();
+        fragment
+    }
+
+    #[test]
+    fn append_composes_pattern_transforms() {
+        // A scene that already has a pattern, so that the indices of the fragment change.
+        let mut scene = Encoding::new();
+        scene.reset(false);
+        let existing = PatternData {
+            colors: PatternData::COLORS_LIST,
+            ..pattern(Affine::IDENTITY, PatternData::TILING_PLACEMENTS)
+        };
+        scene.encode_begin_pattern(existing, stops(1), placements(2));
+        fill_square(&mut scene, Transform::IDENTITY);
+        scene.encode_end_pattern();
+
+        let fragment = fragment();
+        let transform = Affine::translate((20.0, -5.0))
+            * Affine::scale_non_uniform(2.0, 0.5)
+            * Affine::skew(0.0, 0.75);
+        let transform = Transform::from_kurbo(&transform);
+        let transforms_base = scene.transforms.len();
+        scene.append(&fragment, &Some(transform));
+
+        assert_eq!(scene.n_patterns, 6);
+        let [outer, inner] = [scene.pattern_data[1], scene.pattern_data[2]];
+        let [fragment_outer, fragment_inner] = [fragment.pattern_data[0], fragment.pattern_data[1]];
+        // Only the outermost lattice moves, the nested one is in its pattern space.
+        assert_eq!(outer.transform, transform * fragment_outer.transform);
+        assert_eq!(inner.transform, fragment_inner.transform);
+        // Steps and jitter are in pattern space, and move with the lattice.
+        for (pattern, original) in [(outer, fragment_outer), (inner, fragment_inner)] {
+            assert_eq!(pattern.x_step, original.x_step);
+            assert_eq!(pattern.y_step, original.y_step);
+            assert_eq!(pattern.offset_jitter, original.offset_jitter);
+        }
+        let cell = |pattern: &PatternData, i: f32, j: f32| {
+            let x = i * pattern.x_step[0] + j * pattern.y_step[0];
+            let y = i * pattern.x_step[1] + j * pattern.y_step[1];
+            pattern.transform.apply([x, y])
+        };
+        for (i, j) in [(0.0, 0.0), (2.0, -1.0), (-3.0, 4.0)] {
+            let expected = transform.apply(cell(&fragment_outer, i, j));
+            let actual = cell(&outer, i, j);
+            for (a, e) in actual.iter().zip(expected) {
+                assert!((a - e).abs() < 1e-3, "{actual:?} != {expected:?}");
+            }
+        }
+        // Placements and colors follow those of the existing pattern.
+        assert_eq!(outer.placements, [2, 5]);
+        assert_eq!(outer.color_stops, [1, 3]);
+        assert_eq!(inner.placements, [5, 5]);
+        assert_eq!(inner.color_stops, [3, 3]);
+        assert_eq!(
+            scene.pattern_placements[2..],
+            fragment.pattern_placements[..]
+        );
+        assert_eq!(scene.pattern_colors[1..], fragment.pattern_colors[..]);
+        // The square outside of the pattern is transformed, those inside are not.
+        let transforms = &scene.transforms[transforms_base..];
+        assert_eq!(transforms[0], transform * fragment.transforms[0]);
+        let content = Transform::from_kurbo(&Affine::scale(0.5));
+        assert!(transforms.contains(&content));
+        assert!(!transforms.contains(&(transform * content)));
+    }
+}
@@ -3,10 +3,11 @@
 
 use std::collections::HashMap;
 
-use super::{Encoding, StreamOffsets};
+use super::{stroke_to_fill, CacheStats, Encoding, StreamOffsets};
 
-use fello::scale::Scaler;
+use fello::scale::{Pen, Scaler};
 use fello::GlyphId;
+use peniko::kurbo::BezPath;
 use peniko::{Fill, Style};
 
 #[derive(Copy, Clone, PartialEq, Eq, Hash, Default, Debug)]
@@ -18,16 +19,34 @@ pub struct GlyphKey {
     pub hint: bool,
 }
 
+/// Number of cached glyphs above which the cache is cleared when advancing.
+const MAX_CACHED_GLYPHS: usize = 8192;
+
 #[derive(Default)]
 pub struct GlyphCache {
     pub encoding: Encoding,
     glyphs: HashMap<GlyphKey, CachedRange>,
+    /// True if the encoding contains glyphs that aren't in the map.
+    has_uncached: bool,
+    pub stats: CacheStats,
 }
 
 impl GlyphCache {
     pub fn clear(&mut self) {
         self.encoding.reset(true);
         self.glyphs.clear();
+        self.has_uncached = false;
+    }
+
+    /// Prepares the cache for the next frame.
+    ///
+    /// Cached glyphs are kept, unless there are too many of them. Glyphs that
+    /// can't be cached are encoded again in each frame, so the encoding is
+    /// rebuilt if the last frame had any.
+    pub fn advance(&mut self) {
+        if self.has_uncached || self.glyphs.len() > MAX_CACHED_GLYPHS {
+            self.clear();
+        }
     }
 
     pub fn get_or_insert(
@@ -41,15 +60,31 @@ impl GlyphCache {
         let encoding_cache = &mut self.encoding;
         let mut encode_glyph = || {
             let start = encoding_cache.stream_offsets();
-            match style {
-                Style::Fill(Fill::NonZero) => encoding_cache.encode_linewidth(-1.0),
-                Style::Fill(Fill::EvenOdd) => encoding_cache.encode_linewidth(-2.0),
-                Style::Stroke(stroke) => encoding_cache.encode_linewidth(stroke.width),
-            }
-            let mut path = encoding_cache.encode_path(is_fill);
-            scaler
-                .outline(GlyphId::new(key.glyph_id as u16), &mut path)
-                .ok()?;
+            let path = match style {
+                Style::Fill(fill) => {
+                    encoding_cache.encode_linewidth(match fill {
+                        Fill::NonZero => -1.0,
+                        Fill::EvenOdd => -2.0,
+                    });
+                    let mut path = encoding_cache.encode_path(is_fill);
+                    scaler
+                        .outline(GlyphId::new(key.glyph_id as u16), &mut path)
+                        .ok()?;
+                    path
+                }
+                Style::Stroke(stroke) => {
+                    // Strokes are expanded into fills, as for other shapes.
+                    let mut outline = BezPathPen(BezPath::new());
+                    scaler
+                        .outline(GlyphId::new(key.glyph_id as u16), &mut outline)
+                        .ok()?;
+                    let outline = stroke_to_fill(outline.0, stroke, 0.1);
+                    encoding_cache.encode_linewidth(-1.0);
+                    let mut path = encoding_cache.encode_path(true);
+                    path.shape(&outline);
+                    path
+                }
+            };
             if path.finish(false) == 0 {
                 return None;
             }
@@ -61,16 +96,54 @@ impl GlyphCache {
         let range = if matches!(style, Style::Fill(Fill::NonZero)) && !is_var {
             use std::collections::hash_map::Entry;
             match self.glyphs.entry(key) {
-                Entry::Occupied(entry) => *entry.get(),
-                Entry::Vacant(entry) => *entry.insert(encode_glyph()?),
+                Entry::Occupied(entry) => {
+                    self.stats.record(true);
+                    *entry.get()
+                }
+                Entry::Vacant(entry) => {
+                    self.stats.record(false);
+                    *entry.insert(encode_glyph()?)
+                }
             }
         } else {
+            self.stats.record(false);
+            self.has_uncached = true;
             encode_glyph()?
         };
         Some(range)
     }
 }
 
+/// Collects a glyph outline into a path.
+struct BezPathPen(BezPath);
+
+impl Pen for BezPathPen {
+    fn move_to(&mut self, x: f32, y: f32) {
+        self.0.move_to((x as f64, y as f64));
+    }
+
+    fn line_to(&mut self, x: f32, y: f32) {
+        self.0.line_to((x as f64, y as f64));
+    }
+
+    fn quad_to(&mut self, cx0: f32, cy0: f32, x: f32, y: f32) {
+        self.0
+            .quad_to((cx0 as f64, cy0 as f64), (x as f64, y as f64));
+    }
+
+    fn curve_to(&mut self, cx0: f32, cy0: f32, cx1: f32, cy1: f32, x: f32, y: f32) {
+        self.0.curve_to(
+            (cx0 as f64, cy0 as f64),
+            (cx1 as f64, cy1 as f64),
+            (x as f64, y as f64),
+        );
+    }
+
+    fn close(&mut self) {
+        self.0.close_path();
+    }
+}
+
 #[derive(Copy, Clone, Default, Debug)]
 pub struct CachedRange {
     pub start: StreamOffsets,
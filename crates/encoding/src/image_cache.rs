@@ -1,26 +1,50 @@
 // Copyright 2022 The Vello authors
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
-use guillotiere::{size2, AtlasAllocator};
+use guillotiere::{size2, AllocId, AtlasAllocator};
 use peniko::Image;
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::HashMap;
 
 const DEFAULT_ATLAS_SIZE: i32 = 1024;
 const MAX_ATLAS_SIZE: i32 = 8192;
+/// Maximum number of atlas pages. The page index is packed into 6 bits of the
+/// draw data.
+const MAX_ATLAS_PAGES: usize = 64;
 
 #[derive(Default)]
 pub struct Images<'a> {
+    /// Width of each atlas page.
     pub width: u32,
+    /// Height of each atlas page.
     pub height: u32,
-    pub images: &'a [(Image, u32, u32)],
+    /// Number of atlas pages.
+    pub pages: u32,
+    /// Images in the atlas with their locations.
+    pub images: &'a [(Image, u32, u32, u32)],
+    /// Images allocated since the cache was last advanced, which need to be
+    /// written to the atlas. Other images keep their locations until they are
+    /// evicted, including when the atlas grows.
+    pub dirty: &'a [(Image, u32, u32, u32)],
+}
+
+/// Atlas allocation of a cached image.
+struct Slot {
+    alloc: AllocId,
+    /// Frame in which the image was last used.
+    last_used: u64,
 }
 
 pub struct ImageCache {
-    atlas: AtlasAllocator,
-    /// Map from image blob id to atlas location.
-    map: HashMap<u64, (u32, u32)>,
+    /// Allocator for each atlas page. All pages have the same size.
+    pages: Vec<AtlasAllocator>,
+    /// Map from image blob id to index in `images`.
+    map: HashMap<u64, usize>,
     /// List of all allocated images with associated atlas location.
-    images: Vec<(Image, u32, u32)>,
+    images: Vec<(Image, u32, u32, u32)>,
+    /// Allocations of the images in `images`.
+    slots: Vec<Slot>,
+    dirty: Vec<(Image, u32, u32, u32)>,
+    frame: u64,
 }
 
 impl Default for ImageCache {
@@ -32,49 +56,187 @@ impl Default for ImageCache {
 impl ImageCache {
     pub fn new() -> Self {
         Self {
-            atlas: AtlasAllocator::new(size2(DEFAULT_ATLAS_SIZE, DEFAULT_ATLAS_SIZE)),
+            pages: vec![AtlasAllocator::new(size2(
+                DEFAULT_ATLAS_SIZE,
+                DEFAULT_ATLAS_SIZE,
+            ))],
             map: Default::default(),
             images: Default::default(),
+            slots: Default::default(),
+            dirty: Default::default(),
+            frame: 0,
         }
     }
 
     pub fn images(&self) -> Images {
+        let size = self.pages[0].size();
         Images {
-            width: self.atlas.size().width as u32,
-            height: self.atlas.size().height as u32,
+            width: size.width as u32,
+            height: size.height as u32,
+            pages: self.pages.len() as u32,
             images: &self.images,
+            dirty: &self.dirty,
         }
     }
 
-    pub fn bump_size(&mut self) -> bool {
-        let new_size = self.atlas.size().width * 2;
-        if new_size > MAX_ATLAS_SIZE {
+    /// Advances to the next frame. Images that are not used in the new frame
+    /// may be evicted to make room for others.
+    pub fn advance(&mut self) {
+        self.frame += 1;
+        self.dirty.clear();
+    }
+
+    pub fn contains(&self, image: &Image) -> bool {
+        self.map.contains_key(&image.data.id())
+    }
+
+    /// Returns the location of the image in the atlas as x, y and page,
+    /// allocating it if necessary.
+    ///
+    /// To make room, the least recently used images of previous frames are
+    /// evicted first. The atlas then grows up to the maximum size, and finally
+    /// pages are added. Returns `None` if the image can't be allocated.
+    pub fn get_or_insert(&mut self, image: &Image) -> Option<(u32, u32, u32)> {
+        if let Some(&index) = self.map.get(&image.data.id()) {
+            self.slots[index].last_used = self.frame;
+            let (_, x, y, page) = self.images[index];
+            return Some((x, y, page));
+        }
+        if image.width > MAX_ATLAS_SIZE as u32 || image.height > MAX_ATLAS_SIZE as u32 {
+            return None;
+        }
+        let size = size2(image.width as _, image.height as _);
+        loop {
+            for (page, atlas) in self.pages.iter_mut().enumerate() {
+                if let Some(alloc) = atlas.allocate(size) {
+                    let location = (
+                        alloc.rectangle.min.x as u32,
+                        alloc.rectangle.min.y as u32,
+                        page as u32,
+                    );
+                    let entry = (image.clone(), location.0, location.1, location.2);
+                    self.map.insert(image.data.id(), self.images.len());
+                    self.images.push(entry.clone());
+                    self.slots.push(Slot {
+                        alloc: alloc.id,
+                        last_used: self.frame,
+                    });
+                    self.dirty.push(entry);
+                    return Some(location);
+                }
+            }
+            if !self.evict_lru() && !self.grow() && !self.add_page() {
+                return None;
+            }
+        }
+    }
+
+    /// Evicts the least recently used image that wasn't used in the current
+    /// frame. Returns false if there is no such image.
+    fn evict_lru(&mut self) -> bool {
+        let Some(index) = self
+            .slots
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| slot.last_used < self.frame)
+            .min_by_key(|(_, slot)| slot.last_used)
+            .map(|(index, _)| index)
+        else {
             return false;
+        };
+        let (image, _, _, page) = self.images.swap_remove(index);
+        let slot = self.slots.swap_remove(index);
+        self.pages[page as usize].deallocate(slot.alloc);
+        self.map.remove(&image.data.id());
+        if let Some(moved) = self.images.get(index) {
+            self.map.insert(moved.0.data.id(), index);
         }
-        self.atlas = AtlasAllocator::new(size2(new_size, new_size));
-        self.map.clear();
-        self.images.clear();
         true
     }
 
-    pub fn clear(&mut self) {
-        self.atlas.clear();
-        self.map.clear();
-        self.images.clear();
+    /// Doubles the size of the atlas pages, keeping the locations of the
+    /// allocated images. Returns false if the pages are at the maximum size.
+    fn grow(&mut self) -> bool {
+        let new_size = self.pages[0].size().width * 2;
+        if new_size > MAX_ATLAS_SIZE {
+            return false;
+        }
+        for atlas in &mut self.pages {
+            atlas.grow(size2(new_size, new_size));
+        }
+        true
     }
 
-    pub fn get_or_insert(&mut self, image: &Image) -> Option<(u32, u32)> {
-        match self.map.entry(image.data.id()) {
-            Entry::Occupied(occupied) => Some(*occupied.get()),
-            Entry::Vacant(vacant) => {
-                let alloc = self
-                    .atlas
-                    .allocate(size2(image.width as _, image.height as _))?;
-                let x = alloc.rectangle.min.x as u32;
-                let y = alloc.rectangle.min.y as u32;
-                self.images.push((image.clone(), x, y));
-                Some(*vacant.insert((x, y)))
-            }
+    fn add_page(&mut self) -> bool {
+        if self.pages.len() >= MAX_ATLAS_PAGES {
+            return false;
         }
+        let size = self.pages[0].size();
+        self.pages.push(AtlasAllocator::new(size));
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use peniko::{Blob, Format};
+
+    use super::*;
+
+    /// An image of the given size. The cache only looks at the size and blob id,
+    /// so the pixel data is left empty.
+    fn image(width: u32, height: u32) -> Image {
+        Image::new(Blob::from(vec![]), Format::Rgba8, width, height)
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = ImageCache::new();
+        let [a, b, c] = [(); 3].map(|_| image(512, 1024));
+        let a_location = cache.get_or_insert(&a).unwrap();
+        cache.advance();
+        cache.get_or_insert(&b).unwrap();
+        cache.advance();
+        // Using `b` again leaves `a` as the least recently used image.
+        cache.get_or_insert(&b).unwrap();
+        assert_eq!(cache.get_or_insert(&c), Some(a_location));
+        assert!(!cache.contains(&a));
+        assert!(cache.contains(&b));
+        assert_eq!(cache.images().images.len(), 2);
+        assert_eq!(cache.images().dirty.len(), 1);
+    }
+
+    #[test]
+    fn growth_keeps_locations() {
+        let mut cache = ImageCache::new();
+        let a = image(1024, 1024);
+        let a_location = cache.get_or_insert(&a).unwrap();
+        cache.advance();
+        // `a` is used in this frame, so the atlas grows instead of evicting it.
+        cache.get_or_insert(&a).unwrap();
+        let b = image(16, 16);
+        let b_location = cache.get_or_insert(&b).unwrap();
+        let images = cache.images();
+        assert_eq!((images.width, images.height, images.pages), (2048, 2048, 1));
+        assert_eq!(cache.get_or_insert(&a), Some(a_location));
+        assert_ne!(b_location, a_location);
+        // Only the new image needs to be written.
+        let dirty = cache.images().dirty;
+        assert_eq!(dirty.len(), 1);
+        assert_eq!(dirty[0].0.data.id(), b.data.id());
+    }
+
+    #[test]
+    fn adds_pages_at_maximum_size() {
+        let mut cache = ImageCache::new();
+        let full = image(MAX_ATLAS_SIZE as u32, MAX_ATLAS_SIZE as u32);
+        assert_eq!(cache.get_or_insert(&full), Some((0, 0, 0)));
+        let small = image(16, 16);
+        assert_eq!(cache.get_or_insert(&small), Some((0, 0, 1)));
+        let images = cache.images();
+        assert_eq!(images.width, MAX_ATLAS_SIZE as u32);
+        assert_eq!(images.pages, 2);
+        let too_large = image(MAX_ATLAS_SIZE as u32 + 1, 1);
+        assert_eq!(cache.get_or_insert(&too_large), None);
     }
 }
@@ -7,6 +7,9 @@ mod binning;
 mod clip;
 mod config;
 mod draw;
+mod effect;
+#[cfg(feature = "full")]
+mod effect_layers;
 mod encoding;
 #[cfg(feature = "full")]
 mod glyph;
@@ -17,32 +20,45 @@ mod image_cache;
 mod math;
 mod monoid;
 mod path;
+mod pattern;
 #[cfg(feature = "full")]
 mod ramp_cache;
 mod resolve;
+#[cfg(feature = "full")]
+mod serialize;
+mod stroke;
 
 pub use binning::BinHeader;
 pub use clip::{Clip, ClipBbox, ClipBic, ClipElement};
 pub use config::{
-    BufferSize, BufferSizes, BumpAllocators, ConfigUniform, RenderConfig, WorkgroupCounts,
-    WorkgroupSize,
+    AllocationFailed, BufferSize, BufferSizes, BumpAllocators, BumpSizes, ColorSpace,
+    ConfigUniform, RenderConfig, WorkgroupCounts, WorkgroupSize, PATTERN_DRAW_SIZE, STAGE_BINNING,
+    STAGE_COARSE, STAGE_PATH_COARSE, STAGE_PATTERN, STAGE_TILE_ALLOC,
 };
 pub use draw::{
-    DrawBbox, DrawBeginClip, DrawColor, DrawImage, DrawLinearGradient, DrawMonoid,
-    DrawRadialGradient, DrawTag,
+    DrawBbox, DrawBeginClip, DrawBlurRoundedRect, DrawColor, DrawImage, DrawLinearGradient,
+    DrawMonoid, DrawRadialGradient, DrawSweepGradient, DrawTag, ImageQuality, ImageSampling,
 };
+pub use effect::{BlurConfig, EffectData};
 pub use encoding::{Encoding, StreamOffsets};
-pub use math::Transform;
+pub use math::{PatternData, PatternInstance, Transform};
 pub use monoid::Monoid;
 pub use path::{
     Cubic, Path, PathBbox, PathEncoder, PathMonoid, PathSegment, PathSegmentType, PathTag, Tile,
 };
+pub use pattern::{
+    expand_patterns, instance_patterns, PatternError, MAX_PATTERN_CELLS, MAX_PATTERN_DEPTH,
+};
 pub use resolve::{resolve_solid_paths_only, Layout};
+pub use stroke::stroke_to_fill;
 
 #[cfg(feature = "full")]
 pub use {
+    effect_layers::{split_effects, EffectLayer, EffectLayers},
     encoding::Resources,
     glyph::{Glyph, GlyphRun},
+    image_cache::Images,
     ramp_cache::Ramps,
-    resolve::{Patch, Resolver},
+    resolve::{CacheStats, Patch, Resolver, ResolverStats},
+    serialize::{BlobStorage, SERIALIZATION_VERSION},
 };
@@ -4,14 +4,456 @@
 use std::ops::Mul;
 
 use bytemuck::{Pod, Zeroable};
-use peniko::kurbo;
+use peniko::{kurbo, Color, ColorStop};
 
+/// Lattice along which the pattern stage repeats the contents of a pattern.
+///
+/// The contents are encoded in pattern space, relative to the origin cell. On a
+/// grid, the cell at lattice coordinates `(x, y)` is placed at
+/// `x * x_step + y * y_step` and then mapped by `transform`. Other tilings
+/// offset alternate rows or columns, and placed cells are drawn at the
+/// transforms of a list rather than on a lattice.
+///
+/// Cells can vary deterministically: the jitter fields bound a random offset,
+/// rotation and scale of the contents about the center of the cell, drawn from
+/// `seed` and the lattice coordinates.
 #[derive(Copy, Clone, Debug, PartialEq, Pod, Zeroable)]
 #[repr(C)]
 pub struct PatternData {
-    pub start: [f32;2],
-    pub box_scale : [f32;2],
-    pub rotate: f32,
+    /// Transform from pattern space to the coordinate space of the enclosing layer.
+    pub transform: Transform,
+    /// Offset between horizontally adjacent cells, in pattern space.
+    pub x_step: [f32; 2],
+    /// Offset between vertically adjacent cells, in pattern space.
+    pub y_step: [f32; 2],
+    /// How cells are repeated, one of the `EXTEND_*` constants.
+    pub extend: u32,
+    /// Arrangement of the cells, one of the `TILING_*` constants.
+    pub tiling: u32,
+    /// Seed of the per-cell variation.
+    pub seed: u32,
+    /// Largest offset of the contents of a cell, in pattern space.
+    pub offset_jitter: [f32; 2],
+    /// Largest rotation of the contents of a cell, in radians.
+    pub rotation_jitter: f32,
+    /// Largest relative change of the scale of the contents of a cell.
+    pub scale_jitter: f32,
+    /// Range of the transforms of the cells in [`Encoding::pattern_placements`],
+    /// with `TILING_PLACEMENTS`.
+    ///
+    /// [`Encoding::pattern_placements`]: crate::Encoding::pattern_placements
+    pub placements: [u32; 2],
+    /// How cells pick colors, one of the `COLORS_*` constants.
+    ///
+    /// The color of a cell replaces the solid colors of its copy of the
+    /// contents, keeping their alpha, see [`DrawColor::recolor`].
+    ///
+    /// [`DrawColor::recolor`]: crate::DrawColor::recolor
+    pub colors: u32,
+    /// Range of the color stops of the pattern in [`Encoding::pattern_colors`].
+    ///
+    /// [`Encoding::pattern_colors`]: crate::Encoding::pattern_colors
+    pub color_stops: [u32; 2],
+    /// How the contents are bounded by their cell, one of the `OVERFLOW_*` constants.
+    pub overflow: u32,
+}
+
+impl PatternData {
+    /// Only the origin cell is drawn.
+    pub const EXTEND_PAD: u32 = 0;
+    /// Cells are repeated across the lattice.
+    pub const EXTEND_REPEAT: u32 = 1;
+    /// Cells are repeated, with odd columns and rows mirrored.
+    pub const EXTEND_REFLECT: u32 = 2;
+
+    /// Cells are placed on a regular lattice.
+    pub const TILING_GRID: u32 = 0;
+    /// Odd rows are offset by half a cell, like courses of bricks.
+    pub const TILING_BRICK: u32 = 1;
+    /// Odd columns are offset by half a cell.
+    pub const TILING_HALF_DROP: u32 = 2;
+    /// Odd columns and rows are mirrored, the same as `EXTEND_REFLECT`, and
+    /// mirrored once when combined with it.
+    pub const TILING_MIRROR: u32 = 3;
+    /// Odd rows are offset by half a cell and rows are three quarters of a
+    /// cell apart, so that hexagonal cells interlock.
+    pub const TILING_HEX: u32 = 4;
+    /// Cells are placed at the transforms of `placements` instead of on a
+    /// lattice, with the horizontal cell coordinate indexing the transforms.
+    pub const TILING_PLACEMENTS: u32 = 5;
+
+    /// The contents keep their colors.
+    pub const COLORS_NONE: u32 = 0;
+    /// Each cell picks one of the colors of the color stops.
+    pub const COLORS_LIST: u32 = 1;
+    /// Each cell samples the gradient of the color stops at a random offset.
+    pub const COLORS_GRADIENT: u32 = 2;
+
+    /// The contents of a cell may overlap other cells.
+    pub const OVERFLOW_VISIBLE: u32 = 0;
+    /// The contents start with a layer that clips each cell to its shape.
+    ///
+    /// Every cell draws its own copy of the layer, so each is clipped to its
+    /// own cell. The flag is stored with the pattern rather than inferred from
+    /// the layer, so that the clip of the cells can be told apart from layers
+    /// of the contents themselves.
+    pub const OVERFLOW_CLIP: u32 = 1;
+
+    /// Returns the linear transform from lattice coordinates to pattern space.
+    fn steps(&self) -> Transform {
+        let (xs, ys) = (self.x_step, self.y_step);
+        Transform {
+            matrix: [xs[0], xs[1], ys[0], ys[1]],
+            translation: [0.0; 2],
+        }
+    }
+
+    /// Returns the offsets of odd rows and odd columns, and the distance
+    /// between rows, in lattice coordinates.
+    ///
+    /// Mirrors `tiling_offsets` in shared/pattern.wgsl.
+    fn tiling_offsets(&self) -> [f32; 3] {
+        match self.tiling {
+            Self::TILING_BRICK => [0.5, 0.0, 1.0],
+            Self::TILING_HALF_DROP => [0.0, 0.5, 1.0],
+            Self::TILING_HEX => [0.5, 0.0, 0.75],
+            _ => [0.0, 0.0, 1.0],
+        }
+    }
+
+    fn is_mirrored(&self) -> bool {
+        self.extend == Self::EXTEND_REFLECT || self.tiling == Self::TILING_MIRROR
+    }
+
+    fn is_jittered(&self) -> bool {
+        self.offset_jitter != [0.0; 2] || self.rotation_jitter != 0.0 || self.scale_jitter != 0.0
+    }
+
+    /// Returns a random number in `[0, 1)` for the cell at `(x, y)`. Each
+    /// property that varies uses a different `k`.
+    ///
+    /// Mirrors `cell_random` in pattern.wgsl.
+    fn cell_random(&self, x: i32, y: i32, k: u32) -> f32 {
+        let h = hash(self.seed ^ hash(x as u32 ^ hash(y as u32 ^ hash(k))));
+        (h >> 8) as f32 / 16777216.0
+    }
+
+    /// Returns the transform that jitters the contents of the cell at `(x, y)`
+    /// about the center of the cell, in pattern space.
+    ///
+    /// Mirrors `cell_jitter` in pattern.wgsl.
+    fn cell_jitter(&self, x: i32, y: i32) -> Transform {
+        if !self.is_jittered() {
+            return Transform::IDENTITY;
+        }
+        let r = |k| 2.0 * self.cell_random(x, y, k) - 1.0;
+        let offset = [r(0) * self.offset_jitter[0], r(1) * self.offset_jitter[1]];
+        let angle = r(2) * self.rotation_jitter;
+        let scale = 1.0 + r(3) * self.scale_jitter;
+        let (sin, cos) = angle.sin_cos();
+        let matrix = [cos * scale, sin * scale, -sin * scale, cos * scale];
+        let center = self.steps().apply([0.5, 0.5]);
+        let moved = Transform {
+            matrix,
+            translation: [0.0; 2],
+        }
+        .apply(center);
+        Transform {
+            matrix,
+            translation: [
+                center[0] + offset[0] - moved[0],
+                center[1] + offset[1] - moved[1],
+            ],
+        }
+    }
+
+    /// Returns the bounds of the contents of any cell, including jitter.
+    ///
+    /// Mirrors `jittered_bbox` in shared/pattern.wgsl.
+    fn jittered_bbox(&self, content_bbox: [f32; 4]) -> [f32; 4] {
+        if !self.is_jittered() {
+            return content_bbox;
+        }
+        let mut bbox = content_bbox;
+        if self.rotation_jitter != 0.0 || self.scale_jitter != 0.0 {
+            // Rotating and scaling about the center stays within a circle.
+            let center = self.steps().apply([0.5, 0.5]);
+            let dx = (content_bbox[0] - center[0])
+                .abs()
+                .max((content_bbox[2] - center[0]).abs());
+            let dy = (content_bbox[1] - center[1])
+                .abs()
+                .max((content_bbox[3] - center[1]).abs());
+            let radius = dx.hypot(dy) * (1.0 + self.scale_jitter.abs());
+            bbox = [
+                center[0] - radius,
+                center[1] - radius,
+                center[0] + radius,
+                center[1] + radius,
+            ];
+        }
+        let [ox, oy] = self.offset_jitter.map(f32::abs);
+        [bbox[0] - ox, bbox[1] - oy, bbox[2] + ox, bbox[3] + oy]
+    }
+
+    /// Returns the transform from lattice coordinates to the space of the
+    /// enclosing layer.
+    pub fn lattice(&self) -> Transform {
+        self.transform * self.steps()
+    }
+
+    /// Returns the range of cells `[x0, y0, x1, y1)` whose contents may
+    /// intersect `bbox`, given the bounding box of the contents in pattern
+    /// space.
+    ///
+    /// Mirrors `cell_range` in shared/pattern.wgsl.
+    pub fn cell_range(&self, bbox: [f32; 4], content_bbox: [f32; 4]) -> [i32; 4] {
+        if bbox[0] >= bbox[2]
+            || bbox[1] >= bbox[3]
+            || content_bbox[0] > content_bbox[2]
+            || content_bbox[1] > content_bbox[3]
+        {
+            return [0; 4];
+        }
+        if self.tiling == Self::TILING_PLACEMENTS {
+            // Every placement is a cell, so that callers limit their number rather
+            // than dropping the last ones.
+            let n = self.placements[1].saturating_sub(self.placements[0]);
+            return [0, 0, n.min(i32::MAX as u32) as i32, (n > 0) as i32];
+        }
+        if self.extend == Self::EXTEND_PAD {
+            return [0, 0, 1, 1];
+        }
+        let lattice = self.lattice();
+        let m = lattice.matrix;
+        if (m[0] * m[3] - m[1] * m[2]).abs() < 1e-9 {
+            return [0; 4];
+        }
+        let to_lattice = lattice.inverse();
+        let steps = self.steps().inverse();
+        let target_bbox = transformed_bbox(&to_lattice, bbox);
+        let mut content = transformed_bbox(&steps, self.jittered_bbox(content_bbox));
+        if self.is_mirrored() {
+            // Mirrored cells cover the reflection of the contents in the unit cell.
+            content = [
+                content[0].min(1.0 - content[2]),
+                content[1].min(1.0 - content[3]),
+                content[2].max(1.0 - content[0]),
+                content[3].max(1.0 - content[1]),
+            ];
+        }
+        // Offset rows and columns shift the contents by up to the offset.
+        let [row_offset, column_offset, row_scale] = self.tiling_offsets();
+        let lo = |x: f32| x.floor().clamp(-MAX_CELLS, MAX_CELLS) as i32;
+        let hi = |x: f32| x.ceil().clamp(-MAX_CELLS, MAX_CELLS) as i32;
+        [
+            lo(target_bbox[0] - content[2] - row_offset),
+            lo((target_bbox[1] - content[3] - column_offset) / row_scale),
+            hi(target_bbox[2] - content[0]),
+            hi((target_bbox[3] - content[1]) / row_scale),
+        ]
+    }
+
+    /// Returns the transform from pattern space to the space of the enclosing
+    /// layer for the cell at lattice coordinates `(x, y)`.
+    ///
+    /// `placements` is the placement stream of the encoding.
+    ///
+    /// Mirrors `cell_transform` in pattern.wgsl.
+    pub fn cell_transform(&self, x: i32, y: i32, placements: &[Transform]) -> Transform {
+        if self.tiling == Self::TILING_PLACEMENTS {
+            let ix = self.placements[0] as usize + x as usize;
+            let placement = placements.get(ix).copied().unwrap_or(Transform::IDENTITY);
+            return self.transform * placement * self.cell_jitter(x, y);
+        }
+        let (xs, ys) = (self.x_step, self.y_step);
+        let [row_offset, column_offset, row_scale] = self.tiling_offsets();
+        let lx = x as f32 + row_offset * (y & 1) as f32;
+        let ly = y as f32 * row_scale + column_offset * (x & 1) as f32;
+        let mut cell = Transform {
+            matrix: [1.0, 0.0, 0.0, 1.0],
+            translation: [lx * xs[0] + ly * ys[0], lx * xs[1] + ly * ys[1]],
+        };
+        if self.is_mirrored() && (x & 1 | y & 1) != 0 {
+            // Flip across the center of the cell, in lattice coordinates.
+            let steps = self.steps();
+            let fx = (x & 1) as f32;
+            let fy = (y & 1) as f32;
+            let flip = Transform {
+                matrix: [1.0 - 2.0 * fx, 0.0, 0.0, 1.0 - 2.0 * fy],
+                translation: [fx, fy],
+            };
+            cell = cell * steps * flip * steps.inverse();
+        }
+        self.transform * cell * self.cell_jitter(x, y)
+    }
+
+    /// Returns the lattice coordinates of the cells whose contents may
+    /// intersect `bbox`, in the order their copies are drawn.
+    ///
+    /// `content_bbox` is the bounding box of the contents in pattern space.
+    /// The pattern stage lays out the same cells on the GPU, this lists them on
+    /// the CPU to test it against.
+    pub fn cells(&self, bbox: [f32; 4], content_bbox: [f32; 4]) -> Vec<[i32; 2]> {
+        let [x0, y0, x1, y1] = self.cell_range(bbox, content_bbox);
+        (y0..y1)
+            .flat_map(|y| (x0..x1).map(move |x| [x, y]))
+            .collect()
+    }
+
+    /// Returns the transforms of the cells whose contents may intersect
+    /// `bbox`, in the order their copies are drawn.
+    ///
+    /// `content_bbox` is the bounding box of the contents in pattern space, and
+    /// `placements` is the placement stream of the encoding. Like [`cells`],
+    /// this is a CPU reference for the pattern stage.
+    ///
+    /// [`cells`]: Self::cells
+    pub fn instances(
+        &self,
+        bbox: [f32; 4],
+        content_bbox: [f32; 4],
+        placements: &[Transform],
+    ) -> Vec<Transform> {
+        self.cells(bbox, content_bbox)
+            .into_iter()
+            .map(|[x, y]| self.cell_transform(x, y, placements))
+            .collect()
+    }
+
+    /// Returns the color of the cell at `(x, y)`, or `None` if the contents
+    /// keep their colors.
+    ///
+    /// `pattern_colors` is the color stop stream of the encoding. Mirrors
+    /// `cell_color` in pattern.wgsl.
+    pub fn cell_color(&self, x: i32, y: i32, pattern_colors: &[ColorStop]) -> Option<Color> {
+        let [start, end] = self.color_stops.map(|ix| ix as usize);
+        let stops = pattern_colors.get(start..end).unwrap_or_default();
+        let last = stops.last()?;
+        let t = self.cell_random(x, y, 4);
+        match self.colors {
+            Self::COLORS_LIST => {
+                let ix = ((t * stops.len() as f32) as usize).min(stops.len() - 1);
+                Some(stops[ix].color)
+            }
+            Self::COLORS_GRADIENT => {
+                let Some(ix) = stops.iter().position(|stop| stop.offset > t) else {
+                    return Some(last.color);
+                };
+                if ix == 0 {
+                    return Some(stops[0].color);
+                }
+                let (a, b) = (stops[ix - 1], stops[ix]);
+                let f = (t - a.offset) / (b.offset - a.offset);
+                let mix = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * f).round() as u8;
+                let (ca, cb) = (a.color, b.color);
+                Some(Color::rgba8(
+                    mix(ca.r, cb.r),
+                    mix(ca.g, cb.g),
+                    mix(ca.b, cb.b),
+                    mix(ca.a, cb.a),
+                ))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A run of draw objects of an encoding returned by [`instance_patterns`],
+/// drawn once or copied into each cell of a pattern.
+///
+/// The pattern stage computes the cells of each instance with
+/// [`PatternData::cell_range`], and the number of draw objects it expands to,
+/// and lays out the copies of all instances in order with a prefix sum.
+///
+/// This must be kept in sync with `read_instance` in shared/pattern.wgsl.
+///
+/// [`instance_patterns`]: crate::instance_patterns
+#[derive(Copy, Clone, Debug, PartialEq, Pod, Zeroable)]
+#[repr(C)]
+pub struct PatternInstance {
+    /// Index of the pattern in [`Encoding::pattern_data`], or `NONE` for draw
+    /// objects outside of patterns, which are drawn once.
+    ///
+    /// [`Encoding::pattern_data`]: crate::Encoding::pattern_data
+    pub pattern: u32,
+    /// Combination of the `SINGLE_CELL` and `HAS_COLOR` flags.
+    pub flags: u32,
+    /// Range of the draw objects that are drawn, by index in the encoding.
+    ///
+    /// The draw objects of the contents of patterns are template objects at
+    /// the start of the encoding, which are only drawn through their copies.
+    pub objects: [u32; 2],
+    /// Size of the draw info of the draw objects in `objects`, in `u32`s.
+    pub info: u32,
+    /// Transform of the enclosing cell of a nested pattern, applied to the
+    /// lattice of the pattern like [`PatternData::transform`].
+    pub transform: Transform,
+    /// Color of the enclosing cell of a nested pattern, with `HAS_COLOR`, used
+    /// where the pattern has no colors of its own. The channels are packed
+    /// like the colors of the pattern color stream.
+    pub color: u32,
+    /// Lattice coordinates of the only cell with `SINGLE_CELL`.
+    pub cell: [i32; 2],
+    /// Bounds of the cells, in the coordinates of the enclosing layer.
+    pub bbox: [f32; 4],
+    /// Bounds of the contents of the pattern, in pattern space.
+    pub content_bbox: [f32; 4],
+}
+
+impl PatternInstance {
+    /// Marks a missing index.
+    pub const NONE: u32 = u32::MAX;
+    /// Only the cell at `cell` is drawn, for a pattern that holds nested
+    /// patterns, whose cells are enumerated when encoding.
+    pub const SINGLE_CELL: u32 = 1;
+    /// `color` holds the color of the enclosing cell.
+    pub const HAS_COLOR: u32 = 2;
+
+    /// Returns the number of cells that the draw objects are drawn in.
+    pub fn n_cells(&self, pattern_data: &[PatternData]) -> u64 {
+        if self.pattern == Self::NONE || self.flags & Self::SINGLE_CELL != 0 {
+            return 1;
+        }
+        let Some(pattern) = pattern_data.get(self.pattern as usize) else {
+            return 0;
+        };
+        let pattern = PatternData {
+            transform: self.transform * pattern.transform,
+            ..*pattern
+        };
+        let [x0, y0, x1, y1] = pattern.cell_range(self.bbox, self.content_bbox);
+        (x1 - x0).max(0) as u64 * (y1 - y0).max(0) as u64
+    }
+}
+
+/// Integer hash used for the per-cell variation of patterns.
+///
+/// Mirrors `pattern_hash` in pattern.wgsl.
+fn hash(x: u32) -> u32 {
+    let state = x.wrapping_mul(747796405).wrapping_add(2891336453);
+    let word = ((state >> ((state >> 28) + 4)) ^ state).wrapping_mul(277803737);
+    (word >> 22) ^ word
+}
+
+/// Largest cell coordinate of a lattice produced by [`PatternData::cell_range`].
+const MAX_CELLS: f32 = 1048576.0;
+
+pub(crate) fn transformed_bbox(transform: &Transform, bbox: [f32; 4]) -> [f32; 4] {
+    let mut result = [f32::MAX, f32::MAX, f32::MIN, f32::MIN];
+    for p in [
+        [bbox[0], bbox[1]],
+        [bbox[2], bbox[1]],
+        [bbox[0], bbox[3]],
+        [bbox[2], bbox[3]],
+    ] {
+        let p = transform.apply(p);
+        result[0] = result[0].min(p[0]);
+        result[1] = result[1].min(p[1]);
+        result[2] = result[2].max(p[0]);
+        result[3] = result[3].max(p[1]);
+    }
+    result
 }
 
 /// Affine transformation matrix.
@@ -40,6 +482,36 @@ impl Transform {
         }
     }
 
+    /// Applies the transform to a point.
+    pub fn apply(&self, p: [f32; 2]) -> [f32; 2] {
+        [
+            self.matrix[0] * p[0] + self.matrix[2] * p[1] + self.translation[0],
+            self.matrix[1] * p[0] + self.matrix[3] * p[1] + self.translation[1],
+        ]
+    }
+
+    /// Returns the inverse of the transform.
+    ///
+    /// Mirrors `transform_inverse` in transform.wgsl.
+    pub fn inverse(&self) -> Self {
+        let m = self.matrix;
+        let inv_det = 1.0 / (m[0] * m[3] - m[1] * m[2]);
+        let inv = [
+            inv_det * m[3],
+            inv_det * -m[1],
+            inv_det * -m[2],
+            inv_det * m[0],
+        ];
+        let t = self.translation;
+        Self {
+            matrix: inv,
+            translation: [
+                -(inv[0] * t[0] + inv[2] * t[1]),
+                -(inv[1] * t[0] + inv[3] * t[1]),
+            ],
+        }
+    }
+
     /// Converts the transform to a kurbo affine matrix.
     pub fn to_kurbo(&self) -> kurbo::Affine {
         kurbo::Affine::new(
@@ -0,0 +1,1196 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! CPU expansion of patterns.
+
+use std::fmt;
+use std::ops::Range;
+
+use peniko::Color;
+
+use super::math::transformed_bbox;
+use super::{
+    DrawColor, DrawTag, EffectData, Encoding, PathTag, PatternData, PatternInstance, Transform,
+};
+
+#[cfg(feature = "full")]
+use super::Patch;
+
+/// Largest total number of cells of the patterns of an encoding, see
+/// [`PatternError::TooManyCells`].
+pub const MAX_PATTERN_CELLS: u64 = 1 << 20;
+
+/// Deepest nesting of patterns.
+pub const MAX_PATTERN_DEPTH: usize = 16;
+
+/// Bounds of nothing, like the bounding box of an empty path.
+const EMPTY: [f32; 4] = [1e9, 1e9, -1e9, -1e9];
+
+/// Bounds of contents that aren't clipped.
+const UNBOUNDED: [f32; 4] = [-1e9, -1e9, 1e9, 1e9];
+
+/// Error returned when the patterns of an encoding can't be expanded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PatternError {
+    /// The patterns have more than [`MAX_PATTERN_CELLS`] cells in total.
+    ///
+    /// The cells of a nested pattern count once for each cell of the patterns
+    /// that contain it. [`expand_patterns`] counts the cells of every pattern,
+    /// while [`instance_patterns`] only counts those of patterns that hold
+    /// nested patterns, as the pattern stage lays out the others on the GPU.
+    TooManyCells,
+    /// Patterns are nested more than [`MAX_PATTERN_DEPTH`] deep.
+    TooDeep,
+    /// The streams of the encoding don't match: begin and end pattern
+    /// commands are unbalanced, or a command refers past the end of a stream.
+    Malformed,
+}
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooManyCells => write!(f, "patterns have too many cells"),
+            Self::TooDeep => write!(f, "patterns are nested more than {MAX_PATTERN_DEPTH} deep"),
+            Self::Malformed => write!(f, "pattern streams of the encoding are inconsistent"),
+        }
+    }
+}
+
+impl std::error::Error for PatternError {}
+
+/// Expands the patterns of an encoding into ordinary paths.
+///
+/// Each pattern is replaced by a copy of its contents for every cell that
+/// intersects the bounds of the enclosing layer, with the transform of the cell
+/// applied. Rendering the result is a reference for the GPU implementation and
+/// a fallback where the pattern stage isn't wanted. Nested patterns are
+/// expanded within each cell of the patterns that contain them. Per-cell colors
+/// replace the solid colors of the copies, with the colors of the innermost
+/// pattern that has them.
+///
+/// `clip_bbox` bounds the drawing area, usually the viewport. It is intersected
+/// with the bounding boxes of the enclosing clips, which are computed from the
+/// encoded paths. Glyph runs are not resolved here, so their outlines are
+/// bounded by a square of twice the font size around the origin of each glyph.
+///
+/// Fails if the patterns have more than [`MAX_PATTERN_CELLS`] cells in total,
+/// rather than encoding an unbounded number of copies.
+pub fn expand_patterns(encoding: &Encoding, clip_bbox: [f32; 4]) -> Result<Encoding, PatternError> {
+    let objects = draw_objects(encoding)?;
+    let nodes = parse(encoding, &objects)?;
+    let mut expander = Expander::new(encoding);
+    expander.expanded.n_open_clips = encoding.n_open_clips;
+    let mut n_cells = CellCount::new(MAX_PATTERN_CELLS);
+    let mut clip_stack = vec![clip_bbox];
+    for node in &nodes {
+        let layer_bbox = *clip_stack.last().unwrap();
+        match node {
+            Node::Object(object) => {
+                track_clip(&mut clip_stack, object);
+                expander.append(object, None, None);
+            }
+            Node::Pattern(pattern) => {
+                expand_pattern(&mut expander, pattern, None, None, layer_bbox, &mut n_cells)?;
+            }
+        }
+    }
+    Ok(expander.expanded)
+}
+
+/// Appends a copy of the contents of `pattern` for each of its cells.
+///
+/// `instance` and `color` are those of the enclosing cell of a nested pattern,
+/// and `layer_bbox` bounds the cells in the coordinates of the enclosing layer.
+fn expand_pattern(
+    expander: &mut Expander,
+    pattern: &Pattern,
+    instance: Option<Transform>,
+    color: Option<Color>,
+    layer_bbox: [f32; 4],
+    n_cells: &mut CellCount,
+) -> Result<(), PatternError> {
+    let encoding = expander.encoding;
+    let data = pattern.in_layer(instance);
+    let content_bbox = content_bbox(encoding, &pattern.contents);
+    for [x, y] in cells(&data, layer_bbox, content_bbox, n_cells)? {
+        let instance = data.cell_transform(x, y, &encoding.pattern_placements);
+        let color = data.cell_color(x, y, &encoding.pattern_colors).or(color);
+        // Clips of the contents, in pattern space, bound the cells of nested patterns.
+        let mut clip_stack = vec![UNBOUNDED];
+        for node in &pattern.contents {
+            match node {
+                Node::Object(object) => {
+                    track_clip(&mut clip_stack, object);
+                    expander.append(object, Some(instance), color);
+                }
+                Node::Pattern(nested) => {
+                    let visible = transformed_bbox(&instance, *clip_stack.last().unwrap());
+                    let bbox = intersect(layer_bbox, visible);
+                    expand_pattern(expander, nested, Some(instance), color, bbox, n_cells)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Encodes the patterns of an encoding for the pattern stage.
+///
+/// Rather than a copy of the contents of each pattern for every cell, like
+/// [`expand_patterns`], the draw objects of the contents are encoded once as
+/// template objects at the start of the encoding, which aren't drawn
+/// themselves. They are followed by the draw objects outside of patterns, with
+/// open clips closed. [`Encoding::pattern_instances`] lists what is drawn, in
+/// order: a [`PatternInstance`] for each pattern, whose templates are copied
+/// into every cell that intersects the bounds of the enclosing layer, and for
+/// each run of draw objects between patterns.
+///
+/// The pattern stage computes the cells of each instance on the GPU, and lays
+/// out their copies with a prefix sum, so the size of the encoding doesn't
+/// depend on the number of cells. Only the cells of patterns that hold nested
+/// patterns are enumerated here, each with an instance for every nested
+/// pattern. Fails like [`expand_patterns`], except for the cells counted, see
+/// [`PatternError::TooManyCells`].
+pub fn instance_patterns(
+    encoding: &Encoding,
+    clip_bbox: [f32; 4],
+) -> Result<Encoding, PatternError> {
+    let objects = draw_objects(encoding)?;
+    let nodes = parse(encoding, &objects)?;
+    let mut templates = vec![];
+    let mut template_ranges = vec![0..0; encoding.pattern_data.len()];
+    collect_templates(&nodes, &mut templates, &mut template_ranges);
+    let mut instancer = Instancer {
+        encoding,
+        templates: &templates,
+        template_ranges,
+        draws: vec![],
+        n_drawn: 0,
+        instances: vec![],
+        n_cells: CellCount::new(MAX_PATTERN_CELLS),
+    };
+    let mut clip_stack = vec![clip_bbox];
+    for node in &nodes {
+        let layer_bbox = *clip_stack.last().unwrap();
+        match node {
+            Node::Object(object) => {
+                track_clip(&mut clip_stack, object);
+                if object.tag != DrawTag::NOP {
+                    instancer.draws.push(object);
+                }
+            }
+            Node::Pattern(pattern) => {
+                instancer.instance_draws(0);
+                instancer.instance_pattern(pattern, None, None, layer_bbox)?;
+            }
+        }
+    }
+    instancer.instance_draws(encoding.n_open_clips);
+    let Instancer {
+        draws, instances, ..
+    } = instancer;
+    let mut expander = Expander::new(encoding);
+    for object in &templates {
+        expander.append(object, None, None);
+    }
+    // The draw objects outside of patterns restore their transform and
+    // linewidth, which differ from those of the last template.
+    expander.stale = true;
+    for object in draws {
+        expander.append(object, None, None);
+    }
+    let mut instanced = expander.expanded;
+    // Clips are closed by draw objects of the last instance, so that the
+    // pattern stage lays out every draw object.
+    instanced.n_open_clips = encoding.n_open_clips;
+    while instanced.n_open_clips > 0 {
+        instanced.encode_end_clip();
+    }
+    instanced.n_template_paths = templates.len() as u32;
+    instanced.pattern_data.clone_from(&encoding.pattern_data);
+    instanced
+        .pattern_colors
+        .clone_from(&encoding.pattern_colors);
+    instanced
+        .pattern_placements
+        .clone_from(&encoding.pattern_placements);
+    instanced.pattern_instances = instances;
+    Ok(instanced)
+}
+
+/// Lists the draw objects of the contents of every pattern as templates, and
+/// the range of the templates of each pattern.
+fn collect_templates<'a>(
+    nodes: &[Node<'a>],
+    templates: &mut Vec<&'a DrawObject>,
+    ranges: &mut [Range<usize>],
+) {
+    for node in nodes {
+        if let Node::Pattern(pattern) = node {
+            let start = templates.len();
+            templates.extend(pattern.contents.iter().filter_map(|node| match node {
+                Node::Object(object) if object.tag != DrawTag::NOP => Some(*object),
+                _ => None,
+            }));
+            ranges[pattern.index] = start..templates.len();
+            collect_templates(&pattern.contents, templates, ranges);
+        }
+    }
+}
+
+/// Lists the instances of the patterns of an encoding for [`instance_patterns`].
+struct Instancer<'a, 'b> {
+    encoding: &'a Encoding,
+    templates: &'b [&'a DrawObject],
+    /// Range of the templates of the contents of each pattern.
+    template_ranges: Vec<Range<usize>>,
+    /// Draw objects outside of patterns, which follow the templates.
+    draws: Vec<&'a DrawObject>,
+    /// Number of the draw objects in `draws` that have an instance.
+    n_drawn: usize,
+    instances: Vec<PatternInstance>,
+    n_cells: CellCount,
+}
+
+impl<'a> Instancer<'a, '_> {
+    /// Adds an instance that draws the draw objects outside of patterns that
+    /// don't have one yet, and `n_end_clips` end clips that follow them.
+    fn instance_draws(&mut self, n_end_clips: u32) {
+        let n_templates = self.templates.len() as u32;
+        let start = self.n_drawn;
+        self.n_drawn = self.draws.len();
+        if start == self.n_drawn && n_end_clips == 0 {
+            return;
+        }
+        let info = self.draws[start..].iter().map(|o| o.tag.info_size()).sum();
+        self.instances.push(PatternInstance {
+            pattern: PatternInstance::NONE,
+            objects: [
+                n_templates + start as u32,
+                n_templates + self.n_drawn as u32 + n_end_clips,
+            ],
+            info,
+            transform: Transform::IDENTITY,
+            ..bytemuck::Zeroable::zeroed()
+        });
+    }
+
+    /// Adds the instances of `pattern`.
+    ///
+    /// `instance` and `color` are those of the enclosing cell of a nested
+    /// pattern, and `layer_bbox` bounds the cells in the coordinates of the
+    /// enclosing layer.
+    fn instance_pattern(
+        &mut self,
+        pattern: &Pattern<'a>,
+        instance: Option<Transform>,
+        color: Option<Color>,
+        layer_bbox: [f32; 4],
+    ) -> Result<(), PatternError> {
+        let encoding = self.encoding;
+        let content_bbox = content_bbox(encoding, &pattern.contents);
+        let templates = self.template_ranges[pattern.index].clone();
+        let all_cells = PatternInstance {
+            pattern: pattern.index as u32,
+            flags: if color.is_some() {
+                PatternInstance::HAS_COLOR
+            } else {
+                0
+            },
+            objects: [templates.start as u32, templates.end as u32],
+            info: 0,
+            transform: instance.unwrap_or(Transform::IDENTITY),
+            color: color.map_or(0, |c| u32::from_be_bytes([c.r, c.g, c.b, c.a])),
+            cell: [0, 0],
+            bbox: layer_bbox,
+            content_bbox,
+        };
+        let has_nested = pattern
+            .contents
+            .iter()
+            .any(|node| matches!(node, Node::Pattern(_)));
+        if !has_nested {
+            // The pattern stage computes the cells.
+            self.push(all_cells);
+            return Ok(());
+        }
+        // Nested patterns are instanced within each cell, between the copies
+        // of the draw objects that precede and follow them.
+        let data = pattern.in_layer(instance);
+        for [x, y] in cells(&data, layer_bbox, content_bbox, &mut self.n_cells)? {
+            let instance = data.cell_transform(x, y, &encoding.pattern_placements);
+            let cell_color = data.cell_color(x, y, &encoding.pattern_colors).or(color);
+            let cell = PatternInstance {
+                flags: all_cells.flags | PatternInstance::SINGLE_CELL,
+                cell: [x, y],
+                ..all_cells
+            };
+            // Clips of the contents, in pattern space, bound the cells of nested patterns.
+            let mut clip_stack = vec![UNBOUNDED];
+            let mut start = templates.start as u32;
+            let mut end = start;
+            for node in &pattern.contents {
+                match node {
+                    Node::Object(object) => {
+                        track_clip(&mut clip_stack, object);
+                        end += (object.tag != DrawTag::NOP) as u32;
+                    }
+                    Node::Pattern(nested) => {
+                        self.push(PatternInstance {
+                            objects: [start, end],
+                            ..cell
+                        });
+                        start = end;
+                        let visible = transformed_bbox(&instance, *clip_stack.last().unwrap());
+                        let bbox = intersect(layer_bbox, visible);
+                        self.instance_pattern(nested, Some(instance), cell_color, bbox)?;
+                    }
+                }
+            }
+            self.push(PatternInstance {
+                objects: [start, end],
+                ..cell
+            });
+        }
+        Ok(())
+    }
+
+    /// Adds an instance of templates, unless it has none.
+    fn push(&mut self, instance: PatternInstance) {
+        let [start, end] = instance.objects.map(|ix| ix as usize);
+        if start < end {
+            let info = self.templates[start..end]
+                .iter()
+                .map(|o| o.tag.info_size())
+                .sum();
+            self.instances.push(PatternInstance { info, ..instance });
+        }
+    }
+}
+
+/// A draw object, or a pattern and its contents.
+pub(crate) enum Node<'a> {
+    Object(&'a DrawObject),
+    Pattern(Pattern<'a>),
+}
+
+/// A pattern of an encoding.
+pub(crate) struct Pattern<'a> {
+    /// Index of the pattern in the pattern stream.
+    pub(crate) index: usize,
+    pub(crate) data: PatternData,
+    pub(crate) contents: Vec<Node<'a>>,
+}
+
+impl Pattern<'_> {
+    /// Returns the pattern data with the lattice mapped by `instance`, the
+    /// transform of the enclosing cell of a nested pattern.
+    fn in_layer(&self, instance: Option<Transform>) -> PatternData {
+        PatternData {
+            transform: instance.unwrap_or(Transform::IDENTITY) * self.data.transform,
+            ..self.data
+        }
+    }
+}
+
+/// Splits draw objects into patterns and the objects outside of them.
+pub(crate) fn parse<'a>(
+    encoding: &Encoding,
+    objects: &'a [DrawObject],
+) -> Result<Vec<Node<'a>>, PatternError> {
+    // The contents of the patterns that are open, each with its pattern index.
+    let mut stack = vec![(vec![], None)];
+    let mut pattern_ix = 0;
+    for object in objects {
+        match object.tag {
+            DrawTag::BEGIN_PATTERN => {
+                if stack.len() > MAX_PATTERN_DEPTH {
+                    return Err(PatternError::TooDeep);
+                }
+                stack.push((vec![], Some(pattern_ix)));
+                pattern_ix += 1;
+            }
+            DrawTag::END_PATTERN => {
+                let Some((contents, Some(index))) = stack.pop() else {
+                    return Err(PatternError::Malformed);
+                };
+                let data = *encoding
+                    .pattern_data
+                    .get(index)
+                    .ok_or(PatternError::Malformed)?;
+                let Some((parent, _)) = stack.last_mut() else {
+                    return Err(PatternError::Malformed);
+                };
+                parent.push(Node::Pattern(Pattern {
+                    index,
+                    data,
+                    contents,
+                }));
+            }
+            _ => stack.last_mut().unwrap().0.push(Node::Object(object)),
+        }
+    }
+    match stack.pop() {
+        Some((nodes, None)) if stack.is_empty() => Ok(nodes),
+        _ => Err(PatternError::Malformed),
+    }
+}
+
+/// Returns the bounds of the contents of a pattern in pattern space, limited by
+/// the clips of the contents.
+///
+/// Paths with empty bounds are skipped, as their copies draw nothing.
+fn content_bbox(encoding: &Encoding, contents: &[Node]) -> [f32; 4] {
+    let mut bbox = EMPTY;
+    let mut clip_stack = vec![UNBOUNDED];
+    for node in contents {
+        let visible = *clip_stack.last().unwrap();
+        let extent = match node {
+            Node::Object(object) => {
+                track_clip(&mut clip_stack, object);
+                let [x0, y0, x1, y1] = object.bbox;
+                match object.tag {
+                    DrawTag::BEGIN_CLIP | DrawTag::END_CLIP => continue,
+                    _ if x0 >= x1 || y0 >= y1 => continue,
+                    _ => object.bbox,
+                }
+            }
+            Node::Pattern(nested) => {
+                let data = nested.data;
+                let nested_bbox = content_bbox(encoding, &nested.contents);
+                if nested_bbox[0] > nested_bbox[2] || nested_bbox[1] > nested_bbox[3] {
+                    continue;
+                }
+                match (data.extend, data.tiling) {
+                    (_, PatternData::TILING_PLACEMENTS) | (PatternData::EXTEND_PAD, _) => {
+                        // A bounded number of cells, see `PatternData::cell_range`.
+                        let [x0, y0, x1, y1] = data.cell_range(UNBOUNDED, nested_bbox);
+                        let placements = (y0..y1).flat_map(|y| (x0..x1).map(move |x| [x, y]));
+                        placements.fold(EMPTY, |extent, [x, y]| {
+                            let t = data.cell_transform(x, y, &encoding.pattern_placements);
+                            union(extent, transformed_bbox(&t, nested_bbox))
+                        })
+                    }
+                    // Repeated cells cover everything that is visible.
+                    _ => visible,
+                }
+            }
+        };
+        if extent[0] <= extent[2] && extent[1] <= extent[3] {
+            bbox = union(bbox, intersect(visible, extent));
+        }
+    }
+    bbox
+}
+
+/// Number of cells of the patterns of an encoding, and its limit.
+struct CellCount {
+    n: u64,
+    max: u64,
+}
+
+impl CellCount {
+    fn new(max: u64) -> Self {
+        Self { n: 0, max }
+    }
+}
+
+/// Returns the cells of `pattern` whose contents may intersect `bbox`, and adds
+/// their number to `n_cells`.
+fn cells(
+    pattern: &PatternData,
+    bbox: [f32; 4],
+    content_bbox: [f32; 4],
+    n_cells: &mut CellCount,
+) -> Result<impl Iterator<Item = [i32; 2]>, PatternError> {
+    let [x0, y0, x1, y1] = pattern.cell_range(bbox, content_bbox);
+    let n = (x1 - x0).max(0) as u64 * (y1 - y0).max(0) as u64;
+    n_cells.n += n;
+    if n_cells.n > n_cells.max {
+        return Err(PatternError::TooManyCells);
+    }
+    Ok((y0..y1).flat_map(move |y| (x0..x1).map(move |x| [x, y])))
+}
+
+/// Pushes or pops the bounds of a clip onto `clip_stack`.
+fn track_clip(clip_stack: &mut Vec<[f32; 4]>, object: &DrawObject) {
+    match object.tag {
+        DrawTag::BEGIN_CLIP => {
+            let visible = *clip_stack.last().unwrap();
+            clip_stack.push(intersect(visible, object.bbox));
+        }
+        DrawTag::END_CLIP if clip_stack.len() > 1 => {
+            clip_stack.pop();
+        }
+        _ => {}
+    }
+}
+
+/// A draw object and the parts of the streams that encode it.
+pub(crate) struct DrawObject {
+    pub(crate) tag: DrawTag,
+    /// Range of the path tag stream, including transform and linewidth tags.
+    pub(crate) path_tags: Range<usize>,
+    /// Range of the path data stream, in bytes.
+    path_data: Range<usize>,
+    /// Range of the draw data stream, in bytes.
+    pub(crate) draw_data: Range<usize>,
+    /// Index of the transform in effect at the start of the path.
+    pub(crate) transform: Option<usize>,
+    /// Index of the linewidth in effect at the start of the path.
+    linewidth: Option<usize>,
+    /// Bounding box of the path, rounded out to integers like the path bounding
+    /// boxes of the pipeline.
+    pub(crate) bbox: [f32; 4],
+    /// Index of the layer effect of a begin clip object.
+    pub(crate) effect: Option<usize>,
+    /// Index of the glyph run drawn by this object.
+    #[cfg(feature = "full")]
+    glyph_run: Option<usize>,
+    /// Index of the ramp or image patch of this object.
+    #[cfg(feature = "full")]
+    patch: Option<usize>,
+}
+
+/// Splits an encoding into draw objects.
+///
+/// Fails if the streams of the encoding don't match, so that the objects can
+/// index them.
+pub(crate) fn draw_objects(encoding: &Encoding) -> Result<Vec<DrawObject>, PatternError> {
+    let n_transform_tags = count_tags(encoding, PathTag::TRANSFORM);
+    let n_linewidth_tags = count_tags(encoding, PathTag::LINEWIDTH);
+    let mut transform = (encoding.transforms.len() > n_transform_tags).then_some(0);
+    let mut linewidth = (encoding.linewidths.len() > n_linewidth_tags).then_some(0);
+    #[cfg(feature = "full")]
+    let (mut glyph_runs, mut patches) = {
+        let patches = &encoding.resources.patches;
+        let glyph_runs = patches.iter().filter_map(|patch| match patch {
+            Patch::GlyphRun { index } => Some(*index),
+            _ => None,
+        });
+        let patches = patches
+            .iter()
+            .enumerate()
+            .filter_map(|(ix, patch)| match patch {
+                Patch::Ramp {
+                    draw_data_offset, ..
+                }
+                | Patch::Image {
+                    draw_data_offset, ..
+                } => Some((ix, *draw_data_offset)),
+                Patch::GlyphRun { .. } => None,
+            });
+        (glyph_runs.peekable(), patches.peekable())
+    };
+    let mut effects = encoding.effects.iter().enumerate().peekable();
+    let mut objects = Vec::with_capacity(encoding.draw_tags.len());
+    let mut path_tag_ix = 0;
+    let mut path_data_offset = 0;
+    let mut draw_data_offset = 0;
+    for (draw_tag_ix, &tag) in encoding.draw_tags.iter().enumerate() {
+        let draw_data_size = ((tag.0 >> 2) & 0x7) as usize * 4;
+        let draw_data = draw_data_offset..draw_data_offset + draw_data_size;
+        draw_data_offset = draw_data.end;
+        if draw_data.end > encoding.draw_data.len() {
+            return Err(PatternError::Malformed);
+        }
+        // Glyph runs have no paths in the encoding, they are inserted when resolving.
+        #[cfg(feature = "full")]
+        let glyph_run = glyph_runs.next_if(|index| {
+            encoding
+                .resources
+                .glyph_runs
+                .get(*index)
+                .is_some_and(|run| run.stream_offsets.draw_tags == draw_tag_ix)
+        });
+        #[cfg(feature = "full")]
+        let patch = patches
+            .next_if(|(_, offset)| draw_data.contains(offset))
+            .map(|(ix, _)| ix);
+        #[cfg(feature = "full")]
+        let has_path = glyph_run.is_none();
+        #[cfg(not(feature = "full"))]
+        let has_path = true;
+        let has_path = has_path
+            && tag != DrawTag::NOP
+            && tag != DrawTag::BEGIN_PATTERN
+            && tag != DrawTag::END_PATTERN;
+        let mut object = DrawObject {
+            tag,
+            path_tags: path_tag_ix..path_tag_ix,
+            path_data: path_data_offset..path_data_offset,
+            draw_data,
+            transform,
+            linewidth,
+            bbox: EMPTY,
+            effect: effects
+                .next_if(|(_, effect)| effect.draw_tag_ix as usize == draw_tag_ix)
+                .map(|(ix, _)| ix),
+            #[cfg(feature = "full")]
+            glyph_run,
+            #[cfg(feature = "full")]
+            patch,
+        };
+        #[cfg(feature = "full")]
+        if let Some(index) = glyph_run {
+            object.bbox = glyph_run_bbox(encoding, index);
+        }
+        if has_path {
+            let mut bbox = [f32::MAX, f32::MAX, f32::MIN, f32::MIN];
+            while let Some(&path_tag) = encoding.path_tags.get(path_tag_ix) {
+                path_tag_ix += 1;
+                if path_tag == PathTag::TRANSFORM {
+                    let ix = transform.map_or(0, |ix| ix + 1);
+                    if ix >= encoding.transforms.len() {
+                        return Err(PatternError::Malformed);
+                    }
+                    transform = Some(ix);
+                } else if path_tag == PathTag::LINEWIDTH {
+                    let ix = linewidth.map_or(0, |ix| ix + 1);
+                    if ix >= encoding.linewidths.len() {
+                        return Err(PatternError::Malformed);
+                    }
+                    linewidth = Some(ix);
+                } else if path_tag == PathTag::PATH {
+                    break;
+                } else if path_tag.is_path_segment() {
+                    // The first point of a segment is the last point of the previous one.
+                    let n_points = path_tag.path_segment_type().0 as usize;
+                    let point_size = if path_tag.is_f32() { 8 } else { 4 };
+                    if path_data_offset + (n_points + 1) * point_size > encoding.path_data.len() {
+                        return Err(PatternError::Malformed);
+                    }
+                    let t = transform.map_or(Transform::IDENTITY, |ix| encoding.transforms[ix]);
+                    let mut segment_bbox = [f32::MAX, f32::MAX, f32::MIN, f32::MIN];
+                    for i in 0..=n_points {
+                        let offset = path_data_offset + i * point_size;
+                        let p = t.apply(read_point(&encoding.path_data, offset, path_tag.is_f32()));
+                        segment_bbox = union(segment_bbox, [p[0], p[1], p[0], p[1]]);
+                    }
+                    // Mirrors the stroke expansion of the bounding box in pathseg.
+                    let lw = linewidth.map_or(-1.0, |ix| encoding.linewidths[ix]);
+                    if lw >= 0.0 {
+                        let m = t.matrix;
+                        let sx = 0.5 * lw * m[0].hypot(m[2]);
+                        let sy = 0.5 * lw * m[1].hypot(m[3]);
+                        segment_bbox = [
+                            segment_bbox[0] - sx,
+                            segment_bbox[1] - sy,
+                            segment_bbox[2] + sx,
+                            segment_bbox[3] + sy,
+                        ];
+                    }
+                    bbox = union(bbox, segment_bbox);
+                    let n_points = n_points + path_tag.is_subpath_end() as usize;
+                    path_data_offset += n_points * point_size;
+                }
+            }
+            if bbox[0] <= bbox[2] && bbox[1] <= bbox[3] {
+                object.bbox = [
+                    bbox[0].floor(),
+                    bbox[1].floor(),
+                    bbox[2].ceil(),
+                    bbox[3].ceil(),
+                ];
+            }
+            object.path_tags.end = path_tag_ix;
+            object.path_data.end = path_data_offset;
+        }
+        objects.push(object);
+    }
+    Ok(objects)
+}
+
+/// Builds the expanded encoding.
+pub(crate) struct Expander<'a> {
+    encoding: &'a Encoding,
+    pub(crate) expanded: Encoding,
+    /// Set when the transform or linewidth in effect may differ from the last
+    /// ones in the streams of the expanded encoding, after glyph runs, whose
+    /// transforms are inserted when resolving, and after the template objects
+    /// of [`instance_patterns`].
+    stale: bool,
+}
+
+impl<'a> Expander<'a> {
+    /// Creates an expander for the draw objects of `encoding`.
+    pub(crate) fn new(encoding: &'a Encoding) -> Self {
+        let mut expanded = Encoding::new();
+        // Scenes, unlike fragments, start with a transform and linewidth that have no tags.
+        let n_transform_tags = count_tags(encoding, PathTag::TRANSFORM);
+        if encoding.transforms.len() > n_transform_tags {
+            expanded.transforms.push(encoding.transforms[0]);
+        }
+        let n_linewidth_tags = count_tags(encoding, PathTag::LINEWIDTH);
+        if encoding.linewidths.len() > n_linewidth_tags {
+            expanded.linewidths.push(encoding.linewidths[0]);
+        }
+        #[cfg(feature = "full")]
+        {
+            // Copies share the glyphs and color stops of the original.
+            let resources = &mut expanded.resources;
+            resources
+                .color_stops
+                .clone_from(&encoding.resources.color_stops);
+            resources.glyphs.clone_from(&encoding.resources.glyphs);
+            resources
+                .normalized_coords
+                .clone_from(&encoding.resources.normalized_coords);
+        }
+        Self {
+            encoding,
+            expanded,
+            stale: false,
+        }
+    }
+
+    /// Appends a draw object, with `instance` applied to its transforms and
+    /// solid colors replaced by `color`.
+    pub(crate) fn append(
+        &mut self,
+        object: &DrawObject,
+        instance: Option<Transform>,
+        color: Option<Color>,
+    ) {
+        self.append_path(object, instance);
+        self.append_draw(object, color);
+    }
+
+    /// Appends the path or glyph run of a draw object, with `instance` applied
+    /// to its transforms.
+    fn append_path(&mut self, object: &DrawObject, instance: Option<Transform>) {
+        let encoding = self.encoding;
+        let expanded = &mut self.expanded;
+        let transform = |ix: Option<usize>| {
+            let transform = ix.map(|ix| encoding.transforms[ix]);
+            match instance {
+                Some(instance) => Some(instance * transform.unwrap_or(Transform::IDENTITY)),
+                None => transform,
+            }
+        };
+        #[cfg(feature = "full")]
+        if let Some(index) = object.glyph_run {
+            let mut run = encoding.resources.glyph_runs[index].clone();
+            run.stream_offsets = expanded.stream_offsets();
+            let resources = &mut expanded.resources;
+            if let Some(instance) = instance {
+                run.transform = instance * run.transform;
+            }
+            resources.patches.push(Patch::GlyphRun {
+                index: resources.glyph_runs.len(),
+            });
+            resources.glyph_runs.push(run);
+            self.stale = true;
+        }
+        if !object.path_tags.is_empty() {
+            // Restore the state at the start of the path, which differs from the state of the
+            // expanded encoding after the contents of a pattern.
+            let mut transform_ix = object.transform;
+            let mut linewidth_ix = object.linewidth;
+            if self.stale {
+                expanded.path_tags.push(PathTag::TRANSFORM);
+                expanded
+                    .transforms
+                    .push(transform(transform_ix).unwrap_or(Transform::IDENTITY));
+                expanded.path_tags.push(PathTag::LINEWIDTH);
+                expanded
+                    .linewidths
+                    .push(linewidth_ix.map_or(-1.0, |ix| encoding.linewidths[ix]));
+                self.stale = false;
+            } else {
+                if let Some(transform) = transform(transform_ix) {
+                    expanded.encode_transform(transform);
+                }
+                if let Some(ix) = linewidth_ix {
+                    expanded.encode_linewidth(encoding.linewidths[ix]);
+                }
+            }
+            for &tag in &encoding.path_tags[object.path_tags.clone()] {
+                if tag == PathTag::TRANSFORM {
+                    transform_ix = Some(transform_ix.map_or(0, |ix| ix + 1));
+                    if let Some(transform) = transform(transform_ix) {
+                        expanded.encode_transform(transform);
+                    }
+                } else if tag == PathTag::LINEWIDTH {
+                    let ix = linewidth_ix.map_or(0, |ix| ix + 1);
+                    linewidth_ix = Some(ix);
+                    expanded.encode_linewidth(encoding.linewidths[ix]);
+                } else {
+                    expanded.path_tags.push(tag);
+                    expanded.n_paths += (tag == PathTag::PATH) as u32;
+                    expanded.n_path_segments += tag.is_path_segment() as u32;
+                }
+            }
+            expanded
+                .path_data
+                .extend_from_slice(&encoding.path_data[object.path_data.clone()]);
+        }
+    }
+
+    /// Appends the draw tag, draw data and layer effect of a draw object, with
+    /// solid colors replaced by `color`.
+    fn append_draw(&mut self, object: &DrawObject, color: Option<Color>) {
+        let encoding = self.encoding;
+        let expanded = &mut self.expanded;
+        #[cfg(feature = "full")]
+        if let Some(ix) = object.patch {
+            let mut patch = encoding.resources.patches[ix].clone();
+            if let Patch::Ramp {
+                draw_data_offset, ..
+            }
+            | Patch::Image {
+                draw_data_offset, ..
+            } = &mut patch
+            {
+                *draw_data_offset =
+                    *draw_data_offset - object.draw_data.start + expanded.draw_data.len();
+            }
+            expanded.resources.patches.push(patch);
+        }
+        if let Some(ix) = object.effect {
+            expanded.effects.push(EffectData {
+                draw_tag_ix: expanded.draw_tags.len() as u32,
+                ..encoding.effects[ix]
+            });
+        }
+        expanded.draw_tags.push(object.tag);
+        let draw_data = &encoding.draw_data[object.draw_data.clone()];
+        match color {
+            Some(color) if object.tag == DrawTag::COLOR => {
+                let color = bytemuck::pod_read_unaligned::<DrawColor>(draw_data).recolor(color);
+                expanded
+                    .draw_data
+                    .extend_from_slice(bytemuck::bytes_of(&color));
+            }
+            _ => expanded.draw_data.extend_from_slice(draw_data),
+        }
+        expanded.n_clips += object.tag.0 & 1;
+    }
+}
+
+/// Estimates the bounding box of a glyph run from the font size, as the outlines
+/// are only known after resolving.
+#[cfg(feature = "full")]
+fn glyph_run_bbox(encoding: &Encoding, index: usize) -> [f32; 4] {
+    let run = &encoding.resources.glyph_runs[index];
+    let s = run.font_size;
+    let glyph_transform = run.glyph_transform.unwrap_or(Transform::IDENTITY);
+    let mut bbox = [f32::MAX, f32::MAX, f32::MIN, f32::MIN];
+    let glyphs = encoding.resources.glyphs.get(run.glyphs.clone());
+    for glyph in glyphs.unwrap_or_default() {
+        let offset = Transform {
+            matrix: [1.0, 0.0, 0.0, 1.0],
+            translation: [glyph.x, glyph.y],
+        };
+        let t = run.transform * offset * glyph_transform;
+        for p in [[-s, -s], [s, -s], [-s, s], [s, s]] {
+            let p = t.apply(p);
+            bbox = union(bbox, [p[0], p[1], p[0], p[1]]);
+        }
+    }
+    if bbox[0] <= bbox[2] && bbox[1] <= bbox[3] {
+        [
+            bbox[0].floor(),
+            bbox[1].floor(),
+            bbox[2].ceil(),
+            bbox[3].ceil(),
+        ]
+    } else {
+        EMPTY
+    }
+}
+
+fn count_tags(encoding: &Encoding, tag: PathTag) -> usize {
+    encoding.path_tags.iter().filter(|t| **t == tag).count()
+}
+
+fn read_point(data: &[u8], offset: usize, is_f32: bool) -> [f32; 2] {
+    if is_f32 {
+        bytemuck::pod_read_unaligned(&data[offset..offset + 8])
+    } else {
+        let p: [i16; 2] = bytemuck::pod_read_unaligned(&data[offset..offset + 4]);
+        [p[0] as f32, p[1] as f32]
+    }
+}
+
+fn union(a: [f32; 4], b: [f32; 4]) -> [f32; 4] {
+    [
+        a[0].min(b[0]),
+        a[1].min(b[1]),
+        a[2].max(b[2]),
+        a[3].max(b[3]),
+    ]
+}
+
+fn intersect(a: [f32; 4], b: [f32; 4]) -> [f32; 4] {
+    [
+        a[0].max(b[0]),
+        a[1].max(b[1]),
+        a[2].min(b[2]),
+        a[3].min(b[3]),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use peniko::kurbo::{Affine, Rect};
+
+    use super::*;
+
+    fn fill_square(encoding: &mut Encoding, offset: (f64, f64)) {
+        encoding.encode_transform(Transform::from_kurbo(&Affine::translate(offset)));
+        encoding.encode_shape(&Rect::new(0.0, 0.0, 4.0, 4.0), true);
+        encoding.encode_color(DrawColor::new(Color::rgb8(0, 0, 255)));
+    }
+
+    /// Pattern data that draws the contents once at each placement.
+    fn placed(offsets: &[(f64, f64)]) -> (PatternData, Vec<Transform>) {
+        let pattern = PatternData {
+            transform: Transform::IDENTITY,
+            tiling: PatternData::TILING_PLACEMENTS,
+            ..bytemuck::Zeroable::zeroed()
+        };
+        let placements = offsets
+            .iter()
+            .map(|&offset| Transform::from_kurbo(&Affine::translate(offset)))
+            .collect();
+        (pattern, placements)
+    }
+
+    fn begin_placed(encoding: &mut Encoding, offsets: &[(f64, f64)]) {
+        let (pattern, placements) = placed(offsets);
+        encoding.encode_begin_pattern(pattern, std::iter::empty(), placements.into_iter());
+    }
+
+    const VIEWPORT: [f32; 4] = [0.0, 0.0, 1000.0, 1000.0];
+
+    #[test]
+    fn expand_nested_patterns() {
+        let mut encoding = Encoding::new();
+        begin_placed(&mut encoding, &[(0.0, 0.0), (100.0, 0.0)]);
+        fill_square(&mut encoding, (0.0, 0.0));
+        begin_placed(&mut encoding, &[(0.0, 0.0), (0.0, 10.0), (0.0, 20.0)]);
+        fill_square(&mut encoding, (1.0, 0.0));
+        encoding.encode_end_pattern();
+        // Drawn after the nested pattern, in each cell of the outer one.
+        fill_square(&mut encoding, (0.0, 50.0));
+        encoding.encode_end_pattern();
+        fill_square(&mut encoding, (500.0, 500.0));
+
+        let expanded = expand_patterns(&encoding, VIEWPORT).unwrap();
+        assert_eq!(expanded.n_patterns, 0);
+        let bboxes = draw_objects(&expanded)
+            .unwrap()
+            .iter()
+            .map(|object| object.bbox)
+            .collect::<Vec<_>>();
+        let square = |x: f32, y: f32| [x, y, x + 4.0, y + 4.0];
+        let mut expected = vec![];
+        for x in [0.0, 100.0] {
+            expected.push(square(x, 0.0));
+            for y in [0.0, 10.0, 20.0] {
+                expected.push(square(x + 1.0, y));
+            }
+            expected.push(square(x, 50.0));
+        }
+        expected.push(square(500.0, 500.0));
+        assert_eq!(bboxes, expected);
+    }
+
+    #[test]
+    fn instance_nested_patterns() {
+        let mut encoding = Encoding::new();
+        begin_placed(&mut encoding, &[(0.0, 0.0), (100.0, 0.0)]);
+        fill_square(&mut encoding, (0.0, 0.0));
+        begin_placed(&mut encoding, &[(0.0, 0.0), (0.0, 10.0), (0.0, 20.0)]);
+        fill_square(&mut encoding, (1.0, 0.0));
+        encoding.encode_end_pattern();
+        fill_square(&mut encoding, (0.0, 50.0));
+        encoding.encode_end_pattern();
+        fill_square(&mut encoding, (500.0, 500.0));
+
+        let instanced = instance_patterns(&encoding, VIEWPORT).unwrap();
+        assert_eq!(instanced.n_patterns, 0);
+        // The squares of the outer pattern and the one of the nested pattern
+        // are templates, followed by the last square.
+        assert_eq!(instanced.n_template_paths, 3);
+        assert_eq!(instanced.n_paths, 4);
+        assert!(instanced.draw_tags.iter().all(|&tag| tag == DrawTag::COLOR));
+        // Each cell of the outer pattern draws its squares around an instance
+        // of the nested pattern, whose cells are left to the pattern stage.
+        const NONE: u32 = PatternInstance::NONE;
+        const SINGLE_CELL: u32 = PatternInstance::SINGLE_CELL;
+        let mut expected = vec![];
+        for x in 0..2 {
+            expected.push((0, SINGLE_CELL, [0, 1], [x, 0], 0.0));
+            expected.push((1, 0, [2, 3], [0, 0], 100.0 * x as f32));
+            expected.push((0, SINGLE_CELL, [1, 2], [x, 0], 0.0));
+        }
+        expected.push((NONE, 0, [3, 4], [0, 0], 0.0));
+        let instances = instanced
+            .pattern_instances
+            .iter()
+            .map(|i| {
+                (
+                    i.pattern,
+                    i.flags,
+                    i.objects,
+                    i.cell,
+                    i.transform.translation[0],
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(instances, expected);
+        // The instances draw as many objects as expansion encodes.
+        let n_drawn: u64 = instanced
+            .pattern_instances
+            .iter()
+            .map(|i| i.n_cells(&instanced.pattern_data) * (i.objects[1] - i.objects[0]) as u64)
+            .sum();
+        let expanded = expand_patterns(&encoding, VIEWPORT).unwrap();
+        assert_eq!(n_drawn, expanded.draw_tags.len() as u64);
+        for instance in &instanced.pattern_instances {
+            let n_objects = instance.objects[1] - instance.objects[0];
+            assert_eq!(instance.info, n_objects * DrawTag::COLOR.info_size());
+        }
+    }
+
+    #[test]
+    fn nested_pattern_cells_are_bounded_by_clips() {
+        let mut encoding = Encoding::new();
+        begin_placed(&mut encoding, &[(0.0, 0.0), (100.0, 0.0)]);
+        encoding.encode_shape(&Rect::new(0.0, 0.0, 4.0, 4.0), true);
+        encoding.encode_begin_clip(Default::default(), 1.0);
+        // A grid with a cell every 10 units, clipped to the square.
+        let grid = PatternData {
+            transform: Transform::IDENTITY,
+            x_step: [10.0, 0.0],
+            y_step: [0.0, 10.0],
+            extend: PatternData::EXTEND_REPEAT,
+            ..bytemuck::Zeroable::zeroed()
+        };
+        encoding.encode_begin_pattern(grid, std::iter::empty(), std::iter::empty());
+        fill_square(&mut encoding, (0.0, 0.0));
+        encoding.encode_end_pattern();
+        encoding.encode_end_clip();
+        encoding.encode_end_pattern();
+
+        let expanded = expand_patterns(&encoding, VIEWPORT).unwrap();
+        // Per outer cell: the clip, the 2x2 grid cells that reach the square, and the end clip.
+        assert_eq!(expanded.draw_tags.len(), 2 * (1 + 4 + 1));
+    }
+
+    #[test]
+    fn too_many_cells() {
+        let mut encoding = Encoding::new();
+        let grid = PatternData {
+            transform: Transform::IDENTITY,
+            x_step: [1.0, 0.0],
+            y_step: [0.0, 1.0],
+            extend: PatternData::EXTEND_REPEAT,
+            ..bytemuck::Zeroable::zeroed()
+        };
+        encoding.encode_begin_pattern(grid, std::iter::empty(), std::iter::empty());
+        fill_square(&mut encoding, (0.0, 0.0));
+        encoding.encode_end_pattern();
+        let result = expand_patterns(&encoding, [0.0, 0.0, 2000.0, 2000.0]);
+        assert_eq!(result.err(), Some(PatternError::TooManyCells));
+
+        // Cells of nested patterns count for each cell of the enclosing pattern.
+        let mut encoding = Encoding::new();
+        let offsets = (0..1024).map(|i| (i as f64, 0.0)).collect::<Vec<_>>();
+        begin_placed(&mut encoding, &offsets);
+        begin_placed(&mut encoding, &offsets);
+        fill_square(&mut encoding, (0.0, 0.0));
+        encoding.encode_end_pattern();
+        encoding.encode_end_pattern();
+        let result = expand_patterns(&encoding, VIEWPORT);
+        assert_eq!(result.err(), Some(PatternError::TooManyCells));
+
+        // Instancing leaves the cells of a pattern to the pattern stage...
+        let mut encoding = Encoding::new();
+        encoding.encode_begin_pattern(grid, std::iter::empty(), std::iter::empty());
+        fill_square(&mut encoding, (0.0, 0.0));
+        encoding.encode_end_pattern();
+        let instanced = instance_patterns(&encoding, [0.0, 0.0, 2000.0, 2000.0]).unwrap();
+        assert_eq!(instanced.pattern_instances.len(), 1);
+        // ...but enumerates those of a pattern that holds nested patterns.
+        let mut encoding = Encoding::new();
+        encoding.encode_begin_pattern(grid, std::iter::empty(), std::iter::empty());
+        begin_placed(&mut encoding, &offsets[..1]);
+        fill_square(&mut encoding, (0.0, 0.0));
+        encoding.encode_end_pattern();
+        encoding.encode_end_pattern();
+        let result = instance_patterns(&encoding, [0.0, 0.0, 2000.0, 2000.0]);
+        assert_eq!(result.err(), Some(PatternError::TooManyCells));
+    }
+
+    #[test]
+    fn every_placement_is_a_cell() {
+        // More placements than the coordinate range of lattices.
+        let pattern = PatternData {
+            transform: Transform::IDENTITY,
+            tiling: PatternData::TILING_PLACEMENTS,
+            placements: [0, 1 << 21],
+            ..bytemuck::Zeroable::zeroed()
+        };
+        let cells = pattern.cell_range(VIEWPORT, [0.0, 0.0, 4.0, 4.0]);
+        assert_eq!(cells, [0, 0, 1 << 21, 1]);
+    }
+
+    #[test]
+    fn too_deep() {
+        let mut encoding = Encoding::new();
+        for _ in 0..=MAX_PATTERN_DEPTH {
+            begin_placed(&mut encoding, &[(0.0, 0.0)]);
+        }
+        fill_square(&mut encoding, (0.0, 0.0));
+        for _ in 0..=MAX_PATTERN_DEPTH {
+            encoding.encode_end_pattern();
+        }
+        let result = expand_patterns(&encoding, VIEWPORT);
+        assert_eq!(result.err(), Some(PatternError::TooDeep));
+    }
+
+    #[test]
+    fn malformed_encodings() {
+        let nested = || {
+            let mut encoding = Encoding::new();
+            begin_placed(&mut encoding, &[(0.0, 0.0)]);
+            fill_square(&mut encoding, (2.0, 0.0));
+            encoding
+        };
+        // Unbalanced begin and end pattern commands.
+        let unclosed = nested();
+        let mut unopened = Encoding::new();
+        fill_square(&mut unopened, (0.0, 0.0));
+        unopened.encode_end_pattern();
+        // A pattern without pattern data.
+        let mut no_data = nested();
+        no_data.encode_end_pattern();
+        no_data.pattern_data.clear();
+        // Transform tags without transforms.
+        let mut no_transform = nested();
+        no_transform.encode_end_pattern();
+        no_transform.transforms.clear();
+        // Path tags without path data.
+        let mut no_path_data = nested();
+        no_path_data.encode_end_pattern();
+        no_path_data.path_data.truncate(4);
+        // Draw tags without draw data.
+        let mut no_draw_data = nested();
+        no_draw_data.encode_end_pattern();
+        no_draw_data.draw_data.clear();
+        for encoding in [
+            unclosed,
+            unopened,
+            no_data,
+            no_transform,
+            no_path_data,
+            no_draw_data,
+        ] {
+            let result = expand_patterns(&encoding, VIEWPORT);
+            assert_eq!(result.err(), Some(PatternError::Malformed));
+        }
+    }
+}
@@ -2,18 +2,25 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
 use std::collections::HashMap;
+use std::ops::Range;
 
 use peniko::{Color, ColorStop, ColorStops};
 
+use super::{CacheStats, ColorSpace};
+
 const N_SAMPLES: usize = 512;
 const RETAINED_COUNT: usize = 64;
 
 /// Data and dimensions for a set of resolved gradient ramps.
-#[derive(Copy, Clone, Debug, Default)]
+#[derive(Clone, Debug, Default)]
 pub struct Ramps<'a> {
     pub data: &'a [u32],
     pub width: u32,
     pub height: u32,
+    /// Rows that were added or replaced since the last call to
+    /// [`Resolver::advance`](crate::Resolver::advance). Other rows are
+    /// unchanged.
+    pub dirty_rows: Range<u32>,
 }
 
 #[derive(Default)]
@@ -21,11 +28,16 @@ pub struct RampCache {
     epoch: u64,
     map: HashMap<ColorStops, (u32, u64)>,
     data: Vec<u32>,
+    dirty_rows: Range<u32>,
+    /// Color space in which the ramps are interpolated.
+    color_space: ColorSpace,
+    pub stats: CacheStats,
 }
 
 impl RampCache {
     pub fn advance(&mut self) {
         self.epoch += 1;
+        self.dirty_rows = 0..0;
         if self.map.len() > RETAINED_COUNT {
             self.map
                 .retain(|_key, value| value.0 < RETAINED_COUNT as u32);
@@ -33,14 +45,28 @@ impl RampCache {
         }
     }
 
+    /// Sets the color space in which ramps are interpolated, discarding the
+    /// cached ramps if it changed.
+    pub fn set_color_space(&mut self, color_space: ColorSpace) {
+        if color_space != self.color_space {
+            self.color_space = color_space;
+            self.map.clear();
+            self.data.clear();
+            self.dirty_rows = 0..0;
+        }
+    }
+
     pub fn add(&mut self, stops: &[ColorStop]) -> u32 {
+        let color_space = self.color_space;
+        self.stats.record(self.map.contains_key(stops));
         if let Some(entry) = self.map.get_mut(stops) {
             entry.1 = self.epoch;
             entry.0
         } else if self.map.len() < RETAINED_COUNT {
             let id = (self.data.len() / N_SAMPLES) as u32;
-            self.data.extend(make_ramp(stops));
+            self.data.extend(make_ramp(stops, color_space));
             self.map.insert(stops.into(), (id, self.epoch));
+            self.mark_dirty(id);
             id
         } else {
             let mut reuse = None;
@@ -55,16 +81,18 @@ impl RampCache {
                 let start = id as usize * N_SAMPLES;
                 for (dst, src) in self.data[start..start + N_SAMPLES]
                     .iter_mut()
-                    .zip(make_ramp(stops))
+                    .zip(make_ramp(stops, color_space))
                 {
                     *dst = src;
                 }
                 self.map.insert(stops.into(), (id, self.epoch));
+                self.mark_dirty(id);
                 id
             } else {
                 let id = (self.data.len() / N_SAMPLES) as u32;
-                self.data.extend(make_ramp(stops));
+                self.data.extend(make_ramp(stops, color_space));
                 self.map.insert(stops.into(), (id, self.epoch));
+                self.mark_dirty(id);
                 id
             }
         }
@@ -75,13 +103,32 @@ impl RampCache {
             data: &self.data,
             width: N_SAMPLES as u32,
             height: (self.data.len() / N_SAMPLES) as u32,
+            dirty_rows: self.dirty_rows.clone(),
+        }
+    }
+
+    fn mark_dirty(&mut self, id: u32) {
+        if self.dirty_rows.is_empty() {
+            self.dirty_rows = id..id + 1;
+        } else {
+            self.dirty_rows.start = self.dirty_rows.start.min(id);
+            self.dirty_rows.end = self.dirty_rows.end.max(id + 1);
         }
     }
 }
 
-fn make_ramp(stops: &[ColorStop]) -> impl Iterator<Item = u32> + '_ {
+/// Samples a ramp, interpolating in the specified color space. The samples are
+/// always sRGB encoded.
+fn make_ramp(stops: &[ColorStop], color_space: ColorSpace) -> impl Iterator<Item = u32> + '_ {
+    let to_color_space = move |color: Color| {
+        let c = ColorF64::from_color(color);
+        match color_space {
+            ColorSpace::Srgb => c,
+            ColorSpace::LinearSrgb => c.map_rgb(srgb_to_linear),
+        }
+    };
     let mut last_u = 0.0;
-    let mut last_c = ColorF64::from_color(stops[0].color);
+    let mut last_c = to_color_space(stops[0].color);
     let mut this_u = last_u;
     let mut this_c = last_c;
     let mut j = 0;
@@ -92,7 +139,7 @@ fn make_ramp(stops: &[ColorStop]) -> impl Iterator<Item = u32> + '_ {
             last_c = this_c;
             if let Some(s) = stops.get(j + 1) {
                 this_u = s.offset as f64;
-                this_c = ColorF64::from_color(s.color);
+                this_c = to_color_space(s.color);
                 j += 1;
             } else {
                 break;
@@ -104,10 +151,30 @@ fn make_ramp(stops: &[ColorStop]) -> impl Iterator<Item = u32> + '_ {
         } else {
             last_c.lerp(&this_c, (u - last_u) / du)
         };
-        c.as_premul_u32()
+        match color_space {
+            ColorSpace::Srgb => c,
+            ColorSpace::LinearSrgb => c.map_rgb(linear_to_srgb),
+        }
+        .as_premul_u32()
     })
 }
 
+fn srgb_to_linear(c: f64) -> f64 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(c: f64) -> f64 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.powf(1.0 / 2.4) - 0.055
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 struct ColorF64([f64; 4]);
 
@@ -121,6 +188,10 @@ impl ColorF64 {
         ])
     }
 
+    fn map_rgb(&self, f: impl Fn(f64) -> f64) -> Self {
+        Self([f(self.0[0]), f(self.0[1]), f(self.0[2]), self.0[3]])
+    }
+
     fn lerp(&self, other: &Self, a: f64) -> Self {
         fn l(x: f64, y: f64, a: f64) -> f64 {
             x * (1.0 - a) + y * a
@@ -3,7 +3,7 @@
 
 use bytemuck::{Pod, Zeroable};
 
-use super::{DrawTag, Encoding, PathTag, StreamOffsets, Transform};
+use super::{DrawTag, Encoding, PathTag, PatternInstance, StreamOffsets, Transform};
 
 #[cfg(feature = "full")]
 use {
@@ -11,6 +11,7 @@ use {
         glyph_cache::{CachedRange, GlyphCache, GlyphKey},
         image_cache::{ImageCache, Images},
         ramp_cache::{RampCache, Ramps},
+        ColorSpace,
     },
     peniko::{Extend, Image},
     std::ops::Range,
@@ -28,6 +29,10 @@ pub struct Layout {
     pub n_clips: u32,
     /// Number of patterns
     pub n_patterns: u32,
+    /// Number of pattern instances.
+    pub n_pattern_instances: u32,
+    /// Number of template paths, which hold the geometry of pattern instances.
+    pub n_template_paths: u32,
     /// Start of binning data.
     pub bin_data_start: u32,
     /// Start of path tag stream.
@@ -44,6 +49,15 @@ pub struct Layout {
     pub linewidth_base: u32,
     /// Start of pattern stream.
     pub pattern_base: u32,
+    /// Start of pattern placement stream.
+    pub placement_base: u32,
+    /// Start of pattern color stream.
+    pub pattern_color_base: u32,
+    /// Start of pattern instance stream.
+    pub pattern_instance_base: u32,
+    /// Start of the draw source stream, with the tag and draw data offset of
+    /// each draw object, which is only packed with pattern instances.
+    pub draw_source_base: u32,
 }
 
 impl Layout {
@@ -103,7 +117,26 @@ impl Layout {
     /// Returns the linewidth stream.
     pub fn linewidths<'a>(&self, data: &'a [u8]) -> &'a [f32] {
         let start = self.linewidth_base as usize * 4;
-        bytemuck::cast_slice(&data[start..])
+        let end = self.pattern_base as usize * 4;
+        bytemuck::cast_slice(&data[start..end])
+    }
+
+    /// Returns the size of the packed scene in bytes.
+    pub fn scene_size(&self) -> u32 {
+        let n_sources = if self.n_pattern_instances != 0 {
+            self.n_draw_objects
+        } else {
+            0
+        };
+        (self.draw_source_base + n_sources * 2) * 4
+    }
+
+    /// Returns the pattern instance stream.
+    pub fn pattern_instances<'a>(&self, data: &'a [u8]) -> &'a [PatternInstance] {
+        let start = self.pattern_instance_base as usize * 4;
+        let end =
+            start + self.n_pattern_instances as usize * std::mem::size_of::<PatternInstance>();
+        bytemuck::cast_slice(&data[start..end])
     }
 }
 
@@ -158,14 +191,44 @@ pub fn resolve_solid_paths_only(encoding: &Encoding, packed: &mut Vec<u8>) -> La
     // Linewidth stream
     layout.linewidth_base = size_to_words(data.len());
     data.extend_from_slice(bytemuck::cast_slice(&encoding.linewidths));
-    // Pattern stream
-    layout.pattern_base = size_to_words(data.len());
-    data.extend_from_slice(bytemuck::cast_slice(&encoding.pattern_data));
+    pack_patterns(encoding, &mut layout, data);
     layout.n_draw_objects = layout.n_paths + layout.n_patterns;
     assert_eq!(buffer_size, data.len());
     layout
 }
 
+/// Packs the pattern streams, which follow the linewidth stream.
+fn pack_patterns(encoding: &Encoding, layout: &mut Layout, data: &mut Vec<u8>) {
+    // Pattern stream
+    layout.pattern_base = size_to_words(data.len());
+    data.extend_from_slice(bytemuck::cast_slice(&encoding.pattern_data));
+    // Pattern placement stream
+    layout.placement_base = size_to_words(data.len());
+    data.extend_from_slice(bytemuck::cast_slice(&encoding.pattern_placements));
+    // Pattern color stream, with the offset of each stop followed by its color
+    // packed like `Color::to_premul_u32`, but not premultiplied.
+    layout.pattern_color_base = size_to_words(data.len());
+    for stop in &encoding.pattern_colors {
+        let color = stop.color;
+        let rgba = u32::from_be_bytes([color.r, color.g, color.b, color.a]);
+        data.extend_from_slice(bytemuck::bytes_of(&[stop.offset.to_bits(), rgba]));
+    }
+    // Pattern instance stream
+    layout.pattern_instance_base = size_to_words(data.len());
+    data.extend_from_slice(bytemuck::cast_slice(&encoding.pattern_instances));
+    layout.n_pattern_instances = encoding.pattern_instances.len() as u32;
+    layout.n_template_paths = encoding.n_template_paths;
+    // Draw source stream, from which the pattern stage expands the instances
+    layout.draw_source_base = size_to_words(data.len());
+    if !encoding.pattern_instances.is_empty() {
+        let mut offset = 0;
+        for tag in layout.draw_tags(data).to_vec() {
+            data.extend_from_slice(bytemuck::bytes_of(&[tag.0, offset]));
+            offset += (tag.0 >> 2) & 0x7;
+        }
+    }
+}
+
 /// Resolver for late bound resources.
 #[cfg(feature = "full")]
 #[derive(Default)]
@@ -175,6 +238,7 @@ pub struct Resolver {
     glyph_cx: fello::scale::Context,
     ramp_cache: RampCache,
     image_cache: ImageCache,
+    image_stats: CacheStats,
     pending_images: Vec<PendingImage>,
     patches: Vec<ResolvedPatch>,
 }
@@ -186,6 +250,35 @@ impl Resolver {
         Self::default()
     }
 
+    /// Advances the caches to the next frame.
+    ///
+    /// Resources resolved in previous frames are retained, so this should be
+    /// called once per frame rather than creating a new resolver. Gradient
+    /// ramps that haven't been used for a few frames may then be replaced, and
+    /// [`Ramps::dirty_rows`] is reset. Likewise, images of previous frames may
+    /// be evicted from the atlas, and [`Images::dirty`] is reset.
+    pub fn advance(&mut self) {
+        self.ramp_cache.advance();
+        self.image_cache.advance();
+        self.glyph_cache.advance();
+    }
+
+    /// Sets the color space in which gradient ramps are interpolated. Cached
+    /// ramps are discarded when it changes.
+    pub fn set_ramp_color_space(&mut self, color_space: ColorSpace) {
+        self.ramp_cache.set_color_space(color_space);
+    }
+
+    /// Returns the hit and miss counts of the caches since the resolver was
+    /// created.
+    pub fn stats(&self) -> ResolverStats {
+        ResolverStats {
+            glyphs: self.glyph_cache.stats,
+            ramps: self.ramp_cache.stats,
+            images: self.image_stats,
+        }
+    }
+
     /// Resolves late bound resources and packs an encoding. Returns the packed
     /// layout and computed ramp data.
     pub fn resolve<'a>(
@@ -305,15 +398,15 @@ impl Resolver {
                         if pos < *draw_data_offset {
                             data.extend_from_slice(&encoding.draw_data[pos..*draw_data_offset]);
                         }
-                        if let Some((x, y)) = self.pending_images[*index].xy {
-                            let xy = (x << 16) | y;
+                        if let Some((x, y, page)) = self.pending_images[*index].location {
+                            let xy = (page << 26) | (x << 13) | y;
                             data.extend_from_slice(bytemuck::bytes_of(&xy));
                             pos = *draw_data_offset + 4;
                         } else {
                             // If we get here, we failed to allocate a slot for this image in the atlas.
                             // In this case, let's zero out the dimensions so we don't attempt to render
                             // anything.
-                            // TODO: a better strategy: texture array? downsample large images?
+                            // TODO: downsample large images?
                             data.extend_from_slice(&[0u8; 8]);
                             pos = *draw_data_offset + 8;
                         }
@@ -391,16 +484,14 @@ impl Resolver {
                 data.extend_from_slice(bytemuck::cast_slice(&stream[pos..]));
             }
         }
+        pack_patterns(encoding, &mut layout, data);
         layout.n_draw_objects = layout.n_paths + layout.n_patterns;
         assert_eq!(buffer_size, data.len());
         (layout, self.ramp_cache.ramps(), self.image_cache.images())
     }
 
     fn resolve_patches(&mut self, encoding: &Encoding) -> StreamOffsets {
-        self.ramp_cache.advance();
-        self.glyph_cache.clear();
         self.glyph_ranges.clear();
-        self.image_cache.clear();
         self.pending_images.clear();
         self.patches.clear();
         let mut sizes = StreamOffsets::default();
@@ -496,7 +587,7 @@ impl Resolver {
                     let index = self.pending_images.len();
                     self.pending_images.push(PendingImage {
                         image: image.clone(),
-                        xy: None,
+                        location: None,
                     });
                     self.patches.push(ResolvedPatch::Image {
                         index,
@@ -509,30 +600,58 @@ impl Resolver {
     }
 
     fn resolve_pending_images(&mut self) {
-        self.image_cache.clear();
-        'outer: loop {
-            // Loop over the images, attempting to allocate them all into the atlas.
-            for pending_image in &mut self.pending_images {
-                if let Some(xy) = self.image_cache.get_or_insert(&pending_image.image) {
-                    pending_image.xy = Some(xy);
-                } else {
-                    // We failed to allocate. Try to bump the atlas size.
-                    if self.image_cache.bump_size() {
-                        // We were able to increase the atlas size. Restart the outer loop.
-                        continue 'outer;
-                    } else {
-                        // If the atlas is already maximum size, there's nothing we can do. Set
-                        // the xy field to None so this image isn't rendered and then carry on--
-                        // other images might still fit.
-                        pending_image.xy = None;
-                    }
-                }
-            }
-            // If we made it here, we've either successfully allocated all images or we reached
-            // the maximum atlas size.
-            break;
+        for pending_image in &mut self.pending_images {
+            self.image_stats
+                .record(self.image_cache.contains(&pending_image.image));
+            // If the image can't be allocated, its location is left as None so
+            // it isn't rendered, and other images might still fit.
+            pending_image.location = self.image_cache.get_or_insert(&pending_image.image);
+        }
+    }
+}
+
+/// Hit and miss counts of a cache.
+#[cfg(feature = "full")]
+#[derive(Copy, Clone, Default, Debug)]
+pub struct CacheStats {
+    /// Number of lookups that found the resource in the cache.
+    pub hits: u64,
+    /// Number of lookups that added the resource to the cache.
+    pub misses: u64,
+}
+
+#[cfg(feature = "full")]
+impl CacheStats {
+    /// Returns the fraction of lookups that were hits, or zero if there were no
+    /// lookups.
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            0.0
+        } else {
+            self.hits as f64 / lookups as f64
         }
     }
+
+    pub(crate) fn record(&mut self, hit: bool) {
+        if hit {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+    }
+}
+
+/// Cache counters of a [`Resolver`].
+#[cfg(feature = "full")]
+#[derive(Copy, Clone, Default, Debug)]
+pub struct ResolverStats {
+    /// Glyph outlines.
+    pub glyphs: CacheStats,
+    /// Gradient ramps.
+    pub ramps: CacheStats,
+    /// Images in the atlas.
+    pub images: CacheStats,
 }
 
 /// Patch for a late bound resource.
@@ -567,7 +686,8 @@ pub enum Patch {
 #[derive(Clone, Debug)]
 struct PendingImage {
     image: Image,
-    xy: Option<(u32, u32)>,
+    /// Atlas coordinates and page.
+    location: Option<(u32, u32, u32)>,
 }
 
 #[cfg(feature = "full")]
@@ -620,7 +740,16 @@ impl SceneBufferSizes {
             + slice_size_in_bytes(&encoding.draw_data, patch_sizes.draw_data)
             + slice_size_in_bytes(&encoding.transforms, patch_sizes.transforms)
             + slice_size_in_bytes(&encoding.linewidths, patch_sizes.linewidths)
-            + slice_size_in_bytes(&encoding.pattern_data, patch_sizes.patterns);
+            + slice_size_in_bytes(&encoding.pattern_data, patch_sizes.patterns)
+            + slice_size_in_bytes(&encoding.pattern_placements, 0)
+            + encoding.pattern_colors.len() * 8
+            + slice_size_in_bytes(&encoding.pattern_instances, 0)
+            + if encoding.pattern_instances.is_empty() {
+                0
+            } else {
+                (encoding.draw_tags.len() + patch_sizes.draw_tags + encoding.n_open_clips as usize)
+                    * 8
+            };
         Self {
             buffer_size,
             path_tag_padded,
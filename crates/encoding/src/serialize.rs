@@ -0,0 +1,1274 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+
+use bytemuck::Pod;
+use peniko::{Blob, Cap, Color, ColorStop, Extend, Fill, Font, Format, Image, Join, Stroke, Style};
+
+use super::{
+    DrawTag, EffectData, Encoding, Glyph, GlyphRun, Patch, PathTag, PatternData, PatternInstance,
+    StreamOffsets, Transform,
+};
+
+/// Identifies a serialized encoding.
+const MAGIC: [u8; 4] = *b"VENC";
+
+/// Version of the format written by [`Encoding::save`].
+///
+/// This is incremented on any change to the format. Only this version can be
+/// loaded: encodings written with an older version are rejected rather than
+/// converted, and must be saved again.
+pub const SERIALIZATION_VERSION: u32 = 1;
+
+/// How font and image data is stored in a serialized encoding.
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub enum BlobStorage {
+    /// The data is embedded, so the serialized encoding is self-contained.
+    #[default]
+    Embed,
+    /// Only the identifiers of the blobs are stored, and the data is supplied
+    /// by the caller of [`Encoding::load_with_blobs`].
+    Reference,
+}
+
+impl Encoding {
+    /// Writes the encoding in a compact, versioned binary format.
+    ///
+    /// All streams are written as they are, along with the late bound resources
+    /// and their patches. Font and image data is stored as specified by `blobs`,
+    /// once for each distinct blob.
+    pub fn save(&self, writer: &mut impl Write, blobs: BlobStorage) -> io::Result<()> {
+        let mut encoder = Encoder::default();
+        encoder.encoding(self, blobs);
+        writer.write_all(&encoder.data)
+    }
+
+    /// Reads an encoding written by [`save`](Self::save) with embedded blobs.
+    pub fn load(reader: &mut impl Read) -> io::Result<Self> {
+        Self::load_with_blobs(reader, |_| None)
+    }
+
+    /// Reads an encoding written by [`save`](Self::save).
+    ///
+    /// `blob` is called with the identifier of each blob that was stored by
+    /// reference, and returns its data. Loading fails if it returns `None`.
+    pub fn load_with_blobs(
+        reader: &mut impl Read,
+        blob: impl FnMut(u64) -> Option<Blob<u8>>,
+    ) -> io::Result<Self> {
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+        Decoder {
+            data: &data,
+            blobs: vec![],
+        }
+        .encoding(blob)
+    }
+
+    /// Returns a JSON representation of the encoding for debugging.
+    ///
+    /// Blobs are written as their identifiers and sizes. The result can't be
+    /// loaded.
+    pub fn to_json(&self) -> String {
+        let mut json = String::new();
+        write_json(&mut json, self).unwrap();
+        json
+    }
+}
+
+#[derive(Default)]
+struct Encoder {
+    data: Vec<u8>,
+}
+
+impl Encoder {
+    fn encoding(&mut self, encoding: &Encoding, storage: BlobStorage) {
+        let resources = &encoding.resources;
+        // Collect the distinct blobs so that shared fonts and images are only
+        // written once, and resolve to a shared blob when loaded.
+        let mut blobs = vec![];
+        let mut blob_indices = HashMap::new();
+        let fonts = resources.glyph_runs.iter().map(|run| &run.font.data);
+        let images = resources.patches.iter().filter_map(|patch| match patch {
+            Patch::Image { image, .. } => Some(&image.data),
+            _ => None,
+        });
+        for blob in fonts.chain(images) {
+            blob_indices.entry(blob.id()).or_insert_with(|| {
+                blobs.push(blob);
+                blobs.len() as u32 - 1
+            });
+        }
+        self.data.extend_from_slice(&MAGIC);
+        self.u32(SERIALIZATION_VERSION);
+        for n in [
+            encoding.n_paths,
+            encoding.n_path_segments,
+            encoding.n_clips,
+            encoding.n_open_clips,
+            encoding.n_patterns,
+            encoding.n_template_paths,
+        ] {
+            self.u32(n);
+        }
+        self.pod_slice(&encoding.path_tags);
+        self.pod_slice(&encoding.path_data);
+        self.pod_slice(&encoding.draw_tags);
+        self.pod_slice(&encoding.draw_data);
+        self.pod_slice(&encoding.transforms);
+        self.pod_slice(&encoding.pattern_data);
+        self.color_stops(&encoding.pattern_colors);
+        self.pod_slice(&encoding.pattern_placements);
+        self.pod_slice(&encoding.pattern_instances);
+        self.pod_slice(&encoding.effects);
+        self.pod_slice(&encoding.linewidths);
+        self.u32(blobs.len() as u32);
+        for blob in blobs {
+            self.u64(blob.id());
+            match storage {
+                BlobStorage::Embed => {
+                    self.u8(0);
+                    self.pod_slice(blob.data());
+                }
+                BlobStorage::Reference => self.u8(1),
+            }
+        }
+        self.color_stops(&resources.color_stops);
+        self.u32(resources.glyphs.len() as u32);
+        for glyph in &resources.glyphs {
+            self.u32(glyph.id);
+            self.f32(glyph.x);
+            self.f32(glyph.y);
+        }
+        self.pod_slice(&resources.normalized_coords);
+        self.u32(resources.glyph_runs.len() as u32);
+        for run in &resources.glyph_runs {
+            self.u32(blob_indices[&run.font.data.id()]);
+            self.u32(run.font.index);
+            self.transform(&run.transform);
+            match &run.glyph_transform {
+                Some(transform) => {
+                    self.u8(1);
+                    self.transform(transform);
+                }
+                None => self.u8(0),
+            }
+            self.f32(run.font_size);
+            self.u8(run.hint as u8);
+            self.range(&run.normalized_coords);
+            self.style(&run.style);
+            self.range(&run.glyphs);
+            self.stream_offsets(&run.stream_offsets);
+        }
+        self.u32(resources.patches.len() as u32);
+        for patch in &resources.patches {
+            match patch {
+                Patch::Ramp {
+                    draw_data_offset,
+                    stops,
+                    extend,
+                } => {
+                    self.u8(0);
+                    self.u32(*draw_data_offset as u32);
+                    self.range(stops);
+                    self.u8(encode_extend(*extend));
+                }
+                Patch::GlyphRun { index } => {
+                    self.u8(1);
+                    self.u32(*index as u32);
+                }
+                Patch::Image {
+                    draw_data_offset,
+                    image,
+                } => {
+                    self.u8(2);
+                    self.u32(*draw_data_offset as u32);
+                    self.u32(blob_indices[&image.data.id()]);
+                    self.u8(match image.format {
+                        Format::Rgba8 => 0,
+                    });
+                    self.u32(image.width);
+                    self.u32(image.height);
+                    self.u8(encode_extend(image.extend));
+                }
+            }
+        }
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn f32(&mut self, value: f32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Writes the length of a slice followed by its contents.
+    ///
+    /// The contents are written in memory order, which matches the layout
+    /// uploaded to the GPU.
+    fn pod_slice<T: Pod>(&mut self, values: &[T]) {
+        self.u32(values.len() as u32);
+        self.data.extend_from_slice(bytemuck::cast_slice(values));
+    }
+
+    fn range(&mut self, range: &Range<usize>) {
+        self.u32(range.start as u32);
+        self.u32(range.end as u32);
+    }
+
+    fn transform(&mut self, transform: &Transform) {
+        self.data.extend_from_slice(bytemuck::bytes_of(transform));
+    }
+
+    fn color_stops(&mut self, stops: &[ColorStop]) {
+        self.u32(stops.len() as u32);
+        for stop in stops {
+            self.f32(stop.offset);
+            let Color { r, g, b, a } = stop.color;
+            self.data.extend_from_slice(&[r, g, b, a]);
+        }
+    }
+
+    fn style(&mut self, style: &Style) {
+        match style {
+            Style::Fill(Fill::NonZero) => self.u8(0),
+            Style::Fill(Fill::EvenOdd) => self.u8(1),
+            Style::Stroke(stroke) => {
+                self.u8(2);
+                self.f32(stroke.width);
+                self.u8(match stroke.join {
+                    Join::Bevel => 0,
+                    Join::Miter => 1,
+                    Join::Round => 2,
+                });
+                self.f32(stroke.miter_limit);
+                self.u8(encode_cap(stroke.start_cap));
+                self.u8(encode_cap(stroke.end_cap));
+                self.pod_slice(&stroke.dash_pattern);
+                self.f32(stroke.dash_offset);
+                self.u8(stroke.scale as u8);
+            }
+        }
+    }
+
+    fn stream_offsets(&mut self, offsets: &StreamOffsets) {
+        for offset in [
+            offsets.path_tags,
+            offsets.path_data,
+            offsets.draw_tags,
+            offsets.draw_data,
+            offsets.transforms,
+            offsets.linewidths,
+            offsets.patterns,
+        ] {
+            self.u32(offset as u32);
+        }
+    }
+}
+
+fn encode_extend(extend: Extend) -> u8 {
+    match extend {
+        Extend::Pad => 0,
+        Extend::Repeat => 1,
+        Extend::Reflect => 2,
+    }
+}
+
+fn encode_cap(cap: Cap) -> u8 {
+    match cap {
+        Cap::Butt => 0,
+        Cap::Square => 1,
+        Cap::Round => 2,
+    }
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    blobs: Vec<Blob<u8>>,
+}
+
+impl Decoder<'_> {
+    fn encoding(mut self, mut blob: impl FnMut(u64) -> Option<Blob<u8>>) -> io::Result<Encoding> {
+        if self.bytes(4)? != MAGIC {
+            return Err(invalid_data("not a serialized encoding"));
+        }
+        let version = self.u32()?;
+        if version != SERIALIZATION_VERSION {
+            return Err(invalid_data(format!(
+                "unsupported serialization version {version}"
+            )));
+        }
+        let mut encoding = Encoding {
+            n_paths: self.u32()?,
+            n_path_segments: self.u32()?,
+            n_clips: self.u32()?,
+            n_open_clips: self.u32()?,
+            n_patterns: self.u32()?,
+            n_template_paths: self.u32()?,
+            ..Default::default()
+        };
+        encoding.path_tags = self.pod_vec::<PathTag>()?;
+        encoding.path_data = self.pod_vec()?;
+        encoding.draw_tags = self.pod_vec::<DrawTag>()?;
+        encoding.draw_data = self.pod_vec()?;
+        encoding.transforms = self.pod_vec::<Transform>()?;
+        encoding.pattern_data = self.pod_vec::<PatternData>()?;
+        encoding.pattern_colors = self.color_stops()?;
+        encoding.pattern_placements = self.pod_vec::<Transform>()?;
+        encoding.pattern_instances = self.pod_vec::<PatternInstance>()?;
+        encoding.effects = self.pod_vec::<EffectData>()?;
+        encoding.linewidths = self.pod_vec()?;
+        let n_blobs = self.len(9)?;
+        for _ in 0..n_blobs {
+            let id = self.u64()?;
+            let data = match self.u8()? {
+                0 => Blob::from(self.pod_vec::<u8>()?),
+                1 => blob(id).ok_or_else(|| invalid_data(format!("missing blob {id}")))?,
+                _ => return Err(invalid_data("invalid blob storage")),
+            };
+            self.blobs.push(data);
+        }
+        let resources = &mut encoding.resources;
+        resources.color_stops = self.color_stops()?;
+        let n_glyphs = self.len(12)?;
+        resources.glyphs = (0..n_glyphs)
+            .map(|_| {
+                Ok(Glyph {
+                    id: self.u32()?,
+                    x: self.f32()?,
+                    y: self.f32()?,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        resources.normalized_coords = self.pod_vec()?;
+        let n_glyph_runs = self.len(1)?;
+        for _ in 0..n_glyph_runs {
+            let font = Font::new(self.blob()?, self.u32()?);
+            let transform = self.transform()?;
+            let glyph_transform = match self.u8()? {
+                0 => None,
+                _ => Some(self.transform()?),
+            };
+            resources.glyph_runs.push(GlyphRun {
+                font,
+                transform,
+                glyph_transform,
+                font_size: self.f32()?,
+                hint: self.u8()? != 0,
+                normalized_coords: self.range()?,
+                style: self.style()?,
+                glyphs: self.range()?,
+                stream_offsets: self.stream_offsets()?,
+            });
+        }
+        let n_patches = self.len(1)?;
+        for _ in 0..n_patches {
+            let patch = match self.u8()? {
+                0 => Patch::Ramp {
+                    draw_data_offset: self.u32()? as usize,
+                    stops: self.range()?,
+                    extend: self.extend()?,
+                },
+                1 => Patch::GlyphRun {
+                    index: self.u32()? as usize,
+                },
+                2 => {
+                    let draw_data_offset = self.u32()? as usize;
+                    let data = self.blob()?;
+                    let format = match self.u8()? {
+                        0 => Format::Rgba8,
+                        _ => return Err(invalid_data("invalid image format")),
+                    };
+                    let image = Image::new(data, format, self.u32()?, self.u32()?)
+                        .with_extend(self.extend()?);
+                    Patch::Image {
+                        draw_data_offset,
+                        image,
+                    }
+                }
+                _ => return Err(invalid_data("invalid patch")),
+            };
+            resources.patches.push(patch);
+        }
+        if !self.data.is_empty() {
+            return Err(invalid_data("trailing data"));
+        }
+        validate(&encoding)?;
+        Ok(encoding)
+    }
+
+    fn bytes(&mut self, len: usize) -> io::Result<&[u8]> {
+        if len > self.data.len() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    fn f32(&mut self) -> io::Result<f32> {
+        Ok(f32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    /// Reads the length of a sequence with elements of at least `min_size`
+    /// bytes, failing early if the remaining data is too short to hold it.
+    fn len(&mut self, min_size: usize) -> io::Result<usize> {
+        let len = self.u32()? as usize;
+        if len.saturating_mul(min_size) > self.data.len() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(len)
+    }
+
+    fn pod_vec<T: Pod>(&mut self) -> io::Result<Vec<T>> {
+        let size = std::mem::size_of::<T>();
+        let len = self.len(size)?;
+        let bytes = self.bytes(len * size)?;
+        Ok(bytes
+            .chunks_exact(size)
+            .map(bytemuck::pod_read_unaligned)
+            .collect())
+    }
+
+    fn range(&mut self) -> io::Result<Range<usize>> {
+        Ok(self.u32()? as usize..self.u32()? as usize)
+    }
+
+    fn transform(&mut self) -> io::Result<Transform> {
+        Ok(bytemuck::pod_read_unaligned(
+            self.bytes(std::mem::size_of::<Transform>())?,
+        ))
+    }
+
+    fn blob(&mut self) -> io::Result<Blob<u8>> {
+        let index = self.u32()? as usize;
+        self.blobs
+            .get(index)
+            .cloned()
+            .ok_or_else(|| invalid_data("invalid blob index"))
+    }
+
+    fn color_stops(&mut self) -> io::Result<Vec<ColorStop>> {
+        let len = self.len(8)?;
+        (0..len)
+            .map(|_| {
+                let offset = self.f32()?;
+                let [r, g, b, a] = self.bytes(4)?.try_into().unwrap();
+                Ok(ColorStop {
+                    offset,
+                    color: Color::rgba8(r, g, b, a),
+                })
+            })
+            .collect()
+    }
+
+    fn style(&mut self) -> io::Result<Style> {
+        Ok(match self.u8()? {
+            0 => Style::Fill(Fill::NonZero),
+            1 => Style::Fill(Fill::EvenOdd),
+            2 => {
+                let width = self.f32()?;
+                let join = match self.u8()? {
+                    0 => Join::Bevel,
+                    1 => Join::Miter,
+                    2 => Join::Round,
+                    _ => return Err(invalid_data("invalid stroke join")),
+                };
+                let miter_limit = self.f32()?;
+                let start_cap = self.cap()?;
+                let end_cap = self.cap()?;
+                let dashes = self.pod_vec::<f32>()?;
+                let dash_offset = self.f32()?;
+                let mut stroke = Stroke::new(width)
+                    .with_join(join)
+                    .with_miter_limit(miter_limit)
+                    .with_start_cap(start_cap)
+                    .with_end_cap(end_cap)
+                    .with_dashes(dash_offset, dashes);
+                stroke.scale = self.u8()? != 0;
+                Style::Stroke(stroke)
+            }
+            _ => return Err(invalid_data("invalid glyph run style")),
+        })
+    }
+
+    fn cap(&mut self) -> io::Result<Cap> {
+        Ok(match self.u8()? {
+            0 => Cap::Butt,
+            1 => Cap::Square,
+            2 => Cap::Round,
+            _ => return Err(invalid_data("invalid stroke cap")),
+        })
+    }
+
+    fn extend(&mut self) -> io::Result<Extend> {
+        Ok(match self.u8()? {
+            0 => Extend::Pad,
+            1 => Extend::Repeat,
+            2 => Extend::Reflect,
+            _ => return Err(invalid_data("invalid extend mode")),
+        })
+    }
+
+    fn stream_offsets(&mut self) -> io::Result<StreamOffsets> {
+        Ok(StreamOffsets {
+            path_tags: self.u32()? as usize,
+            path_data: self.u32()? as usize,
+            draw_tags: self.u32()? as usize,
+            draw_data: self.u32()? as usize,
+            transforms: self.u32()? as usize,
+            linewidths: self.u32()? as usize,
+            patterns: self.u32()? as usize,
+        })
+    }
+}
+
+/// Checks that the indices and ranges of the late bound resources of a decoded
+/// encoding are within the streams they refer to, so that resolving it can't
+/// fail.
+fn validate(encoding: &Encoding) -> io::Result<()> {
+    let resources = &encoding.resources;
+    let check_range = |range: &Range<usize>, len: usize, what: &str| {
+        if range.start > range.end || range.end > len {
+            return Err(invalid_data(format!("{what} out of bounds")));
+        }
+        Ok(())
+    };
+    // Patches overwrite a ramp index, or the location and size of an image.
+    let check_draw_data = |offset: usize, size: usize| {
+        check_range(
+            &(offset..offset.saturating_add(size)),
+            encoding.draw_data.len(),
+            "patch draw data offset",
+        )
+    };
+    // Each stream must hold what its tags consume. A scene starts with an
+    // untagged transform and line width, which a fragment has neither of.
+    let count_tags = |tag: PathTag| encoding.path_tags.iter().filter(|t| **t == tag).count();
+    let n_transforms = count_tags(PathTag::TRANSFORM);
+    let n_linewidths = count_tags(PathTag::LINEWIDTH);
+    let untagged = encoding.transforms.len().checked_sub(n_transforms);
+    if !matches!(untagged, Some(0 | 1))
+        || encoding.linewidths.len().checked_sub(n_linewidths) != untagged
+    {
+        return Err(invalid_data(
+            "transforms or line widths don't match the path tags",
+        ));
+    }
+    let draw_data_words: usize = encoding
+        .draw_tags
+        .iter()
+        .map(|tag| ((tag.0 >> 2) & 0x7) as usize)
+        .sum();
+    if encoding.draw_data.len() != draw_data_words * 4 {
+        return Err(invalid_data("draw data doesn't match the draw tags"));
+    }
+    let n_pattern_tags = |tag: DrawTag| encoding.draw_tags.iter().filter(|t| **t == tag).count();
+    let n_begin_patterns = n_pattern_tags(DrawTag::BEGIN_PATTERN);
+    if n_begin_patterns + n_pattern_tags(DrawTag::END_PATTERN) != encoding.n_patterns as usize
+        || n_begin_patterns != encoding.pattern_data.len()
+    {
+        return Err(invalid_data("pattern data doesn't match the pattern tags"));
+    }
+    for pattern in &encoding.pattern_data {
+        let [start, end] = pattern.placements;
+        check_range(
+            &(start as usize..end as usize),
+            encoding.pattern_placements.len(),
+            "pattern placements",
+        )?;
+        let [start, end] = pattern.color_stops;
+        check_range(
+            &(start as usize..end as usize),
+            encoding.pattern_colors.len(),
+            "pattern colors",
+        )?;
+    }
+    for run in &resources.glyph_runs {
+        check_range(&run.glyphs, resources.glyphs.len(), "glyph run glyphs")?;
+        check_range(
+            &run.normalized_coords,
+            resources.normalized_coords.len(),
+            "glyph run normalized coordinates",
+        )?;
+        let offsets = &run.stream_offsets;
+        for (offset, len) in [
+            (offsets.path_tags, encoding.path_tags.len()),
+            (offsets.path_data, encoding.path_data.len()),
+            (offsets.draw_tags, encoding.draw_tags.len()),
+            (offsets.draw_data, encoding.draw_data.len()),
+            (offsets.transforms, encoding.transforms.len()),
+            (offsets.linewidths, encoding.linewidths.len()),
+            (offsets.patterns, encoding.pattern_data.len()),
+        ] {
+            check_range(&(offset..offset), len, "glyph run stream offset")?;
+        }
+    }
+    for instance in &encoding.pattern_instances {
+        let [start, end] = instance.objects;
+        if (instance.pattern != PatternInstance::NONE
+            && instance.pattern as usize >= encoding.pattern_data.len())
+            || start > end
+            || end as usize > encoding.draw_tags.len()
+        {
+            return Err(invalid_data("pattern instance out of bounds"));
+        }
+    }
+    for patch in &resources.patches {
+        match patch {
+            Patch::Ramp {
+                draw_data_offset,
+                stops,
+                ..
+            } => {
+                check_draw_data(*draw_data_offset, 4)?;
+                check_range(stops, resources.color_stops.len(), "ramp color stops")?;
+            }
+            Patch::GlyphRun { index } => {
+                if *index >= resources.glyph_runs.len() {
+                    return Err(invalid_data("glyph run index out of bounds"));
+                }
+            }
+            Patch::Image {
+                draw_data_offset,
+                image,
+            } => {
+                check_draw_data(*draw_data_offset, 8)?;
+                let size = image.width as u64 * image.height as u64 * 4;
+                if (image.data.data().len() as u64) < size {
+                    return Err(invalid_data("image data is shorter than its size"));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+fn write_json(json: &mut String, encoding: &Encoding) -> std::fmt::Result {
+    let resources = &encoding.resources;
+    write!(
+        json,
+        "{{\"version\":{SERIALIZATION_VERSION},\"n_paths\":{},\"n_path_segments\":{},\
+         \"n_clips\":{},\"n_open_clips\":{},\"n_patterns\":{},\"n_template_paths\":{}",
+        encoding.n_paths,
+        encoding.n_path_segments,
+        encoding.n_clips,
+        encoding.n_open_clips,
+        encoding.n_patterns,
+        encoding.n_template_paths
+    )?;
+    write!(json, ",\"path_tags\":")?;
+    json_array(json, &encoding.path_tags, |json, tag| {
+        write!(json, "{}", tag.0)
+    })?;
+    write!(json, ",\"path_data\":")?;
+    json_array(json, &encoding.path_data, |json, byte| {
+        write!(json, "{byte}")
+    })?;
+    write!(json, ",\"draw_tags\":")?;
+    json_array(json, &encoding.draw_tags, |json, tag| {
+        write!(json, "{}", tag.0)
+    })?;
+    write!(json, ",\"draw_data\":")?;
+    json_array(json, &encoding.draw_data, |json, byte| {
+        write!(json, "{byte}")
+    })?;
+    write!(json, ",\"transforms\":")?;
+    json_array(json, &encoding.transforms, json_transform)?;
+    write!(json, ",\"pattern_data\":")?;
+    json_array(json, &encoding.pattern_data, |json, pattern| {
+        write!(json, "{{\"transform\":")?;
+        json_transform(json, &pattern.transform)?;
+        write!(json, ",\"x_step\":")?;
+        json_array(json, &pattern.x_step, json_f32)?;
+        write!(json, ",\"y_step\":")?;
+        json_array(json, &pattern.y_step, json_f32)?;
+        write!(
+            json,
+            ",\"extend\":{},\"tiling\":{},\"seed\":{},\"offset_jitter\":",
+            pattern.extend, pattern.tiling, pattern.seed
+        )?;
+        json_array(json, &pattern.offset_jitter, json_f32)?;
+        write!(json, ",\"rotation_jitter\":")?;
+        json_f32(json, &pattern.rotation_jitter)?;
+        write!(json, ",\"scale_jitter\":")?;
+        json_f32(json, &pattern.scale_jitter)?;
+        write!(
+            json,
+            ",\"placements\":[{},{}],\"colors\":{},\"color_stops\":[{},{}],\"overflow\":{}}}",
+            pattern.placements[0],
+            pattern.placements[1],
+            pattern.colors,
+            pattern.color_stops[0],
+            pattern.color_stops[1],
+            pattern.overflow
+        )
+    })?;
+    write!(json, ",\"pattern_colors\":")?;
+    json_array(json, &encoding.pattern_colors, json_color_stop)?;
+    write!(json, ",\"pattern_placements\":")?;
+    json_array(json, &encoding.pattern_placements, json_transform)?;
+    write!(json, ",\"pattern_instances\":")?;
+    json_array(json, &encoding.pattern_instances, |json, instance| {
+        write!(
+            json,
+            "{{\"pattern\":{},\"flags\":{},\"objects\":[{},{}],\"info\":{},\"transform\":",
+            instance.pattern,
+            instance.flags,
+            instance.objects[0],
+            instance.objects[1],
+            instance.info
+        )?;
+        json_transform(json, &instance.transform)?;
+        write!(
+            json,
+            ",\"color\":{},\"cell\":[{},{}],\"bbox\":",
+            instance.color, instance.cell[0], instance.cell[1]
+        )?;
+        json_array(json, &instance.bbox, json_f32)?;
+        write!(json, ",\"content_bbox\":")?;
+        json_array(json, &instance.content_bbox, json_f32)?;
+        write!(json, "}}")
+    })?;
+    write!(json, ",\"effects\":")?;
+    json_array(json, &encoding.effects, |json, effect| {
+        write!(
+            json,
+            "{{\"draw_tag_ix\":{},\"kind\":{},\"std_dev\":",
+            effect.draw_tag_ix, effect.kind
+        )?;
+        json_f32(json, &effect.std_dev)?;
+        write!(json, ",\"offset\":")?;
+        json_array(json, &effect.offset, json_f32)?;
+        write!(json, ",\"color\":{}}}", effect.color)
+    })?;
+    write!(json, ",\"linewidths\":")?;
+    json_array(json, &encoding.linewidths, json_f32)?;
+    write!(json, ",\"resources\":{{\"color_stops\":")?;
+    json_array(json, &resources.color_stops, json_color_stop)?;
+    write!(json, ",\"glyphs\":")?;
+    json_array(json, &resources.glyphs, |json, glyph| {
+        write!(json, "{{\"id\":{},\"x\":", glyph.id)?;
+        json_f32(json, &glyph.x)?;
+        write!(json, ",\"y\":")?;
+        json_f32(json, &glyph.y)?;
+        write!(json, "}}")
+    })?;
+    write!(json, ",\"normalized_coords\":")?;
+    json_array(json, &resources.normalized_coords, |json, coord| {
+        write!(json, "{coord}")
+    })?;
+    write!(json, ",\"glyph_runs\":")?;
+    json_array(json, &resources.glyph_runs, |json, run| {
+        write!(json, "{{\"font\":")?;
+        json_blob(json, &run.font.data)?;
+        write!(json, ",\"font_index\":{},\"transform\":", run.font.index)?;
+        json_transform(json, &run.transform)?;
+        write!(json, ",\"glyph_transform\":")?;
+        match &run.glyph_transform {
+            Some(transform) => json_transform(json, transform)?,
+            None => write!(json, "null")?,
+        }
+        write!(json, ",\"font_size\":")?;
+        json_f32(json, &run.font_size)?;
+        write!(
+            json,
+            ",\"hint\":{},\"normalized_coords\":[{},{}],\"style\":",
+            run.hint, run.normalized_coords.start, run.normalized_coords.end
+        )?;
+        match &run.style {
+            Style::Fill(Fill::NonZero) => write!(json, "\"non_zero\"")?,
+            Style::Fill(Fill::EvenOdd) => write!(json, "\"even_odd\"")?,
+            Style::Stroke(stroke) => {
+                write!(json, "{{\"width\":")?;
+                json_f32(json, &stroke.width)?;
+                write!(json, ",\"join\":\"{:?}\",\"miter_limit\":", stroke.join)?;
+                json_f32(json, &stroke.miter_limit)?;
+                write!(
+                    json,
+                    ",\"start_cap\":\"{:?}\",\"end_cap\":\"{:?}\",\"dash_pattern\":",
+                    stroke.start_cap, stroke.end_cap
+                )?;
+                json_array(json, &stroke.dash_pattern, json_f32)?;
+                write!(json, ",\"dash_offset\":")?;
+                json_f32(json, &stroke.dash_offset)?;
+                write!(json, ",\"scale\":{}}}", stroke.scale)?;
+            }
+        }
+        let offsets = &run.stream_offsets;
+        write!(
+            json,
+            ",\"glyphs\":[{},{}],\"stream_offsets\":{{\"path_tags\":{},\"path_data\":{},\
+             \"draw_tags\":{},\"draw_data\":{},\"transforms\":{},\"linewidths\":{},\
+             \"patterns\":{}}}}}",
+            run.glyphs.start,
+            run.glyphs.end,
+            offsets.path_tags,
+            offsets.path_data,
+            offsets.draw_tags,
+            offsets.draw_data,
+            offsets.transforms,
+            offsets.linewidths,
+            offsets.patterns
+        )
+    })?;
+    write!(json, ",\"patches\":")?;
+    json_array(json, &resources.patches, |json, patch| match patch {
+        Patch::Ramp {
+            draw_data_offset,
+            stops,
+            extend,
+        } => write!(
+            json,
+            "{{\"ramp\":{{\"draw_data_offset\":{draw_data_offset},\"stops\":[{},{}],\
+             \"extend\":\"{extend:?}\"}}}}",
+            stops.start, stops.end
+        ),
+        Patch::GlyphRun { index } => write!(json, "{{\"glyph_run\":{{\"index\":{index}}}}}"),
+        Patch::Image {
+            draw_data_offset,
+            image,
+        } => {
+            write!(
+                json,
+                "{{\"image\":{{\"draw_data_offset\":{draw_data_offset},\"data\":"
+            )?;
+            json_blob(json, &image.data)?;
+            write!(
+                json,
+                ",\"format\":\"{:?}\",\"width\":{},\"height\":{},\"extend\":\"{:?}\"}}}}",
+                image.format, image.width, image.height, image.extend
+            )
+        }
+    })?;
+    write!(json, "}}}}")
+}
+
+fn json_array<T>(
+    json: &mut String,
+    values: &[T],
+    mut write_value: impl FnMut(&mut String, &T) -> std::fmt::Result,
+) -> std::fmt::Result {
+    json.push('[');
+    for (i, value) in values.iter().enumerate() {
+        if i != 0 {
+            json.push(',');
+        }
+        write_value(json, value)?;
+    }
+    json.push(']');
+    Ok(())
+}
+
+/// Writes a number, or null if it isn't finite as JSON has no representation
+/// for infinities or NaN.
+fn json_f32(json: &mut String, value: &f32) -> std::fmt::Result {
+    if value.is_finite() {
+        write!(json, "{value}")
+    } else {
+        write!(json, "null")
+    }
+}
+
+fn json_transform(json: &mut String, transform: &Transform) -> std::fmt::Result {
+    let values: &[f32] = bytemuck::cast_slice(std::slice::from_ref(transform));
+    json_array(json, values, json_f32)
+}
+
+fn json_color_stop(json: &mut String, stop: &ColorStop) -> std::fmt::Result {
+    let Color { r, g, b, a } = stop.color;
+    write!(json, "{{\"offset\":")?;
+    json_f32(json, &stop.offset)?;
+    write!(json, ",\"color\":[{r},{g},{b},{a}]}}")
+}
+
+fn json_blob(json: &mut String, blob: &Blob<u8>) -> std::fmt::Result {
+    write!(
+        json,
+        "{{\"id\":{},\"size\":{}}}",
+        blob.id(),
+        blob.data().len()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use peniko::kurbo::{Affine, Rect};
+
+    use super::*;
+    use crate::{DrawColor, DrawLinearGradient};
+
+    /// Returns an encoding that uses every stream and kind of resource.
+    fn encoding() -> Encoding {
+        let mut encoding = Encoding::new();
+        let rect = Rect::new(0.0, 0.0, 16.0, 8.0);
+        // A pattern with per-cell colors and placements.
+        let pattern = PatternData {
+            transform: Transform::from_kurbo(&Affine::translate((4.0, 2.0))),
+            x_step: [16.0, 0.0],
+            y_step: [4.0, 12.0],
+            extend: PatternData::EXTEND_REPEAT,
+            tiling: PatternData::TILING_PLACEMENTS,
+            colors: PatternData::COLORS_LIST,
+            ..bytemuck::Zeroable::zeroed()
+        };
+        let colors = [Color::rgb8(255, 0, 0), Color::rgb8(0, 0, 255)];
+        let color_stops = colors.iter().map(|&color| ColorStop { offset: 0.0, color });
+        let placements = [Affine::IDENTITY, Affine::rotate(0.5)].map(|a| Transform::from_kurbo(&a));
+        encoding.encode_begin_pattern(pattern, color_stops, placements.into_iter());
+        encoding.encode_shape(&rect, true);
+        encoding.encode_color(DrawColor::new(Color::rgb8(0, 128, 0)));
+        encoding.encode_end_pattern();
+        // A gradient ramp.
+        encoding.encode_transform(Transform::from_kurbo(&Affine::scale(2.0)));
+        encoding.encode_linewidth(2.0);
+        encoding.encode_shape(&rect, false);
+        let stops = [0.0, 1.0].map(|offset| ColorStop {
+            offset,
+            color: Color::rgba8(255, (offset * 255.0) as u8, 0, 200),
+        });
+        let gradient = DrawLinearGradient {
+            index: 0,
+            p0: [0.0, 0.0],
+            p1: [16.0, 0.0],
+        };
+        encoding.encode_linear_gradient(gradient, stops.into_iter(), 1.0, Extend::Reflect);
+        // A layer with an effect, containing an image.
+        encoding.encode_shape(&rect, true);
+        encoding.encode_begin_clip(Default::default(), 1.0);
+        encoding.encode_layer_effect(EffectData {
+            draw_tag_ix: 0,
+            kind: EffectData::KIND_DROP_SHADOW,
+            std_dev: 3.0,
+            offset: [2.0, 2.0],
+            color: 0x80000000,
+        });
+        encoding.encode_shape(&rect, true);
+        let pixels: Vec<u8> = (0..2 * 3 * 4).map(|i| i as u8).collect();
+        let image = Image::new(Blob::from(pixels), Format::Rgba8, 2, 3).with_extend(Extend::Repeat);
+        encoding.encode_image(&image, 1.0);
+        encoding.encode_end_clip();
+        // Glyph runs sharing their font data.
+        let font = Font::new(Blob::from(vec![7u8; 64]), 1);
+        encoding
+            .resources
+            .normalized_coords
+            .push(bytemuck::cast(8192i16));
+        let styles = [
+            Style::Fill(Fill::EvenOdd),
+            Style::Stroke(
+                Stroke::new(1.5)
+                    .with_join(Join::Round)
+                    .with_caps(Cap::Square)
+                    .with_dashes(1.0, [2.0, 3.0]),
+            ),
+        ];
+        for (i, style) in styles.into_iter().enumerate() {
+            let stream_offsets = encoding.stream_offsets();
+            let resources = &mut encoding.resources;
+            let start = resources.glyphs.len();
+            resources.glyphs.extend((0..3).map(|id| Glyph {
+                id,
+                x: id as f32 * 8.0,
+                y: 12.0,
+            }));
+            let translate = Affine::translate((0.0, 20.0 * i as f64));
+            let skew = Affine::skew(0.2, 0.0);
+            resources.glyph_runs.push(GlyphRun {
+                font: font.clone(),
+                transform: Transform::from_kurbo(&translate),
+                glyph_transform: (i == 1).then(|| Transform::from_kurbo(&skew)),
+                font_size: 12.0,
+                hint: i == 0,
+                normalized_coords: 0..1,
+                style,
+                glyphs: start..resources.glyphs.len(),
+                stream_offsets,
+            });
+            let index = resources.glyph_runs.len() - 1;
+            resources.patches.push(Patch::GlyphRun { index });
+            encoding.encode_color(DrawColor::new(Color::rgb8(0, 0, 0)));
+        }
+        encoding
+    }
+
+    fn save(encoding: &Encoding, blobs: BlobStorage) -> Vec<u8> {
+        let mut data = vec![];
+        encoding.save(&mut data, blobs).unwrap();
+        data
+    }
+
+    fn bytes<T: Pod>(values: &[T]) -> &[u8] {
+        bytemuck::cast_slice(values)
+    }
+
+    fn assert_same_encoding(a: &Encoding, b: &Encoding) {
+        let counts = |e: &Encoding| {
+            [
+                e.n_paths,
+                e.n_path_segments,
+                e.n_clips,
+                e.n_open_clips,
+                e.n_patterns,
+                e.n_template_paths,
+            ]
+        };
+        assert_eq!(counts(a), counts(b));
+        assert_eq!(bytes(&a.path_tags), bytes(&b.path_tags));
+        assert_eq!(a.path_data, b.path_data);
+        assert_eq!(bytes(&a.draw_tags), bytes(&b.draw_tags));
+        assert_eq!(a.draw_data, b.draw_data);
+        assert_eq!(a.transforms, b.transforms);
+        assert_eq!(a.pattern_data, b.pattern_data);
+        assert_eq!(a.pattern_colors, b.pattern_colors);
+        assert_eq!(a.pattern_placements, b.pattern_placements);
+        assert_eq!(a.pattern_instances, b.pattern_instances);
+        assert_eq!(a.effects, b.effects);
+        assert_eq!(a.linewidths, b.linewidths);
+        let (a, b) = (&a.resources, &b.resources);
+        assert_eq!(a.color_stops, b.color_stops);
+        assert_eq!(format!("{:?}", a.glyphs), format!("{:?}", b.glyphs));
+        assert_eq!(bytes(&a.normalized_coords), bytes(&b.normalized_coords));
+        assert_eq!(a.glyph_runs.len(), b.glyph_runs.len());
+        for (a, b) in a.glyph_runs.iter().zip(&b.glyph_runs) {
+            assert_eq!(a.font.data.data(), b.font.data.data());
+            assert_eq!(a.font.index, b.font.index);
+            assert_eq!(a.transform, b.transform);
+            assert_eq!(a.glyph_transform, b.glyph_transform);
+            assert_eq!(a.font_size, b.font_size);
+            assert_eq!(a.hint, b.hint);
+            assert_eq!(a.normalized_coords, b.normalized_coords);
+            assert_eq!(a.style, b.style);
+            assert_eq!(a.glyphs, b.glyphs);
+            assert_eq!(
+                format!("{:?}", a.stream_offsets),
+                format!("{:?}", b.stream_offsets)
+            );
+        }
+        assert_eq!(a.patches.len(), b.patches.len());
+        for (a, b) in a.patches.iter().zip(&b.patches) {
+            match (a, b) {
+                (
+                    Patch::Ramp {
+                        draw_data_offset: a_offset,
+                        stops: a_stops,
+                        extend: a_extend,
+                    },
+                    Patch::Ramp {
+                        draw_data_offset: b_offset,
+                        stops: b_stops,
+                        extend: b_extend,
+                    },
+                ) => {
+                    assert_eq!(a_offset, b_offset);
+                    assert_eq!(a_stops, b_stops);
+                    assert_eq!(a_extend, b_extend);
+                }
+                (Patch::GlyphRun { index: a }, Patch::GlyphRun { index: b }) => assert_eq!(a, b),
+                (
+                    Patch::Image {
+                        draw_data_offset: a_offset,
+                        image: a,
+                    },
+                    Patch::Image {
+                        draw_data_offset: b_offset,
+                        image: b,
+                    },
+                ) => {
+                    assert_eq!(a_offset, b_offset);
+                    assert_eq!(a.data.data(), b.data.data());
+                    assert_eq!(a.format, b.format);
+                    assert_eq!([a.width, a.height], [b.width, b.height]);
+                    assert_eq!(a.extend, b.extend);
+                }
+                _ => panic!("patches differ"),
+            }
+        }
+    }
+
+    fn assert_invalid_data(data: &[u8]) {
+        let error = Encoding::load(&mut &data[..]).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{error}");
+    }
+
+    #[test]
+    fn round_trip() {
+        let encoding = encoding();
+        let loaded = Encoding::load(&mut &save(&encoding, BlobStorage::Embed)[..]).unwrap();
+        assert_same_encoding(&encoding, &loaded);
+        // The font shared by the glyph runs is stored once, and shared when loaded.
+        let runs = &loaded.resources.glyph_runs;
+        assert_eq!(runs[0].font.data.id(), runs[1].font.data.id());
+    }
+
+    #[test]
+    fn round_trip_with_referenced_blobs() {
+        let encoding = encoding();
+        let embedded = save(&encoding, BlobStorage::Embed);
+        let referenced = save(&encoding, BlobStorage::Reference);
+        assert!(referenced.len() < embedded.len());
+        let mut blobs = HashMap::new();
+        blobs.insert(
+            encoding.resources.glyph_runs[0].font.data.id(),
+            encoding.resources.glyph_runs[0].font.data.clone(),
+        );
+        for patch in &encoding.resources.patches {
+            if let Patch::Image { image, .. } = patch {
+                blobs.insert(image.data.id(), image.data.clone());
+            }
+        }
+        let loaded =
+            Encoding::load_with_blobs(&mut &referenced[..], |id| blobs.get(&id).cloned()).unwrap();
+        assert_same_encoding(&encoding, &loaded);
+        // Blobs that aren't supplied fail the load.
+        let error = Encoding::load_with_blobs(&mut &referenced[..], |_| None)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_wrong_magic() {
+        let mut data = save(&encoding(), BlobStorage::Embed);
+        data[0] = b'X';
+        assert_invalid_data(&data);
+    }
+
+    #[test]
+    fn rejects_wrong_version() {
+        let mut data = save(&encoding(), BlobStorage::Embed);
+        for version in [0, SERIALIZATION_VERSION + 1] {
+            data[4..8].copy_from_slice(&version.to_le_bytes());
+            assert_invalid_data(&data);
+        }
+    }
+
+    #[test]
+    fn rejects_truncated_data() {
+        let data = save(&encoding(), BlobStorage::Embed);
+        for len in 0..data.len() {
+            assert!(
+                Encoding::load(&mut &data[..len]).is_err(),
+                "loaded {len} of {} bytes",
+                data.len()
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_trailing_data() {
+        let mut data = save(&encoding(), BlobStorage::Embed);
+        data.push(0);
+        assert_invalid_data(&data);
+    }
+
+    #[test]
+    fn rejects_out_of_bounds_resources() {
+        let corruptions: [fn(&mut Encoding); 8] = [
+            |encoding| {
+                for patch in &mut encoding.resources.patches {
+                    if let Patch::Ramp {
+                        draw_data_offset, ..
+                    } = patch
+                    {
+                        *draw_data_offset = encoding.draw_data.len() - 2;
+                    }
+                }
+            },
+            |encoding| {
+                for patch in &mut encoding.resources.patches {
+                    if let Patch::Ramp { stops, .. } = patch {
+                        stops.end += 1;
+                    }
+                }
+            },
+            |encoding| {
+                for patch in &mut encoding.resources.patches {
+                    if let Patch::Image {
+                        draw_data_offset, ..
+                    } = patch
+                    {
+                        *draw_data_offset = encoding.draw_data.len() - 4;
+                    }
+                }
+            },
+            |encoding| {
+                for patch in &mut encoding.resources.patches {
+                    if let Patch::Image { image, .. } = patch {
+                        image.height += 1;
+                    }
+                }
+            },
+            |encoding| {
+                encoding
+                    .resources
+                    .patches
+                    .push(Patch::GlyphRun { index: 2 })
+            },
+            |encoding| encoding.resources.glyph_runs[1].glyphs.end += 1,
+            |encoding| encoding.resources.glyph_runs[0].normalized_coords = 1..2,
+            |encoding| encoding.resources.glyph_runs[0].stream_offsets.draw_data += 1024,
+        ];
+        for corrupt in corruptions {
+            let mut encoding = encoding();
+            corrupt(&mut encoding);
+            assert_invalid_data(&save(&encoding, BlobStorage::Embed));
+        }
+    }
+
+    #[test]
+    fn rejects_short_streams() {
+        let corruptions: [fn(&mut Encoding); 8] = [
+            |encoding| {
+                encoding.pattern_data.pop();
+            },
+            |encoding| encoding.n_patterns -= 1,
+            |encoding| encoding.pattern_data[0].placements[1] += 1,
+            |encoding| encoding.pattern_data[0].color_stops = [1, 3],
+            |encoding| {
+                encoding.transforms.pop();
+                encoding.transforms.pop();
+            },
+            |encoding| encoding.transforms.push(Transform::IDENTITY),
+            |encoding| {
+                encoding.linewidths.pop();
+                encoding.linewidths.pop();
+            },
+            |encoding| encoding.draw_data.truncate(encoding.draw_data.len() - 4),
+        ];
+        for corrupt in corruptions {
+            let mut encoding = encoding();
+            corrupt(&mut encoding);
+            assert_invalid_data(&save(&encoding, BlobStorage::Embed));
+        }
+    }
+}
@@ -0,0 +1,548 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Expansion of strokes into fill outlines.
+//!
+//! The outline produces the stroked area when filled with the non-zero rule.
+//! Paths are flattened before expansion, so the tolerance should be chosen in
+//! the coordinate space of the path, taking the eventual transform into account.
+
+use peniko::kurbo::{BezPath, PathEl, Point, Vec2};
+use peniko::{Cap, Join, Stroke};
+
+/// A flattened subpath.
+struct Polyline {
+    points: Vec<Point>,
+    /// Whether each point is the end of a segment of the input path, rather
+    /// than a point introduced by flattening or dashing.
+    corners: Vec<bool>,
+    closed: bool,
+}
+
+impl Polyline {
+    fn new() -> Self {
+        Self {
+            points: vec![],
+            corners: vec![],
+            closed: false,
+        }
+    }
+
+    fn push(&mut self, point: Point, corner: bool) {
+        self.points.push(point);
+        self.corners.push(corner);
+    }
+}
+
+/// Expands the stroke of a path into a path that covers the stroked area
+/// when filled with the non-zero rule.
+///
+/// Joins, caps, the miter limit and dashes of `style` are applied. Curves are
+/// flattened to lines within `tolerance`.
+pub fn stroke_to_fill(
+    path: impl IntoIterator<Item = PathEl>,
+    style: &Stroke,
+    tolerance: f64,
+) -> BezPath {
+    let mut out = BezPath::new();
+    let half_width = style.width as f64 * 0.5;
+    if half_width <= 0.0 {
+        return out;
+    }
+    let stroker = Stroker {
+        half_width,
+        join: style.join,
+        miter_limit: style.miter_limit as f64,
+        start_cap: style.start_cap,
+        end_cap: style.end_cap,
+        tolerance,
+    };
+    let dashes = dash_pattern(&style.dash_pattern);
+    for polyline in flatten(path, tolerance) {
+        match &dashes {
+            Some(dashes) => {
+                for dash in dash(&polyline, dashes, style.dash_offset as f64) {
+                    stroker.stroke(&dash, &mut out);
+                }
+            }
+            None => stroker.stroke(&polyline, &mut out),
+        }
+    }
+    out
+}
+
+/// Flattens a path into polylines, one per subpath.
+fn flatten(path: impl IntoIterator<Item = PathEl>, tolerance: f64) -> Vec<Polyline> {
+    let mut polylines = vec![];
+    let mut current = Polyline::new();
+    let mut start = Point::ZERO;
+    let mut last = Point::ZERO;
+    let mut finish = |current: &mut Polyline| {
+        let polyline = std::mem::replace(current, Polyline::new());
+        // A subpath that is only a move to isn't drawn, but a zero length
+        // segment or closed subpath gets caps.
+        if polyline.points.len() > 1 || polyline.closed {
+            polylines.push(polyline);
+        }
+    };
+    for el in path {
+        match el {
+            PathEl::MoveTo(p) => {
+                finish(&mut current);
+                current.push(p, true);
+                start = p;
+                last = p;
+                continue;
+            }
+            PathEl::LineTo(p) => {
+                if current.points.is_empty() {
+                    current.push(last, true);
+                }
+                current.push(p, true);
+                last = p;
+                continue;
+            }
+            PathEl::ClosePath => {
+                if !current.points.is_empty() {
+                    current.closed = true;
+                }
+                finish(&mut current);
+                last = start;
+                continue;
+            }
+            _ => (),
+        }
+        if current.points.is_empty() {
+            current.push(last, true);
+        }
+        let end = match el {
+            PathEl::QuadTo(_, p) | PathEl::CurveTo(_, _, p) => p,
+            _ => unreachable!(),
+        };
+        let segment = [PathEl::MoveTo(last), el];
+        peniko::kurbo::flatten(segment, tolerance, |el| {
+            if let PathEl::LineTo(p) = el {
+                current.push(p, false);
+            }
+        });
+        // The last point of the flattened curve ends the segment.
+        if let Some(corner) = current.corners.last_mut() {
+            *corner = true;
+        }
+        last = end;
+    }
+    finish(&mut current);
+    polylines
+}
+
+/// Returns the dash pattern to apply, or `None` if the stroke is solid.
+///
+/// As in SVG, a pattern with an odd number of entries is repeated to make it
+/// even, and patterns with negative entries or zero length are ignored.
+fn dash_pattern(pattern: &[f32]) -> Option<Vec<f64>> {
+    if pattern.is_empty() || pattern.iter().any(|len| *len < 0.0) {
+        return None;
+    }
+    let mut dashes: Vec<f64> = pattern.iter().map(|len| *len as f64).collect();
+    if dashes.len() % 2 == 1 {
+        dashes.extend_from_within(..);
+    }
+    let total: f64 = dashes.iter().sum();
+    (total > 0.0).then_some(dashes)
+}
+
+/// Splits a polyline into the polylines of its dashes.
+fn dash(polyline: &Polyline, dashes: &[f64], offset: f64) -> Vec<Polyline> {
+    let total: f64 = dashes.iter().sum();
+    // Find the position in the pattern at the start of the subpath.
+    let mut ix = 0;
+    let mut remaining = dashes[0];
+    let mut skip = offset.rem_euclid(total);
+    while skip > 0.0 && skip >= remaining {
+        skip -= remaining;
+        ix = (ix + 1) % dashes.len();
+        remaining = dashes[ix];
+    }
+    remaining -= skip;
+    let starts_on = ix % 2 == 0;
+
+    let mut points = polyline.points.clone();
+    let mut corners = polyline.corners.clone();
+    if polyline.closed {
+        points.push(points[0]);
+        corners.push(true);
+    }
+    let mut result = vec![];
+    let mut current = Polyline::new();
+    let mut is_on = starts_on;
+    let mut toggled = false;
+    if is_on {
+        current.push(points[0], corners[0]);
+    }
+    for i in 1..points.len() {
+        let (p0, p1) = (points[i - 1], points[i]);
+        let len = (p1 - p0).hypot();
+        let mut t = 0.0;
+        while len - t > remaining {
+            t += remaining;
+            let p = p0.lerp(p1, t / len);
+            if is_on {
+                current.push(p, false);
+                result.push(std::mem::replace(&mut current, Polyline::new()));
+            } else {
+                current.push(p, false);
+            }
+            is_on = !is_on;
+            toggled = true;
+            ix = (ix + 1) % dashes.len();
+            remaining = dashes[ix];
+        }
+        remaining -= len - t;
+        if is_on {
+            current.push(p1, corners[i]);
+        }
+    }
+    let ends_on = is_on && current.points.len() > 1;
+    if ends_on {
+        result.push(current);
+    }
+    if polyline.closed {
+        if !toggled && starts_on {
+            // The dash covers the whole subpath.
+            return vec![Polyline {
+                points: polyline.points.clone(),
+                corners: polyline.corners.clone(),
+                closed: true,
+            }];
+        }
+        // Join the dash that ends the subpath with the one that starts it.
+        if starts_on && ends_on && result.len() > 1 {
+            let first = result.remove(0);
+            let last = result.last_mut().unwrap();
+            last.points.extend_from_slice(&first.points[1..]);
+            last.corners.extend_from_slice(&first.corners[1..]);
+        }
+    }
+    result
+}
+
+struct Stroker {
+    half_width: f64,
+    join: Join,
+    miter_limit: f64,
+    start_cap: Cap,
+    end_cap: Cap,
+    tolerance: f64,
+}
+
+impl Stroker {
+    fn stroke(&self, polyline: &Polyline, out: &mut BezPath) {
+        // Remove zero length segments.
+        let mut points: Vec<Point> = vec![];
+        let mut corners: Vec<bool> = vec![];
+        for (p, corner) in polyline.points.iter().zip(&polyline.corners) {
+            match points.last() {
+                Some(last) if (*p - *last).hypot2() <= 1e-12 => {
+                    *corners.last_mut().unwrap() |= *corner;
+                }
+                _ => {
+                    points.push(*p);
+                    corners.push(*corner);
+                }
+            }
+        }
+        if polyline.closed
+            && points.len() > 1
+            && (points[0] - points[points.len() - 1]).hypot2() <= 1e-12
+        {
+            points.pop();
+            corners.pop();
+        }
+        match points.len() {
+            0 => (),
+            1 => self.dot(points[0], out),
+            _ if polyline.closed => {
+                self.closed_side(&points, &corners, out);
+                points.reverse();
+                corners.reverse();
+                self.closed_side(&points, &corners, out);
+            }
+            _ => self.open(&points, &corners, out),
+        }
+    }
+
+    /// Returns the offset to the left side of the segment from `p0` to `p1`.
+    fn normal(&self, p0: Point, p1: Point) -> Vec2 {
+        let d = (p1 - p0).normalize();
+        Vec2::new(-d.y, d.x) * self.half_width
+    }
+
+    /// Outlines one side of a closed polyline.
+    fn closed_side(&self, points: &[Point], corners: &[bool], out: &mut BezPath) {
+        let n = points.len();
+        let normals: Vec<Vec2> = (0..n)
+            .map(|i| self.normal(points[i], points[(i + 1) % n]))
+            .collect();
+        out.move_to(points[0] + normals[0]);
+        for i in 1..=n {
+            let ix = i % n;
+            self.join(points[ix], normals[i - 1], normals[ix], corners[ix], out);
+        }
+        out.close_path();
+    }
+
+    /// Outlines an open polyline, with caps at both ends.
+    fn open(&self, points: &[Point], corners: &[bool], out: &mut BezPath) {
+        let n = points.len();
+        let normals: Vec<Vec2> = (0..n - 1)
+            .map(|i| self.normal(points[i], points[i + 1]))
+            .collect();
+        out.move_to(points[0] + normals[0]);
+        for i in 1..n - 1 {
+            self.join(points[i], normals[i - 1], normals[i], corners[i], out);
+        }
+        let end_normal = normals[n - 2];
+        out.line_to(points[n - 1] + end_normal);
+        self.cap(points[n - 1], end_normal, self.end_cap, out);
+        for i in (1..n - 1).rev() {
+            self.join(points[i], -normals[i], -normals[i - 1], corners[i], out);
+        }
+        out.line_to(points[0] - normals[0]);
+        self.cap(points[0], -normals[0], self.start_cap, out);
+        out.close_path();
+    }
+
+    /// Adds a join at `p` between segments offset by `a` and `b`, from the end
+    /// of the incoming segment at `p + a` to the start of the outgoing one at
+    /// `p + b`.
+    ///
+    /// Points introduced by flattening use round joins, which only add points
+    /// where the flattened curve turns sharply.
+    fn join(&self, p: Point, a: Vec2, b: Vec2, corner: bool, out: &mut BezPath) {
+        let cross = a.cross(b);
+        let dot = a.dot(b);
+        if cross.abs() <= 1e-9 * self.half_width * self.half_width && dot > 0.0 {
+            // Collinear segments.
+            out.line_to(p + b);
+            return;
+        }
+        out.line_to(p + a);
+        if cross > 0.0 {
+            // Inner side of the turn. Passing through the pivot keeps the
+            // winding of the outline consistent.
+            out.line_to(p);
+            out.line_to(p + b);
+            return;
+        }
+        let join = if corner { self.join } else { Join::Round };
+        match join {
+            Join::Bevel => (),
+            Join::Miter => {
+                let cos_half = ((1.0 + dot / (self.half_width * self.half_width)) * 0.5)
+                    .max(0.0)
+                    .sqrt();
+                if cos_half > 0.0 && 1.0 / cos_half <= self.miter_limit {
+                    let mid = (a + b).normalize() * (self.half_width / cos_half);
+                    out.line_to(p + mid);
+                }
+            }
+            Join::Round => self.arc(p, a, b.atan2() - a.atan2(), false, out),
+        }
+        out.line_to(p + b);
+    }
+
+    /// Adds a cap at the end point `p` of a segment, going from `p + a` to
+    /// `p - a`.
+    fn cap(&self, p: Point, a: Vec2, cap: Cap, out: &mut BezPath) {
+        // The direction of the segment at the cap.
+        let d = Vec2::new(a.y, -a.x);
+        match cap {
+            Cap::Butt => (),
+            Cap::Square => {
+                out.line_to(p + a + d);
+                out.line_to(p - a + d);
+            }
+            Cap::Round => self.arc(p, a, -std::f64::consts::PI, false, out),
+        }
+        out.line_to(p - a);
+    }
+
+    /// Adds the points of a circular arc around `center`, starting from
+    /// `center + start` and sweeping clockwise in the coordinate space of the
+    /// path by `angle`, which is normalized into `(-2π, 0]` unless `full` is
+    /// set.
+    fn arc(&self, center: Point, start: Vec2, angle: f64, full: bool, out: &mut BezPath) {
+        use std::f64::consts::TAU;
+        let angle = if full {
+            angle
+        } else {
+            let angle = angle.rem_euclid(TAU);
+            if angle > 0.0 {
+                angle - TAU
+            } else {
+                angle
+            }
+        };
+        let radius = self.half_width;
+        let step = if self.tolerance < radius {
+            2.0 * (1.0 - self.tolerance / radius).acos()
+        } else {
+            TAU / 4.0
+        };
+        let n = (angle.abs() / step).ceil().max(1.0) as usize;
+        let start_angle = start.atan2();
+        for i in 1..n {
+            let theta = start_angle + angle * i as f64 / n as f64;
+            out.line_to(center + Vec2::from_angle(theta) * radius);
+        }
+    }
+
+    /// Outlines a zero length subpath, which is only visible with round or
+    /// square caps.
+    fn dot(&self, p: Point, out: &mut BezPath) {
+        let a = Vec2::new(0.0, self.half_width);
+        match self.start_cap {
+            Cap::Butt => (),
+            Cap::Square => {
+                let d = Vec2::new(self.half_width, 0.0);
+                out.move_to(p + a - d);
+                out.line_to(p + a + d);
+                out.line_to(p - a + d);
+                out.line_to(p - a - d);
+                out.close_path();
+            }
+            Cap::Round => {
+                out.move_to(p + a);
+                self.arc(p, a, -std::f64::consts::TAU, true, out);
+                out.close_path();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use peniko::kurbo::{Line, Rect, Shape};
+
+    const TOLERANCE: f64 = 0.01;
+
+    fn stroke(path: &BezPath, style: &Stroke) -> BezPath {
+        stroke_to_fill(path.iter(), style, TOLERANCE)
+    }
+
+    fn assert_near(a: Rect, b: Rect) {
+        let d = [a.x0 - b.x0, a.y0 - b.y0, a.x1 - b.x1, a.y1 - b.y1];
+        assert!(d.iter().all(|d| d.abs() <= TOLERANCE), "{a:?} != {b:?}");
+    }
+
+    fn subpaths(path: &BezPath) -> usize {
+        path.elements()
+            .iter()
+            .filter(|el| matches!(el, PathEl::MoveTo(_)))
+            .count()
+    }
+
+    #[test]
+    fn miter_limit() {
+        // A right angle, whose miter is sqrt(2) times the stroke width.
+        let mut corner = BezPath::new();
+        corner.move_to((0.0, 0.0));
+        corner.line_to((10.0, 0.0));
+        corner.line_to((10.0, 10.0));
+        let tip = Point::new(10.9, -0.9);
+        let miter = Stroke::new(2.0).with_join(Join::Miter).with_caps(Cap::Butt);
+        let mitered = stroke(&corner, &miter.clone().with_miter_limit(1.5));
+        assert!(mitered.contains(tip));
+        assert_near(mitered.bounding_box(), Rect::new(0.0, -1.0, 11.0, 10.0));
+        // Past the limit, the join falls back to a bevel.
+        let beveled = stroke(&corner, &miter.with_miter_limit(1.4));
+        assert!(!beveled.contains(tip));
+        assert!(beveled.contains(Point::new(10.4, -0.4)));
+        let bevel = Stroke::new(2.0).with_join(Join::Bevel).with_caps(Cap::Butt);
+        let bevel = stroke(&corner, &bevel);
+        assert_eq!(beveled.elements(), bevel.elements());
+    }
+
+    #[test]
+    fn round_caps() {
+        let line = Line::new((0.0, 0.0), (10.0, 0.0)).to_path(TOLERANCE);
+        let round = stroke(&line, &Stroke::new(4.0).with_caps(Cap::Round));
+        assert_near(round.bounding_box(), Rect::new(-2.0, -2.0, 12.0, 2.0));
+        assert!(round.contains(Point::new(-1.9, 0.0)));
+        assert!(round.contains(Point::new(11.0, 1.0)));
+        // Outside of the semicircle, but inside a square cap.
+        assert!(!round.contains(Point::new(-1.5, 1.5)));
+        assert!(!round.contains(Point::new(11.5, -1.5)));
+        let butt = stroke(&line, &Stroke::new(4.0).with_caps(Cap::Butt));
+        assert_near(butt.bounding_box(), Rect::new(0.0, -2.0, 10.0, 2.0));
+        assert!(!butt.contains(Point::new(-0.5, 0.0)));
+    }
+
+    #[test]
+    fn dash_phase() {
+        let line = Line::new((0.0, 0.0), (30.0, 0.0)).to_path(TOLERANCE);
+        let dashed = |offset| stroke(&line, &Stroke::new(2.0).with_dashes(offset, [5.0, 5.0]));
+        // Dashes at [0, 5], [10, 15] and [20, 25].
+        let zero = dashed(0.0);
+        assert_eq!(subpaths(&zero), 3);
+        assert!(zero.contains(Point::new(2.0, 0.0)));
+        assert!(!zero.contains(Point::new(7.0, 0.0)));
+        assert!(zero.contains(Point::new(24.0, 0.0)));
+        // Half a period later, the gaps and dashes swap.
+        let half = dashed(5.0);
+        assert_eq!(subpaths(&half), 3);
+        assert!(!half.contains(Point::new(2.0, 0.0)));
+        assert!(half.contains(Point::new(7.0, 0.0)));
+        assert!(half.contains(Point::new(29.0, 0.0)));
+        // Dashes at [0, 2.5], [7.5, 12.5], [17.5, 22.5] and [27.5, 30].
+        let quarter = dashed(2.5);
+        assert_eq!(subpaths(&quarter), 4);
+        assert!(quarter.contains(Point::new(1.0, 0.0)));
+        assert!(!quarter.contains(Point::new(4.0, 0.0)));
+        assert!(quarter.contains(Point::new(28.0, 0.0)));
+        // A whole period of offset doesn't change the dashes.
+        assert_eq!(dashed(10.0).elements(), zero.elements());
+        assert_eq!(dashed(-7.5).elements(), quarter.elements());
+    }
+
+    #[test]
+    fn degenerate_subpath() {
+        let mut point = BezPath::new();
+        point.move_to((5.0, 5.0));
+        point.line_to((5.0, 5.0));
+        let butt = Stroke::new(2.0).with_caps(Cap::Butt);
+        assert!(stroke(&point, &butt).elements().is_empty());
+        let round = stroke(&point, &Stroke::new(2.0).with_caps(Cap::Round));
+        assert_eq!(subpaths(&round), 1);
+        assert_near(round.bounding_box(), Rect::new(4.0, 4.0, 6.0, 6.0));
+        assert!(round.contains(Point::new(5.9, 5.0)));
+        assert!(!round.contains(Point::new(5.8, 5.8)));
+        let square = stroke(&point, &Stroke::new(2.0).with_caps(Cap::Square));
+        assert_near(square.bounding_box(), Rect::new(4.0, 4.0, 6.0, 6.0));
+        assert!(square.contains(Point::new(5.8, 5.8)));
+        // A closed subpath of a single point is also capped.
+        let mut closed = BezPath::new();
+        closed.move_to((5.0, 5.0));
+        closed.close_path();
+        let round_closed = stroke(&closed, &Stroke::new(2.0).with_caps(Cap::Round));
+        assert_eq!(round_closed.elements(), round.elements());
+    }
+
+    #[test]
+    fn move_to_only_subpath() {
+        let round = Stroke::new(2.0).with_caps(Cap::Round);
+        let mut moves = BezPath::new();
+        moves.move_to((5.0, 5.0));
+        moves.move_to((10.0, 5.0));
+        assert!(stroke(&moves, &round).elements().is_empty());
+        // A trailing move to doesn't add to the stroke of the subpath before it.
+        let line = Line::new((0.0, 0.0), (10.0, 0.0)).to_path(TOLERANCE);
+        let mut trailing = line.clone();
+        trailing.move_to((20.0, 0.0));
+        assert_eq!(
+            stroke(&trailing, &round).elements(),
+            stroke(&line, &round).elements()
+        );
+    }
+}
@@ -10,11 +10,7 @@ use vello::{
     block_on_wgpu,
     kurbo::{Affine, Vec2},
     util::RenderContext,
-    RendererOptions, Scene, SceneBuilder, SceneFragment,
-};
-use wgpu::{
-    BufferDescriptor, BufferUsages, CommandEncoderDescriptor, Extent3d, ImageCopyBuffer,
-    TextureDescriptor, TextureFormat, TextureUsages,
+    AlphaMode, RendererOptions, Scene, SceneBuilder, SceneFragment,
 };
 
 fn main() -> Result<()> {
@@ -90,6 +86,9 @@ async fn render(mut scenes: SceneSet, index: usize, args: &Args) -> Result<()> {
         &RendererOptions {
             surface_format: None,
             timestamp_period: queue.get_timestamp_period(),
+            memory_limit: None,
+            expand_patterns: false,
+            target_format: vello::TargetFormat::Rgba8,
         },
     )
     .or_else(|_| bail!("Got non-Send/Sync error from creating renderer"))?;
@@ -139,76 +138,17 @@ async fn render(mut scenes: SceneSet, index: usize, args: &Args) -> Result<()> {
             .unwrap_or(vello::peniko::Color::BLACK),
         width,
         height,
+        target_color_space: vello::ColorSpace::Srgb,
+        blend_color_space: vello::ColorSpace::Srgb,
     };
     let mut scene = Scene::new();
     let mut builder = SceneBuilder::for_scene(&mut scene);
     builder.append(&fragment, Some(transform));
-    let size = Extent3d {
-        width,
-        height,
-        depth_or_array_layers: 1,
-    };
-    let target = device.create_texture(&TextureDescriptor {
-        label: Some("Target texture"),
-        size,
-        mip_level_count: 1,
-        sample_count: 1,
-        dimension: wgpu::TextureDimension::D2,
-        format: TextureFormat::Rgba8Unorm,
-        usage: TextureUsages::STORAGE_BINDING | TextureUsages::COPY_SRC,
-        view_formats: &[],
-    });
-    let view = target.create_view(&wgpu::TextureViewDescriptor::default());
-    renderer
-        .render_to_texture(device, queue, &scene, &view, &render_params)
+    let readback = renderer
+        .render_to_buffer(device, queue, &scene, &render_params)
         .or_else(|_| bail!("Got non-Send/Sync error from rendering"))?;
-    // (width * 4).next_multiple_of(256)
-    let padded_byte_width = {
-        let w = width * 4;
-        match w % 256 {
-            0 => w,
-            r => w + (256 - r),
-        }
-    };
-    let buffer_size = padded_byte_width as u64 * height as u64;
-    let buffer = device.create_buffer(&BufferDescriptor {
-        label: Some("val"),
-        size: buffer_size,
-        usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
-        mapped_at_creation: false,
-    });
-    let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor {
-        label: Some("Copy out buffer"),
-    });
-    encoder.copy_texture_to_buffer(
-        target.as_image_copy(),
-        ImageCopyBuffer {
-            buffer: &buffer,
-            layout: wgpu::ImageDataLayout {
-                offset: 0,
-                bytes_per_row: Some(padded_byte_width),
-                rows_per_image: None,
-            },
-        },
-        size,
-    );
-    queue.submit([encoder.finish()]);
-    let buf_slice = buffer.slice(..);
-
-    let (sender, receiver) = futures_intrusive::channel::shared::oneshot_channel();
-    buf_slice.map_async(wgpu::MapMode::Read, move |v| sender.send(v).unwrap());
-    if let Some(recv_result) = block_on_wgpu(device, receiver.receive()) {
-        recv_result?;
-    } else {
-        bail!("channel was closed");
-    }
-
-    let data = buf_slice.get_mapped_range();
-    let mut result_unpadded = Vec::<u8>::with_capacity((width * height * 4).try_into()?);
-    for row in 0..height {
-        let start = (row * padded_byte_width).try_into()?;
-        result_unpadded.extend(&data[start..start + (width * 4) as usize]);
-    }
+    let pixels = block_on_wgpu(device, readback.pixels(AlphaMode::Separated))
+        .or_else(|_| bail!("Got non-Send/Sync error from reading back the render"))?;
     let out_path = args
         .out_directory
         .join(&example_scene.config.name)
@@ -218,7 +158,7 @@ async fn render(mut scenes: SceneSet, index: usize, args: &Args) -> Result<()> {
     encoder.set_color(png::ColorType::Rgba);
     encoder.set_depth(png::BitDepth::Eight);
     let mut writer = encoder.write_header()?;
-    writer.write_image_data(&result_unpadded)?;
+    writer.write_image_data(&pixels)?;
     writer.finish()?;
     println!("Wrote result ({width}x{height}) to {out_path:?}");
     Ok(())
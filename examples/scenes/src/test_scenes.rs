@@ -1,7 +1,7 @@
 use std::f32::consts::PI;
 
 use crate::{ExampleScene, SceneConfig, SceneParams, SceneSet};
-use vello::kurbo::{Affine, BezPath, Ellipse, PathEl, Point, Rect, Vec2};
+use vello::kurbo::{Affine, BezPath, Ellipse, PathEl, Point, Rect, Shape, Vec2};
 use vello::peniko::*;
 use vello::*;
 
@@ -42,6 +42,11 @@ pub fn test_scenes() -> SceneSet {
     };
     let scenes = vec![
         scene!(pattern_test),
+        scene!(pattern_brush),
+        scene!(pattern_along_path),
+        scene!(instanced_markers),
+        scene!(pattern_overflow),
+        scene!(pattern_brushes),
         splash_scene,
         mmark_scene,
         scene!(clip_test: animated),
@@ -55,6 +60,7 @@ pub fn test_scenes() -> SceneSet {
         scene!(conflation_artifacts),
         scene!(labyrinth),
         scene!(base_color_test: animated),
+        scene!(blurred_shadows),
     ];
 
     SceneSet { scenes }
@@ -225,6 +231,17 @@ fn animated_text(sb: &mut SceneBuilder, params: &mut SceneParams) {
         &piet_logo,
         Affine::translate((800.0, 50.0)) * Affine::rotate(20f64.to_radians()),
     );
+    sb.fill_image(
+        Fill::NonZero,
+        Affine::translate((50.0, 600.0)),
+        &piet_logo,
+        0.5,
+        ImageSampling::new(Extend::Repeat)
+            .with_y_extend(Extend::Reflect)
+            .with_quality(ImageQuality::Nearest),
+        Some(Affine::scale(0.25)),
+        &Rect::new(0.0, 0.0, 600.0, 200.0),
+    );
 }
 
 fn brush_transform(sb: &mut SceneBuilder, params: &mut SceneParams) {
@@ -262,22 +279,37 @@ fn brush_transform(sb: &mut SceneBuilder, params: &mut SceneParams) {
 }
 
 fn gradient_extend(sb: &mut SceneBuilder, params: &mut SceneParams) {
-    fn square(sb: &mut SceneBuilder, is_radial: bool, transform: Affine, extend: Extend) {
+    enum Kind {
+        Linear,
+        Radial,
+        Sweep,
+    }
+    fn square(sb: &mut SceneBuilder, kind: &Kind, transform: Affine, extend: Extend) {
         let colors = [Color::RED, Color::rgb8(0, 255, 0), Color::BLUE];
         let width = 300f64;
         let height = 300f64;
-        let gradient: Brush = if is_radial {
-            let center = (width * 0.5, height * 0.5);
-            let radius = (width * 0.25) as f32;
-            Gradient::new_two_point_radial(center, radius * 0.25, center, radius)
-                .with_stops(colors)
-                .with_extend(extend)
-                .into()
-        } else {
-            Gradient::new_linear((width * 0.35, height * 0.5), (width * 0.65, height * 0.5))
-                .with_stops(colors)
-                .with_extend(extend)
-                .into()
+        let gradient: Brush = match kind {
+            Kind::Linear => {
+                Gradient::new_linear((width * 0.35, height * 0.5), (width * 0.65, height * 0.5))
+                    .with_stops(colors)
+                    .with_extend(extend)
+                    .into()
+            }
+            Kind::Radial => {
+                let center = (width * 0.5, height * 0.5);
+                let radius = (width * 0.25) as f32;
+                Gradient::new_two_point_radial(center, radius * 0.25, center, radius)
+                    .with_stops(colors)
+                    .with_extend(extend)
+                    .into()
+            }
+            Kind::Sweep => {
+                let center = (width * 0.5, height * 0.5);
+                Gradient::new_sweep(center, 0.0, std::f32::consts::FRAC_PI_2)
+                    .with_stops(colors)
+                    .with_extend(extend)
+                    .into()
+            }
         };
         sb.fill(
             Fill::NonZero,
@@ -289,10 +321,9 @@ fn gradient_extend(sb: &mut SceneBuilder, params: &mut SceneParams) {
     }
     let extend_modes = [Extend::Pad, Extend::Repeat, Extend::Reflect];
     for (x, extend) in extend_modes.iter().enumerate() {
-        for y in 0..2 {
-            let is_radial = y & 1 != 0;
+        for (y, kind) in [Kind::Linear, Kind::Radial, Kind::Sweep].iter().enumerate() {
             let transform = Affine::translate((x as f64 * 350.0 + 50.0, y as f64 * 350.0 + 100.0));
-            square(sb, is_radial, transform, *extend);
+            square(sb, kind, transform, *extend);
         }
     }
     for (i, label) in ["Pad", "Repeat", "Reflect"].iter().enumerate() {
@@ -828,6 +859,89 @@ fn base_color_test(sb: &mut SceneBuilder, params: &mut SceneParams) {
     );
 }
 
+fn blurred_shadows(sb: &mut SceneBuilder, _: &mut SceneParams) {
+    sb.fill(
+        Fill::NonZero,
+        Affine::IDENTITY,
+        Color::rgb8(240, 240, 240),
+        None,
+        &Rect::new(0.0, 0.0, 1000.0, 800.0),
+    );
+    // Cards with analytic box shadows of increasing softness.
+    for (i, std_dev) in [1.0, 4.0, 10.0, 20.0].into_iter().enumerate() {
+        let card = Rect::from_origin_size((60.0 + 230.0 * i as f64, 60.0), (180.0, 120.0));
+        let radius = 12.0;
+        sb.draw_blurred_rounded_rect(
+            Affine::translate((0.0, std_dev / 2.0)),
+            card,
+            Color::rgba8(0, 0, 0, 96),
+            radius,
+            std_dev,
+        );
+        sb.fill(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            Color::WHITE,
+            None,
+            &card.to_rounded_rect(radius),
+        );
+    }
+    // A drop shadow follows the shape of the contents of its layer.
+    let layer = Rect::new(0.0, 240.0, 500.0, 800.0);
+    sb.push_layer_with_effect(
+        Mix::Normal,
+        1.0,
+        Affine::IDENTITY,
+        &layer,
+        LayerEffect::DropShadow {
+            offset: Vec2::new(12.0, 16.0),
+            std_dev: 8.0,
+            color: Color::rgba8(0, 0, 0, 128),
+        },
+    );
+    sb.fill(
+        Fill::NonZero,
+        Affine::IDENTITY,
+        Color::rgb8(40, 120, 220),
+        None,
+        &Ellipse::new((200.0, 420.0), (120.0, 80.0), 0.3),
+    );
+    sb.stroke(
+        &Stroke::new(24.0),
+        Affine::IDENTITY,
+        Color::rgb8(230, 80, 60),
+        None,
+        &Rect::new(120.0, 560.0, 380.0, 700.0).to_rounded_rect(30.0),
+    );
+    sb.pop_layer();
+    // The contents of a blurred layer are clipped to its shape first.
+    let layer = Rect::new(560.0, 280.0, 900.0, 720.0).to_rounded_rect(40.0);
+    sb.push_layer_with_effect(
+        Mix::Normal,
+        1.0,
+        Affine::IDENTITY,
+        &layer,
+        LayerEffect::Blur { std_dev: 6.0 },
+    );
+    let colors = [
+        Color::rgb8(230, 80, 60),
+        Color::rgb8(240, 180, 40),
+        Color::rgb8(60, 170, 90),
+        Color::rgb8(40, 120, 220),
+    ];
+    for i in 0..8 {
+        let x = 520.0 + 60.0 * i as f64;
+        sb.fill(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            colors[i % colors.len()],
+            None,
+            &Rect::new(x, 240.0, x + 30.0, 760.0),
+        );
+    }
+    sb.pop_layer();
+}
+
 fn clip_test(sb: &mut SceneBuilder, params: &mut SceneParams) {
     let clip1 = {
         const X0: f64 = 10.0;
@@ -1014,6 +1128,260 @@ fn pattern_test(sb: &mut SceneBuilder,params: &mut SceneParams) {
     
 }
 
+fn pattern_brush(sb: &mut SceneBuilder, _: &mut SceneParams) {
+    let mut content = SceneFragment::new();
+    {
+        let mut sb = SceneBuilder::for_fragment(&mut content);
+        sb.fill(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            Color::rgb8(255, 0, 0),
+            None,
+            &kurbo::Circle::new((10.0, 10.0), 8.0),
+        );
+        sb.fill(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            Color::rgb8(0, 0, 255),
+            None,
+            &Rect::new(20.0, 20.0, 30.0, 30.0),
+        );
+    }
+    let pattern = Pattern::new(&content, Rect::new(0.0, 0.0, 40.0, 40.0));
+    sb.fill_pattern(
+        Affine::translate((50.0, 50.0)),
+        &pattern
+            .with_transform(Affine::rotate(0.3))
+            .with_tiling(PatternTiling::Brick),
+        &kurbo::Circle::new((150.0, 150.0), 140.0),
+    );
+    sb.fill_pattern(
+        Affine::translate((350.0, 50.0)),
+        &pattern
+            .with_spacing(Vec2::new(40.0, 0.0), Vec2::new(20.0, 40.0))
+            .with_extend(Extend::Reflect),
+        &Rect::new(0.0, 0.0, 300.0, 300.0),
+    );
+    sb.stroke_pattern(
+        &Stroke::new(40.0),
+        Affine::translate((50.0, 400.0)),
+        &Pattern::new(&content, Rect::new(0.0, 0.0, 0.125, 0.25))
+            .with_units(PatternUnits::ObjectBoundingBox),
+        &Rect::new(20.0, 20.0, 580.0, 260.0),
+    );
+    sb.fill_pattern(
+        Affine::translate((700.0, 50.0)),
+        &pattern
+            .with_seed(1)
+            .with_offset_jitter(Vec2::new(8.0, 8.0))
+            .with_rotation_jitter(0.5)
+            .with_scale_jitter(0.25)
+            .with_colors(PatternColors::Gradient(&[
+                ColorStop::from((0.0, Color::rgb8(0, 128, 0))),
+                ColorStop::from((1.0, Color::rgb8(255, 192, 0))),
+            ])),
+        &Rect::new(0.0, 0.0, 300.0, 300.0),
+    );
+}
+
+fn pattern_along_path(sb: &mut SceneBuilder, _: &mut SceneParams) {
+    let mut arrow = SceneFragment::new();
+    {
+        let mut sb = SceneBuilder::for_fragment(&mut arrow);
+        let mut path = BezPath::new();
+        path.move_to((-8.0, -6.0));
+        path.line_to((10.0, 0.0));
+        path.line_to((-8.0, 6.0));
+        path.close_path();
+        sb.fill(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            Color::rgb8(200, 40, 40),
+            None,
+            &path,
+        );
+    }
+    let circle = kurbo::Circle::new((150.0, 150.0), 120.0).to_path(0.1);
+    sb.stroke(
+        &Stroke::new(1.0),
+        Affine::IDENTITY,
+        Color::rgb8(128, 128, 128),
+        None,
+        &circle,
+    );
+    sb.draw_pattern(
+        Affine::IDENTITY,
+        &Pattern::new(&arrow, Rect::new(0.0, 0.0, 1.0, 1.0)).with_placement(
+            PatternPlacement::Path {
+                guide: &circle,
+                spacing: 40.0,
+                offset: 0.0,
+                orientation: PatternOrientation::Tangent,
+            },
+        ),
+    );
+    let mut wave = BezPath::new();
+    wave.move_to((350.0, 150.0));
+    wave.curve_to((450.0, 0.0), (550.0, 300.0), (650.0, 150.0));
+    wave.curve_to((750.0, 0.0), (850.0, 300.0), (950.0, 150.0));
+    for (dy, orientation) in [
+        (0.0, PatternOrientation::Tangent),
+        (250.0, PatternOrientation::Fixed),
+    ] {
+        let transform = Affine::translate((0.0, dy));
+        sb.stroke(
+            &Stroke::new(1.0),
+            transform,
+            Color::rgb8(128, 128, 128),
+            None,
+            &wave,
+        );
+        sb.draw_pattern(
+            transform,
+            &Pattern::new(&arrow, Rect::new(0.0, 0.0, 1.0, 1.0)).with_placement(
+                PatternPlacement::Path {
+                    guide: &wave,
+                    spacing: 30.0,
+                    offset: -15.0,
+                    orientation,
+                },
+            ),
+        );
+    }
+}
+
+fn instanced_markers(sb: &mut SceneBuilder, _: &mut SceneParams) {
+    let mut marker = SceneFragment::new();
+    {
+        let mut sb = SceneBuilder::for_fragment(&mut marker);
+        sb.fill(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            Color::rgb8(30, 90, 200),
+            None,
+            &kurbo::Circle::new((0.0, 0.0), 3.0),
+        );
+    }
+    // A sunflower spiral of ten thousand markers, with the geometry encoded once.
+    let golden_angle = std::f64::consts::PI * (3.0 - 5f64.sqrt());
+    let transforms: Vec<Affine> = (0..10000)
+        .map(|i| {
+            let r = 4.0 * (i as f64).sqrt();
+            let a = i as f64 * golden_angle;
+            Affine::translate((500.0 + r * a.cos(), 450.0 + r * a.sin()))
+                * Affine::scale(0.4 + 0.6 * i as f64 / 10000.0)
+        })
+        .collect();
+    sb.draw_instances(&marker, &transforms);
+}
+
+fn pattern_overflow(sb: &mut SceneBuilder, _: &mut SceneParams) {
+    // Contents that overflow their 40x40 tile.
+    let mut content = SceneFragment::new();
+    {
+        let mut sb = SceneBuilder::for_fragment(&mut content);
+        sb.fill(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            Color::rgba8(255, 0, 0, 160),
+            None,
+            &kurbo::Circle::new((20.0, 20.0), 26.0),
+        );
+    }
+    let mut diamond = BezPath::new();
+    diamond.move_to((20.0, 0.0));
+    diamond.line_to((40.0, 20.0));
+    diamond.line_to((20.0, 40.0));
+    diamond.line_to((0.0, 20.0));
+    diamond.close_path();
+    let pattern = Pattern::new(&content, Rect::new(0.0, 0.0, 40.0, 40.0));
+    for (i, overflow) in [
+        PatternOverflow::Visible,
+        PatternOverflow::Hidden,
+        PatternOverflow::Clip(&diamond),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        sb.fill_pattern(
+            Affine::translate((50.0 + 320.0 * i as f64, 50.0)),
+            &pattern.with_overflow(overflow),
+            &Rect::new(0.0, 0.0, 300.0, 300.0),
+        );
+    }
+}
+
+fn pattern_brushes(sb: &mut SceneBuilder, params: &mut SceneParams) {
+    let flower = params
+        .images
+        .from_bytes(FLOWER_IMAGE.as_ptr() as usize, FLOWER_IMAGE)
+        .unwrap();
+    // A cell of 100x100 with every kind of draw, each of which must follow the
+    // transform of its cell.
+    let mut content = SceneFragment::new();
+    {
+        let mut sb = SceneBuilder::for_fragment(&mut content);
+        let linear =
+            Gradient::new_linear((5.0, 0.0), (45.0, 0.0)).with_stops([Color::RED, Color::YELLOW]);
+        sb.fill(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            &linear,
+            None,
+            &Rect::new(5.0, 5.0, 45.0, 45.0),
+        );
+        let radial = Gradient::new_radial((75.0, 25.0), 20.0)
+            .with_stops([Color::WHITE, Color::rgb8(0, 120, 60)]);
+        sb.fill(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            &radial,
+            None,
+            &kurbo::Circle::new((75.0, 25.0), 20.0),
+        );
+        let scale = 40.0 / flower.width.max(flower.height) as f64;
+        sb.fill(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            &flower,
+            Some(Affine::translate((5.0, 55.0)) * Affine::scale(scale)),
+            &Rect::new(5.0, 55.0, 45.0, 95.0),
+        );
+        // A nested layer with a stroke that is clipped by it.
+        sb.push_layer(
+            Mix::Multiply,
+            1.0,
+            Affine::IDENTITY,
+            &Rect::new(55.0, 55.0, 95.0, 95.0),
+        );
+        sb.stroke(
+            &Stroke::new(6.0),
+            Affine::IDENTITY,
+            Color::rgb8(40, 40, 200),
+            None,
+            &kurbo::Circle::new((75.0, 75.0), 22.0),
+        );
+        sb.pop_layer();
+        let text = Gradient::new_linear((55.0, 0.0), (95.0, 0.0))
+            .with_stops([Color::BLACK, Color::MAGENTA]);
+        params.text.add_run(
+            &mut sb,
+            None,
+            16.0,
+            &text,
+            Affine::translate((60.0, 80.0)),
+            None,
+            Fill::NonZero,
+            "vello",
+        );
+    }
+    sb.fill_pattern(
+        Affine::translate((50.0, 50.0)) * Affine::rotate(0.2),
+        &Pattern::new(&content, Rect::new(0.0, 0.0, 100.0, 100.0)),
+        &Rect::new(0.0, 0.0, 900.0, 700.0),
+    );
+}
+
 fn around_center(xform: Affine, center: Point) -> Affine {
     Affine::translate(center.to_vec2()) * xform * Affine::translate(-center.to_vec2())
 }
@@ -30,6 +30,9 @@ impl FromWorld for VelloRenderer {
                 &RendererOptions {
                     surface_format: None,
                     timestamp_period: queue.0.get_timestamp_period(),
+                    memory_limit: None,
+                    expand_patterns: false,
+                    target_format: vello::TargetFormat::Rgba8,
                 },
             )
             .unwrap(),
@@ -65,6 +68,8 @@ fn render_scenes(
             base_color: vello::peniko::Color::AQUAMARINE,
             width: gpu_image.size.x as u32,
             height: gpu_image.size.y as u32,
+            target_color_space: vello::ColorSpace::Srgb,
+            blend_color_space: vello::ColorSpace::Srgb,
         };
         renderer
             .0
@@ -85,6 +85,9 @@ fn run(
                 &RendererOptions {
                     surface_format: Some(render_state.surface.format),
                     timestamp_period: render_cx.devices[id].queue.get_timestamp_period(),
+                    memory_limit: None,
+                    expand_patterns: false,
+                    target_format: vello::TargetFormat::Rgba8,
                 },
             )
             .expect("Could create renderer"),
@@ -348,6 +351,8 @@ fn run(
                     .unwrap_or(Color::BLACK),
                 width,
                 height,
+                target_color_space: vello::ColorSpace::Srgb,
+                blend_color_space: vello::ColorSpace::Srgb,
             };
             let mut builder = SceneBuilder::for_scene(&mut scene);
             let mut transform = transform;
@@ -476,6 +481,9 @@ fn run(
                                 timestamp_period: render_cx.devices[id]
                                     .queue
                                     .get_timestamp_period(),
+                                memory_limit: None,
+                                expand_patterns: false,
+                                target_format: vello::TargetFormat::Rgba8,
                             },
                         )
                         .expect("Could create renderer")
@@ -25,11 +25,10 @@ fn main(
     @builtin(global_invocation_id) global_id: vec3<u32>,
     @builtin(local_invocation_id) local_id: vec3<u32>,
 ) {
-    let drawobj_ix = global_id.x;
+    let path_ix = global_id.x;
     var row_count = 0u;
-    if drawobj_ix < config.n_drawobj {
-        // TODO: when rectangles, path and draw obj are not the same
-        let path = paths[drawobj_ix];
+    if path_ix < config.n_path {
+        let path = paths[path_ix];
         sh_row_width[local_id.x] = path.bbox.z - path.bbox.x;
         row_count = path.bbox.w - path.bbox.y;
         sh_offset[local_id.x] = path.tiles;
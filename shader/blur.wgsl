@@ -0,0 +1,101 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT OR Unlicense
+
+// Gaussian blur of the layers of layer effects. The blur is separable: the
+// first pass blurs the rendered contents of a layer horizontally into a
+// temporary image, and the second pass blurs that vertically and writes the
+// filtered layer to its region of the layer image.
+
+// This must be kept in sync with the struct in crates/encoding/src/effect.rs
+struct BlurConfig {
+    width: u32,
+    height: u32,
+    x: u32,
+    y: u32,
+    vertical: u32,
+    kind: u32,
+    std_dev: f32,
+    color: u32,
+    offset: vec2<i32>,
+}
+
+let KIND_DROP_SHADOW = 1u;
+
+@group(0) @binding(0)
+var<uniform> config: BlurConfig;
+
+// The rendered contents of the layer.
+@group(0) @binding(1)
+var contents: texture_2d<f32>;
+
+// The output of the first pass, read by the second.
+@group(0) @binding(2)
+var blurred: texture_2d<f32>;
+
+#ifdef rgba16float
+@group(0) @binding(3)
+var output: texture_storage_2d<rgba16float, write>;
+#else
+@group(0) @binding(3)
+var output: texture_storage_2d<rgba8unorm, write>;
+#endif
+
+fn in_bounds(xy: vec2<i32>) -> bool {
+    return all(xy >= vec2(0)) && xy.x < i32(config.width) && xy.y < i32(config.height);
+}
+
+// Premultiplied color of the contents, transparent outside of the layer.
+fn load_contents(xy: vec2<i32>) -> vec4<f32> {
+    if !in_bounds(xy) {
+        return vec4(0.0);
+    }
+    let rgba = textureLoad(contents, xy, 0);
+    return vec4(rgba.rgb * rgba.a, rgba.a);
+}
+
+fn load_blurred(xy: vec2<i32>) -> vec4<f32> {
+    if !in_bounds(xy) {
+        return vec4(0.0);
+    }
+    return textureLoad(blurred, xy, 0);
+}
+
+@compute @workgroup_size(16, 16)
+fn main(
+    @builtin(global_invocation_id) global_id: vec3<u32>,
+) {
+    if global_id.x >= config.width || global_id.y >= config.height {
+        return;
+    }
+    let xy = vec2<i32>(global_id.xy);
+    // The kernel is cut off at three standard deviations, and a zero standard
+    // deviation leaves the contents unchanged.
+    let radius = i32(ceil(3.0 * config.std_dev));
+    let scale = select(0.0, -0.5 / (config.std_dev * config.std_dev), radius > 0);
+    var sum = vec4(0.0);
+    var total = 0.0;
+    for (var i = -radius; i <= radius; i += 1) {
+        let weight = exp(f32(i * i) * scale);
+        var rgba: vec4<f32>;
+        if config.vertical == 0u {
+            rgba = load_contents(xy + vec2(i, 0) - config.offset);
+        } else {
+            rgba = load_blurred(xy + vec2(0, i));
+        }
+        sum += weight * rgba;
+        total += weight;
+    }
+    var rgba = sum / total;
+    if config.vertical == 0u {
+        textureStore(output, xy, rgba);
+        return;
+    }
+    if config.kind == KIND_DROP_SHADOW {
+        let shadow = unpack4x8unorm(config.color).wzyx * rgba.a;
+        let fg = load_contents(xy);
+        rgba = fg + shadow * (1.0 - fg.a);
+    }
+    // Max with a small epsilon to avoid NaNs
+    let a_inv = 1.0 / max(rgba.a, 1e-6);
+    let rgba_sep = vec4(rgba.rgb * a_inv, rgba.a);
+    textureStore(output, xy + vec2(i32(config.x), i32(config.y)), rgba_sep);
+}
@@ -133,6 +133,14 @@ fn write_image(info_offset: u32) {
     cmd_offset += 2u;
 }
 
+fn write_blur_rect(color: CmdColor, info_offset: u32) {
+    alloc_cmd(3u);
+    ptcl[cmd_offset] = CMD_BLUR_RECT;
+    ptcl[cmd_offset + 1u] = color.rgba_color;
+    ptcl[cmd_offset + 2u] = info_offset;
+    cmd_offset += 3u;
+}
+
 fn write_begin_clip() {
     alloc_cmd(1u);
     ptcl[cmd_offset] = CMD_BEGIN_CLIP;
@@ -375,13 +383,30 @@ fn main(
                             write_grad(CMD_RAD_GRAD, index, info_offset);
                         }
                     }
+                    // DRAWTAG_FILL_SWEEP_GRADIENT
+                    case 0x254u: {
+                        let linewidth = bitcast<f32>(info_bin_data[di]);
+                        if write_path(tile, linewidth) {
+                            let index = scene[dd];
+                            let info_offset = di + 1u;
+                            write_grad(CMD_SWEEP_GRAD, index, info_offset);
+                        }
+                    }
                     // DRAWTAG_FILL_IMAGE
-                    case 0x248u: {
+                    case 0x28cu: {
                         let linewidth = bitcast<f32>(info_bin_data[di]);
                         if write_path(tile, linewidth) {                            
                             write_image(di + 1u);
                         }
                     }
+                    // DRAWTAG_BLUR_RECT
+                    case 0x2d4u: {
+                        let linewidth = bitcast<f32>(info_bin_data[di]);
+                        if write_path(tile, linewidth) {
+                            let rgba_color = scene[dd];
+                            write_blur_rect(CmdColor(rgba_color), di + 1u);
+                        }
+                    }
                     // DRAWTAG_BEGIN_CLIP
                     case 0x9u: {
                         if tile.segments == 0u && tile.backdrop == 0 {
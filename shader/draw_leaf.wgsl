@@ -29,9 +29,6 @@ var<storage, read_write> info: array<u32>;
 @group(0) @binding(6)
 var<storage, read_write> clip_inp: array<ClipInp>;
 
-@group(0) @binding(7)
-var<storage, read_write> pattern_inp: array<PatternInp>;
-
 #import util
 
 let WG_SIZE = 256u;
@@ -95,14 +92,22 @@ fn main(
         m = combine_draw_monoid(m, sh_scratch[local_id.x - 1u]);
     }
     // m now contains exclusive prefix sum of draw monoid
+    if config.n_pattern_instances != 0u && ix < config.n_drawobj {
+        // Draw objects expanded by the pattern stage have their own path and
+        // draw data.
+        let slot = config.pattern_slot_base + ix * 3u;
+        m.path_ix = scene[slot];
+        m.scene_offset = scene[slot + 1u];
+    }
     if ix < config.n_drawobj {
         draw_monoid[ix] = m;
     }
     let dd = config.drawdata_base + m.scene_offset;
     let di = m.info_offset;
     if tag_word == DRAWTAG_FILL_COLOR || tag_word == DRAWTAG_FILL_LIN_GRADIENT ||
-        tag_word == DRAWTAG_FILL_RAD_GRADIENT || tag_word == DRAWTAG_FILL_IMAGE ||
-        tag_word == DRAWTAG_BEGIN_CLIP || tag_word == DRAWTAG_BEGIN_PATTERN || tag_word == DRAWTAG_END_PATTERN
+        tag_word == DRAWTAG_FILL_RAD_GRADIENT || tag_word == DRAWTAG_FILL_SWEEP_GRADIENT ||
+        tag_word == DRAWTAG_FILL_IMAGE || tag_word == DRAWTAG_BLUR_RECT ||
+        tag_word == DRAWTAG_BEGIN_CLIP
     {
         let bbox = path_bbox[m.path_ix];
         // TODO: bbox is mostly yagni here, sort that out. Maybe clips?
@@ -115,7 +120,8 @@ fn main(
         var transform = Transform();
         var linewidth = bbox.linewidth;
         if linewidth >= 0.0 || tag_word == DRAWTAG_FILL_LIN_GRADIENT || tag_word == DRAWTAG_FILL_RAD_GRADIENT ||
-            tag_word == DRAWTAG_FILL_IMAGE 
+            tag_word == DRAWTAG_FILL_SWEEP_GRADIENT || tag_word == DRAWTAG_FILL_IMAGE ||
+            tag_word == DRAWTAG_BLUR_RECT
         {
             transform = read_transform(config.transform_base, bbox.trans_ix);
         }
@@ -124,7 +130,6 @@ fn main(
             let matrx = transform.matrx;
             linewidth *= sqrt(abs(matrx.x * matrx.w - matrx.y * matrx.z));
         }
-        let pattern_ix = select(0u, (m.pattern_ix - 1u) >> 1u, m.pattern_ix > 0u);
         switch tag_word {
             // DRAWTAG_FILL_COLOR
             case 0x44u: {
@@ -229,8 +234,26 @@ fn main(
                 info[di + 8u] = bitcast<u32>(radius);
                 info[di + 9u] = bitcast<u32>((flags << 3u) | kind);
             }
+            // DRAWTAG_FILL_SWEEP_GRADIENT
+            case 0x254u: {
+                info[di] = bitcast<u32>(linewidth);
+                let p0 = bitcast<vec2<f32>>(vec2(scene[dd + 1u], scene[dd + 2u]));
+                // Map user space to gradient space, centered on p0
+                let xform = transform_mul(
+                    Transform(vec4(1.0, 0.0, 0.0, 1.0), -p0),
+                    transform_inverse(transform)
+                );
+                info[di + 1u] = bitcast<u32>(xform.matrx.x);
+                info[di + 2u] = bitcast<u32>(xform.matrx.y);
+                info[di + 3u] = bitcast<u32>(xform.matrx.z);
+                info[di + 4u] = bitcast<u32>(xform.matrx.w);
+                info[di + 5u] = bitcast<u32>(xform.translate.x);
+                info[di + 6u] = bitcast<u32>(xform.translate.y);
+                info[di + 7u] = scene[dd + 3u];
+                info[di + 8u] = scene[dd + 4u];
+            }
             // DRAWTAG_FILL_IMAGE
-            case 0x248u: {
+            case 0x28cu: {
                 info[di] = bitcast<u32>(linewidth);
                 let inv = transform_inverse(transform);
                 info[di + 1u] = bitcast<u32>(inv.matrx.x);
@@ -241,18 +264,22 @@ fn main(
                 info[di + 6u] = bitcast<u32>(inv.translate.y);
                 info[di + 7u] = scene[dd];
                 info[di + 8u] = scene[dd + 1u];
+                info[di + 9u] = scene[dd + 2u];
             }
-            ///DRAWTAG_BEGIN_PATTERN
-            case 0x400u: {
-                let out = &pattern_inp[pattern_ix];
-                (*out).begin_path_ix = m.path_ix;
-                (*out).clip_ix = min(m.clip_ix - 1u, config.n_clip - 1u);
-            }
-            ///DRAWTAG_END_PATTERN
-            case 0xC00u: {
-                let out = &pattern_inp[pattern_ix];
-                (*out).end_path_ix = m.path_ix;
-                (*out).clip_ix = min(m.clip_ix - 1u, config.n_clip - 1u);
+            // DRAWTAG_BLUR_RECT
+            case 0x2d4u: {
+                info[di] = bitcast<u32>(linewidth);
+                let inv = transform_inverse(transform);
+                info[di + 1u] = bitcast<u32>(inv.matrx.x);
+                info[di + 2u] = bitcast<u32>(inv.matrx.y);
+                info[di + 3u] = bitcast<u32>(inv.matrx.z);
+                info[di + 4u] = bitcast<u32>(inv.matrx.w);
+                info[di + 5u] = bitcast<u32>(inv.translate.x);
+                info[di + 6u] = bitcast<u32>(inv.translate.y);
+                info[di + 7u] = scene[dd + 1u];
+                info[di + 8u] = scene[dd + 2u];
+                info[di + 9u] = scene[dd + 3u];
+                info[di + 10u] = scene[dd + 4u];
             }
             default: {}
         }
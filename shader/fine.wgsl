@@ -29,7 +29,11 @@ var<storage> segments: array<Segment>;
 let GRADIENT_WIDTH = 512;
 
 @group(0) @binding(3)
+#ifdef rgba16float
+var output: texture_storage_2d<rgba16float, write>;
+#else
 var output: texture_storage_2d<rgba8unorm, write>;
+#endif
 
 @group(0) @binding(4)
 var<storage> ptcl: array<u32>;
@@ -41,7 +45,10 @@ var gradients: texture_2d<f32>;
 var<storage> info: array<u32>;
 
 @group(0) @binding(7)
-var image_atlas: texture_2d<f32>;
+var image_atlas: texture_2d_array<f32>;
+
+@group(0) @binding(8)
+var layer_images: texture_2d<f32>;
 
 fn read_fill(cmd_ix: u32) -> CmdFill {
     let tile = ptcl[cmd_ix + 1u];
@@ -90,6 +97,22 @@ fn read_rad_grad(cmd_ix: u32) -> CmdRadGrad {
     return CmdRadGrad(index, extend_mode, matrx, xlat, focal_x, radius, kind, flags);
 }
 
+fn read_sweep_grad(cmd_ix: u32) -> CmdSweepGrad {
+    let index_mode = ptcl[cmd_ix + 1u];
+    let index = index_mode >> 2u;
+    let extend_mode = index_mode & 0x3u;
+    let info_offset = ptcl[cmd_ix + 2u];
+    let m0 = bitcast<f32>(info[info_offset]);
+    let m1 = bitcast<f32>(info[info_offset + 1u]);
+    let m2 = bitcast<f32>(info[info_offset + 2u]);
+    let m3 = bitcast<f32>(info[info_offset + 3u]);
+    let matrx = vec4(m0, m1, m2, m3);
+    let xlat = vec2(bitcast<f32>(info[info_offset + 4u]), bitcast<f32>(info[info_offset + 5u]));
+    let t0 = bitcast<f32>(info[info_offset + 6u]);
+    let t1 = bitcast<f32>(info[info_offset + 7u]);
+    return CmdSweepGrad(index, extend_mode, matrx, xlat, t0, t1);
+}
+
 fn read_image(cmd_ix: u32) -> CmdImage {
     let info_offset = ptcl[cmd_ix + 1u];
     let m0 = bitcast<f32>(info[info_offset]);
@@ -100,12 +123,35 @@ fn read_image(cmd_ix: u32) -> CmdImage {
     let xlat = vec2(bitcast<f32>(info[info_offset + 4u]), bitcast<f32>(info[info_offset + 5u]));
     let xy = info[info_offset + 6u];
     let width_height = info[info_offset + 7u];
+    let sampling = info[info_offset + 8u];
     // The following are not intended to be bitcasts
-    let x = f32(xy >> 16u);
-    let y = f32(xy & 0xffffu);
+    let page = xy >> 26u;
+    let x = f32((xy >> 13u) & 0x1fffu);
+    let y = f32(xy & 0x1fffu);
     let width = f32(width_height >> 16u);
     let height = f32(width_height & 0xffffu);
-    return CmdImage(matrx, xlat, vec2(x, y), vec2(width, height));
+    let x_extend = sampling & 0x3u;
+    let y_extend = (sampling >> 2u) & 0x3u;
+    let quality = (sampling >> 4u) & 0x3u;
+    let from_layer = (sampling & 0x40u) != 0u;
+    let alpha = f32(sampling >> 24u) * (1.0 / 255.0);
+    return CmdImage(matrx, xlat, vec2(x, y), page, vec2(width, height), x_extend, y_extend, quality, from_layer, alpha);
+}
+
+fn read_blur_rect(cmd_ix: u32) -> CmdBlurRect {
+    let rgba_color = ptcl[cmd_ix + 1u];
+    let info_offset = ptcl[cmd_ix + 2u];
+    let m0 = bitcast<f32>(info[info_offset]);
+    let m1 = bitcast<f32>(info[info_offset + 1u]);
+    let m2 = bitcast<f32>(info[info_offset + 2u]);
+    let m3 = bitcast<f32>(info[info_offset + 3u]);
+    let matrx = vec4(m0, m1, m2, m3);
+    let xlat = vec2(bitcast<f32>(info[info_offset + 4u]), bitcast<f32>(info[info_offset + 5u]));
+    let width = bitcast<f32>(info[info_offset + 6u]);
+    let height = bitcast<f32>(info[info_offset + 7u]);
+    let radius = bitcast<f32>(info[info_offset + 8u]);
+    let std_dev = bitcast<f32>(info[info_offset + 9u]);
+    return CmdBlurRect(rgba_color, matrx, xlat, width, height, radius, std_dev);
 }
 
 fn read_end_clip(cmd_ix: u32) -> CmdEndClip {
@@ -134,6 +180,127 @@ fn extend_mode(t: f32, mode: u32) -> f32 {
     }
 }
 
+// Applies an extend mode to the integer coordinate of a texel in an image of
+// the given size.
+fn extend_texel(x: i32, size: i32, mode: u32) -> i32 {
+    switch mode {
+        // EXTEND_PAD
+        case 0u: {
+            return clamp(x, 0, size - 1);
+        }
+        // EXTEND_REPEAT
+        case 1u: {
+            return ((x % size) + size) % size;
+        }
+        // EXTEND_REFLECT
+        default: {
+            let period = 2 * size;
+            let r = ((x % period) + period) % period;
+            return select(r, period - 1 - r, r >= size);
+        }
+    }
+}
+
+// Loads a premultiplied texel of an image, applying its extend modes.
+fn load_image(image: CmdImage, xy: vec2<i32>) -> vec4<f32> {
+    let size = vec2<i32>(image.extents);
+    let x = extend_texel(xy.x, size.x, image.x_extend);
+    let y = extend_texel(xy.y, size.y, image.y_extend);
+    let atlas_xy = vec2(x, y) + vec2<i32>(image.atlas_offset);
+    var texel: vec4<f32>;
+    if image.from_layer {
+        texel = textureLoad(layer_images, atlas_xy, 0);
+    } else {
+        texel = textureLoad(image_atlas, atlas_xy, i32(image.atlas_page), 0);
+    }
+    return to_blend_space(premul_alpha(texel));
+}
+
+// Weights of the Mitchell-Netravali filter with B = C = 1/3 for the four
+// texels around a sample at fractional offset t from the second one.
+fn cubic_weights(t: f32) -> vec4<f32> {
+    let x = vec4(1.0 + t, t, 1.0 - t, 2.0 - t);
+    let x2 = x * x;
+    let x3 = x2 * x;
+    let near = (7.0 * x3 - 12.0 * x2 + 16.0 / 3.0) * (1.0 / 6.0);
+    let far = (-7.0 / 3.0 * x3 + 12.0 * x2 - 20.0 * x + 32.0 / 3.0) * (1.0 / 6.0);
+    return select(far, near, x < vec4(1.0));
+}
+
+// Approximation of the error function with a maximum error of about 1e-4, see
+// https://raphlinus.github.io/audio/2018/09/05/sigmoid.html
+fn erf7(x: f32) -> f32 {
+    let y = x * 1.1283791671;
+    let yy = y * y;
+    let z = y + (0.24295 + (0.03395 + 0.0104 * yy) * yy) * (y * yy);
+    return z / sqrt(1.0 + z * z);
+}
+
+// Coverage of a rounded rectangle centered at the origin, convolved with a
+// gaussian. This approximates the rectangle as a superellipse, see
+// https://raphlinus.github.io/graphics/2020/04/21/blurred-rounded-rects.html
+fn blur_rect_coverage(xy: vec2<f32>, size: vec2<f32>, radius: f32, std_dev: f32) -> f32 {
+    // Scale so that erf of the distance over s is the integral of the gaussian.
+    let s = max(std_dev, 1e-3) * 1.4142135624;
+    let s_inv = 1.0 / s;
+    let min_edge = min(size.x, size.y);
+    let r_max = 0.5 * min_edge;
+    let r0 = min(length(vec2(radius, 1.15 * s)), r_max);
+    let r1 = min(length(vec2(radius, 2.0 * s)), r_max);
+    let exponent = 2.0 * r1 / r0;
+    // Pull in the long end, making the rectangle less eccentric.
+    let ex = 0.5 * s_inv * size.x;
+    let ey = 0.5 * s_inv * size.y;
+    let delta = 1.25 * s * (exp(-ex * ex) - exp(-ey * ey));
+    let w = size.x + min(delta, 0.0);
+    let h = size.y - max(delta, 0.0);
+    let scale = 0.5 * erf7(s_inv * 0.5 * (max(w, h) - 0.5 * radius));
+    let x0 = abs(xy.x) - (0.5 * w - r1);
+    let y0 = abs(xy.y) - (0.5 * h - r1);
+    let x1 = max(x0, 0.0);
+    let y1 = max(y0, 0.0);
+    let d_pos = pow(pow(x1, exponent) + pow(y1, exponent), 1.0 / exponent);
+    let d_neg = min(max(x0, y0), 0.0);
+    let d = d_pos + d_neg - r1;
+    return scale * (erf7(s_inv * (min_edge + d)) - erf7(s_inv * d));
+}
+
+// Angle of a point around the origin in radians, in the range [0, 2pi).
+fn xy_to_angle(xy: vec2<f32>) -> f32 {
+    let angle = atan2(xy.y, xy.x);
+    return select(angle, angle + 6.283185307179586, angle < 0.0);
+}
+
+// Applies the sRGB transfer function in reverse, decoding sRGB to linear light.
+fn srgb_to_linear(rgb: vec3<f32>) -> vec3<f32> {
+    let lo = rgb * (1.0 / 12.92);
+    let hi = pow((rgb + 0.055) * (1.0 / 1.055), vec3(2.4));
+    return select(hi, lo, rgb <= vec3(0.04045));
+}
+
+// Applies the sRGB transfer function, encoding linear light to sRGB.
+fn linear_to_srgb(rgb: vec3<f32>) -> vec3<f32> {
+    let lo = rgb * 12.92;
+    let hi = 1.055 * pow(max(rgb, vec3(0.0)), vec3(1.0 / 2.4)) - 0.055;
+    return select(hi, lo, rgb <= vec3(0.0031308));
+}
+
+// Converts a premultiplied sRGB color to the color space used for blending.
+fn to_blend_space(rgba: vec4<f32>) -> vec4<f32> {
+    if config.blend_color_space == COLOR_SPACE_SRGB || rgba.a == 0.0 {
+        return rgba;
+    }
+    return vec4(srgb_to_linear(rgba.rgb / rgba.a) * rgba.a, rgba.a);
+}
+
+// Converts a premultiplied color in the color space used for blending to sRGB.
+fn from_blend_space(rgba: vec4<f32>) -> vec4<f32> {
+    if config.blend_color_space == COLOR_SPACE_SRGB || rgba.a == 0.0 {
+        return rgba;
+    }
+    return vec4(linear_to_srgb(rgba.rgb / rgba.a) * rgba.a, rgba.a);
+}
+
 #else
 
 @group(0) @binding(3)
@@ -234,8 +401,9 @@ fn main(
     let xy = vec2(f32(global_id.x * PIXELS_PER_THREAD), f32(global_id.y));
 #ifdef full
     var rgba: array<vec4<f32>, PIXELS_PER_THREAD>;
+    let base_color = to_blend_space(unpack4x8unorm(config.base_color).wzyx);
     for (var i = 0u; i < PIXELS_PER_THREAD; i += 1u) {
-        rgba[i] = unpack4x8unorm(config.base_color).wzyx;
+        rgba[i] = base_color;
     }
     //4x4
     var blend_stack: array<array<u32, PIXELS_PER_THREAD>, BLEND_STACK_SPLIT>;
@@ -276,7 +444,7 @@ fn main(
             // CMD_COLOR
             case 5u: {
                 let color = read_color(cmd_ix);
-                let fg = unpack4x8unorm(color.rgba_color).wzyx;
+                let fg = to_blend_space(unpack4x8unorm(color.rgba_color).wzyx);
                 for (var i = 0u; i < PIXELS_PER_THREAD; i += 1u) {
                     let fg_i = fg * area[i];
                     rgba[i] = rgba[i] * (1.0 - fg_i.a) + fg_i;
@@ -290,7 +458,7 @@ fn main(
                 for (var i = 0u; i < PIXELS_PER_THREAD; i += 1u) {
                     let my_d = d + lin.line_x * f32(i);
                     let x = i32(round(extend_mode(my_d, lin.extend_mode) * f32(GRADIENT_WIDTH - 1)));
-                    let fg_rgba = textureLoad(gradients, vec2(x, i32(lin.index)), 0);
+                    let fg_rgba = to_blend_space(textureLoad(gradients, vec2(x, i32(lin.index)), 0));
                     let fg_i = fg_rgba * area[i];
                     rgba[i] = rgba[i] * (1.0 - fg_i.a) + fg_i;
                 }
@@ -335,30 +503,92 @@ fn main(
                         t = extend_mode(focal_x + t_sign * t, rad.extend_mode);
                         t = select(t, 1.0 - t, is_swapped);
                         let x = i32(round(t * f32(GRADIENT_WIDTH - 1)));
-                        let fg_rgba = textureLoad(gradients, vec2(x, i32(rad.index)), 0);
+                        let fg_rgba = to_blend_space(textureLoad(gradients, vec2(x, i32(rad.index)), 0));
                         let fg_i = fg_rgba * area[i];
                         rgba[i] = rgba[i] * (1.0 - fg_i.a) + fg_i;
                     }
                 }
                 cmd_ix += 3u;
             }
+            // CMD_SWEEP_GRAD
+            case 12u: {
+                let sweep = read_sweep_grad(cmd_ix);
+                let scale = 1.0 / (sweep.t1 - sweep.t0);
+                for (var i = 0u; i < PIXELS_PER_THREAD; i += 1u) {
+                    let my_xy = vec2(xy.x + f32(i), xy.y);
+                    let local_xy = sweep.matrx.xy * my_xy.x + sweep.matrx.zw * my_xy.y + sweep.xlat;
+                    let t = extend_mode((xy_to_angle(local_xy) - sweep.t0) * scale, sweep.extend_mode);
+                    let x = i32(round(t * f32(GRADIENT_WIDTH - 1)));
+                    let fg_rgba = to_blend_space(textureLoad(gradients, vec2(x, i32(sweep.index)), 0));
+                    let fg_i = fg_rgba * area[i];
+                    rgba[i] = rgba[i] * (1.0 - fg_i.a) + fg_i;
+                }
+                cmd_ix += 3u;
+            }
+            // CMD_BLUR_RECT
+            case 13u: {
+                let blur = read_blur_rect(cmd_ix);
+                let fg = to_blend_space(unpack4x8unorm(blur.rgba_color).wzyx);
+                let size = vec2(blur.width, blur.height);
+                for (var i = 0u; i < PIXELS_PER_THREAD; i += 1u) {
+                    let my_xy = vec2(xy.x + f32(i) + 0.5, xy.y + 0.5);
+                    let local_xy = blur.matrx.xy * my_xy.x + blur.matrx.zw * my_xy.y + blur.xlat;
+                    let coverage = blur_rect_coverage(local_xy, size, blur.radius, blur.std_dev);
+                    let fg_i = fg * (coverage * area[i]);
+                    rgba[i] = rgba[i] * (1.0 - fg_i.a) + fg_i;
+                }
+                cmd_ix += 3u;
+            }
             // CMD_IMAGE
             case 8u: {
                 let image = read_image(cmd_ix);
-                let atlas_extents = image.atlas_offset + image.extents;
-                for (var i = 0u; i < PIXELS_PER_THREAD; i += 1u) {
-                    let my_xy = vec2(xy.x + f32(i), xy.y);
-                    let atlas_uv = image.matrx.xy * my_xy.x + image.matrx.zw * my_xy.y + image.xlat + image.atlas_offset;
-                    // This currently clips to the image bounds. TODO: extend modes
-                    if all(atlas_uv < atlas_extents) && area[i] != 0.0 {
-                        let uv_quad = vec4(max(floor(atlas_uv), image.atlas_offset), min(ceil(atlas_uv), atlas_extents));
-                        let uv_frac = fract(atlas_uv);
-                        let a = premul_alpha(textureLoad(image_atlas, vec2<i32>(uv_quad.xy), 0));
-                        let b = premul_alpha(textureLoad(image_atlas, vec2<i32>(uv_quad.xw), 0));
-                        let c = premul_alpha(textureLoad(image_atlas, vec2<i32>(uv_quad.zy), 0));
-                        let d = premul_alpha(textureLoad(image_atlas, vec2<i32>(uv_quad.zw), 0));
-                        let fg_rgba = mix(mix(a, b, uv_frac.y), mix(c, d, uv_frac.y), uv_frac.x);
-                        let fg_i = fg_rgba * area[i];
+                // Images that couldn't be allocated in the atlas have zero extents.
+                if all(image.extents > vec2(0.0)) {
+                    for (var i = 0u; i < PIXELS_PER_THREAD; i += 1u) {
+                        if area[i] == 0.0 {
+                            continue;
+                        }
+                        // Sample at the pixel center, with texel centers at half integers.
+                        let my_xy = vec2(xy.x + f32(i) + 0.5, xy.y + 0.5);
+                        let uv = image.matrx.xy * my_xy.x + image.matrx.zw * my_xy.y + image.xlat;
+                        var fg_rgba: vec4<f32>;
+                        switch image.quality {
+                            // IMAGE_QUALITY_NEAREST
+                            case 0u: {
+                                fg_rgba = load_image(image, vec2<i32>(floor(uv)));
+                            }
+                            // IMAGE_QUALITY_BILINEAR
+                            case 1u: {
+                                let uv0 = floor(uv - 0.5);
+                                let uv_frac = uv - 0.5 - uv0;
+                                let xy0 = vec2<i32>(uv0);
+                                let a = load_image(image, xy0);
+                                let b = load_image(image, xy0 + vec2(0, 1));
+                                let c = load_image(image, xy0 + vec2(1, 0));
+                                let d = load_image(image, xy0 + vec2(1, 1));
+                                fg_rgba = mix(mix(a, b, uv_frac.y), mix(c, d, uv_frac.y), uv_frac.x);
+                            }
+                            // IMAGE_QUALITY_BICUBIC
+                            default: {
+                                let uv0 = floor(uv - 0.5);
+                                let uv_frac = uv - 0.5 - uv0;
+                                let xy0 = vec2<i32>(uv0);
+                                var wx = cubic_weights(uv_frac.x);
+                                var wy = cubic_weights(uv_frac.y);
+                                fg_rgba = vec4(0.0);
+                                for (var j = 0; j < 4; j += 1) {
+                                    var row = vec4(0.0);
+                                    for (var k = 0; k < 4; k += 1) {
+                                        row += wx[k] * load_image(image, xy0 + vec2(k - 1, j - 1));
+                                    }
+                                    fg_rgba += wy[j] * row;
+                                }
+                                // The filter overshoots, so clamp to a valid premultiplied color.
+                                let alpha = clamp(fg_rgba.a, 0.0, 1.0);
+                                fg_rgba = vec4(clamp(fg_rgba.rgb, vec3(0.0), vec3(alpha)), alpha);
+                            }
+                        }
+                        let fg_i = fg_rgba * (image.alpha * area[i]);
                         rgba[i] = rgba[i] * (1.0 - fg_i.a) + fg_i;
                     }
                 }
@@ -368,7 +598,8 @@ fn main(
             case 9u: {
                 if clip_depth < BLEND_STACK_SPLIT {
                     for (var i = 0u; i < PIXELS_PER_THREAD; i += 1u) {
-                        blend_stack[clip_depth][i] = pack4x8unorm(rgba[i]);
+                        // Stored as sRGB to keep precision in dark colors
+                        blend_stack[clip_depth][i] = pack4x8unorm(from_blend_space(rgba[i]));
                         rgba[i] = vec4(0.0);
                     }
                 } else {
@@ -388,7 +619,7 @@ fn main(
                     } else {
                         // load from memory
                     }
-                    let bg = unpack4x8unorm(bg_rgba);
+                    let bg = to_blend_space(unpack4x8unorm(bg_rgba));
                     let fg = rgba[i] * area[i] * end_clip.alpha;
                     rgba[i] = blend_mix_compose(bg, fg, end_clip.blend);
                 }
@@ -408,7 +639,14 @@ fn main(
             let fg = rgba[i];
             // Max with a small epsilon to avoid NaNs
             let a_inv = 1.0 / max(fg.a, 1e-6);
-            let rgba_sep = vec4(fg.rgb * a_inv, fg.a);
+            var rgba_sep = vec4(fg.rgb * a_inv, fg.a);
+            if config.target_color_space != config.blend_color_space {
+                if config.target_color_space == COLOR_SPACE_LINEAR_SRGB {
+                    rgba_sep = vec4(srgb_to_linear(rgba_sep.rgb), rgba_sep.a);
+                } else {
+                    rgba_sep = vec4(linear_to_srgb(rgba_sep.rgb), rgba_sep.a);
+                }
+            }
             textureStore(output, vec2<i32>(coords), rgba_sep);
         }
     } 
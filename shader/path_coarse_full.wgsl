@@ -115,11 +115,21 @@ fn main(
         return;
     }
     let ix = global_id.x;
-    let tag_word = scene[config.pathtag_base + (ix >> 2u)];
-    let shift = (ix & 3u) * 8u;
-    var tag_byte = (tag_word >> shift) & 0xffu;
-    
-    if ((tag_byte & PATH_TAG_SEG_TYPE) != 0u)||(global_id.x > PATTERN_CUBMIC_START && (global_id.x - PATTERN_CUBMIC_START) < bump.pattern_cubic) {
+    var is_segment = false;
+    if ix < config.pattern_cubics_base {
+        let tag_word = scene[config.pathtag_base + (ix >> 2u)];
+        let shift = (ix & 3u) * 8u;
+        let tag_byte = (tag_word >> shift) & 0xffu;
+        // The cubics of template paths are only drawn through their instances.
+        is_segment = (tag_byte & PATH_TAG_SEG_TYPE) != 0u && cubics[ix].path_ix >= config.n_template_paths;
+    } else if (atomicLoad(&bump.failed) & STAGE_PATTERN) == 0u {
+        // Pattern instances are allocated after the cubics of the scene. If the
+        // pattern stage failed, the slots it reserved may not have been written.
+        let n_instances = min(atomicLoad(&bump.pattern_cubic), config.pattern_cubics_size);
+        is_segment = ix - config.pattern_cubics_base < n_instances;
+    }
+
+    if is_segment {
         // Discussion question: it might actually be cheaper to do the path segment
         // decoding & transform again rather than store the result in a buffer;
         // classic memory vs ALU tradeoff.
@@ -186,7 +186,9 @@ fn main(
         cubics[global_id.x] = Cubic(p0, p1, p2, p3, stroke, tm.path_ix, flags);
         // Update bounding box using atomics only. Computing a monoid is a
         // potential future optimization.
-        if bbox.z > bbox.x || bbox.w > bbox.y {
+        // Template paths only hold the geometry of pattern instances, which
+        // the pattern stage places, so they keep an empty bounding box.
+        if (bbox.z > bbox.x || bbox.w > bbox.y) && tm.path_ix >= config.n_template_paths {
             atomicMin(&(*out).x0, round_down(bbox.x));
             atomicMin(&(*out).y0, round_down(bbox.y));
             atomicMax(&(*out).x1, round_up(bbox.z));
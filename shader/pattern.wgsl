@@ -1,20 +1,19 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT OR Unlicense
 
-// Path segment decoding for the full case.
+// Pattern expansion.
 
-// In the simple case, path segments are decoded as part of the coarse
-// path rendering stage. In the full case, they are separated, as the
-// decoding process also generates bounding boxes, and those in turn are
-// used for tile allocation and clipping; actual coarse path rasterization
-// can't proceed until those are complete.
-
-// There's some duplication of the decoding code but we won't worry about
-// that just now. Perhaps it could be factored more nicely later.
+// The draw objects of the contents of a pattern are encoded once, as template
+// objects that aren't drawn, and each pattern instance draws a range of them
+// in each of its cells. With the offsets from the prefix sum over the
+// instances, each invocation of this stage writes one draw object of the
+// expanded scene: its tag, the index of its path and the offset of its draw
+// data. A copy also gets the transform, cubics and bounding box of a new path
+// from those of its template, and the color of its cell.
 
 #import config
 #import pathtag
+#import drawtag
 #import cubic
-#import clip
 #import transform
 #import bbox
 #import bump
@@ -23,154 +22,269 @@
 var<uniform> config: Config;
 
 @group(0) @binding(1)
-var<storage> scene: array<u32>;
+var<storage, read_write> scene: array<u32>;
 
 @group(0) @binding(2)
-var<storage> pattern_inp: array<PatternInp>;
+var<storage> offsets: array<vec2<u32>>;
 
 @group(0) @binding(3)
-var<storage> clip_bbox_buf: array<vec4<f32>>;
-
-@group(0) @binding(4)
 var<storage, read_write> path_bboxes: array<PathBbox>;
 
-@group(0) @binding(5)
+@group(0) @binding(4)
 var<storage, read_write> cubics: array<Cubic>;
 
-@group(0) @binding(6)
+@group(0) @binding(5)
 var<storage, read_write> bump: BumpAllocators;
 
-@group(0) @binding(7)
-var<storage, read_write> debug: array<vec4<f32>>;
-
-let WG_SIZE = 256u;
-var<workgroup> sh_cubic_counts: array<u32, WG_SIZE>;
-
-var<private> bbox: vec4<f32>;
-var<private> to_world: Transform;
-var<private> to_pattern: Transform;
-
-fn read_pattern(pattern_base:u32, ix:u32) -> Pattern {
-    let base = pattern_base + ix * 5u;
-    let c0 = bitcast<f32>(scene[base]);
-    let c1 = bitcast<f32>(scene[base + 1u]);
-    let c2 = bitcast<f32>(scene[base + 2u]);
-    let c3 = bitcast<f32>(scene[base + 3u]);
-    let c4 = bitcast<f32>(scene[base + 4u]);
-    let start = vec2(c0, c1);
-    let box_scale = vec2(c2, c3);
-    return Pattern(start, box_scale, c4);
+#import pattern
+
+fn write_transform(transform_base: u32, ix: u32, transform: Transform) {
+    let base = transform_base + ix * 6u;
+    scene[base] = bitcast<u32>(transform.matrx.x);
+    scene[base + 1u] = bitcast<u32>(transform.matrx.y);
+    scene[base + 2u] = bitcast<u32>(transform.matrx.z);
+    scene[base + 3u] = bitcast<u32>(transform.matrx.w);
+    scene[base + 4u] = bitcast<u32>(transform.translate.x);
+    scene[base + 5u] = bitcast<u32>(transform.translate.y);
+}
+
+fn pattern_hash(x: u32) -> u32 {
+    let state = x * 747796405u + 2891336453u;
+    let word = ((state >> ((state >> 28u) + 4u)) ^ state) * 277803737u;
+    return (word >> 22u) ^ word;
+}
+
+// Random number in [0, 1) for the cell at (x, y). Each property that varies
+// uses a different k.
+fn cell_random(pattern: Pattern, x: i32, y: i32, k: u32) -> f32 {
+    let h = pattern_hash(pattern.seed ^ pattern_hash(u32(x) ^ pattern_hash(u32(y) ^ pattern_hash(k))));
+    return f32(h >> 8u) / 16777216.0;
+}
+
+// Transform that jitters the contents of the cell at (x, y) about the center
+// of the cell, in pattern space.
+fn cell_jitter(pattern: Pattern, x: i32, y: i32) -> Transform {
+    if !is_jittered(pattern) {
+        return Transform(vec4(1.0, 0.0, 0.0, 1.0), vec2(0.0));
+    }
+    let offset = vec2(2.0 * cell_random(pattern, x, y, 0u) - 1.0, 2.0 * cell_random(pattern, x, y, 1u) - 1.0) * pattern.offset_jitter;
+    let angle = (2.0 * cell_random(pattern, x, y, 2u) - 1.0) * pattern.rotation_jitter;
+    let scale = 1.0 + (2.0 * cell_random(pattern, x, y, 3u) - 1.0) * pattern.scale_jitter;
+    let matrx = vec4(cos(angle), sin(angle), -sin(angle), cos(angle)) * scale;
+    let center = 0.5 * (pattern.x_step + pattern.y_step);
+    let moved = matrx.xy * center.x + matrx.zw * center.y;
+    return Transform(matrx, center + offset - moved);
+}
+
+// Transform from pattern space to the space of the enclosing layer for the
+// cell at lattice coordinates (x, y).
+fn cell_transform(pattern: Pattern, x: i32, y: i32) -> Transform {
+    if pattern.tiling == PATTERN_TILING_PLACEMENTS {
+        let placement = read_transform(config.placement_base, pattern.placements.x + u32(x));
+        return transform_mul(transform_mul(pattern.transform, placement), cell_jitter(pattern, x, y));
+    }
+    let xs = pattern.x_step;
+    let ys = pattern.y_step;
+    let offsets = tiling_offsets(pattern);
+    let lx = f32(x) + offsets.x * f32(y & 1);
+    let ly = f32(y) * offsets.z + offsets.y * f32(x & 1);
+    var cell = Transform(vec4(1.0, 0.0, 0.0, 1.0), lx * xs + ly * ys);
+    if is_mirrored(pattern) && ((x & 1) | (y & 1)) != 0 {
+        // Flip across the center of the cell, in lattice coordinates.
+        let steps = Transform(vec4(xs, ys), vec2(0.0));
+        let fx = f32(x & 1);
+        let fy = f32(y & 1);
+        let flip = Transform(vec4(1.0 - 2.0 * fx, 0.0, 0.0, 1.0 - 2.0 * fy), vec2(fx, fy));
+        cell = transform_mul(transform_mul(transform_mul(cell, steps), flip), transform_inverse(steps));
+    }
+    return transform_mul(transform_mul(pattern.transform, cell), cell_jitter(pattern, x, y));
+}
+
+// Color of the cell at (x, y), with channels from 0 to 255 and a negative
+// alpha if the contents keep their colors. Mirrors `PatternData::cell_color`.
+fn cell_color(pattern: Pattern, x: i32, y: i32) -> vec4<f32> {
+    let start = pattern.color_stops.x;
+    let end = pattern.color_stops.y;
+    if end <= start || (pattern.colors != PATTERN_COLORS_LIST && pattern.colors != PATTERN_COLORS_GRADIENT) {
+        return vec4(-1.0);
+    }
+    let t = cell_random(pattern, x, y, 4u);
+    if pattern.colors == PATTERN_COLORS_LIST {
+        let ix = min(u32(t * f32(end - start)), end - start - 1u);
+        return unpack_stop_color(start + ix);
+    }
+    var ix = start;
+    while ix < end && stop_offset(ix) <= t {
+        ix += 1u;
+    }
+    if ix == end {
+        return unpack_stop_color(end - 1u);
+    }
+    if ix == start {
+        return unpack_stop_color(start);
+    }
+    let a = stop_offset(ix - 1u);
+    let f = (t - a) / (stop_offset(ix) - a);
+    // Rounds half away from zero like the CPU.
+    return floor(mix(unpack_stop_color(ix - 1u), unpack_stop_color(ix), f) + 0.5);
+}
+
+fn stop_offset(ix: u32) -> f32 {
+    return bitcast<f32>(scene[config.pattern_color_base + ix * 2u]);
 }
 
-fn round_down(x: f32) -> i32 {
-    return i32(floor(x));
+fn unpack_stop_color(ix: u32) -> vec4<f32> {
+    let rgba = scene[config.pattern_color_base + ix * 2u + 1u];
+    return vec4(f32(rgba >> 24u), f32((rgba >> 16u) & 0xffu), f32((rgba >> 8u) & 0xffu), f32(rgba & 0xffu));
 }
 
-fn round_up(x: f32) -> i32 {
-    return i32(ceil(x));
+// Replaces a premultiplied color with the color of a cell, keeping its alpha.
+// Mirrors `DrawColor::recolor`.
+fn recolor(rgba: u32, color: vec4<f32>) -> u32 {
+    let c = vec4<u32>(color);
+    let a = (c.a * (rgba & 0xffu) + 127u) / 255u;
+    let rgb = (c.rgb * a + 127u) / 255u;
+    return (rgb.r << 24u) | (rgb.g << 16u) | (rgb.b << 8u) | a;
 }
 
-fn compare_bbox(point: vec2<f32>){
-    let p = transform_apply(to_pattern, point);
-    bbox.x = min(p.x, bbox.x);
-    bbox.y = min(p.y, bbox.y);
-    bbox.z = max(p.x, bbox.z);
-    bbox.w = max(p.y, bbox.w);
+fn is_segment(tag_ix: u32) -> bool {
+    let tag_word = scene[config.pathtag_base + (tag_ix >> 2u)];
+    let tag_byte = (tag_word >> ((tag_ix & 3u) * 8u)) & 0xffu;
+    return (tag_byte & PATH_TAG_SEG_TYPE) != 0u;
+}
+
+// Finds the instance that draws the expanded draw object `ix`: the last one
+// whose offset isn't past it.
+fn find_instance(ix: u32) -> u32 {
+    var lo = 0u;
+    var hi = config.n_pattern_instances;
+    while hi - lo > 1u {
+        let mid = (lo + hi) >> 1u;
+        if offsets[mid].x <= ix {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+    return lo;
 }
 
-fn apply_offset(p: vec2<f32>, offset: vec2<f32>) -> vec2<f32>{
-    var pattern = p + offset;
-    pattern = transform_apply(to_world, pattern);
-    return pattern;
+// Writes the tag of the expanded draw object `ix`, and the index of its path
+// and the offset of its draw data, which override those of the draw monoid.
+fn write_draw(ix: u32, tag: u32, path_ix: u32, scene_offset: u32) {
+    scene[config.drawtag_base + ix] = tag;
+    let slot = config.pattern_slot_base + ix * 3u;
+    scene[slot] = path_ix;
+    scene[slot + 1u] = scene_offset;
 }
 
 @compute @workgroup_size(256)
 fn main(
     @builtin(global_invocation_id) global_id: vec3<u32>,
-    @builtin(local_invocation_id) local_id: vec3<u32>,
 ) {
     let ix = global_id.x;
-    //if ix < 0u {
-    if ix < (config.n_patterns >> 1u){
-        bbox = vec4(1e9, 1e9, -1e9, -1e9);
-        let pattern = pattern_inp[ix];
-        let clip_bbox = clip_bbox_buf[pattern.clip_ix];
-        let pattern_des = read_pattern(config.pattern_base, ix);
-        let sin_theta = sin(pattern_des.rotation);
-        let cos_theta = cos(pattern_des.rotation);
-        
-        to_world = Transform(vec4(cos_theta, sin_theta, -1.0 * sin_theta, cos_theta), vec2(0.0, 0.0));
-        let rotate = vec4(cos_theta, -1.0 * sin_theta, sin_theta, cos_theta);
-        let translated = rotate.xy * -1.0 * pattern_des.start.x + rotate.zw * -1.0 * pattern_des.start.y;
-        to_pattern = Transform(rotate, translated);
-
-        compare_bbox(clip_bbox.xy);
-        compare_bbox(clip_bbox.xw);
-        compare_bbox(clip_bbox.zy);
-        compare_bbox(clip_bbox.zw);
-
-        debug[local_id.x] = bbox;
-
-        let SX = (1.0 / pattern_des.box_scale.x);
-        let SY = (1.0 / pattern_des.box_scale.y);
-        let min_x = round_down(bbox.x * SX);
-        let min_y = round_down(bbox.y * SY);
-        let max_x = round_up(bbox.z * SX);
-        let max_y = round_up(bbox.w * SX);
-
-        let pox_x = clip_bbox.x + pattern_des.start.x;
-        let pox_y = clip_bbox.y + pattern_des.start.y;
-        let delta_x = pattern_des.box_scale.x;
-        let delta_y = pattern_des.box_scale.y;
-
-        let previous_cubic_count = select(0u, path_bboxes[pattern.begin_path_ix - 1u].last_tag_ix, pattern.begin_path_ix > 0u);
-        let finish_cubic_count = select(0u, path_bboxes[pattern.end_path_ix - 1u].last_tag_ix, pattern.end_path_ix > 0u);
-        var cubic_count = (finish_cubic_count - previous_cubic_count) * u32(max_x - min_x) * u32(max_y - min_y);
-        sh_cubic_counts[local_id.x] = cubic_count;
-        for (var i = 0u; i < firstTrailingBit(WG_SIZE); i += 1u) {
-            workgroupBarrier();
-            if local_id.x >= (1u << i) {
-                cubic_count += sh_cubic_counts[local_id.x - (1u << i)];
-            }
-            workgroupBarrier();
-        }
-        if (local_id.x == 0u){
-            let ix = min((config.n_patterns >> 1u) - 1u, WG_SIZE - 1u);
-            bump.pattern_cubic = sh_cubic_counts[ix];
-        }
-        let cubic_offset = 128u + select(0u, sh_cubic_counts[local_id.x - 1u], local_id.x > 1u);
-        var local_count = 0u;
-        for (var i = pattern.begin_path_ix; i < pattern.end_path_ix; i += 1u) {
-            let out = &path_bboxes[i];
-            (*out).x0 = i32(clip_bbox.x);
-            (*out).y0 = i32(clip_bbox.y);
-            (*out).x1 = i32(clip_bbox.z);
-            (*out).y1 = i32(clip_bbox.w);
-        }
-        var local_offset = 0u;
-        for(var ix = min_x; ix < max_x; ix += 1){
-            for(var iy = min_y; iy < max_y; iy += 1){
-                let pivot_x = pox_x + f32(ix) * delta_x;
-                let pivot_y = pox_y +  f32(iy) * delta_y;
-                let pivot = vec2(pivot_x, pivot_y);
-                for (var i = pattern.begin_path_ix; i < pattern.end_path_ix; i += 1u) {
-                    let cubic_start = select(0u, path_bboxes[i - 1u].last_tag_ix, i > 0u);
-                    let cubic_end = path_bboxes[i].last_tag_ix;
-                    for(var cubic_ix = cubic_start; cubic_ix < cubic_end; cubic_ix += 1u){
-                        var instance = cubics[cubic_ix];
-                        instance.p0 = apply_offset(instance.p0, pivot);
-                        instance.p1 = apply_offset(instance.p1, pivot);
-                        instance.p2 = apply_offset(instance.p2, pivot);
-                        instance.p3 = apply_offset(instance.p3, pivot);
-                        if(ix == max_x - 1 && iy == max_y - 1){
-                            cubics[cubic_ix] = instance;
-                        }else{
-                            cubics[cubic_offset + local_offset] = instance;
-                            local_offset += 1u;
-                        }
-                    }
-                }
-            }
+    if ix >= config.n_drawobj {
+        return;
+    }
+    // Draw objects past the end, or of instances that don't fit, are no-ops
+    // with an empty path.
+    let dst = config.pattern_path_base + ix;
+    let instance_ix = find_instance(ix);
+    let end = offsets[instance_ix + 1u];
+    if ix >= end.x || end.x > config.n_drawobj || end.y > config.bin_data_start {
+        write_draw(ix, DRAWTAG_NOP, dst, 0u);
+        return;
+    }
+    let instance = read_instance(instance_ix);
+    let n_objects = instance.objects.y - instance.objects.x;
+    let local_ix = ix - offsets[instance_ix].x;
+    let cell_ix = local_ix / n_objects;
+    let source = instance.objects.x + local_ix % n_objects;
+    let source_base = config.draw_source_base + source * 2u;
+    let tag = scene[source_base];
+    let source_offset = scene[source_base + 1u];
+    if instance.pattern == PATTERN_NONE {
+        write_draw(ix, tag, source, source_offset);
+        return;
+    }
+
+    // Transform from the space of the contents to the enclosing layer, and the
+    // color of the innermost pattern that has one.
+    let pattern = instance_pattern(instance);
+    var cell_xy = instance.cell;
+    if (instance.flags & PATTERN_SINGLE_CELL) == 0u {
+        let range = cell_range(pattern, instance.bbox, instance.content_bbox);
+        let width = u32(range.z - range.x);
+        cell_xy = range.xy + vec2(i32(cell_ix % width), i32(cell_ix / width));
+    }
+    let cell = cell_transform(pattern, cell_xy.x, cell_xy.y);
+    var color = cell_color(pattern, cell_xy.x, cell_xy.y);
+    if color.a < 0.0 && (instance.flags & PATTERN_HAS_COLOR) != 0u {
+        let rgba = instance.color;
+        color = vec4(f32(rgba >> 24u), f32((rgba >> 16u) & 0xffu), f32((rgba >> 8u) & 0xffu), f32(rgba & 0xffu));
+    }
+
+    let src = path_bboxes[source];
+    let trans_ix = config.pattern_transform_base + ix;
+    let transform = transform_mul(cell, read_transform(config.transform_base, src.trans_ix));
+    write_transform(config.transform_base, trans_ix, transform);
+    path_bboxes[dst].trans_ix = trans_ix;
+    path_bboxes[dst].linewidth = src.linewidth;
+    var scene_offset = source_offset;
+    if color.a >= 0.0 && tag == DRAWTAG_FILL_COLOR {
+        // The color of a copy is stored after its slot.
+        let slot = config.pattern_slot_base + ix * 3u;
+        scene[slot + 2u] = recolor(scene[config.drawdata_base + source_offset], color);
+        scene_offset = slot + 2u - config.drawdata_base;
+    }
+    write_draw(ix, tag, dst, scene_offset);
+
+    // The cubics of a path are stored at the indices of its segment tags, which
+    // follow the tag of the previous path.
+    let tag_start = select(0u, path_bboxes[source - 1u].last_tag_ix + 1u, source > 0u);
+    let tag_end = src.last_tag_ix;
+    var n_cubics = 0u;
+    for (var tag_ix = tag_start; tag_ix < tag_end; tag_ix += 1u) {
+        n_cubics += u32(is_segment(tag_ix));
+    }
+    if n_cubics == 0u {
+        return;
+    }
+    let cubic_offset = atomicAdd(&bump.pattern_cubic, n_cubics);
+    if n_cubics > config.pattern_cubics_size || cubic_offset > config.pattern_cubics_size - n_cubics {
+        // Report the overflow and draw nothing rather than writing past the
+        // end of the buffer.
+        atomicOr(&bump.failed, STAGE_PATTERN);
+        return;
+    }
+    // Mirrors the stroke expansion in pathseg.
+    var stroke = vec2(0.0);
+    if src.linewidth >= 0.0 {
+        stroke = 0.5 * src.linewidth * vec2(length(transform.matrx.xz), length(transform.matrx.yw));
+    }
+    var out_ix = config.pattern_cubics_base + cubic_offset;
+    var bbox = vec4(1e9, 1e9, -1e9, -1e9);
+    for (var tag_ix = tag_start; tag_ix < tag_end; tag_ix += 1u) {
+        if is_segment(tag_ix) {
+            var c = cubics[tag_ix];
+            c.p0 = transform_apply(cell, c.p0);
+            c.p1 = transform_apply(cell, c.p1);
+            c.p2 = transform_apply(cell, c.p2);
+            c.p3 = transform_apply(cell, c.p3);
+            c.stroke = stroke;
+            c.path_ix = dst;
+            cubics[out_ix] = c;
+            out_ix += 1u;
+            let lo = min(min(c.p0, c.p1), min(c.p2, c.p3));
+            let hi = max(max(c.p0, c.p1), max(c.p2, c.p3));
+            bbox = vec4(min(bbox.xy, lo), max(bbox.zw, hi));
         }
     }
+    bbox += vec4(-stroke, stroke);
+    if bbox.z > bbox.x || bbox.w > bbox.y {
+        path_bboxes[dst].x0 = i32(floor(bbox.x));
+        path_bboxes[dst].y0 = i32(floor(bbox.y));
+        path_bboxes[dst].x1 = i32(ceil(bbox.z));
+        path_bboxes[dst].y1 = i32(ceil(bbox.w));
+    }
 }
@@ -0,0 +1,90 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT OR Unlicense
+
+// Finish the prefix sum of the sizes of the pattern instances, which gives the
+// offsets of their copies in the expanded draw objects and draw info.
+
+#import config
+#import bump
+#import transform
+
+@group(0) @binding(0)
+var<uniform> config: Config;
+
+@group(0) @binding(1)
+var<storage> scene: array<u32>;
+
+@group(0) @binding(2)
+var<storage> reduced: array<vec2<u32>>;
+
+@group(0) @binding(3)
+var<storage, read_write> offsets: array<vec2<u32>>;
+
+@group(0) @binding(4)
+var<storage, read_write> bump: BumpAllocators;
+
+#import pattern
+
+let WG_SIZE = 256u;
+
+var<workgroup> sh_scratch: array<vec2<u32>, WG_SIZE>;
+
+@compute @workgroup_size(256)
+fn main(
+    @builtin(global_invocation_id) global_id: vec3<u32>,
+    @builtin(local_invocation_id) local_id: vec3<u32>,
+    @builtin(workgroup_id) wg_id: vec3<u32>,
+) {
+    let ix = global_id.x;
+    // Reduce prefix of workgroups up to this one. Each invocation sums a
+    // strided subset, so that any number of workgroups is supported.
+    var agg = vec2(0u);
+    for (var i = local_id.x; i < wg_id.x; i += WG_SIZE) {
+        agg = combine_instance_sizes(agg, reduced[i]);
+    }
+    sh_scratch[local_id.x] = agg;
+    for (var i = 0u; i < firstTrailingBit(WG_SIZE); i += 1u) {
+        workgroupBarrier();
+        if local_id.x + (1u << i) < WG_SIZE {
+            let other = sh_scratch[local_id.x + (1u << i)];
+            agg = combine_instance_sizes(agg, other);
+        }
+        workgroupBarrier();
+        sh_scratch[local_id.x] = agg;
+    }
+    workgroupBarrier();
+    var prefix = sh_scratch[0];
+    workgroupBarrier();
+    var size = vec2(0u);
+    if ix < config.n_pattern_instances {
+        size = instance_sizes(read_instance(ix));
+    }
+    agg = size;
+    sh_scratch[local_id.x] = agg;
+    for (var i = 0u; i < firstTrailingBit(WG_SIZE); i += 1u) {
+        workgroupBarrier();
+        if local_id.x >= 1u << i {
+            let other = sh_scratch[local_id.x - (1u << i)];
+            agg = combine_instance_sizes(agg, other);
+        }
+        workgroupBarrier();
+        sh_scratch[local_id.x] = agg;
+    }
+    workgroupBarrier();
+    if local_id.x > 0u {
+        prefix = combine_instance_sizes(prefix, sh_scratch[local_id.x - 1u]);
+    }
+    // prefix now contains the exclusive prefix sum of the sizes
+    if ix < config.n_pattern_instances {
+        offsets[ix] = prefix;
+    }
+    if ix + 1u == config.n_pattern_instances {
+        let total = combine_instance_sizes(prefix, size);
+        offsets[ix + 1u] = total;
+        atomicStore(&bump.pattern_draw, total.x);
+        atomicStore(&bump.pattern_info, total.y);
+        if total.x > config.n_drawobj || total.y > config.bin_data_start {
+            // The instances that don't fit aren't expanded.
+            atomicOr(&bump.failed, STAGE_PATTERN);
+        }
+    }
+}
@@ -0,0 +1,48 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT OR Unlicense
+
+// Reduce the number of draw objects and the size of the draw info that the
+// pattern instances of each workgroup expand to.
+
+#import config
+#import transform
+
+@group(0) @binding(0)
+var<uniform> config: Config;
+
+@group(0) @binding(1)
+var<storage> scene: array<u32>;
+
+@group(0) @binding(2)
+var<storage, read_write> reduced: array<vec2<u32>>;
+
+#import pattern
+
+let WG_SIZE = 256u;
+
+var<workgroup> sh_scratch: array<vec2<u32>, WG_SIZE>;
+
+@compute @workgroup_size(256)
+fn main(
+    @builtin(global_invocation_id) global_id: vec3<u32>,
+    @builtin(local_invocation_id) local_id: vec3<u32>,
+    @builtin(workgroup_id) wg_id: vec3<u32>,
+) {
+    let ix = global_id.x;
+    var agg = vec2(0u);
+    if ix < config.n_pattern_instances {
+        agg = instance_sizes(read_instance(ix));
+    }
+    sh_scratch[local_id.x] = agg;
+    for (var i = 0u; i < firstTrailingBit(WG_SIZE); i += 1u) {
+        workgroupBarrier();
+        if local_id.x + (1u << i) < WG_SIZE {
+            let other = sh_scratch[local_id.x + (1u << i)];
+            agg = combine_instance_sizes(agg, other);
+        }
+        workgroupBarrier();
+        sh_scratch[local_id.x] = agg;
+    }
+    if local_id.x == 0u {
+        reduced[wg_id.x] = agg;
+    }
+}
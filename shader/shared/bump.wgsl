@@ -5,7 +5,7 @@ let STAGE_BINNING: u32 = 0x1u;
 let STAGE_TILE_ALLOC: u32 = 0x2u;
 let STAGE_PATH_COARSE: u32 = 0x4u;
 let STAGE_COARSE: u32 = 0x8u;
-let PATTERN_CUBMIC_START:u32 = 0x80u;
+let STAGE_PATTERN: u32 = 0x10u;
 
 // This must be kept in sync with the struct in src/render.rs
 struct BumpAllocators {
@@ -17,4 +17,6 @@ struct BumpAllocators {
     segments: atomic<u32>,
     blend: atomic<u32>,
     pattern_cubic: atomic<u32>,
+    pattern_draw: atomic<u32>,
+    pattern_info: atomic<u32>,
 }
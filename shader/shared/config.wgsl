@@ -17,6 +17,10 @@ struct Config {
     n_path: u32,
     n_clip: u32,
     n_patterns: u32,
+    n_pattern_instances: u32,
+    // Paths at the start of the scene that hold the geometry of pattern
+    // instances and aren't drawn.
+    n_template_paths: u32,
 
     // To reduce the number of bindings, info and bin data are combined
     // into one buffer.
@@ -33,13 +37,38 @@ struct Config {
     linewidth_base: u32,
 
     pattern_base: u32,
+    placement_base: u32,
+    pattern_color_base: u32,
+    pattern_instance_base: u32,
+    // Tag and draw data offset of each draw object, for pattern instances
+    draw_source_base: u32,
     // Sizes of bump allocated buffers (in element size units)
     binning_size: u32,
     tiles_size: u32,
     segments_size: u32,    
     ptcl_size: u32,
+
+    // Start and size of the pattern instances in the cubic buffer
+    pattern_cubics_base: u32,
+    pattern_cubics_size: u32,
+
+    // Draw objects expanded from the pattern instances: the start of their
+    // slots in the scene buffer, of their transforms (in transform units) and
+    // of their paths, which follow the paths of the scene
+    pattern_slot_base: u32,
+    pattern_transform_base: u32,
+    pattern_path_base: u32,
+
+    // Color space of the output, one of the COLOR_SPACE constants
+    target_color_space: u32,
+    // Color space in which colors are blended, one of the COLOR_SPACE constants
+    blend_color_space: u32,
 }
 
+// Must be kept in sync with ColorSpace in crates/encoding/src/config.rs
+let COLOR_SPACE_SRGB = 0u;
+let COLOR_SPACE_LINEAR_SRGB = 1u;
+
 // Geometry of tiles and bins
 
 let TILE_WIDTH = 16u;
@@ -21,7 +21,9 @@ let DRAWTAG_NOP = 0u;
 let DRAWTAG_FILL_COLOR = 0x44u;
 let DRAWTAG_FILL_LIN_GRADIENT = 0x114u;
 let DRAWTAG_FILL_RAD_GRADIENT = 0x29cu;
-let DRAWTAG_FILL_IMAGE = 0x248u;
+let DRAWTAG_FILL_SWEEP_GRADIENT = 0x254u;
+let DRAWTAG_FILL_IMAGE = 0x28cu;
+let DRAWTAG_BLUR_RECT = 0x2d4u;
 let DRAWTAG_BEGIN_CLIP = 0x9u;
 let DRAWTAG_END_CLIP = 0x21u;
 let DRAWTAG_BEGIN_PATTERN = 0x400u;
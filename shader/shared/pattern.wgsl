@@ -0,0 +1,243 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT OR Unlicense
+
+// Pattern instances and the lattices of their patterns, used by the stages
+// that count the draw objects of each instance and expand them.
+//
+// This file depends on transform.wgsl and on the following global
+// declarations:
+//    * `scene`: array<u32>
+//    * `config`: Config (see config.wgsl)
+
+// This must be kept in sync with `PatternInstance` in the encoding crate.
+struct PatternInstance {
+    // Index of the pattern, or PATTERN_NONE for draw objects outside of
+    // patterns, which are drawn once.
+    pattern: u32,
+    flags: u32,
+    // Range of the draw objects of the scene that are drawn.
+    objects: vec2<u32>,
+    // Size of the draw info of the draw objects, in u32s.
+    info: u32,
+    // Transform and color of the enclosing cell of a nested pattern.
+    transform: Transform,
+    color: u32,
+    // The only cell drawn with PATTERN_SINGLE_CELL.
+    cell: vec2<i32>,
+    // Bounds of the cells in the enclosing layer, and of the contents in
+    // pattern space.
+    bbox: vec4<f32>,
+    content_bbox: vec4<f32>,
+}
+
+let PATTERN_INSTANCE_SIZE = 22u;
+let PATTERN_NONE = 0xffffffffu;
+let PATTERN_SINGLE_CELL = 1u;
+let PATTERN_HAS_COLOR = 2u;
+
+// Largest cell coordinate of a lattice, see `MAX_CELLS` in the encoding crate.
+let PATTERN_MAX_CELLS = 1048576.0;
+
+fn read_transform(transform_base: u32, ix: u32) -> Transform {
+    let base = transform_base + ix * 6u;
+    let c0 = bitcast<f32>(scene[base]);
+    let c1 = bitcast<f32>(scene[base + 1u]);
+    let c2 = bitcast<f32>(scene[base + 2u]);
+    let c3 = bitcast<f32>(scene[base + 3u]);
+    let c4 = bitcast<f32>(scene[base + 4u]);
+    let c5 = bitcast<f32>(scene[base + 5u]);
+    return Transform(vec4(c0, c1, c2, c3), vec2(c4, c5));
+}
+
+fn read_bbox(base: u32) -> vec4<f32> {
+    return bitcast<vec4<f32>>(vec4(scene[base], scene[base + 1u], scene[base + 2u], scene[base + 3u]));
+}
+
+fn read_instance(ix: u32) -> PatternInstance {
+    let base = config.pattern_instance_base + ix * PATTERN_INSTANCE_SIZE;
+    let objects = vec2(scene[base + 2u], scene[base + 3u]);
+    let transform = read_transform(base + 5u, 0u);
+    let cell = vec2(bitcast<i32>(scene[base + 12u]), bitcast<i32>(scene[base + 13u]));
+    return PatternInstance(
+        scene[base],
+        scene[base + 1u],
+        objects,
+        scene[base + 4u],
+        transform,
+        scene[base + 11u],
+        cell,
+        read_bbox(base + 14u),
+        read_bbox(base + 18u)
+    );
+}
+
+fn read_pattern(ix: u32) -> Pattern {
+    let base = config.pattern_base + ix * PATTERN_SIZE;
+    let transform = read_transform(base, 0u);
+    let x_step = bitcast<vec2<f32>>(vec2(scene[base + 6u], scene[base + 7u]));
+    let y_step = bitcast<vec2<f32>>(vec2(scene[base + 8u], scene[base + 9u]));
+    let extend = scene[base + 10u];
+    let tiling = scene[base + 11u];
+    let seed = scene[base + 12u];
+    let offset_jitter = bitcast<vec2<f32>>(vec2(scene[base + 13u], scene[base + 14u]));
+    let rotation_jitter = bitcast<f32>(scene[base + 15u]);
+    let scale_jitter = bitcast<f32>(scene[base + 16u]);
+    let placements = vec2(scene[base + 17u], scene[base + 18u]);
+    let colors = scene[base + 19u];
+    let color_stops = vec2(scene[base + 20u], scene[base + 21u]);
+    return Pattern(
+        transform,
+        x_step,
+        y_step,
+        extend,
+        tiling,
+        seed,
+        offset_jitter,
+        rotation_jitter,
+        scale_jitter,
+        placements,
+        colors,
+        color_stops
+    );
+}
+
+// Returns the pattern of an instance, with its lattice mapped by the transform
+// of the enclosing cell.
+fn instance_pattern(instance: PatternInstance) -> Pattern {
+    var pattern = read_pattern(instance.pattern);
+    pattern.transform = transform_mul(instance.transform, pattern.transform);
+    return pattern;
+}
+
+// Linear transform from lattice coordinates to pattern space.
+fn pattern_steps(pattern: Pattern) -> Transform {
+    return Transform(vec4(pattern.x_step, pattern.y_step), vec2(0.0));
+}
+
+// Offsets of odd rows and odd columns, and the distance between rows, in
+// lattice coordinates.
+fn tiling_offsets(pattern: Pattern) -> vec3<f32> {
+    switch pattern.tiling {
+        // PATTERN_TILING_BRICK
+        case 1u: {
+            return vec3(0.5, 0.0, 1.0);
+        }
+        // PATTERN_TILING_HALF_DROP
+        case 2u: {
+            return vec3(0.0, 0.5, 1.0);
+        }
+        // PATTERN_TILING_HEX
+        case 4u: {
+            return vec3(0.5, 0.0, 0.75);
+        }
+        default: {
+            return vec3(0.0, 0.0, 1.0);
+        }
+    }
+}
+
+fn is_mirrored(pattern: Pattern) -> bool {
+    return pattern.extend == PATTERN_EXTEND_REFLECT || pattern.tiling == PATTERN_TILING_MIRROR;
+}
+
+fn is_jittered(pattern: Pattern) -> bool {
+    return any(pattern.offset_jitter != vec2(0.0)) || pattern.rotation_jitter != 0.0 || pattern.scale_jitter != 0.0;
+}
+
+fn transformed_bbox(transform: Transform, bbox: vec4<f32>) -> vec4<f32> {
+    let p0 = transform_apply(transform, bbox.xy);
+    let p1 = transform_apply(transform, bbox.zy);
+    let p2 = transform_apply(transform, bbox.xw);
+    let p3 = transform_apply(transform, bbox.zw);
+    return vec4(min(min(p0, p1), min(p2, p3)), max(max(p0, p1), max(p2, p3)));
+}
+
+// Bounds of the contents of any cell, including jitter. Mirrors
+// `PatternData::jittered_bbox`.
+fn jittered_bbox(pattern: Pattern, content_bbox: vec4<f32>) -> vec4<f32> {
+    if !is_jittered(pattern) {
+        return content_bbox;
+    }
+    var bbox = content_bbox;
+    if pattern.rotation_jitter != 0.0 || pattern.scale_jitter != 0.0 {
+        // Rotating and scaling about the center stays within a circle.
+        let center = transform_apply(pattern_steps(pattern), vec2(0.5));
+        let d = max(abs(content_bbox.xy - center), abs(content_bbox.zw - center));
+        let radius = length(d) * (1.0 + abs(pattern.scale_jitter));
+        bbox = vec4(center - radius, center + radius);
+    }
+    let offset = abs(pattern.offset_jitter);
+    return vec4(bbox.xy - offset, bbox.zw + offset);
+}
+
+// Rounds a bound of a cell range, mapping NaN to 0 like a cast on the CPU.
+fn cell_bound(x: f32) -> i32 {
+    return i32(clamp(select(0.0, x, x == x), -PATTERN_MAX_CELLS, PATTERN_MAX_CELLS));
+}
+
+// Range of cells [x0, y0, x1, y1) whose contents may intersect `bbox`.
+// Mirrors `PatternData::cell_range`.
+fn cell_range(pattern: Pattern, bbox: vec4<f32>, content_bbox: vec4<f32>) -> vec4<i32> {
+    if !(bbox.x < bbox.z && bbox.y < bbox.w && content_bbox.x <= content_bbox.z && content_bbox.y <= content_bbox.w) {
+        return vec4(0);
+    }
+    if pattern.tiling == PATTERN_TILING_PLACEMENTS {
+        // Every placement is a cell.
+        let n = select(0u, pattern.placements.y - pattern.placements.x, pattern.placements.y > pattern.placements.x);
+        return vec4(0, 0, i32(min(n, 0x7fffffffu)), i32(n > 0u));
+    }
+    if pattern.extend == PATTERN_EXTEND_PAD {
+        return vec4(0, 0, 1, 1);
+    }
+    let steps = pattern_steps(pattern);
+    let lattice = transform_mul(pattern.transform, steps);
+    let m = lattice.matrx;
+    if abs(m.x * m.w - m.y * m.z) < 1e-9 {
+        return vec4(0);
+    }
+    let target_bbox = transformed_bbox(transform_inverse(lattice), bbox);
+    var content = transformed_bbox(transform_inverse(steps), jittered_bbox(pattern, content_bbox));
+    if is_mirrored(pattern) {
+        // Mirrored cells cover the reflection of the contents in the unit cell.
+        content = vec4(min(content.xy, 1.0 - content.zw), max(content.zw, 1.0 - content.xy));
+    }
+    // Offset rows and columns shift the contents by up to the offset.
+    let offsets = tiling_offsets(pattern);
+    return vec4(
+        cell_bound(floor(target_bbox.x - content.z - offsets.x)),
+        cell_bound(floor((target_bbox.y - content.w - offsets.y) / offsets.z)),
+        cell_bound(ceil(target_bbox.z - content.x)),
+        cell_bound(ceil((target_bbox.w - content.y) / offsets.z))
+    );
+}
+
+// Sizes add up to at most u32::MAX rather than wrapping, so that an instance
+// that doesn't fit can't appear to.
+fn saturating_add(a: u32, b: u32) -> u32 {
+    return select(a + b, 0xffffffffu, a > 0xffffffffu - b);
+}
+
+fn saturating_mul(a: u32, b: u32) -> u32 {
+    return select(a * b, 0xffffffffu, b != 0u && a > 0xffffffffu / b);
+}
+
+// Returns the range of cells an instance is drawn in, in row-major order.
+fn instance_cells(instance: PatternInstance) -> vec4<i32> {
+    if instance.pattern == PATTERN_NONE || (instance.flags & PATTERN_SINGLE_CELL) != 0u {
+        return vec4(instance.cell, instance.cell + 1);
+    }
+    return cell_range(instance_pattern(instance), instance.bbox, instance.content_bbox);
+}
+
+// Number of draw objects an instance expands to, and the size of their draw
+// info. The number of cells mirrors `PatternInstance::n_cells`.
+fn instance_sizes(instance: PatternInstance) -> vec2<u32> {
+    let range = instance_cells(instance);
+    let size = vec2<u32>(max(range.zw - range.xy, vec2(0)));
+    let n_cells = saturating_mul(size.x, size.y);
+    let n_objects = instance.objects.y - min(instance.objects.x, instance.objects.y);
+    return vec2(saturating_mul(n_cells, n_objects), saturating_mul(n_cells, instance.info));
+}
+
+fn combine_instance_sizes(a: vec2<u32>, b: vec2<u32>) -> vec2<u32> {
+    return vec2(saturating_add(a.x, b.x), saturating_add(a.y, b.y));
+}
@@ -20,6 +20,8 @@ let CMD_IMAGE = 8u;
 let CMD_BEGIN_CLIP = 9u;
 let CMD_END_CLIP = 10u;
 let CMD_JUMP = 11u;
+let CMD_SWEEP_GRAD = 12u;
+let CMD_BLUR_RECT = 13u;
 
 // The individual PTCL structs are written here, but read/write is by
 // hand in the relevant shaders
@@ -61,11 +63,36 @@ struct CmdRadGrad {
     flags: u32,
 }
 
+struct CmdSweepGrad {
+    index: u32,
+    extend_mode: u32,
+    matrx: vec4<f32>,
+    xlat: vec2<f32>,
+    t0: f32,
+    t1: f32,
+}
+
 struct CmdImage {
     matrx: vec4<f32>,
     xlat: vec2<f32>,
     atlas_offset: vec2<f32>,
+    atlas_page: u32,
     extents: vec2<f32>,
+    x_extend: u32,
+    y_extend: u32,
+    quality: u32,
+    from_layer: bool,
+    alpha: f32,
+}
+
+struct CmdBlurRect {
+    rgba_color: u32,
+    matrx: vec4<f32>,
+    xlat: vec2<f32>,
+    width: f32,
+    height: f32,
+    radius: f32,
+    std_dev: f32,
 }
 
 struct CmdEndClip {
@@ -2,17 +2,47 @@
 
 // Helpers for working with transforms.
 
-struct Pattern{
-    start: vec2<f32>,
-    box_scale: vec2<f32>,
-    rotation: f32,
-}
-
 struct Transform {
     matrx: vec4<f32>,
     translate: vec2<f32>,
 }
 
+// Lattice of a pattern, see `PatternData` in the encoding crate.
+struct Pattern {
+    transform: Transform,
+    x_step: vec2<f32>,
+    y_step: vec2<f32>,
+    extend: u32,
+    tiling: u32,
+    seed: u32,
+    offset_jitter: vec2<f32>,
+    rotation_jitter: f32,
+    scale_jitter: f32,
+    placements: vec2<u32>,
+    colors: u32,
+    color_stops: vec2<u32>,
+}
+
+// Size of a pattern in the scene, in u32s. The overflow that follows the
+// fields above is only used on the CPU, which encodes the clip of each cell
+// with its copy of the contents.
+let PATTERN_SIZE = 23u;
+
+let PATTERN_EXTEND_PAD = 0u;
+let PATTERN_EXTEND_REPEAT = 1u;
+let PATTERN_EXTEND_REFLECT = 2u;
+
+let PATTERN_TILING_GRID = 0u;
+let PATTERN_TILING_BRICK = 1u;
+let PATTERN_TILING_HALF_DROP = 2u;
+let PATTERN_TILING_MIRROR = 3u;
+let PATTERN_TILING_HEX = 4u;
+let PATTERN_TILING_PLACEMENTS = 5u;
+
+let PATTERN_COLORS_NONE = 0u;
+let PATTERN_COLORS_LIST = 1u;
+let PATTERN_COLORS_GRADIENT = 2u;
+
 fn transform_apply(transform: Transform, p: vec2<f32>) -> vec2<f32> {
     return transform.matrx.xy * p.x + transform.matrx.zw * p.y + transform.translate;
 }
@@ -4,25 +4,23 @@
 
 #import config
 #import bump
-#import drawtag
 #import tile
 
 @group(0) @binding(0)
 var<uniform> config: Config;
 
+// Bounding boxes indexed by path, which are empty for the dummy paths of
+// clip ends.
 @group(0) @binding(1)
-var<storage> scene: array<u32>;
-
-@group(0) @binding(2)
 var<storage> draw_bboxes: array<vec4<f32>>;
 
-@group(0) @binding(3)
+@group(0) @binding(2)
 var<storage, read_write> bump: BumpAllocators;
 
-@group(0) @binding(4)
+@group(0) @binding(3)
 var<storage, read_write> paths: array<Path>;
 
-@group(0) @binding(5)
+@group(0) @binding(4)
 var<storage, read_write> tiles: array<Tile>;
 
 let WG_SIZE = 256u;
@@ -51,18 +49,16 @@ fn main(
     let SX = 1.0 / f32(TILE_WIDTH);
     let SY = 1.0 / f32(TILE_HEIGHT);
 
-    let drawobj_ix = global_id.x;
-    var drawtag = DRAWTAG_NOP;
-    if drawobj_ix < config.n_drawobj {
-        drawtag = scene[config.drawtag_base + drawobj_ix];
-    }
+    // Pattern commands are draw objects without a path, so this runs over
+    // paths rather than draw objects.
+    let path_ix = global_id.x;
     //range in tile
     var x0 = 0;
     var y0 = 0;
     var x1 = 0;
     var y1 = 0;
-    if drawtag != DRAWTAG_NOP && drawtag != DRAWTAG_END_CLIP {
-        let bbox = draw_bboxes[drawobj_ix];
+    if path_ix < config.n_path {
+        let bbox = draw_bboxes[path_ix];
 
         // Don't round up the bottom-right corner of the bbox if the area is zero and leave the
         // coordinates at 0. This will make `tile_count` zero as the shape is clipped out.
@@ -95,18 +91,18 @@ fn main(
             offset = 0u;
             atomicOr(&bump.failed, STAGE_TILE_ALLOC);
         }
-        paths[drawobj_ix].tiles = offset;
+        paths[path_ix].tiles = offset;
     }    
     // Using storage barriers is a workaround for what appears to be a miscompilation
     // when a normal workgroup-shared variable is used to broadcast the value.
     storageBarrier();
-    let tile_offset = paths[drawobj_ix | (WG_SIZE - 1u)].tiles;
+    let tile_offset = paths[path_ix | (WG_SIZE - 1u)].tiles;
     storageBarrier();
-    if drawobj_ix < config.n_drawobj {
+    if path_ix < config.n_path {
         let tile_subix = select(0u, sh_tile_count[local_id.x - 1u], local_id.x > 0u);
         let bbox = vec4(ux0, uy0, ux1, uy1);
         let path = Path(bbox, tile_offset + tile_subix);
-        paths[drawobj_ix] = path;
+        paths[path_ix] = path;
     }
 
     // zero allocated memory
@@ -0,0 +1,749 @@
+// Copyright 2023 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//
+// Also licensed under MIT license, at your choice.
+
+//! Execution of recordings on the CPU.
+//!
+//! This mirrors [`Engine`](crate::engine::Engine), but runs each dispatch
+//! through a Rust port of the corresponding shader instead of wgpu.
+
+use std::{
+    cell::{Ref, RefCell, RefMut},
+    collections::{HashMap, HashSet},
+};
+
+use bytemuck::Pod;
+
+use crate::engine::{BufProxy, Command, Error, Id, ImageProxy, Recording, ResourceProxy, ShaderId};
+
+/// Signature of a CPU shader.
+///
+/// The first argument is the number of workgroups of the dispatch. Binding a
+/// resource of the wrong type to a shader is an error.
+pub type CpuShaderType = fn((u32, u32, u32), &[CpuBinding]) -> Result<(), Error>;
+
+/// An RGBA8 image living in CPU memory.
+///
+/// Each pixel is packed with red in the least significant byte, matching the
+/// in-memory layout of `Rgba8Unorm` textures. An image array stores its layers
+/// one after another.
+#[derive(Clone, Default)]
+pub struct CpuTexture {
+    pub width: u32,
+    pub height: u32,
+    pub layers: u32,
+    pub pixels: Vec<u32>,
+}
+
+/// A resource bound to a CPU shader.
+#[derive(Clone, Copy)]
+pub enum CpuBinding<'a> {
+    Buffer(&'a RefCell<Vec<u32>>),
+    Texture(&'a RefCell<CpuTexture>),
+}
+
+/// A resource supplied by the caller of [`CpuEngine::run_recording`].
+pub enum CpuExternalResource<'a> {
+    Buf(BufProxy, &'a RefCell<Vec<u32>>),
+    Image(ImageProxy, &'a RefCell<CpuTexture>),
+}
+
+pub struct CpuEngine {
+    shaders: Vec<CpuShader>,
+    buf_map: HashMap<Id, RefCell<Vec<u32>>>,
+    image_map: HashMap<Id, RefCell<CpuTexture>>,
+    downloads: HashMap<Id, Vec<u8>>,
+}
+
+struct CpuShader {
+    shader: CpuShaderType,
+    #[allow(unused)]
+    label: &'static str,
+}
+
+impl CpuTexture {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self::with_layers(width, height, 1)
+    }
+
+    pub fn with_layers(width: u32, height: u32, layers: u32) -> Self {
+        Self {
+            width,
+            height,
+            layers,
+            pixels: vec![0; (width * height * layers) as usize],
+        }
+    }
+
+    fn from_bytes(width: u32, height: u32, bytes: &[u8]) -> Self {
+        let mut texture = Self::new(width, height);
+        for (pixel, chunk) in texture.pixels.iter_mut().zip(bytes.chunks_exact(4)) {
+            *pixel = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+        texture
+    }
+
+    /// Returns the pixel at the given coordinates, or zero if out of bounds.
+    ///
+    /// This matches the behavior of `textureLoad` under robust buffer access.
+    pub fn load(&self, x: i32, y: i32) -> u32 {
+        self.load_layer(x, y, 0)
+    }
+
+    /// Returns the pixel at the given coordinates of a layer, or zero if out of bounds.
+    pub fn load_layer(&self, x: i32, y: i32, layer: u32) -> u32 {
+        if x < 0 || y < 0 || x as u32 >= self.width || y as u32 >= self.height {
+            return 0;
+        }
+        if layer >= self.layers {
+            return 0;
+        }
+        let row = layer * self.height + y as u32;
+        self.pixels[(row * self.width + x as u32) as usize]
+    }
+
+    /// Writes a pixel at the given coordinates, ignoring out of bounds writes.
+    pub fn store(&mut self, x: u32, y: u32, value: u32) {
+        self.store_layer(x, y, 0, value);
+    }
+
+    /// Writes a pixel at the given coordinates of a layer, ignoring out of bounds writes.
+    pub fn store_layer(&mut self, x: u32, y: u32, layer: u32, value: u32) {
+        if x < self.width && y < self.height && layer < self.layers {
+            let row = layer * self.height + y;
+            self.pixels[(row * self.width + x) as usize] = value;
+        }
+    }
+
+    /// Returns the image contents as tightly packed RGBA8 bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        bytemuck::cast_slice(&self.pixels)
+    }
+}
+
+impl<'a> CpuBinding<'a> {
+    /// Views the start of a buffer as a single value of type `T`.
+    ///
+    /// Fails if the buffer is smaller than a `T`.
+    pub fn as_typed<T: Pod>(&self) -> Result<Ref<'a, T>, Error> {
+        Ref::filter_map(self.as_slice::<T>()?, |slice| slice.first()).map_err(|_| too_small::<T>())
+    }
+
+    pub fn as_typed_mut<T: Pod>(&self) -> Result<RefMut<'a, T>, Error> {
+        RefMut::filter_map(self.as_slice_mut::<T>()?, |slice| slice.first_mut())
+            .map_err(|_| too_small::<T>())
+    }
+
+    /// Views a buffer as a slice of `T`, ignoring any trailing partial element.
+    pub fn as_slice<T: Pod>(&self) -> Result<Ref<'a, [T]>, Error> {
+        match self {
+            CpuBinding::Buffer(buf) => Ok(Ref::map(buf.borrow(), |words| {
+                bytemuck::cast_slice(&words[..whole_elements::<T>(words.len())])
+            })),
+            _ => Err("resource type mismatch: expected a buffer".into()),
+        }
+    }
+
+    pub fn as_slice_mut<T: Pod>(&self) -> Result<RefMut<'a, [T]>, Error> {
+        match self {
+            CpuBinding::Buffer(buf) => Ok(RefMut::map(buf.borrow_mut(), |words| {
+                let len = whole_elements::<T>(words.len());
+                bytemuck::cast_slice_mut(&mut words[..len])
+            })),
+            _ => Err("resource type mismatch: expected a buffer".into()),
+        }
+    }
+
+    pub fn as_tex(&self) -> Result<Ref<'a, CpuTexture>, Error> {
+        match self {
+            CpuBinding::Texture(tex) => Ok(tex.borrow()),
+            _ => Err("resource type mismatch: expected a texture".into()),
+        }
+    }
+
+    pub fn as_tex_mut(&self) -> Result<RefMut<'a, CpuTexture>, Error> {
+        match self {
+            CpuBinding::Texture(tex) => Ok(tex.borrow_mut()),
+            _ => Err("resource type mismatch: expected a texture".into()),
+        }
+    }
+}
+
+/// Number of u32 words covering the whole elements of type `T` in a buffer.
+fn whole_elements<T>(n_words: usize) -> usize {
+    let words_per_element = (std::mem::size_of::<T>() / 4).max(1);
+    n_words / words_per_element * words_per_element
+}
+
+fn too_small<T>() -> Error {
+    format!("buffer is smaller than a {}", std::any::type_name::<T>()).into()
+}
+
+fn zeroed_words(size: u64) -> Vec<u32> {
+    vec![0; size.div_ceil(4) as usize]
+}
+
+impl CpuEngine {
+    pub fn new() -> CpuEngine {
+        CpuEngine {
+            shaders: vec![],
+            buf_map: Default::default(),
+            image_map: Default::default(),
+            downloads: Default::default(),
+        }
+    }
+
+    /// Add a shader.
+    pub fn add_shader(&mut self, label: &'static str, shader: CpuShaderType) -> ShaderId {
+        let id = self.shaders.len();
+        self.shaders.push(CpuShader { shader, label });
+        ShaderId(id)
+    }
+
+    pub fn run_recording(
+        &mut self,
+        recording: &Recording,
+        external_resources: &[CpuExternalResource],
+    ) -> Result<(), Error> {
+        let mut free_bufs: HashSet<Id> = Default::default();
+        let mut free_images: HashSet<Id> = Default::default();
+        for command in &recording.commands {
+            match command {
+                Command::Upload(buf_proxy, bytes) | Command::UploadUniform(buf_proxy, bytes) => {
+                    let mut words = zeroed_words(buf_proxy.size);
+                    bytemuck::cast_slice_mut::<u32, u8>(&mut words)[..bytes.len()]
+                        .copy_from_slice(bytes);
+                    self.buf_map.insert(buf_proxy.id, RefCell::new(words));
+                }
+                Command::UploadImage(image_proxy, bytes) => {
+                    let texture =
+                        CpuTexture::from_bytes(image_proxy.width, image_proxy.height, bytes);
+                    self.image_map.insert(image_proxy.id, RefCell::new(texture));
+                }
+                Command::WriteImage(proxy, layer, [x, y, width, height], data) => {
+                    let mut texture = self
+                        .image_map
+                        .entry(proxy.id)
+                        .or_insert_with(|| RefCell::new(new_texture(proxy)))
+                        .borrow_mut();
+                    let src = CpuTexture::from_bytes(*width, *height, data);
+                    for row in 0..*height {
+                        for col in 0..*width {
+                            texture.store_layer(
+                                x + col,
+                                y + row,
+                                *layer,
+                                src.pixels[(row * width + col) as usize],
+                            );
+                        }
+                    }
+                }
+                Command::Dispatch(shader_id, wg_size, bindings) => {
+                    for proxy in bindings {
+                        match proxy {
+                            ResourceProxy::Buf(proxy) => {
+                                if find_buf(external_resources, proxy).is_none() {
+                                    self.buf_map
+                                        .entry(proxy.id)
+                                        .or_insert_with(|| RefCell::new(zeroed_words(proxy.size)));
+                                }
+                            }
+                            ResourceProxy::Image(proxy) => {
+                                if find_image(external_resources, proxy).is_none() {
+                                    self.image_map
+                                        .entry(proxy.id)
+                                        .or_insert_with(|| RefCell::new(new_texture(proxy)));
+                                }
+                            }
+                        }
+                    }
+                    let resources = bindings
+                        .iter()
+                        .map(|proxy| match proxy {
+                            ResourceProxy::Buf(proxy) => CpuBinding::Buffer(
+                                find_buf(external_resources, proxy)
+                                    .unwrap_or_else(|| &self.buf_map[&proxy.id]),
+                            ),
+                            ResourceProxy::Image(proxy) => CpuBinding::Texture(
+                                find_image(external_resources, proxy)
+                                    .unwrap_or_else(|| &self.image_map[&proxy.id]),
+                            ),
+                        })
+                        .collect::<Vec<_>>();
+                    let shader = &self.shaders[shader_id.0];
+                    (shader.shader)(*wg_size, &resources)?;
+                }
+                Command::Download(proxy) => {
+                    let buf = find_buf(external_resources, proxy)
+                        .or_else(|| self.buf_map.get(&proxy.id))
+                        .ok_or("buffer not in map")?;
+                    let bytes = bytemuck::cast_slice(&buf.borrow()[..]).to_vec();
+                    self.downloads.insert(proxy.id, bytes);
+                }
+                Command::Clear(proxy, offset, size) => {
+                    let buf = self
+                        .buf_map
+                        .entry(proxy.id)
+                        .or_insert_with(|| RefCell::new(zeroed_words(proxy.size)));
+                    let mut buf = buf.borrow_mut();
+                    let bytes = bytemuck::cast_slice_mut::<u32, u8>(&mut buf);
+                    let start = *offset as usize;
+                    let end = match size {
+                        Some(size) => start + size.get() as usize,
+                        None => bytes.len(),
+                    };
+                    bytes[start..end].fill(0);
+                }
+                Command::FreeBuf(proxy) => {
+                    free_bufs.insert(proxy.id);
+                }
+                Command::FreeImage(proxy) => {
+                    free_images.insert(proxy.id);
+                }
+            }
+        }
+        for id in free_bufs {
+            self.buf_map.remove(&id);
+        }
+        for id in free_images {
+            self.image_map.remove(&id);
+        }
+        Ok(())
+    }
+
+    pub fn get_download(&self, buf: BufProxy) -> Option<&[u8]> {
+        self.downloads.get(&buf.id).map(|bytes| &bytes[..])
+    }
+
+    pub fn free_download(&mut self, buf: BufProxy) {
+        self.downloads.remove(&buf.id);
+    }
+}
+
+impl Default for CpuEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn find_buf<'a>(
+    resources: &[CpuExternalResource<'a>],
+    proxy: &BufProxy,
+) -> Option<&'a RefCell<Vec<u32>>> {
+    resources.iter().find_map(|resource| match resource {
+        CpuExternalResource::Buf(p, buf) if p.id == proxy.id => Some(*buf),
+        _ => None,
+    })
+}
+
+fn find_image<'a>(
+    resources: &[CpuExternalResource<'a>],
+    proxy: &ImageProxy,
+) -> Option<&'a RefCell<CpuTexture>> {
+    resources.iter().find_map(|resource| match resource {
+        CpuExternalResource::Image(p, image) if p.id == proxy.id => Some(*image),
+        _ => None,
+    })
+}
+
+fn new_texture(proxy: &ImageProxy) -> CpuTexture {
+    CpuTexture::with_layers(proxy.width, proxy.height, proxy.layer_count())
+}
+
+#[cfg(test)]
+mod tests {
+    use bytemuck::Pod;
+    use peniko::kurbo::{Affine, Rect};
+    use peniko::{Color, Fill};
+    use vello_encoding::{
+        BinHeader, BumpAllocators, BumpSizes, DrawColor, DrawMonoid, Monoid, Path, PathBbox,
+        PathMonoid, PathSegment, RenderConfig, Tile,
+    };
+
+    use super::*;
+    use crate::render::{Render, RenderCache};
+    use crate::shaders::{full_shaders_cpu, FullShaders};
+    use crate::{cpu_shader, CpuRenderer, RenderParams, Scene, SceneBuilder};
+
+    const RED: u32 = 0xff0000ff;
+    const WHITE: u32 = 0xffffffff;
+    const RED_COLOR: Color = Color::rgb8(255, 0, 0);
+
+    fn rect_scene(rect: Rect) -> Scene {
+        let mut scene = Scene::new();
+        let mut sb = SceneBuilder::for_scene(&mut scene);
+        sb.fill(Fill::NonZero, Affine::IDENTITY, RED_COLOR, None, &rect);
+        scene
+    }
+
+    const BLUE_COLOR: Color = Color::rgb8(0, 0, 255);
+
+    /// A rect spanning 4x4 tiles, and a translated rect within a single tile.
+    fn two_rect_scene() -> Scene {
+        let mut scene = Scene::new();
+        let mut sb = SceneBuilder::for_scene(&mut scene);
+        let rect = Rect::new(20.0, 20.0, 76.0, 76.0);
+        sb.fill(Fill::NonZero, Affine::IDENTITY, RED_COLOR, None, &rect);
+        let rect = Rect::new(0.0, 0.0, 8.0, 8.0);
+        let transform = Affine::translate((96.0, 16.0));
+        sb.fill(Fill::NonZero, transform, BLUE_COLOR, None, &rect);
+        scene
+    }
+
+    /// Buffers bound to a stage, as they are right after it runs.
+    struct StageBuffers {
+        config: RenderConfig,
+        packed: Vec<u8>,
+        buffers: Vec<Vec<u8>>,
+    }
+
+    impl StageBuffers {
+        /// Runs the coarse phase of `scene` in a 128x128 target, reading back the
+        /// buffers bound to the stage selected by `stage`.
+        fn new(scene: &Scene, stage: fn(&FullShaders) -> ShaderId) -> Self {
+            let mut engine = CpuEngine::new();
+            let shaders = full_shaders_cpu(&mut engine);
+            let params = RenderParams {
+                base_color: Color::WHITE,
+                width: 128,
+                height: 128,
+                target_color_space: Default::default(),
+                blend_color_space: Default::default(),
+            };
+            let mut recording = Render::new().render_encoding_coarse(
+                scene.data(),
+                &mut RenderCache::default(),
+                &shaders,
+                &params,
+                &BumpSizes::default(),
+                false,
+            );
+            let stage = stage(&shaders);
+            let ix = recording
+                .commands
+                .iter()
+                .position(|command| matches!(command, Command::Dispatch(id, ..) if id.0 == stage.0))
+                .expect("stage should be dispatched");
+            let Command::Dispatch(_, _, bindings) = &recording.commands[ix] else {
+                unreachable!();
+            };
+            let bufs = bindings
+                .iter()
+                .filter_map(|binding| binding.as_buf().copied())
+                .collect::<Vec<_>>();
+            recording.commands.splice(
+                ix + 1..ix + 1,
+                bufs.iter().map(|buf| Command::Download(*buf)),
+            );
+            engine.run_recording(&recording, &[]).unwrap();
+            let mut packed = vec![];
+            let mut resolver = vello_encoding::Resolver::new();
+            let (layout, _, _) = resolver.resolve(scene.data(), &mut packed);
+            Self {
+                config: RenderConfig::new(&layout, params.width, params.height, &Color::WHITE),
+                packed,
+                buffers: bufs
+                    .iter()
+                    .map(|buf| engine.get_download(*buf).unwrap().to_vec())
+                    .collect(),
+            }
+        }
+
+        /// Returns the contents of the buffer bound at `binding`.
+        fn get<T: Pod>(&self, binding: usize) -> Vec<T> {
+            self.buffers[binding]
+                .chunks_exact(std::mem::size_of::<T>())
+                .map(bytemuck::pod_read_unaligned)
+                .collect()
+        }
+    }
+
+    /// Exclusive prefix sums of the monoids of `values`.
+    fn exclusive_scan<M: Monoid + Default + Copy>(values: &[M::SourceValue]) -> Vec<M>
+    where
+        M::SourceValue: Copy,
+    {
+        let mut agg = M::default();
+        values
+            .iter()
+            .map(|value| {
+                let prefix = agg;
+                agg = agg.combine(&M::new(*value));
+                prefix
+            })
+            .collect()
+    }
+
+    fn assert_same_bytes<T: Pod>(actual: &[T], expected: &[T]) {
+        assert_eq!(
+            bytemuck::cast_slice::<T, u8>(&actual[..expected.len()]),
+            bytemuck::cast_slice::<T, u8>(expected)
+        );
+    }
+
+    /// Number of segments in the list of each tile.
+    fn segment_counts(tiles: &[Tile], segments: &[PathSegment]) -> Vec<u32> {
+        tiles
+            .iter()
+            .map(|tile| {
+                let mut count = 0;
+                let mut ix = tile.segments;
+                while ix != 0 {
+                    count += 1;
+                    ix = segments[ix as usize].next;
+                }
+                count
+            })
+            .collect()
+    }
+
+    #[test]
+    fn pathtag_reduce_matches_monoid() {
+        let scene = rect_scene(Rect::new(16.0, 16.0, 48.0, 32.0));
+        let mut packed = vec![];
+        let layout = vello_encoding::resolve_solid_paths_only(scene.data(), &mut packed);
+        let config = RenderConfig::new(&layout, 64, 64, &Color::WHITE);
+        let mut engine = CpuEngine::new();
+        let shader = engine.add_shader("pathtag_reduce", cpu_shader::pathtag_reduce);
+        let mut recording = Recording::default();
+        let config_buf = recording.upload_uniform("config", bytemuck::bytes_of(&config.gpu));
+        let scene_buf = recording.upload("scene", packed.clone());
+        let reduced_buf = BufProxy::new(
+            config.buffer_sizes.path_reduced.size_in_bytes().into(),
+            "reduced_buf",
+        );
+        recording.dispatch(
+            shader,
+            config.workgroup_counts.path_reduce,
+            [config_buf, scene_buf, reduced_buf],
+        );
+        recording.download(reduced_buf);
+        engine.run_recording(&recording, &[]).unwrap();
+        let reduced: &[PathMonoid] =
+            bytemuck::cast_slice(engine.get_download(reduced_buf).unwrap());
+        let expected = layout
+            .path_tags_chunked(&packed)
+            .iter()
+            .map(|word| PathMonoid::new(*word))
+            .fold(PathMonoid::default(), |a, b| a.combine(&b));
+        assert_eq!(
+            bytemuck::bytes_of(&reduced[0]),
+            bytemuck::bytes_of(&expected)
+        );
+        // The identity transform of the rect is the initial transform of the
+        // scene, so only its four segments and path are tagged.
+        assert_eq!(expected.trans_ix, 0);
+        assert_eq!(expected.pathseg_ix, 4);
+        assert_eq!(expected.path_ix, 1);
+    }
+
+    #[test]
+    fn renders_pixel_aligned_rect() {
+        let (width, height) = (64, 64);
+        let scene = rect_scene(Rect::new(16.0, 16.0, 48.0, 32.0));
+        let mut texture = CpuTexture::new(width, height);
+        let params = RenderParams {
+            base_color: Color::WHITE,
+            width,
+            height,
+            target_color_space: Default::default(),
+            blend_color_space: Default::default(),
+        };
+        CpuRenderer::new()
+            .render_to_texture(&scene, &mut texture, &params)
+            .unwrap();
+        for y in 0..height as i32 {
+            for x in 0..width as i32 {
+                let inside = (16..48).contains(&x) && (16..32).contains(&y);
+                let expected = if inside { RED } else { WHITE };
+                assert_eq!(texture.load(x, y), expected, "pixel ({x}, {y})");
+            }
+        }
+    }
+
+    #[test]
+    fn binding_type_mismatch_is_an_error() {
+        let texture = RefCell::new(CpuTexture::new(1, 1));
+        let buffer = RefCell::new(vec![0u32; 4]);
+        assert!(CpuBinding::Texture(&texture).as_slice::<u32>().is_err());
+        assert!(CpuBinding::Buffer(&buffer).as_tex().is_err());
+        assert!(CpuBinding::Buffer(&buffer).as_typed::<u32>().is_ok());
+    }
+
+    #[test]
+    fn buffer_too_small_is_an_error() {
+        let buffer = RefCell::new(vec![0u32; 4]);
+        let binding = CpuBinding::Buffer(&buffer);
+        assert!(binding.as_typed::<[u32; 4]>().is_ok());
+        assert!(binding.as_typed::<[u32; 5]>().is_err());
+        assert!(binding.as_typed_mut::<[u32; 5]>().is_err());
+    }
+
+    #[test]
+    fn pathtag_scan_matches_monoid() {
+        let scene = two_rect_scene();
+        let stage = StageBuffers::new(&scene, |shaders| shaders.pathtag_scan);
+        let layout = &stage.config.gpu.layout;
+        let expected = exclusive_scan::<PathMonoid>(layout.path_tags_chunked(&stage.packed));
+        assert_same_bytes(&stage.get::<PathMonoid>(3), &expected);
+        // The second rect has its own transform.
+        assert_eq!(expected.last().unwrap().trans_ix, 1);
+    }
+
+    #[test]
+    fn pathseg_writes_path_bboxes() {
+        let scene = two_rect_scene();
+        let stage = StageBuffers::new(&scene, |shaders| shaders.pathseg);
+        let bboxes = stage.get::<PathBbox>(3);
+        let bbox = |ix: usize| {
+            let bbox = bboxes[ix];
+            (
+                [bbox.x0, bbox.y0, bbox.x1, bbox.y1],
+                bbox.linewidth,
+                bbox.trans_ix,
+            )
+        };
+        assert_eq!(bbox(0), ([20, 20, 76, 76], -1.0, 0));
+        assert_eq!(bbox(1), ([96, 16, 104, 24], -1.0, 1));
+    }
+
+    #[test]
+    fn draw_leaf_matches_monoid() {
+        let scene = two_rect_scene();
+        let stage = StageBuffers::new(&scene, |shaders| shaders.draw_leaf);
+        let expected = exclusive_scan::<DrawMonoid>(&scene.data().draw_tags);
+        assert_eq!(expected.len(), 2);
+        assert_same_bytes(&stage.get::<DrawMonoid>(4), &expected);
+    }
+
+    #[test]
+    fn binning_assigns_draw_objects_to_bins() {
+        let scene = two_rect_scene();
+        let stage = StageBuffers::new(&scene, |shaders| shaders.binning);
+        let draw_bboxes = stage.get::<[f32; 4]>(4);
+        assert_eq!(draw_bboxes[0], [20.0, 20.0, 76.0, 76.0]);
+        assert_eq!(draw_bboxes[1], [96.0, 16.0, 104.0, 24.0]);
+        let bump = stage.get::<BumpAllocators>(5)[0];
+        assert_eq!((bump.failed, bump.binning), (0, 2));
+        // Both draw objects are in the only bin of the target.
+        let headers = stage.get::<BinHeader>(7);
+        assert_eq!((headers[0].element_count, headers[0].chunk_offset), (2, 0));
+        let bin_data_start = stage.config.gpu.layout.bin_data_start as usize;
+        assert_eq!(stage.get::<u32>(6)[bin_data_start..][..2], [0, 1]);
+    }
+
+    #[test]
+    fn tile_alloc_allocates_path_tiles() {
+        let scene = two_rect_scene();
+        let stage = StageBuffers::new(&scene, |shaders| shaders.tile_alloc);
+        let bump = stage.get::<BumpAllocators>(2)[0];
+        assert_eq!((bump.failed, bump.tile), (0, 17));
+        let paths = stage.get::<Path>(3);
+        assert_eq!((paths[0].bbox, paths[0].tiles), ([1, 1, 5, 5], 0));
+        assert_eq!((paths[1].bbox, paths[1].tiles), ([6, 1, 7, 2], 16));
+    }
+
+    #[test]
+    fn path_coarse_bins_segments_into_tiles() {
+        let scene = two_rect_scene();
+        let stage = StageBuffers::new(&scene, |shaders| shaders.path_coarse);
+        let bump = stage.get::<BumpAllocators>(4)[0];
+        assert_eq!((bump.failed, bump.segments), (0, 20));
+        let tiles = stage.get::<Tile>(5);
+        let counts = segment_counts(&tiles[..17], &stage.get::<PathSegment>(6));
+        // Each edge of the large rect crosses 4 tiles, and the small rect is in one.
+        #[rustfmt::skip]
+        assert_eq!(counts, [
+            2, 1, 1, 2,
+            1, 0, 0, 1,
+            1, 0, 0, 1,
+            2, 1, 1, 2,
+            4,
+        ]);
+        // The left edge adds to the backdrop of the tiles right of it, in the rows
+        // below its first one.
+        let backdrops = tiles[..17]
+            .iter()
+            .map(|tile| tile.backdrop)
+            .collect::<Vec<_>>();
+        #[rustfmt::skip]
+        assert_eq!(backdrops, [
+            0, 0, 0, 0,
+            0, 1, 0, 0,
+            0, 1, 0, 0,
+            0, 1, 0, 0,
+            0,
+        ]);
+    }
+
+    #[test]
+    fn backdrop_sums_rows() {
+        let scene = two_rect_scene();
+        let stage = StageBuffers::new(&scene, |shaders| shaders.backdrop);
+        let tiles = stage.get::<Tile>(2);
+        let backdrops = tiles[..17]
+            .iter()
+            .map(|tile| tile.backdrop)
+            .collect::<Vec<_>>();
+        #[rustfmt::skip]
+        assert_eq!(backdrops, [
+            0, 0, 0, 0,
+            0, 1, 1, 1,
+            0, 1, 1, 1,
+            0, 1, 1, 1,
+            0,
+        ]);
+    }
+
+    #[test]
+    fn coarse_writes_tile_commands() {
+        const CMD_END: u32 = 0;
+        const CMD_FILL: u32 = 1;
+        const CMD_SOLID: u32 = 3;
+        const CMD_COLOR: u32 = 5;
+        let scene = two_rect_scene();
+        let stage = StageBuffers::new(&scene, |shaders| shaders.coarse);
+        let bump = stage.get::<BumpAllocators>(7)[0];
+        assert_eq!(bump.failed, 0);
+        let ptcl = stage.get::<u32>(8);
+        // Each tile has 64 words, starting with the offset of its blend stack.
+        let commands = |x: usize, y: usize| &ptcl[(y * 8 + x) * 64 + 1..][..6];
+        let red = DrawColor::new(RED_COLOR).rgba;
+        let blue = DrawColor::new(BLUE_COLOR).rgba;
+        // Tiles crossed by an edge fill from their segments, with their backdrop.
+        for (x, y, backdrop, color) in [
+            (1, 2, 0, red),
+            (3, 1, 0, red),
+            (4, 4, 1, red),
+            (6, 1, 0, blue),
+        ] {
+            let cmds = commands(x, y);
+            assert_eq!(cmds[0], CMD_FILL, "tile ({x}, {y})");
+            assert_ne!(cmds[1], 0, "tile ({x}, {y})");
+            assert_eq!(
+                cmds[2..],
+                [backdrop, CMD_COLOR, color, CMD_END],
+                "tile ({x}, {y})"
+            );
+        }
+        // Tiles inside the rect are solid.
+        assert_eq!(commands(2, 2)[..4], [CMD_SOLID, CMD_COLOR, red, CMD_END]);
+        assert_eq!(commands(3, 3)[..4], [CMD_SOLID, CMD_COLOR, red, CMD_END]);
+        // Other tiles are empty.
+        for (x, y) in [(0, 0), (5, 2), (6, 2), (7, 7)] {
+            assert_eq!(commands(x, y)[0], CMD_END, "tile ({x}, {y})");
+        }
+    }
+}
@@ -0,0 +1,36 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use vello_encoding::{ConfigUniform, Path, Tile};
+
+use crate::cpu_dispatch::CpuBinding;
+use crate::engine::Error;
+
+fn backdrop_main(config: &ConfigUniform, paths: &[Path], tiles: &mut [Tile]) {
+    for path in &paths[..config.layout.n_paths as usize] {
+        let width = path.bbox[2].wrapping_sub(path.bbox[0]);
+        let height = path.bbox[3].wrapping_sub(path.bbox[1]);
+        if width == 0 {
+            continue;
+        }
+        for row in 0..height {
+            let base = path.tiles.wrapping_add(row.wrapping_mul(width)) as usize;
+            // Tiles may be out of bounds when tile allocation failed.
+            let mut sum = tiles.get(base).map_or(0, |tile| tile.backdrop);
+            for x in 1..width as usize {
+                if let Some(tile) = tiles.get_mut(base + x) {
+                    sum = sum.wrapping_add(tile.backdrop);
+                    tile.backdrop = sum;
+                }
+            }
+        }
+    }
+}
+
+pub fn backdrop(_n_wg: (u32, u32, u32), resources: &[CpuBinding]) -> Result<(), Error> {
+    let config = resources[0].as_typed()?;
+    let paths = resources[1].as_slice()?;
+    let mut tiles = resources[2].as_slice_mut()?;
+    backdrop_main(&config, &paths, &mut tiles);
+    Ok(())
+}
@@ -0,0 +1,23 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use vello_encoding::{ConfigUniform, PathBbox};
+
+use crate::cpu_dispatch::CpuBinding;
+use crate::engine::Error;
+
+fn bbox_clear_main(config: &ConfigUniform, path_bboxes: &mut [PathBbox]) {
+    for bbox in &mut path_bboxes[..config.layout.n_paths as usize] {
+        bbox.x0 = 0x7fff_ffff;
+        bbox.y0 = 0x7fff_ffff;
+        bbox.x1 = -0x8000_0000;
+        bbox.y1 = -0x8000_0000;
+    }
+}
+
+pub fn bbox_clear(_n_wg: (u32, u32, u32), resources: &[CpuBinding]) -> Result<(), Error> {
+    let config = resources[0].as_typed()?;
+    let mut path_bboxes = resources[1].as_slice_mut()?;
+    bbox_clear_main(&config, &mut path_bboxes);
+    Ok(())
+}
@@ -0,0 +1,139 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use vello_encoding::{BinHeader, BumpAllocators, ConfigUniform, DrawMonoid, PathBbox};
+
+use super::util::{N_TILE, N_TILE_X, N_TILE_Y, WG_SIZE};
+use crate::cpu_dispatch::CpuBinding;
+use crate::engine::Error;
+
+const SX: f32 = 1.0 / ((N_TILE_X * 16) as f32);
+const SY: f32 = 1.0 / ((N_TILE_Y * 16) as f32);
+
+// Bitflags for each stage that can fail allocation.
+const STAGE_BINNING: u32 = 0x1;
+
+fn bbox_intersect(a: [f32; 4], b: [f32; 4]) -> [f32; 4] {
+    [
+        a[0].max(b[0]),
+        a[1].max(b[1]),
+        a[2].min(b[2]),
+        a[3].min(b[3]),
+    ]
+}
+
+#[allow(clippy::too_many_arguments)]
+fn binning_main(
+    n_wg: u32,
+    config: &ConfigUniform,
+    draw_monoids: &[DrawMonoid],
+    path_bbox_buf: &[PathBbox],
+    clip_bbox_buf: &[[f32; 4]],
+    intersected_bbox: &mut [[f32; 4]],
+    bump: &mut BumpAllocators,
+    bin_data: &mut [u32],
+    bin_header: &mut [BinHeader],
+) {
+    let width_in_bins = (config.width_in_tiles as usize).div_ceil(N_TILE_X) as i32;
+    let height_in_bins = (config.height_in_tiles as usize).div_ceil(N_TILE_Y) as i32;
+    for wg in 0..n_wg as usize {
+        // Draw objects of this workgroup touching each bin, in element order.
+        let mut bins: Vec<Vec<u32>> = vec![vec![]; N_TILE];
+        for local_ix in 0..WG_SIZE {
+            let element_ix = (wg * WG_SIZE + local_ix) as u32;
+            let mut x0 = 0;
+            let mut y0 = 0;
+            let mut x1 = 0;
+            let mut y1 = 0;
+            if element_ix < config.layout.n_draw_objects {
+                let draw_monoid = draw_monoids[element_ix as usize];
+                let mut clip_bbox = [-1e9, -1e9, 1e9, 1e9];
+                if draw_monoid.clip_ix > 0 {
+                    let clip_ix =
+                        (draw_monoid.clip_ix - 1).min(config.layout.n_clips.wrapping_sub(1));
+                    clip_bbox = clip_bbox_buf[clip_ix as usize];
+                }
+                let path_bbox = path_bbox_buf
+                    .get(draw_monoid.path_ix as usize)
+                    .copied()
+                    .unwrap_or_default();
+                let pb = [
+                    path_bbox.x0 as f32,
+                    path_bbox.y0 as f32,
+                    path_bbox.x1 as f32,
+                    path_bbox.y1 as f32,
+                ];
+                let bbox = bbox_intersect(clip_bbox, pb);
+                if let Some(out) = intersected_bbox.get_mut(draw_monoid.path_ix as usize) {
+                    *out = bbox;
+                }
+                // A zero or negative area intersection leaves the coordinates at
+                // 0, so the path is clipped out and isn't assigned to a bin.
+                if bbox[0] < bbox[2] && bbox[1] < bbox[3] {
+                    x0 = (bbox[0] * SX).floor() as i32;
+                    y0 = (bbox[1] * SY).floor() as i32;
+                    x1 = (bbox[2] * SX).ceil() as i32;
+                    y1 = (bbox[3] * SY).ceil() as i32;
+                }
+            }
+            x0 = x0.clamp(0, width_in_bins);
+            y0 = y0.clamp(0, height_in_bins);
+            x1 = x1.clamp(0, width_in_bins);
+            y1 = y1.clamp(0, height_in_bins);
+            if x0 == x1 {
+                y1 = y0;
+            }
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    let bin_ix = (y * width_in_bins + x) as usize;
+                    if bin_ix < N_TILE {
+                        bins[bin_ix].push(element_ix);
+                    }
+                }
+            }
+        }
+        // Allocate output segments
+        for (bin_ix, elements) in bins.iter().enumerate() {
+            let element_count = elements.len() as u32;
+            let mut chunk_offset = bump.binning;
+            bump.binning += element_count;
+            if chunk_offset + element_count > config.binning_size {
+                chunk_offset = 0;
+                bump.failed |= STAGE_BINNING;
+            }
+            bin_header[wg * WG_SIZE + bin_ix] = BinHeader {
+                element_count,
+                chunk_offset,
+            };
+            let offset = (config.layout.bin_data_start + chunk_offset) as usize;
+            for (idx, element_ix) in elements.iter().enumerate() {
+                if let Some(out) = bin_data.get_mut(offset + idx) {
+                    *out = *element_ix;
+                }
+            }
+        }
+    }
+}
+
+pub fn binning(n_wg: (u32, u32, u32), resources: &[CpuBinding]) -> Result<(), Error> {
+    let config = resources[0].as_typed()?;
+    let draw_monoids = resources[1].as_slice()?;
+    let path_bbox_buf = resources[2].as_slice()?;
+    let clip_bbox_buf = resources[3].as_slice()?;
+    let mut intersected_bbox = resources[4].as_slice_mut()?;
+    let mut bump = resources[5].as_typed_mut()?;
+    let mut bin_data = resources[6].as_slice_mut()?;
+    let mut bin_header = resources[7].as_slice_mut()?;
+    binning_main(
+        n_wg.0,
+        &config,
+        &draw_monoids,
+        &path_bbox_buf,
+        &clip_bbox_buf,
+        &mut intersected_bbox,
+        &mut bump,
+        &mut bin_data,
+        &mut bin_header,
+    );
+    Ok(())
+}
@@ -0,0 +1,224 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Color mixing and composition, ported from blend.wgsl.
+
+type Vec3 = [f32; 3];
+
+fn map3(a: Vec3, b: Vec3, f: impl Fn(f32, f32) -> f32) -> Vec3 {
+    [f(a[0], b[0]), f(a[1], b[1]), f(a[2], b[2])]
+}
+
+fn screen(cb: f32, cs: f32) -> f32 {
+    cb + cs - (cb * cs)
+}
+
+fn color_dodge(cb: f32, cs: f32) -> f32 {
+    if cb == 0.0 {
+        0.0
+    } else if cs == 1.0 {
+        1.0
+    } else {
+        1.0f32.min(cb / (1.0 - cs))
+    }
+}
+
+fn color_burn(cb: f32, cs: f32) -> f32 {
+    if cb == 1.0 {
+        1.0
+    } else if cs == 0.0 {
+        0.0
+    } else {
+        1.0 - 1.0f32.min((1.0 - cb) / cs)
+    }
+}
+
+fn hard_light(cb: f32, cs: f32) -> f32 {
+    if cs <= 0.5 {
+        cb * 2.0 * cs
+    } else {
+        screen(cb, 2.0 * cs - 1.0)
+    }
+}
+
+fn soft_light(cb: f32, cs: f32) -> f32 {
+    let d = if cb <= 0.25 {
+        ((16.0 * cb - 12.0) * cb + 4.0) * cb
+    } else {
+        cb.sqrt()
+    };
+    if cs <= 0.5 {
+        cb - (1.0 - 2.0 * cs) * cb * (1.0 - cb)
+    } else {
+        cb + (2.0 * cs - 1.0) * (d - cb)
+    }
+}
+
+fn sat(c: Vec3) -> f32 {
+    c[0].max(c[1].max(c[2])) - c[0].min(c[1].min(c[2]))
+}
+
+fn lum(c: Vec3) -> f32 {
+    c[0] * 0.3 + c[1] * 0.59 + c[2] * 0.11
+}
+
+fn clip_color(c_in: Vec3) -> Vec3 {
+    let mut c = c_in;
+    let l = lum(c);
+    let n = c[0].min(c[1].min(c[2]));
+    let x = c[0].max(c[1].max(c[2]));
+    if n < 0.0 {
+        c = c.map(|c| l + (((c - l) * l) / (l - n)));
+    }
+    if x > 1.0 {
+        c = c.map(|c| l + (((c - l) * (1.0 - l)) / (x - l)));
+    }
+    c
+}
+
+fn set_lum(c: Vec3, l: f32) -> Vec3 {
+    let d = l - lum(c);
+    clip_color(c.map(|c| c + d))
+}
+
+fn set_sat_inner(c: &mut Vec3, min: usize, mid: usize, max: usize, s: f32) {
+    if c[max] > c[min] {
+        c[mid] = ((c[mid] - c[min]) * s) / (c[max] - c[min]);
+        c[max] = s;
+    } else {
+        c[mid] = 0.0;
+        c[max] = 0.0;
+    }
+    c[min] = 0.0;
+}
+
+fn set_sat(c: Vec3, s: f32) -> Vec3 {
+    let mut c = c;
+    let [r, g, b] = c;
+    if r <= g {
+        if g <= b {
+            set_sat_inner(&mut c, 0, 1, 2, s);
+        } else if r <= b {
+            set_sat_inner(&mut c, 0, 2, 1, s);
+        } else {
+            set_sat_inner(&mut c, 2, 0, 1, s);
+        }
+    } else if r <= b {
+        set_sat_inner(&mut c, 1, 0, 2, s);
+    } else if g <= b {
+        set_sat_inner(&mut c, 1, 2, 0, s);
+    } else {
+        set_sat_inner(&mut c, 2, 1, 0, s);
+    }
+    c
+}
+
+// Blends two RGB colors together. The colors are assumed to be in sRGB
+// color space, and this function does not take alpha into account.
+fn blend_mix(cb: Vec3, cs: Vec3, mode: u32) -> Vec3 {
+    match mode {
+        // MIX_MULTIPLY
+        1 => map3(cb, cs, |cb, cs| cb * cs),
+        // MIX_SCREEN
+        2 => map3(cb, cs, screen),
+        // MIX_OVERLAY
+        3 => map3(cs, cb, hard_light),
+        // MIX_DARKEN
+        4 => map3(cb, cs, f32::min),
+        // MIX_LIGHTEN
+        5 => map3(cb, cs, f32::max),
+        // MIX_COLOR_DODGE
+        6 => map3(cb, cs, color_dodge),
+        // MIX_COLOR_BURN
+        7 => map3(cb, cs, color_burn),
+        // MIX_HARD_LIGHT
+        8 => map3(cb, cs, hard_light),
+        // MIX_SOFT_LIGHT
+        9 => map3(cb, cs, soft_light),
+        // MIX_DIFFERENCE
+        10 => map3(cb, cs, |cb, cs| (cb - cs).abs()),
+        // MIX_EXCLUSION
+        11 => map3(cb, cs, |cb, cs| cb + cs - 2.0 * cb * cs),
+        // MIX_HUE
+        12 => set_lum(set_sat(cs, sat(cb)), lum(cb)),
+        // MIX_SATURATION
+        13 => set_lum(set_sat(cb, sat(cs)), lum(cb)),
+        // MIX_COLOR
+        14 => set_lum(cs, lum(cb)),
+        // MIX_LUMINOSITY
+        15 => set_lum(cb, lum(cs)),
+        _ => cs,
+    }
+}
+
+const COMPOSE_SRC_OVER: u32 = 3;
+
+// Apply general compositing operation.
+// Inputs are separated colors and alpha, output is premultiplied.
+fn blend_compose(cb: Vec3, cs: Vec3, ab: f32, as_: f32, mode: u32) -> [f32; 4] {
+    let (fa, fb) = match mode {
+        // COMPOSE_COPY
+        1 => (1.0, 0.0),
+        // COMPOSE_DEST
+        2 => (0.0, 1.0),
+        // COMPOSE_SRC_OVER
+        3 => (1.0, 1.0 - as_),
+        // COMPOSE_DEST_OVER
+        4 => (1.0 - ab, 1.0),
+        // COMPOSE_SRC_IN
+        5 => (ab, 0.0),
+        // COMPOSE_DEST_IN
+        6 => (0.0, as_),
+        // COMPOSE_SRC_OUT
+        7 => (1.0 - ab, 0.0),
+        // COMPOSE_DEST_OUT
+        8 => (0.0, 1.0 - as_),
+        // COMPOSE_SRC_ATOP
+        9 => (ab, 1.0 - as_),
+        // COMPOSE_DEST_ATOP
+        10 => (1.0 - ab, as_),
+        // COMPOSE_XOR
+        11 => (1.0 - ab, 1.0 - as_),
+        // COMPOSE_PLUS
+        12 => (1.0, 1.0),
+        // COMPOSE_PLUS_LIGHTER
+        13 => {
+            let co = map3(cs, cb, |cs, cb| (as_ * cs + ab * cb).min(1.0));
+            return [co[0], co[1], co[2], (as_ + ab).min(1.0)];
+        }
+        _ => (0.0, 0.0),
+    };
+    let as_fa = as_ * fa;
+    let ab_fb = ab * fb;
+    let co = map3(cs, cb, |cs, cb| as_fa * cs + ab_fb * cb);
+    // Modes like COMPOSE_PLUS can generate alpha > 1.0, so clamp.
+    [co[0], co[1], co[2], (as_fa + ab_fb).min(1.0)]
+}
+
+// Apply color mixing and composition. Both input and output colors are
+// premultiplied RGB.
+pub fn blend_mix_compose(backdrop: [f32; 4], src: [f32; 4], mode: u32) -> [f32; 4] {
+    const BLEND_DEFAULT: u32 = COMPOSE_SRC_OVER;
+    const EPSILON: f32 = 1e-15;
+    if (mode & 0x7fff) == BLEND_DEFAULT {
+        // Both normal+src_over blend and clip case
+        return [0, 1, 2, 3].map(|i| backdrop[i] * (1.0 - src[3]) + src[i]);
+    }
+    // Un-premultiply colors for blending. Max with a small epsilon to avoid NaNs.
+    let inv_src_a = 1.0 / src[3].max(EPSILON);
+    let cs = [src[0], src[1], src[2]].map(|c| c * inv_src_a);
+    let inv_backdrop_a = 1.0 / backdrop[3].max(EPSILON);
+    let cb = [backdrop[0], backdrop[1], backdrop[2]].map(|c| c * inv_backdrop_a);
+    let mix_mode = mode >> 8;
+    let mixed = blend_mix(cb, cs, mix_mode);
+    let cs = map3(cs, mixed, |cs, mixed| {
+        cs * (1.0 - backdrop[3]) + mixed * backdrop[3]
+    });
+    let compose_mode = mode & 0xff;
+    if compose_mode == COMPOSE_SRC_OVER {
+        let co = [0, 1, 2].map(|i| backdrop[i] * (1.0 - src[3]) + cs[i] * src[3]);
+        [co[0], co[1], co[2], src[3] + backdrop[3] * (1.0 - src[3])]
+    } else {
+        blend_compose(cb, cs, backdrop[3], src[3], compose_mode)
+    }
+}
@@ -0,0 +1,93 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use vello_encoding::{BlurConfig, EffectData};
+
+use super::util::{pack4x8unorm, unpack4x8unorm};
+use crate::cpu_dispatch::{CpuBinding, CpuTexture};
+use crate::engine::Error;
+
+type Rgba = [f32; 4];
+
+fn in_bounds(config: &BlurConfig, x: i32, y: i32) -> bool {
+    x >= 0 && y >= 0 && x < config.width as i32 && y < config.height as i32
+}
+
+/// Premultiplied color of the contents, transparent outside of the layer.
+fn load_contents(config: &BlurConfig, contents: &CpuTexture, x: i32, y: i32) -> Rgba {
+    if !in_bounds(config, x, y) {
+        return [0.0; 4];
+    }
+    let [r, g, b, a] = unpack4x8unorm(contents.load(x, y));
+    [r * a, g * a, b * a, a]
+}
+
+fn load_blurred(config: &BlurConfig, blurred: &CpuTexture, x: i32, y: i32) -> Rgba {
+    if !in_bounds(config, x, y) {
+        return [0.0; 4];
+    }
+    unpack4x8unorm(blurred.load(x, y))
+}
+
+fn blur_main(
+    config: &BlurConfig,
+    contents: &CpuTexture,
+    blurred: &CpuTexture,
+    output: &mut CpuTexture,
+) {
+    // The kernel is cut off at three standard deviations, and a zero standard
+    // deviation leaves the contents unchanged.
+    let radius = (3.0 * config.std_dev).ceil() as i32;
+    let scale = if radius > 0 {
+        -0.5 / (config.std_dev * config.std_dev)
+    } else {
+        0.0
+    };
+    let [dx, dy] = config.offset;
+    for y in 0..config.height as i32 {
+        for x in 0..config.width as i32 {
+            let mut sum = [0.0f32; 4];
+            let mut total = 0.0;
+            for i in -radius..=radius {
+                let weight = ((i * i) as f32 * scale).exp();
+                let rgba = if config.vertical == 0 {
+                    load_contents(config, contents, x + i - dx, y - dy)
+                } else {
+                    load_blurred(config, blurred, x, y + i)
+                };
+                for (s, c) in sum.iter_mut().zip(rgba) {
+                    *s += weight * c;
+                }
+                total += weight;
+            }
+            let mut rgba = sum.map(|s| s / total);
+            if config.vertical == 0 {
+                output.store(x as u32, y as u32, pack4x8unorm(rgba));
+                continue;
+            }
+            if config.kind == EffectData::KIND_DROP_SHADOW {
+                let color = unpack4x8unorm(config.color);
+                let shadow = [color[3], color[2], color[1], color[0]].map(|c| c * rgba[3]);
+                let fg = load_contents(config, contents, x, y);
+                rgba = [0, 1, 2, 3].map(|i| fg[i] + shadow[i] * (1.0 - fg[3]));
+            }
+            // Max with a small epsilon to avoid NaNs
+            let a_inv = 1.0 / rgba[3].max(1e-6);
+            let rgba_sep = [rgba[0] * a_inv, rgba[1] * a_inv, rgba[2] * a_inv, rgba[3]];
+            output.store(
+                x as u32 + config.x,
+                y as u32 + config.y,
+                pack4x8unorm(rgba_sep),
+            );
+        }
+    }
+}
+
+pub fn blur(_n_wg: (u32, u32, u32), resources: &[CpuBinding]) -> Result<(), Error> {
+    let config = resources[0].as_typed()?;
+    let contents = resources[1].as_tex()?;
+    let blurred = resources[2].as_tex()?;
+    let mut output = resources[3].as_tex_mut()?;
+    blur_main(&config, &contents, &blurred, &mut output);
+    Ok(())
+}
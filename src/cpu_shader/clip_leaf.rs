@@ -0,0 +1,89 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use vello_encoding::{Clip, ConfigUniform, DrawMonoid, PathBbox};
+
+use crate::cpu_dispatch::CpuBinding;
+use crate::engine::Error;
+
+struct ClipStackElement {
+    // index of draw object
+    parent_ix: u32,
+    path_ix: u32,
+    bbox: [f32; 4],
+}
+
+const BIG_BBOX: [f32; 4] = [-1e9, -1e9, 1e9, 1e9];
+
+fn bbox_intersect(a: [f32; 4], b: [f32; 4]) -> [f32; 4] {
+    [
+        a[0].max(b[0]),
+        a[1].max(b[1]),
+        a[2].min(b[2]),
+        a[3].min(b[3]),
+    ]
+}
+
+// The GPU version resolves the clip stack with a bicyclic semigroup scan over
+// the reductions of clip_reduce. Sequentially, an explicit stack is enough.
+fn clip_leaf_main(
+    config: &ConfigUniform,
+    clip_inp: &[Clip],
+    path_bboxes: &[PathBbox],
+    draw_monoids: &mut [DrawMonoid],
+    clip_bboxes: &mut [[f32; 4]],
+) {
+    let mut stack: Vec<ClipStackElement> = Vec::new();
+    for global_ix in 0..config.layout.n_clips as usize {
+        let clip_el = clip_inp[global_ix];
+        if clip_el.path_ix >= 0 {
+            // begin clip
+            let path_ix = clip_el.path_ix as u32;
+            let path_bbox = path_bboxes[path_ix as usize];
+            let p_bbox = [
+                path_bbox.x0 as f32,
+                path_bbox.y0 as f32,
+                path_bbox.x1 as f32,
+                path_bbox.y1 as f32,
+            ];
+            let bbox = match stack.last() {
+                Some(tos) => bbox_intersect(tos.bbox, p_bbox),
+                None => p_bbox,
+            };
+            clip_bboxes[global_ix] = bbox;
+            stack.push(ClipStackElement {
+                parent_ix: clip_el.ix,
+                path_ix,
+                bbox,
+            });
+        } else {
+            // end clip
+            let tos = stack.pop().unwrap();
+            let bbox = match stack.last() {
+                Some(nos) => nos.bbox,
+                None => BIG_BBOX,
+            };
+            clip_bboxes[global_ix] = bbox;
+            let ix = !clip_el.path_ix as usize;
+            draw_monoids[ix].path_ix = tos.path_ix;
+            // Make EndClip point to the same draw data as BeginClip
+            draw_monoids[ix].scene_offset = draw_monoids[tos.parent_ix as usize].scene_offset;
+        }
+    }
+}
+
+pub fn clip_leaf(_n_wg: (u32, u32, u32), resources: &[CpuBinding]) -> Result<(), Error> {
+    let config = resources[0].as_typed()?;
+    let clip_inp = resources[1].as_slice()?;
+    let path_bboxes = resources[2].as_slice()?;
+    let mut draw_monoids = resources[5].as_slice_mut()?;
+    let mut clip_bboxes = resources[6].as_slice_mut()?;
+    clip_leaf_main(
+        &config,
+        &clip_inp,
+        &path_bboxes,
+        &mut draw_monoids,
+        &mut clip_bboxes,
+    );
+    Ok(())
+}
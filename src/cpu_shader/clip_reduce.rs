@@ -0,0 +1,59 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use bytemuck::Zeroable;
+use vello_encoding::{Clip, ClipBic, ClipElement, PathBbox};
+
+use super::util::WG_SIZE;
+use crate::cpu_dispatch::CpuBinding;
+use crate::engine::Error;
+
+fn clip_reduce_main(
+    n_wg: u32,
+    clip_inp: &[Clip],
+    path_bboxes: &[PathBbox],
+    reduced: &mut [ClipBic],
+    clip_out: &mut [ClipElement],
+) {
+    for wg_ix in 0..n_wg as usize {
+        // Pushes that are unmatched within this workgroup, from the bottom
+        // of the stack up.
+        let mut stack: Vec<usize> = Vec::new();
+        let mut n_pops = 0;
+        for local_ix in 0..WG_SIZE {
+            let inp = clip_inp[wg_ix * WG_SIZE + local_ix];
+            if inp.path_ix >= 0 {
+                stack.push(local_ix);
+            } else if stack.pop().is_none() {
+                n_pops += 1;
+            }
+        }
+        reduced[wg_ix] = ClipBic {
+            a: n_pops,
+            b: stack.len() as u32,
+        };
+        for (i, local_ix) in stack.into_iter().enumerate() {
+            let global_ix = wg_ix * WG_SIZE + local_ix;
+            let path_ix = clip_inp[global_ix].path_ix as usize;
+            let path_bbox = path_bboxes[path_ix];
+            let mut el = ClipElement::zeroed();
+            el.parent_ix = global_ix as u32;
+            el.bbox = [
+                path_bbox.x0 as f32,
+                path_bbox.y0 as f32,
+                path_bbox.x1 as f32,
+                path_bbox.y1 as f32,
+            ];
+            clip_out[wg_ix * WG_SIZE + i] = el;
+        }
+    }
+}
+
+pub fn clip_reduce(n_wg: (u32, u32, u32), resources: &[CpuBinding]) -> Result<(), Error> {
+    let clip_inp = resources[1].as_slice()?;
+    let path_bboxes = resources[2].as_slice()?;
+    let mut reduced = resources[3].as_slice_mut()?;
+    let mut clip_out = resources[4].as_slice_mut()?;
+    clip_reduce_main(n_wg.0, &clip_inp, &path_bboxes, &mut reduced, &mut clip_out);
+    Ok(())
+}
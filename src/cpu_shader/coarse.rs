@@ -0,0 +1,481 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use vello_encoding::{BinHeader, BumpAllocators, ConfigUniform, DrawMonoid, DrawTag, Path, Tile};
+
+use super::util::{N_TILE, N_TILE_X, N_TILE_Y, TILE_HEIGHT, TILE_WIDTH};
+use crate::cpu_dispatch::CpuBinding;
+use crate::engine::Error;
+
+// Bitflags for each stage that can fail allocation.
+const STAGE_BINNING: u32 = 0x1;
+const STAGE_TILE_ALLOC: u32 = 0x2;
+const STAGE_PATH_COARSE: u32 = 0x4;
+const STAGE_COARSE: u32 = 0x8;
+
+// Layout of per-tile command list
+// Initial allocation, in u32's.
+const PTCL_INITIAL_ALLOC: u32 = 64;
+const PTCL_INCREMENT: u32 = 256;
+
+// Amount of space taken by jump
+const PTCL_HEADROOM: u32 = 2;
+
+// Tags for PTCL commands
+const CMD_END: u32 = 0;
+const CMD_FILL: u32 = 1;
+const CMD_STROKE: u32 = 2;
+const CMD_SOLID: u32 = 3;
+const CMD_COLOR: u32 = 5;
+const CMD_LIN_GRAD: u32 = 6;
+const CMD_RAD_GRAD: u32 = 7;
+const CMD_IMAGE: u32 = 8;
+const CMD_BEGIN_CLIP: u32 = 9;
+const CMD_END_CLIP: u32 = 10;
+const CMD_JUMP: u32 = 11;
+const CMD_SWEEP_GRAD: u32 = 12;
+const CMD_BLUR_RECT: u32 = 13;
+
+const BLEND_STACK_SPLIT: u32 = 4;
+
+const BLEND_CLIP: u32 = (128 << 8) | 3;
+
+/// Writer for the command list of a single tile.
+struct TileState {
+    cmd_offset: u32,
+    cmd_limit: u32,
+}
+
+impl TileState {
+    fn new(tile_ix: u32) -> TileState {
+        let cmd_offset = tile_ix * PTCL_INITIAL_ALLOC;
+        let cmd_limit = cmd_offset + (PTCL_INITIAL_ALLOC - PTCL_HEADROOM);
+        TileState {
+            cmd_offset,
+            cmd_limit,
+        }
+    }
+
+    fn write(&mut self, ptcl: &mut [u32], offset: u32, value: u32) {
+        if let Some(slot) = ptcl.get_mut((self.cmd_offset + offset) as usize) {
+            *slot = value;
+        }
+    }
+
+    // Make sure there is space for a command of given size, plus a jump if needed
+    fn alloc_cmd(
+        &mut self,
+        size: u32,
+        config: &ConfigUniform,
+        bump: &mut BumpAllocators,
+        ptcl: &mut [u32],
+    ) {
+        if self.cmd_offset + size >= self.cmd_limit {
+            let ptcl_dyn_start =
+                config.width_in_tiles * config.height_in_tiles * PTCL_INITIAL_ALLOC;
+            let mut new_cmd = ptcl_dyn_start + bump.ptcl;
+            bump.ptcl += PTCL_INCREMENT;
+            if new_cmd + PTCL_INCREMENT > config.ptcl_size {
+                new_cmd = 0;
+                bump.failed |= STAGE_COARSE;
+            }
+            self.write(ptcl, 0, CMD_JUMP);
+            self.write(ptcl, 1, new_cmd);
+            self.cmd_offset = new_cmd;
+            self.cmd_limit = new_cmd + (PTCL_INCREMENT - PTCL_HEADROOM);
+        }
+    }
+
+    fn write_path(
+        &mut self,
+        config: &ConfigUniform,
+        bump: &mut BumpAllocators,
+        ptcl: &mut [u32],
+        tile: &Tile,
+        linewidth: f32,
+    ) -> bool {
+        self.alloc_cmd(3, config, bump, ptcl);
+        if linewidth < 0.0 {
+            let even_odd = linewidth < -1.0;
+            if tile.segments != 0 {
+                let segments_and_rule = (tile.segments << 1) | even_odd as u32;
+                self.write(ptcl, 0, CMD_FILL);
+                self.write(ptcl, 1, segments_and_rule);
+                self.write(ptcl, 2, tile.backdrop as u32);
+                self.cmd_offset += 3;
+            } else {
+                if even_odd && (tile.backdrop.abs() & 1) == 0 {
+                    return false;
+                }
+                self.write(ptcl, 0, CMD_SOLID);
+                self.cmd_offset += 1;
+            }
+        } else {
+            self.write(ptcl, 0, CMD_STROKE);
+            self.write(ptcl, 1, tile.segments);
+            self.write(ptcl, 2, (0.5 * linewidth).to_bits());
+            self.cmd_offset += 3;
+        }
+        true
+    }
+
+    fn write_color(
+        &mut self,
+        config: &ConfigUniform,
+        bump: &mut BumpAllocators,
+        ptcl: &mut [u32],
+        rgba_color: u32,
+    ) {
+        self.alloc_cmd(2, config, bump, ptcl);
+        self.write(ptcl, 0, CMD_COLOR);
+        self.write(ptcl, 1, rgba_color);
+        self.cmd_offset += 2;
+    }
+
+    fn write_grad(
+        &mut self,
+        config: &ConfigUniform,
+        bump: &mut BumpAllocators,
+        ptcl: &mut [u32],
+        ty: u32,
+        index: u32,
+        info_offset: u32,
+    ) {
+        self.alloc_cmd(3, config, bump, ptcl);
+        self.write(ptcl, 0, ty);
+        self.write(ptcl, 1, index);
+        self.write(ptcl, 2, info_offset);
+        self.cmd_offset += 3;
+    }
+
+    fn write_image(
+        &mut self,
+        config: &ConfigUniform,
+        bump: &mut BumpAllocators,
+        ptcl: &mut [u32],
+        info_offset: u32,
+    ) {
+        self.alloc_cmd(2, config, bump, ptcl);
+        self.write(ptcl, 0, CMD_IMAGE);
+        self.write(ptcl, 1, info_offset);
+        self.cmd_offset += 2;
+    }
+
+    fn write_blur_rect(
+        &mut self,
+        config: &ConfigUniform,
+        bump: &mut BumpAllocators,
+        ptcl: &mut [u32],
+        rgba_color: u32,
+        info_offset: u32,
+    ) {
+        self.alloc_cmd(3, config, bump, ptcl);
+        self.write(ptcl, 0, CMD_BLUR_RECT);
+        self.write(ptcl, 1, rgba_color);
+        self.write(ptcl, 2, info_offset);
+        self.cmd_offset += 3;
+    }
+
+    fn write_begin_clip(
+        &mut self,
+        config: &ConfigUniform,
+        bump: &mut BumpAllocators,
+        ptcl: &mut [u32],
+    ) {
+        self.alloc_cmd(1, config, bump, ptcl);
+        self.write(ptcl, 0, CMD_BEGIN_CLIP);
+        self.cmd_offset += 1;
+    }
+
+    fn write_end_clip(
+        &mut self,
+        config: &ConfigUniform,
+        bump: &mut BumpAllocators,
+        ptcl: &mut [u32],
+        blend: u32,
+        alpha: f32,
+    ) {
+        self.alloc_cmd(3, config, bump, ptcl);
+        self.write(ptcl, 0, CMD_END_CLIP);
+        self.write(ptcl, 1, blend);
+        self.write(ptcl, 2, alpha.to_bits());
+        self.cmd_offset += 3;
+    }
+}
+
+/// Placement of a draw object's path relative to a bin.
+struct BinnedPath {
+    drawobj_ix: u32,
+    tag: DrawTag,
+    tile_base: u32,
+    stride: u32,
+    x0: u32,
+    y0: u32,
+    x1: u32,
+    y1: u32,
+}
+
+#[allow(clippy::too_many_arguments)]
+fn coarse_main(
+    n_wg: (u32, u32),
+    config: &ConfigUniform,
+    scene: &[u32],
+    draw_monoids: &[DrawMonoid],
+    bin_headers: &[BinHeader],
+    info_bin_data: &[u32],
+    paths: &[Path],
+    tiles: &[Tile],
+    bump: &mut BumpAllocators,
+    ptcl: &mut [u32],
+) {
+    // Exit early if prior stages failed, as we can't run this stage.
+    if (bump.failed & (STAGE_BINNING | STAGE_TILE_ALLOC | STAGE_PATH_COARSE)) != 0 {
+        return;
+    }
+    let width_in_bins = config.width_in_tiles.div_ceil(N_TILE_X as u32);
+    let n_partitions = config.layout.n_draw_objects.div_ceil(N_TILE as u32);
+    let read_tag = |drawobj_ix: u32| {
+        DrawTag(
+            scene
+                .get((config.layout.draw_tag_base + drawobj_ix) as usize)
+                .copied()
+                .unwrap_or_default(),
+        )
+    };
+    for bin_y in 0..n_wg.1 {
+        for bin_x in 0..n_wg.0 {
+            let bin_ix = width_in_bins * bin_y + bin_x;
+            // Coordinates of the top left of this bin, in tiles.
+            let bin_tile_x = N_TILE_X as u32 * bin_x;
+            let bin_tile_y = N_TILE_Y as u32 * bin_y;
+
+            // Merge the binning results of all partitions, in draw order.
+            let mut binned = vec![];
+            for partition_ix in 0..n_partitions {
+                let in_ix = partition_ix * N_TILE as u32 + bin_ix;
+                let Some(bin_header) = bin_headers.get(in_ix as usize) else {
+                    continue;
+                };
+                let offset = config.layout.bin_data_start + bin_header.chunk_offset;
+                for i in 0..bin_header.element_count {
+                    let drawobj_ix = info_bin_data
+                        .get((offset + i) as usize)
+                        .copied()
+                        .unwrap_or_default();
+                    let tag = read_tag(drawobj_ix);
+                    if tag == DrawTag::NOP {
+                        continue;
+                    }
+                    let path_ix = draw_monoids
+                        .get(drawobj_ix as usize)
+                        .map(|dm| dm.path_ix)
+                        .unwrap_or_default();
+                    let path = paths.get(path_ix as usize).copied().unwrap_or_default();
+                    let stride = path.bbox[2].wrapping_sub(path.bbox[0]);
+                    let dx = path.bbox[0] as i32 - bin_tile_x as i32;
+                    let dy = path.bbox[1] as i32 - bin_tile_y as i32;
+                    let x0 = dx.clamp(0, N_TILE_X as i32);
+                    let y0 = dy.clamp(0, N_TILE_Y as i32);
+                    let x1 = (path.bbox[2] as i32 - bin_tile_x as i32).clamp(0, N_TILE_X as i32);
+                    let y1 = (path.bbox[3] as i32 - bin_tile_y as i32).clamp(0, N_TILE_Y as i32);
+                    // base relative to bin
+                    let tile_base = path
+                        .tiles
+                        .wrapping_sub((dy.wrapping_mul(stride as i32)).wrapping_add(dx) as u32);
+                    binned.push(BinnedPath {
+                        drawobj_ix,
+                        tag,
+                        tile_base,
+                        stride,
+                        x0: x0 as u32,
+                        y0: y0 as u32,
+                        x1: x1 as u32,
+                        y1: y1 as u32,
+                    });
+                }
+            }
+
+            // Write per-tile command list for each tile in the bin.
+            for tile_y in 0..N_TILE_Y as u32 {
+                for tile_x in 0..N_TILE_X as u32 {
+                    if bin_tile_x + tile_x >= config.width_in_tiles
+                        || bin_tile_y + tile_y >= config.height_in_tiles
+                    {
+                        continue;
+                    }
+                    let this_tile_ix =
+                        (bin_tile_y + tile_y) * config.width_in_tiles + bin_tile_x + tile_x;
+                    let mut state = TileState::new(this_tile_ix);
+                    let blend_offset = state.cmd_offset;
+                    state.cmd_offset += 1;
+
+                    // clip state
+                    let mut clip_zero_depth = 0;
+                    let mut clip_depth = 0;
+                    // blend state
+                    let mut render_blend_depth = 0;
+                    let mut max_blend_depth = 0;
+
+                    for el in &binned {
+                        if tile_x < el.x0 || tile_x >= el.x1 || tile_y < el.y0 || tile_y >= el.y1 {
+                            continue;
+                        }
+                        let tile_ix = el
+                            .tile_base
+                            .wrapping_add(el.stride.wrapping_mul(tile_y))
+                            .wrapping_add(tile_x);
+                        let tile = tiles.get(tile_ix as usize).copied().unwrap_or_default();
+                        let dm = draw_monoids
+                            .get(el.drawobj_ix as usize)
+                            .copied()
+                            .unwrap_or_default();
+                        let dd = config.layout.draw_data_base + dm.scene_offset;
+                        let di = dm.info_offset;
+                        let scene_at =
+                            |ix: u32| scene.get(ix as usize).copied().unwrap_or_default();
+                        let is_clip = (el.tag.0 & 1) != 0;
+                        let is_blend = is_clip && scene_at(dd) != BLEND_CLIP;
+                        let include_tile =
+                            tile.segments != 0 || (tile.backdrop == 0) == is_clip || is_blend;
+                        if !include_tile {
+                            continue;
+                        }
+                        let linewidth = || {
+                            f32::from_bits(
+                                info_bin_data.get(di as usize).copied().unwrap_or_default(),
+                            )
+                        };
+                        if clip_zero_depth == 0 {
+                            match el.tag {
+                                // The guards write the path; a brush follows unless the
+                                // tile has nothing to cover.
+                                DrawTag::COLOR
+                                    if state.write_path(config, bump, ptcl, &tile, linewidth()) =>
+                                {
+                                    state.write_color(config, bump, ptcl, scene_at(dd));
+                                }
+                                DrawTag::LINEAR_GRADIENT
+                                    if state.write_path(config, bump, ptcl, &tile, linewidth()) =>
+                                {
+                                    let index = scene_at(dd);
+                                    state.write_grad(
+                                        config,
+                                        bump,
+                                        ptcl,
+                                        CMD_LIN_GRAD,
+                                        index,
+                                        di + 1,
+                                    );
+                                }
+                                DrawTag::RADIAL_GRADIENT
+                                    if state.write_path(config, bump, ptcl, &tile, linewidth()) =>
+                                {
+                                    let index = scene_at(dd);
+                                    state.write_grad(
+                                        config,
+                                        bump,
+                                        ptcl,
+                                        CMD_RAD_GRAD,
+                                        index,
+                                        di + 1,
+                                    );
+                                }
+                                DrawTag::SWEEP_GRADIENT
+                                    if state.write_path(config, bump, ptcl, &tile, linewidth()) =>
+                                {
+                                    let index = scene_at(dd);
+                                    state.write_grad(
+                                        config,
+                                        bump,
+                                        ptcl,
+                                        CMD_SWEEP_GRAD,
+                                        index,
+                                        di + 1,
+                                    );
+                                }
+                                DrawTag::IMAGE
+                                    if state.write_path(config, bump, ptcl, &tile, linewidth()) =>
+                                {
+                                    state.write_image(config, bump, ptcl, di + 1);
+                                }
+                                DrawTag::BLUR_RECT
+                                    if state.write_path(config, bump, ptcl, &tile, linewidth()) =>
+                                {
+                                    let rgba_color = scene_at(dd);
+                                    state.write_blur_rect(config, bump, ptcl, rgba_color, di + 1);
+                                }
+                                DrawTag::BEGIN_CLIP => {
+                                    if tile.segments == 0 && tile.backdrop == 0 {
+                                        clip_zero_depth = clip_depth + 1;
+                                    } else {
+                                        state.write_begin_clip(config, bump, ptcl);
+                                        render_blend_depth += 1;
+                                        max_blend_depth = max_blend_depth.max(render_blend_depth);
+                                    }
+                                    clip_depth += 1;
+                                }
+                                DrawTag::END_CLIP => {
+                                    clip_depth -= 1;
+                                    state.write_path(config, bump, ptcl, &tile, -1.0);
+                                    let blend = scene_at(dd);
+                                    let alpha = f32::from_bits(scene_at(dd + 1));
+                                    state.write_end_clip(config, bump, ptcl, blend, alpha);
+                                    render_blend_depth -= 1;
+                                }
+                                _ => {}
+                            }
+                        } else {
+                            // In "clip zero" state, suppress all drawing
+                            match el.tag {
+                                DrawTag::BEGIN_CLIP => {
+                                    clip_depth += 1;
+                                }
+                                DrawTag::END_CLIP => {
+                                    if clip_depth == clip_zero_depth {
+                                        clip_zero_depth = 0;
+                                    }
+                                    clip_depth -= 1;
+                                }
+                                _ => {}
+                            }
+                        }
+                    }
+
+                    state.write(ptcl, 0, CMD_END);
+                    if max_blend_depth > BLEND_STACK_SPLIT {
+                        let scratch_size = max_blend_depth * TILE_WIDTH * TILE_HEIGHT;
+                        if let Some(slot) = ptcl.get_mut(blend_offset as usize) {
+                            *slot = bump.blend;
+                        }
+                        bump.blend += scratch_size;
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn coarse(n_wg: (u32, u32, u32), resources: &[CpuBinding]) -> Result<(), Error> {
+    let config = resources[0].as_typed()?;
+    let scene = resources[1].as_slice()?;
+    let draw_monoids = resources[2].as_slice()?;
+    let bin_headers = resources[3].as_slice()?;
+    let info_bin_data = resources[4].as_slice()?;
+    let paths = resources[5].as_slice()?;
+    let tiles = resources[6].as_slice()?;
+    let mut bump = resources[7].as_typed_mut()?;
+    let mut ptcl = resources[8].as_slice_mut()?;
+    coarse_main(
+        (n_wg.0, n_wg.1),
+        &config,
+        &scene,
+        &draw_monoids,
+        &bin_headers,
+        &info_bin_data,
+        &paths,
+        &tiles,
+        &mut bump,
+        &mut ptcl,
+    );
+    Ok(())
+}
@@ -0,0 +1,285 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use vello_encoding::{Clip, ConfigUniform, DrawMonoid, DrawTag, Monoid, PathBbox, Transform};
+
+use super::util::{
+    read_draw_tag_from_scene, read_transform, transform_apply, transform_inverse, transform_mul,
+    Vec2, WG_SIZE,
+};
+use crate::cpu_dispatch::CpuBinding;
+use crate::engine::Error;
+
+// Radial gradient kinds
+const RAD_GRAD_KIND_CIRCULAR: u32 = 1;
+const RAD_GRAD_KIND_STRIP: u32 = 2;
+const RAD_GRAD_KIND_FOCAL_ON_CIRCLE: u32 = 3;
+const RAD_GRAD_KIND_CONE: u32 = 4;
+
+// Radial gradient flags
+const RAD_GRAD_SWAPPED: u32 = 1;
+
+#[allow(clippy::too_many_arguments)]
+fn draw_leaf_main(
+    n_wg: u32,
+    config: &ConfigUniform,
+    scene: &[u32],
+    reduced: &[DrawMonoid],
+    path_bbox: &[PathBbox],
+    draw_monoid: &mut [DrawMonoid],
+    info: &mut [u32],
+    clip_inp: &mut [Clip],
+) {
+    let mut prefix = DrawMonoid::default();
+    for i in 0..n_wg {
+        // As in draw_reduce, the prefix of the first workgroup is the identity,
+        // while later prefixes keep the pattern flag of the first reduction.
+        let mut m = prefix;
+        for j in 0..WG_SIZE as u32 {
+            let ix = i * WG_SIZE as u32 + j;
+            let tag_word = read_draw_tag_from_scene(config, scene, ix);
+            let next = m.combine(&DrawMonoid::new(tag_word));
+            if config.layout.n_pattern_instances != 0 && ix < config.layout.n_draw_objects {
+                // Draw objects expanded by the pattern stage have their own
+                // path and draw data.
+                let slot = (config.pattern_slot_base + ix * 3) as usize;
+                m.path_ix = scene[slot];
+                m.scene_offset = scene[slot + 1];
+            }
+            if ix < config.layout.n_draw_objects {
+                draw_monoid[ix as usize] = m;
+            }
+            let dd = (config.layout.draw_data_base + m.scene_offset) as usize;
+            let di = m.info_offset as usize;
+            if tag_word == DrawTag::COLOR
+                || tag_word == DrawTag::LINEAR_GRADIENT
+                || tag_word == DrawTag::RADIAL_GRADIENT
+                || tag_word == DrawTag::SWEEP_GRADIENT
+                || tag_word == DrawTag::IMAGE
+                || tag_word == DrawTag::BLUR_RECT
+                || tag_word == DrawTag::BEGIN_CLIP
+            {
+                let bbox = path_bbox
+                    .get(m.path_ix as usize)
+                    .copied()
+                    .unwrap_or_default();
+                let mut transform = Transform {
+                    matrix: [0.0; 4],
+                    translation: [0.0; 2],
+                };
+                let mut linewidth = bbox.linewidth;
+                if linewidth >= 0.0
+                    || tag_word == DrawTag::LINEAR_GRADIENT
+                    || tag_word == DrawTag::RADIAL_GRADIENT
+                    || tag_word == DrawTag::SWEEP_GRADIENT
+                    || tag_word == DrawTag::IMAGE
+                    || tag_word == DrawTag::BLUR_RECT
+                {
+                    transform = read_transform(scene, config.layout.transform_base, bbox.trans_ix);
+                }
+                if linewidth >= 0.0 {
+                    // Note: doesn't deal with anisotropic case
+                    let matrx = transform.matrix;
+                    linewidth *= (matrx[0] * matrx[3] - matrx[1] * matrx[2]).abs().sqrt();
+                }
+                match tag_word {
+                    DrawTag::COLOR => {
+                        info[di] = linewidth.to_bits();
+                    }
+                    DrawTag::LINEAR_GRADIENT => {
+                        info[di] = linewidth.to_bits();
+                        let p0 = read_point(scene, dd + 1);
+                        let p1 = read_point(scene, dd + 3);
+                        let p0 = transform_apply(&transform, p0);
+                        let p1 = transform_apply(&transform, p1);
+                        let dxy = p1 - p0;
+                        let scale = 1.0 / dxy.dot(dxy);
+                        let line_xy = dxy * scale;
+                        let line_c = -p0.dot(line_xy);
+                        info[di + 1] = line_xy.x.to_bits();
+                        info[di + 2] = line_xy.y.to_bits();
+                        info[di + 3] = line_c.to_bits();
+                    }
+                    DrawTag::RADIAL_GRADIENT => {
+                        // Two-point conical gradient implementation based
+                        // on the algorithm at <https://skia.org/docs/dev/design/conical/>
+                        // This epsilon matches what Skia uses
+                        const GRADIENT_EPSILON: f32 = 1.0 / (1 << 12) as f32;
+                        info[di] = linewidth.to_bits();
+                        let mut p0 = read_point(scene, dd + 1);
+                        let mut p1 = read_point(scene, dd + 3);
+                        let mut r0 = f32::from_bits(scene[dd + 5]);
+                        let mut r1 = f32::from_bits(scene[dd + 6]);
+                        let user_to_gradient = transform_inverse(&transform);
+                        let xform;
+                        let mut focal_x = 0.0;
+                        let radius;
+                        let mut kind;
+                        let mut flags = 0;
+                        if (r0 - r1).abs() <= GRADIENT_EPSILON {
+                            // When the radii are the same, emit a strip gradient
+                            kind = RAD_GRAD_KIND_STRIP;
+                            let scaled = r0 / p0.distance(p1);
+                            xform =
+                                transform_mul(&two_point_to_unit_line(p0, p1), &user_to_gradient);
+                            radius = scaled * scaled;
+                        } else {
+                            // Assume a two point conical gradient unless the centers
+                            // are equal.
+                            kind = RAD_GRAD_KIND_CONE;
+                            if p0 == p1 {
+                                kind = RAD_GRAD_KIND_CIRCULAR;
+                                // Nudge p0 a bit to avoid denormals.
+                                p0 = p0 + Vec2::splat(GRADIENT_EPSILON);
+                            }
+                            if r1 == 0.0 {
+                                // If r1 == 0.0, swap the points and radii
+                                flags |= RAD_GRAD_SWAPPED;
+                                std::mem::swap(&mut p0, &mut p1);
+                                std::mem::swap(&mut r0, &mut r1);
+                            }
+                            focal_x = r0 / (r0 - r1);
+                            let cf = p0 * (1.0 - focal_x) + p1 * focal_x;
+                            radius = r1 / cf.distance(p1);
+                            let user_to_unit_line =
+                                transform_mul(&two_point_to_unit_line(cf, p1), &user_to_gradient);
+                            // When r == 1.0, focal point is on circle
+                            let user_to_scaled = if (radius - 1.0).abs() <= GRADIENT_EPSILON {
+                                kind = RAD_GRAD_KIND_FOCAL_ON_CIRCLE;
+                                let scale = 0.5 * (1.0 - focal_x).abs();
+                                transform_mul(&scale_transform(scale, scale), &user_to_unit_line)
+                            } else {
+                                let a = radius * radius - 1.0;
+                                let scale_ratio = (1.0 - focal_x).abs() / a;
+                                let scale_x = radius * scale_ratio;
+                                let scale_y = a.abs().sqrt() * scale_ratio;
+                                transform_mul(
+                                    &scale_transform(scale_x, scale_y),
+                                    &user_to_unit_line,
+                                )
+                            };
+                            xform = user_to_scaled;
+                        }
+                        info[di + 1] = xform.matrix[0].to_bits();
+                        info[di + 2] = xform.matrix[1].to_bits();
+                        info[di + 3] = xform.matrix[2].to_bits();
+                        info[di + 4] = xform.matrix[3].to_bits();
+                        info[di + 5] = xform.translation[0].to_bits();
+                        info[di + 6] = xform.translation[1].to_bits();
+                        info[di + 7] = f32::to_bits(focal_x);
+                        info[di + 8] = radius.to_bits();
+                        info[di + 9] = (flags << 3) | kind;
+                    }
+                    DrawTag::SWEEP_GRADIENT => {
+                        info[di] = linewidth.to_bits();
+                        let p0 = read_point(scene, dd + 1);
+                        // Map user space to gradient space, centered on p0
+                        let xform = transform_mul(
+                            &Transform {
+                                matrix: [1.0, 0.0, 0.0, 1.0],
+                                translation: [-p0.x, -p0.y],
+                            },
+                            &transform_inverse(&transform),
+                        );
+                        info[di + 1] = xform.matrix[0].to_bits();
+                        info[di + 2] = xform.matrix[1].to_bits();
+                        info[di + 3] = xform.matrix[2].to_bits();
+                        info[di + 4] = xform.matrix[3].to_bits();
+                        info[di + 5] = xform.translation[0].to_bits();
+                        info[di + 6] = xform.translation[1].to_bits();
+                        info[di + 7] = scene[dd + 3];
+                        info[di + 8] = scene[dd + 4];
+                    }
+                    DrawTag::IMAGE => {
+                        info[di] = linewidth.to_bits();
+                        let inv = transform_inverse(&transform);
+                        info[di + 1] = inv.matrix[0].to_bits();
+                        info[di + 2] = inv.matrix[1].to_bits();
+                        info[di + 3] = inv.matrix[2].to_bits();
+                        info[di + 4] = inv.matrix[3].to_bits();
+                        info[di + 5] = inv.translation[0].to_bits();
+                        info[di + 6] = inv.translation[1].to_bits();
+                        info[di + 7] = scene[dd];
+                        info[di + 8] = scene[dd + 1];
+                        info[di + 9] = scene[dd + 2];
+                    }
+                    DrawTag::BLUR_RECT => {
+                        info[di] = linewidth.to_bits();
+                        let inv = transform_inverse(&transform);
+                        info[di + 1] = inv.matrix[0].to_bits();
+                        info[di + 2] = inv.matrix[1].to_bits();
+                        info[di + 3] = inv.matrix[2].to_bits();
+                        info[di + 4] = inv.matrix[3].to_bits();
+                        info[di + 5] = inv.translation[0].to_bits();
+                        info[di + 6] = inv.translation[1].to_bits();
+                        info[di + 7] = scene[dd + 1];
+                        info[di + 8] = scene[dd + 2];
+                        info[di + 9] = scene[dd + 3];
+                        info[di + 10] = scene[dd + 4];
+                    }
+                    _ => {}
+                }
+            }
+            if tag_word == DrawTag::BEGIN_CLIP || tag_word == DrawTag::END_CLIP {
+                let path_ix = if tag_word == DrawTag::BEGIN_CLIP {
+                    m.path_ix as i32
+                } else {
+                    !ix as i32
+                };
+                clip_inp[m.clip_ix as usize] = Clip { ix, path_ix };
+            }
+            m = next;
+        }
+        prefix = if i == 0 {
+            reduced[0]
+        } else {
+            prefix.combine(&reduced[i as usize])
+        };
+    }
+}
+
+fn read_point(scene: &[u32], ix: usize) -> Vec2 {
+    Vec2::new(f32::from_bits(scene[ix]), f32::from_bits(scene[ix + 1]))
+}
+
+fn scale_transform(x: f32, y: f32) -> Transform {
+    Transform {
+        matrix: [x, 0.0, 0.0, y],
+        translation: [0.0; 2],
+    }
+}
+
+fn two_point_to_unit_line(p0: Vec2, p1: Vec2) -> Transform {
+    let tmp1 = from_poly2(p0, p1);
+    let inv = transform_inverse(&tmp1);
+    let tmp2 = from_poly2(Vec2::default(), Vec2::new(1.0, 0.0));
+    transform_mul(&tmp2, &inv)
+}
+
+fn from_poly2(p0: Vec2, p1: Vec2) -> Transform {
+    Transform {
+        matrix: [p1.y - p0.y, p0.x - p1.x, p1.x - p0.x, p1.y - p0.y],
+        translation: [p0.x, p0.y],
+    }
+}
+
+pub fn draw_leaf(n_wg: (u32, u32, u32), resources: &[CpuBinding]) -> Result<(), Error> {
+    let config = resources[0].as_typed()?;
+    let scene = resources[1].as_slice()?;
+    let reduced = resources[2].as_slice()?;
+    let path_bbox = resources[3].as_slice()?;
+    let mut draw_monoid = resources[4].as_slice_mut()?;
+    let mut info = resources[5].as_slice_mut()?;
+    let mut clip_inp = resources[6].as_slice_mut()?;
+    draw_leaf_main(
+        n_wg.0,
+        &config,
+        &scene,
+        &reduced,
+        &path_bbox,
+        &mut draw_monoid,
+        &mut info,
+        &mut clip_inp,
+    );
+    Ok(())
+}
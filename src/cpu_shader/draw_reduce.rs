@@ -0,0 +1,34 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use vello_encoding::{ConfigUniform, DrawMonoid, Monoid};
+
+use super::util::{read_draw_tag_from_scene, WG_SIZE};
+use crate::cpu_dispatch::CpuBinding;
+use crate::engine::Error;
+
+fn draw_reduce_main(n_wg: u32, config: &ConfigUniform, scene: &[u32], reduced: &mut [DrawMonoid]) {
+    for i in 0..n_wg {
+        // The fold starts from the first element rather than the identity, as
+        // the left operand determines the pattern flag bit of the result.
+        let m = (0..WG_SIZE as u32)
+            .map(|j| {
+                DrawMonoid::new(read_draw_tag_from_scene(
+                    config,
+                    scene,
+                    i * WG_SIZE as u32 + j,
+                ))
+            })
+            .reduce(|a, b| a.combine(&b))
+            .unwrap();
+        reduced[i as usize] = m;
+    }
+}
+
+pub fn draw_reduce(n_wg: (u32, u32, u32), resources: &[CpuBinding]) -> Result<(), Error> {
+    let config = resources[0].as_typed()?;
+    let scene = resources[1].as_slice()?;
+    let mut reduced = resources[2].as_slice_mut()?;
+    draw_reduce_main(n_wg.0, &config, &scene, &mut reduced);
+    Ok(())
+}
@@ -0,0 +1,689 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use vello_encoding::{ColorSpace, ConfigUniform, DrawImage, PathSegment, Tile};
+
+use super::{
+    blend::blend_mix_compose,
+    util::{pack4x8unorm, unpack4x8unorm, Vec2, TILE_HEIGHT, TILE_WIDTH},
+};
+use crate::cpu_dispatch::{CpuBinding, CpuTexture};
+use crate::engine::Error;
+
+const PTCL_INITIAL_ALLOC: u32 = 64;
+
+// Tags for PTCL commands
+const CMD_END: u32 = 0;
+const CMD_FILL: u32 = 1;
+const CMD_STROKE: u32 = 2;
+const CMD_SOLID: u32 = 3;
+const CMD_COLOR: u32 = 5;
+const CMD_LIN_GRAD: u32 = 6;
+const CMD_RAD_GRAD: u32 = 7;
+const CMD_IMAGE: u32 = 8;
+const CMD_BEGIN_CLIP: u32 = 9;
+const CMD_END_CLIP: u32 = 10;
+const CMD_JUMP: u32 = 11;
+const CMD_SWEEP_GRAD: u32 = 12;
+const CMD_BLUR_RECT: u32 = 13;
+
+const BLEND_STACK_SPLIT: usize = 4;
+
+// Radial gradient kinds
+const RAD_GRAD_KIND_CIRCULAR: u32 = 1;
+const RAD_GRAD_KIND_STRIP: u32 = 2;
+const RAD_GRAD_KIND_FOCAL_ON_CIRCLE: u32 = 3;
+
+// Radial gradient flags
+const RAD_GRAD_SWAPPED: u32 = 1;
+
+const GRADIENT_WIDTH: i32 = 512;
+
+const PIXELS_PER_THREAD: usize = 4;
+
+type Rgba = [f32; 4];
+
+/// Equivalent of WGSL `sign`, which maps zero to zero.
+fn sign(x: f32) -> f32 {
+    if x > 0.0 {
+        1.0
+    } else if x < 0.0 {
+        -1.0
+    } else {
+        0.0
+    }
+}
+
+/// Equivalent of WGSL `round`, which rounds half to even.
+fn round(x: f32) -> f32 {
+    x.round_ties_even()
+}
+
+fn fract(x: f32) -> f32 {
+    x - x.floor()
+}
+
+fn extend_mode(t: f32, mode: u32) -> f32 {
+    match mode {
+        // EXTEND_PAD
+        0 => t.clamp(0.0, 1.0),
+        // EXTEND_REPEAT
+        1 => fract(t),
+        // EXTEND_REFLECT
+        _ => (t - 2.0 * round(0.5 * t)).abs(),
+    }
+}
+
+/// Applies an extend mode to the integer coordinate of a texel in an image of
+/// the given size.
+fn extend_texel(x: i32, size: i32, mode: u32) -> i32 {
+    match mode {
+        // EXTEND_PAD
+        0 => x.clamp(0, size - 1),
+        // EXTEND_REPEAT
+        1 => x.rem_euclid(size),
+        // EXTEND_REFLECT
+        _ => {
+            let r = x.rem_euclid(2 * size);
+            if r >= size {
+                2 * size - 1 - r
+            } else {
+                r
+            }
+        }
+    }
+}
+
+/// Weights of the Mitchell-Netravali filter with B = C = 1/3 for the four
+/// texels around a sample at fractional offset t from the second one.
+fn cubic_weights(t: f32) -> [f32; 4] {
+    [1.0 + t, t, 1.0 - t, 2.0 - t].map(|x| {
+        if x < 1.0 {
+            (7.0 * x * x * x - 12.0 * x * x + 16.0 / 3.0) * (1.0 / 6.0)
+        } else {
+            (-7.0 / 3.0 * x * x * x + 12.0 * x * x - 20.0 * x + 32.0 / 3.0) * (1.0 / 6.0)
+        }
+    })
+}
+
+/// Approximation of the error function with a maximum error of about 1e-4, see
+/// <https://raphlinus.github.io/audio/2018/09/05/sigmoid.html>
+fn erf7(x: f32) -> f32 {
+    let y = x * std::f32::consts::FRAC_2_SQRT_PI;
+    let yy = y * y;
+    let z = y + (0.24295 + (0.03395 + 0.0104 * yy) * yy) * (y * yy);
+    z / (1.0 + z * z).sqrt()
+}
+
+/// Coverage of a rounded rectangle centered at the origin, convolved with a
+/// gaussian. This approximates the rectangle as a superellipse, see
+/// <https://raphlinus.github.io/graphics/2020/04/21/blurred-rounded-rects.html>
+fn blur_rect_coverage(xy: Vec2, size: Vec2, radius: f32, std_dev: f32) -> f32 {
+    // Scale so that erf of the distance over s is the integral of the gaussian.
+    let s = std_dev.max(1e-3) * std::f32::consts::SQRT_2;
+    let s_inv = 1.0 / s;
+    let min_edge = size.x.min(size.y);
+    let r_max = 0.5 * min_edge;
+    let r0 = radius.hypot(1.15 * s).min(r_max);
+    let r1 = radius.hypot(2.0 * s).min(r_max);
+    let exponent = 2.0 * r1 / r0;
+    // Pull in the long end, making the rectangle less eccentric.
+    let ex = 0.5 * s_inv * size.x;
+    let ey = 0.5 * s_inv * size.y;
+    let delta = 1.25 * s * ((-ex * ex).exp() - (-ey * ey).exp());
+    let w = size.x + delta.min(0.0);
+    let h = size.y - delta.max(0.0);
+    let scale = 0.5 * erf7(s_inv * 0.5 * (w.max(h) - 0.5 * radius));
+    let x0 = xy.x.abs() - (0.5 * w - r1);
+    let y0 = xy.y.abs() - (0.5 * h - r1);
+    let x1 = x0.max(0.0);
+    let y1 = y0.max(0.0);
+    let d_pos = (x1.powf(exponent) + y1.powf(exponent)).powf(1.0 / exponent);
+    let d_neg = x0.max(y0).min(0.0);
+    let d = d_pos + d_neg - r1;
+    scale * (erf7(s_inv * (min_edge + d)) - erf7(s_inv * d))
+}
+
+/// Angle of a point around the origin in radians, in the range [0, 2pi).
+fn xy_to_angle(xy: Vec2) -> f32 {
+    let angle = xy.y.atan2(xy.x);
+    if angle < 0.0 {
+        angle + std::f32::consts::TAU
+    } else {
+        angle
+    }
+}
+
+fn scale(c: Rgba, s: f32) -> Rgba {
+    c.map(|x| x * s)
+}
+
+fn over(bg: Rgba, fg: Rgba) -> Rgba {
+    [0, 1, 2, 3].map(|i| bg[i] * (1.0 - fg[3]) + fg[i])
+}
+
+fn mix4(a: Rgba, b: Rgba, t: f32) -> Rgba {
+    [0, 1, 2, 3].map(|i| a[i] * (1.0 - t) + b[i] * t)
+}
+
+fn premul_alpha(rgba: Rgba) -> Rgba {
+    [
+        rgba[0] * rgba[3],
+        rgba[1] * rgba[3],
+        rgba[2] * rgba[3],
+        rgba[3],
+    ]
+}
+
+/// Decodes an sRGB encoded color channel to linear light.
+fn srgb_to_linear(c: f32) -> f32 {
+    if c <= 0.04045 {
+        c * (1.0 / 12.92)
+    } else {
+        ((c + 0.055) * (1.0 / 1.055)).powf(2.4)
+    }
+}
+
+/// Encodes a linear color channel to sRGB.
+fn linear_to_srgb(c: f32) -> f32 {
+    if c <= 0.0031308 {
+        c * 12.92
+    } else {
+        1.055 * c.max(0.0).powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Applies a transfer function to the color channels of a premultiplied color.
+fn map_premul(rgba: Rgba, f: fn(f32) -> f32) -> Rgba {
+    if rgba[3] == 0.0 {
+        return rgba;
+    }
+    let a = rgba[3];
+    [
+        f(rgba[0] / a) * a,
+        f(rgba[1] / a) * a,
+        f(rgba[2] / a) * a,
+        a,
+    ]
+}
+
+/// Reorders a color unpacked from the scene, which stores red in the most
+/// significant byte.
+fn wzyx(c: Rgba) -> Rgba {
+    [c[3], c[2], c[1], c[0]]
+}
+
+fn fill_path(
+    segments: &[PathSegment],
+    tile: &Tile,
+    xy: Vec2,
+    even_odd: bool,
+) -> [f32; PIXELS_PER_THREAD] {
+    let mut area = [tile.backdrop as f32; PIXELS_PER_THREAD];
+    let mut segment_ix = tile.segments;
+    while segment_ix != 0 {
+        let segment = segments
+            .get(segment_ix as usize)
+            .copied()
+            .unwrap_or_default();
+        let y = segment.origin[1] - xy.y;
+        let y0 = y.clamp(0.0, 1.0);
+        let y1 = (y + segment.delta[1]).clamp(0.0, 1.0);
+        let dy = y0 - y1;
+        if dy != 0.0 {
+            let vec_y_recip = 1.0 / segment.delta[1];
+            let t0 = (y0 - y) * vec_y_recip;
+            let t1 = (y1 - y) * vec_y_recip;
+            let startx = segment.origin[0] - xy.x;
+            let x0 = startx + t0 * segment.delta[0];
+            let x1 = startx + t1 * segment.delta[0];
+            let xmin0 = x0.min(x1);
+            let xmax0 = x0.max(x1);
+            for (i, a_i) in area.iter_mut().enumerate() {
+                let i_f = i as f32;
+                let xmin = (xmin0 - i_f).min(1.0) - 1.0e-6;
+                let xmax = xmax0 - i_f;
+                let b = xmax.min(1.0);
+                let c = b.max(0.0);
+                let d = xmin.max(0.0);
+                let a = (b + 0.5 * (d * d - c * c) - xmin) / (xmax - xmin);
+                *a_i += a * dy;
+            }
+        }
+        let y_edge = sign(segment.delta[0]) * (xy.y - segment.y_edge + 1.0).clamp(0.0, 1.0);
+        for a_i in &mut area {
+            *a_i += y_edge;
+        }
+        segment_ix = segment.next;
+    }
+    if even_odd {
+        // even-odd winding rule
+        area.map(|a| (a - 2.0 * round(0.5 * a)).abs())
+    } else {
+        // non-zero winding rule
+        area.map(|a| a.abs().min(1.0))
+    }
+}
+
+fn stroke_path(
+    segments: &[PathSegment],
+    seg: u32,
+    half_width: f32,
+    xy: Vec2,
+) -> [f32; PIXELS_PER_THREAD] {
+    let mut df = [1e9f32; PIXELS_PER_THREAD];
+    let mut segment_ix = seg;
+    while segment_ix != 0 {
+        let segment = segments
+            .get(segment_ix as usize)
+            .copied()
+            .unwrap_or_default();
+        let delta = Vec2::from_array(segment.delta);
+        let dpos0 = xy + Vec2::splat(0.5) - Vec2::from_array(segment.origin);
+        let scale = 1.0 / delta.dot(delta);
+        for (i, df_i) in df.iter_mut().enumerate() {
+            let dpos = Vec2::new(dpos0.x + i as f32, dpos0.y);
+            let t = (dpos.dot(delta) * scale).clamp(0.0, 1.0);
+            *df_i = df_i.min((delta * t - dpos).length());
+        }
+        segment_ix = segment.next;
+    }
+    // reuse array; return alpha rather than distance
+    df.map(|d| (half_width + 0.5 - d).clamp(0.0, 1.0))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn fine_main(
+    n_wg: (u32, u32),
+    config: &ConfigUniform,
+    segments: &[PathSegment],
+    output: &mut CpuTexture,
+    ptcl: &[u32],
+    gradients: &CpuTexture,
+    info: &[u32],
+    image_atlas: &CpuTexture,
+    layer_images: &CpuTexture,
+) {
+    let ptcl_at = |ix: u32| ptcl.get(ix as usize).copied().unwrap_or_default();
+    let info_at = |ix: u32| info.get(ix as usize).copied().unwrap_or_default();
+    let info_f32 = |ix: u32| f32::from_bits(info_at(ix));
+    let blend_linear = config.blend_color_space == ColorSpace::LinearSrgb as u32;
+    // Converts a premultiplied sRGB color to the color space used for blending, and back.
+    let to_blend_space = |rgba: Rgba| {
+        if blend_linear {
+            map_premul(rgba, srgb_to_linear)
+        } else {
+            rgba
+        }
+    };
+    let from_blend_space = |rgba: Rgba| {
+        if blend_linear {
+            map_premul(rgba, linear_to_srgb)
+        } else {
+            rgba
+        }
+    };
+    let base_color = to_blend_space(wzyx(unpack4x8unorm(config.base_color)));
+    for wg_y in 0..n_wg.1 {
+        for wg_x in 0..n_wg.0 {
+            let tile_ix = wg_y * config.width_in_tiles + wg_x;
+            for local_y in 0..TILE_HEIGHT {
+                for local_x in 0..TILE_WIDTH / PIXELS_PER_THREAD as u32 {
+                    let global_x = wg_x * (TILE_WIDTH / PIXELS_PER_THREAD as u32) + local_x;
+                    let global_y = wg_y * TILE_HEIGHT + local_y;
+                    let xy = Vec2::new(
+                        (global_x * PIXELS_PER_THREAD as u32) as f32,
+                        global_y as f32,
+                    );
+                    let mut rgba = [base_color; PIXELS_PER_THREAD];
+                    let mut blend_stack = [[0u32; PIXELS_PER_THREAD]; BLEND_STACK_SPLIT];
+                    let mut clip_depth = 0;
+                    let mut area = [0f32; PIXELS_PER_THREAD];
+                    let mut cmd_ix = tile_ix * PTCL_INITIAL_ALLOC;
+                    cmd_ix += 1;
+                    // main interpretation loop
+                    loop {
+                        let tag = ptcl_at(cmd_ix);
+                        if tag == CMD_END {
+                            break;
+                        }
+                        match tag {
+                            CMD_FILL => {
+                                let fill_tile = ptcl_at(cmd_ix + 1);
+                                let backdrop = ptcl_at(cmd_ix + 2) as i32;
+                                let tile = Tile {
+                                    backdrop,
+                                    segments: fill_tile >> 1,
+                                };
+                                let even_odd = (fill_tile & 1) != 0;
+                                area = fill_path(segments, &tile, xy, even_odd);
+                                cmd_ix += 3;
+                            }
+                            CMD_STROKE => {
+                                let stroke_tile = ptcl_at(cmd_ix + 1);
+                                let half_width = f32::from_bits(ptcl_at(cmd_ix + 2));
+                                area = stroke_path(segments, stroke_tile, half_width, xy);
+                                cmd_ix += 3;
+                            }
+                            CMD_SOLID => {
+                                area = [1.0; PIXELS_PER_THREAD];
+                                cmd_ix += 1;
+                            }
+                            CMD_COLOR => {
+                                let fg = to_blend_space(wzyx(unpack4x8unorm(ptcl_at(cmd_ix + 1))));
+                                for i in 0..PIXELS_PER_THREAD {
+                                    rgba[i] = over(rgba[i], scale(fg, area[i]));
+                                }
+                                cmd_ix += 2;
+                            }
+                            CMD_LIN_GRAD => {
+                                let index_mode = ptcl_at(cmd_ix + 1);
+                                let index = index_mode >> 2;
+                                let mode = index_mode & 0x3;
+                                let info_offset = ptcl_at(cmd_ix + 2);
+                                let line_x = info_f32(info_offset);
+                                let line_y = info_f32(info_offset + 1);
+                                let line_c = info_f32(info_offset + 2);
+                                let d = line_x * xy.x + line_y * xy.y + line_c;
+                                for i in 0..PIXELS_PER_THREAD {
+                                    let my_d = d + line_x * i as f32;
+                                    let x = round(
+                                        extend_mode(my_d, mode) * (GRADIENT_WIDTH - 1) as f32,
+                                    ) as i32;
+                                    let fg_rgba = to_blend_space(unpack4x8unorm(
+                                        gradients.load(x, index as i32),
+                                    ));
+                                    rgba[i] = over(rgba[i], scale(fg_rgba, area[i]));
+                                }
+                                cmd_ix += 3;
+                            }
+                            CMD_RAD_GRAD => {
+                                let index_mode = ptcl_at(cmd_ix + 1);
+                                let index = index_mode >> 2;
+                                let mode = index_mode & 0x3;
+                                let info_offset = ptcl_at(cmd_ix + 2);
+                                let m = [0, 1, 2, 3].map(|i| info_f32(info_offset + i));
+                                let xlat =
+                                    Vec2::new(info_f32(info_offset + 4), info_f32(info_offset + 5));
+                                let focal_x = info_f32(info_offset + 6);
+                                let radius = info_f32(info_offset + 7);
+                                let flags_kind = info_at(info_offset + 8);
+                                let flags = flags_kind >> 3;
+                                let kind = flags_kind & 0x7;
+                                let is_strip = kind == RAD_GRAD_KIND_STRIP;
+                                let is_circular = kind == RAD_GRAD_KIND_CIRCULAR;
+                                let is_focal_on_circle = kind == RAD_GRAD_KIND_FOCAL_ON_CIRCLE;
+                                let is_swapped = (flags & RAD_GRAD_SWAPPED) != 0;
+                                let r1_recip = if is_circular { 0.0 } else { 1.0 / radius };
+                                let less_scale = if is_swapped || (1.0 - focal_x) < 0.0 {
+                                    -1.0
+                                } else {
+                                    1.0
+                                };
+                                let t_sign = sign(1.0 - focal_x);
+                                for i in 0..PIXELS_PER_THREAD {
+                                    let my_xy = Vec2::new(xy.x + i as f32, xy.y);
+                                    let local_xy = Vec2::new(m[0], m[1]) * my_xy.x
+                                        + Vec2::new(m[2], m[3]) * my_xy.y
+                                        + xlat;
+                                    let x = local_xy.x;
+                                    let y = local_xy.y;
+                                    let xx = x * x;
+                                    let yy = y * y;
+                                    let mut t;
+                                    let mut is_valid = true;
+                                    if is_strip {
+                                        let a = radius - yy;
+                                        t = a.sqrt() + x;
+                                        is_valid = a >= 0.0;
+                                    } else if is_focal_on_circle {
+                                        t = (xx + yy) / x;
+                                        is_valid = t >= 0.0 && x != 0.0;
+                                    } else if radius > 1.0 {
+                                        t = (xx + yy).sqrt() - x * r1_recip;
+                                    } else {
+                                        // radius < 1.0
+                                        let a = xx - yy;
+                                        t = less_scale * a.sqrt() - x * r1_recip;
+                                        is_valid = a >= 0.0 && t >= 0.0;
+                                    }
+                                    if is_valid {
+                                        t = extend_mode(focal_x + t_sign * t, mode);
+                                        if is_swapped {
+                                            t = 1.0 - t;
+                                        }
+                                        let x = round(t * (GRADIENT_WIDTH - 1) as f32) as i32;
+                                        let fg_rgba = to_blend_space(unpack4x8unorm(
+                                            gradients.load(x, index as i32),
+                                        ));
+                                        rgba[i] = over(rgba[i], scale(fg_rgba, area[i]));
+                                    }
+                                }
+                                cmd_ix += 3;
+                            }
+                            CMD_SWEEP_GRAD => {
+                                let index_mode = ptcl_at(cmd_ix + 1);
+                                let index = index_mode >> 2;
+                                let mode = index_mode & 0x3;
+                                let info_offset = ptcl_at(cmd_ix + 2);
+                                let m = [0, 1, 2, 3].map(|i| info_f32(info_offset + i));
+                                let xlat =
+                                    Vec2::new(info_f32(info_offset + 4), info_f32(info_offset + 5));
+                                let t0 = info_f32(info_offset + 6);
+                                let t1 = info_f32(info_offset + 7);
+                                let scale_t = 1.0 / (t1 - t0);
+                                for i in 0..PIXELS_PER_THREAD {
+                                    let my_xy = Vec2::new(xy.x + i as f32, xy.y);
+                                    let local_xy = Vec2::new(m[0], m[1]) * my_xy.x
+                                        + Vec2::new(m[2], m[3]) * my_xy.y
+                                        + xlat;
+                                    let t =
+                                        extend_mode((xy_to_angle(local_xy) - t0) * scale_t, mode);
+                                    let x = round(t * (GRADIENT_WIDTH - 1) as f32) as i32;
+                                    let fg_rgba = to_blend_space(unpack4x8unorm(
+                                        gradients.load(x, index as i32),
+                                    ));
+                                    rgba[i] = over(rgba[i], scale(fg_rgba, area[i]));
+                                }
+                                cmd_ix += 3;
+                            }
+                            CMD_BLUR_RECT => {
+                                let fg = to_blend_space(wzyx(unpack4x8unorm(ptcl_at(cmd_ix + 1))));
+                                let info_offset = ptcl_at(cmd_ix + 2);
+                                let m = [0, 1, 2, 3].map(|i| info_f32(info_offset + i));
+                                let xlat =
+                                    Vec2::new(info_f32(info_offset + 4), info_f32(info_offset + 5));
+                                let size =
+                                    Vec2::new(info_f32(info_offset + 6), info_f32(info_offset + 7));
+                                let radius = info_f32(info_offset + 8);
+                                let std_dev = info_f32(info_offset + 9);
+                                for i in 0..PIXELS_PER_THREAD {
+                                    let my_xy = Vec2::new(xy.x + i as f32 + 0.5, xy.y + 0.5);
+                                    let local_xy = Vec2::new(m[0], m[1]) * my_xy.x
+                                        + Vec2::new(m[2], m[3]) * my_xy.y
+                                        + xlat;
+                                    let coverage =
+                                        blur_rect_coverage(local_xy, size, radius, std_dev);
+                                    rgba[i] = over(rgba[i], scale(fg, coverage * area[i]));
+                                }
+                                cmd_ix += 3;
+                            }
+                            CMD_IMAGE => {
+                                let info_offset = ptcl_at(cmd_ix + 1);
+                                let m = [0, 1, 2, 3].map(|i| info_f32(info_offset + i));
+                                let xlat =
+                                    Vec2::new(info_f32(info_offset + 4), info_f32(info_offset + 5));
+                                let xy_packed = info_at(info_offset + 6);
+                                let width_height = info_at(info_offset + 7);
+                                let sampling = info_at(info_offset + 8);
+                                // The following are not intended to be bitcasts
+                                let page = xy_packed >> 26;
+                                let atlas_x = ((xy_packed >> 13) & 0x1fff) as i32;
+                                let atlas_y = (xy_packed & 0x1fff) as i32;
+                                let width = (width_height >> 16) as i32;
+                                let height = (width_height & 0xffff) as i32;
+                                let x_extend = sampling & 0x3;
+                                let y_extend = (sampling >> 2) & 0x3;
+                                let quality = (sampling >> 4) & 0x3;
+                                let alpha = (sampling >> 24) as f32 * (1.0 / 255.0);
+                                let from_layer = sampling & DrawImage::SAMPLING_LAYER != 0;
+                                let load = |x: i32, y: i32| {
+                                    let x = extend_texel(x, width, x_extend) + atlas_x;
+                                    let y = extend_texel(y, height, y_extend) + atlas_y;
+                                    let texel = if from_layer {
+                                        layer_images.load(x, y)
+                                    } else {
+                                        image_atlas.load_layer(x, y, page)
+                                    };
+                                    to_blend_space(premul_alpha(unpack4x8unorm(texel)))
+                                };
+                                // Images that couldn't be allocated in the atlas have zero extents.
+                                if width > 0 && height > 0 {
+                                    for i in 0..PIXELS_PER_THREAD {
+                                        if area[i] == 0.0 {
+                                            continue;
+                                        }
+                                        // Sample at the pixel center, with texel centers at half
+                                        // integers.
+                                        let my_xy = Vec2::new(xy.x + i as f32 + 0.5, xy.y + 0.5);
+                                        let uv = Vec2::new(m[0], m[1]) * my_xy.x
+                                            + Vec2::new(m[2], m[3]) * my_xy.y
+                                            + xlat;
+                                        let uv0 = (uv - Vec2::splat(0.5)).floor();
+                                        let uv_frac = uv - Vec2::splat(0.5) - uv0;
+                                        let (x0, y0) = (uv0.x as i32, uv0.y as i32);
+                                        let fg_rgba = match quality {
+                                            // IMAGE_QUALITY_NEAREST
+                                            0 => {
+                                                let texel = uv.floor();
+                                                load(texel.x as i32, texel.y as i32)
+                                            }
+                                            // IMAGE_QUALITY_BILINEAR
+                                            1 => {
+                                                let a = load(x0, y0);
+                                                let b = load(x0, y0 + 1);
+                                                let c = load(x0 + 1, y0);
+                                                let d = load(x0 + 1, y0 + 1);
+                                                mix4(
+                                                    mix4(a, b, uv_frac.y),
+                                                    mix4(c, d, uv_frac.y),
+                                                    uv_frac.x,
+                                                )
+                                            }
+                                            // IMAGE_QUALITY_BICUBIC
+                                            _ => {
+                                                let wx = cubic_weights(uv_frac.x);
+                                                let wy = cubic_weights(uv_frac.y);
+                                                let mut sum = [0.0; 4];
+                                                for (dy, wy) in (-1..3).zip(wy) {
+                                                    for (dx, wx) in (-1..3).zip(wx) {
+                                                        let texel = load(x0 + dx, y0 + dy);
+                                                        for (sum, texel) in
+                                                            sum.iter_mut().zip(texel)
+                                                        {
+                                                            *sum += wy * wx * texel;
+                                                        }
+                                                    }
+                                                }
+                                                // The filter overshoots, so clamp to a valid
+                                                // premultiplied color.
+                                                let a = sum[3].clamp(0.0, 1.0);
+                                                [
+                                                    sum[0].clamp(0.0, a),
+                                                    sum[1].clamp(0.0, a),
+                                                    sum[2].clamp(0.0, a),
+                                                    a,
+                                                ]
+                                            }
+                                        };
+                                        rgba[i] = over(rgba[i], scale(fg_rgba, alpha * area[i]));
+                                    }
+                                }
+                                cmd_ix += 2;
+                            }
+                            CMD_BEGIN_CLIP => {
+                                if clip_depth < BLEND_STACK_SPLIT {
+                                    for i in 0..PIXELS_PER_THREAD {
+                                        // Stored as sRGB to keep precision in dark colors
+                                        blend_stack[clip_depth][i] =
+                                            pack4x8unorm(from_blend_space(rgba[i]));
+                                        rgba[i] = [0.0; 4];
+                                    }
+                                } else {
+                                    // TODO: spill to memory
+                                }
+                                clip_depth += 1;
+                                cmd_ix += 1;
+                            }
+                            CMD_END_CLIP => {
+                                let blend = ptcl_at(cmd_ix + 1);
+                                let alpha = f32::from_bits(ptcl_at(cmd_ix + 2));
+                                clip_depth -= 1;
+                                for i in 0..PIXELS_PER_THREAD {
+                                    let bg_rgba = if clip_depth < BLEND_STACK_SPLIT {
+                                        blend_stack[clip_depth][i]
+                                    } else {
+                                        // load from memory
+                                        0
+                                    };
+                                    let bg = to_blend_space(unpack4x8unorm(bg_rgba));
+                                    let fg = scale(rgba[i], area[i] * alpha);
+                                    rgba[i] = blend_mix_compose(bg, fg, blend);
+                                }
+                                cmd_ix += 3;
+                            }
+                            CMD_JUMP => {
+                                cmd_ix = ptcl_at(cmd_ix + 1);
+                            }
+                            _ => {}
+                        }
+                    }
+                    let x0 = xy.x as u32;
+                    let y = xy.y as u32;
+                    for (i, fg) in rgba.iter().enumerate() {
+                        let x = x0 + i as u32;
+                        if x < config.target_width && y < config.target_height {
+                            // Max with a small epsilon to avoid NaNs
+                            let a_inv = 1.0 / fg[3].max(1e-6);
+                            let mut rgba_sep = [fg[0] * a_inv, fg[1] * a_inv, fg[2] * a_inv, fg[3]];
+                            if config.target_color_space != config.blend_color_space {
+                                let transfer = if blend_linear {
+                                    linear_to_srgb
+                                } else {
+                                    srgb_to_linear
+                                };
+                                for c in &mut rgba_sep[..3] {
+                                    *c = transfer(*c);
+                                }
+                            }
+                            output.store(x, y, pack4x8unorm(rgba_sep));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn fine(n_wg: (u32, u32, u32), resources: &[CpuBinding]) -> Result<(), Error> {
+    let config = resources[0].as_typed()?;
+    let segments = resources[2].as_slice()?;
+    let mut output = resources[3].as_tex_mut()?;
+    let ptcl = resources[4].as_slice()?;
+    let gradients = resources[5].as_tex()?;
+    let info = resources[6].as_slice()?;
+    let image_atlas = resources[7].as_tex()?;
+    let layer_images = resources[8].as_tex()?;
+    fine_main(
+        (n_wg.0, n_wg.1),
+        &config,
+        &segments,
+        &mut output,
+        &ptcl,
+        &gradients,
+        &info,
+        &image_atlas,
+        &layer_images,
+    );
+    Ok(())
+}
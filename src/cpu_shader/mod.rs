@@ -0,0 +1,48 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! CPU implementations of the shader stages.
+//!
+//! Each stage is a direct port of the corresponding WGSL shader, taking the
+//! same bindings in the same order. Work that the GPU spreads across
+//! invocations of a workgroup is performed sequentially.
+
+mod backdrop;
+mod bbox_clear;
+mod binning;
+mod blend;
+mod blur;
+mod clip_leaf;
+mod clip_reduce;
+mod coarse;
+mod draw_leaf;
+mod draw_reduce;
+mod fine;
+mod path_coarse;
+mod pathseg;
+mod pathtag_reduce;
+mod pathtag_scan;
+mod pattern;
+mod pattern_leaf;
+mod pattern_reduce;
+mod tile_alloc;
+mod util;
+
+pub use backdrop::backdrop;
+pub use bbox_clear::bbox_clear;
+pub use binning::binning;
+pub use blur::blur;
+pub use clip_leaf::clip_leaf;
+pub use clip_reduce::clip_reduce;
+pub use coarse::coarse;
+pub use draw_leaf::draw_leaf;
+pub use draw_reduce::draw_reduce;
+pub use fine::fine;
+pub use path_coarse::path_coarse;
+pub use pathseg::pathseg;
+pub use pathtag_reduce::{pathtag_reduce, pathtag_reduce2};
+pub use pathtag_scan::{pathtag_scan1, pathtag_scan_large, pathtag_scan_small};
+pub use pattern::pattern;
+pub use pattern_leaf::pattern_leaf;
+pub use pattern_reduce::pattern_reduce;
+pub use tile_alloc::tile_alloc;
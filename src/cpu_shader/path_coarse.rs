@@ -0,0 +1,329 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use vello_encoding::{BumpAllocators, ConfigUniform, Cubic, Path, PathSegment, Tile};
+
+use super::util::{Vec2, TILE_HEIGHT, TILE_WIDTH, WG_SIZE};
+use crate::cpu_dispatch::CpuBinding;
+use crate::engine::Error;
+
+// Bitflags for each stage that can fail allocation.
+const STAGE_BINNING: u32 = 0x1;
+const STAGE_TILE_ALLOC: u32 = 0x2;
+const STAGE_PATH_COARSE: u32 = 0x4;
+const STAGE_PATTERN: u32 = 0x10;
+
+const PATH_TAG_SEG_TYPE: u32 = 3;
+const CUBIC_IS_STROKE: u32 = 1;
+
+const MAX_QUADS: usize = 16;
+
+#[derive(Clone, Copy, Default)]
+struct SubdivResult {
+    val: f32,
+    a0: f32,
+    a2: f32,
+}
+
+const D: f32 = 0.67;
+fn approx_parabola_integral(x: f32) -> f32 {
+    x * (1.0 / (1.0 - D + (D * D * D * D + 0.25 * x * x)).sqrt().sqrt())
+}
+
+const B: f32 = 0.39;
+fn approx_parabola_inv_integral(x: f32) -> f32 {
+    x * (1.0 - B + (B * B + 0.5 * x * x)).sqrt()
+}
+
+/// Equivalent of WGSL `sign`, which maps zero to zero.
+fn sign(x: f32) -> f32 {
+    if x > 0.0 {
+        1.0
+    } else if x < 0.0 {
+        -1.0
+    } else {
+        0.0
+    }
+}
+
+fn mix(a: f32, b: f32, t: f32) -> f32 {
+    a * (1.0 - t) + b * t
+}
+
+fn estimate_subdiv(p0: Vec2, p1: Vec2, p2: Vec2, sqrt_tol: f32) -> SubdivResult {
+    let d01 = p1 - p0;
+    let d12 = p2 - p1;
+    let dd = d01 - d12;
+    let cross = (p2.x - p0.x) * dd.y - (p2.y - p0.y) * dd.x;
+    let cross_inv = if cross.abs() < 1.0e-9 {
+        1.0e9
+    } else {
+        1.0 / cross
+    };
+    let x0 = d01.dot(dd) * cross_inv;
+    let x2 = d12.dot(dd) * cross_inv;
+    let scale = (cross / (dd.length() * (x2 - x0))).abs();
+
+    let a0 = approx_parabola_integral(x0);
+    let a2 = approx_parabola_integral(x2);
+    let mut val = 0.0;
+    if scale < 1e9 {
+        let da = (a2 - a0).abs();
+        let sqrt_scale = scale.sqrt();
+        if sign(x0) == sign(x2) {
+            val = sqrt_scale;
+        } else {
+            let xmin = sqrt_tol / sqrt_scale;
+            val = sqrt_tol / approx_parabola_integral(xmin);
+        }
+        val *= da;
+    }
+    SubdivResult { val, a0, a2 }
+}
+
+fn eval_quad(p0: Vec2, p1: Vec2, p2: Vec2, t: f32) -> Vec2 {
+    let mt = 1.0 - t;
+    p0 * (mt * mt) + (p1 * (mt * 2.0) + p2 * t) * t
+}
+
+fn eval_cubic(p0: Vec2, p1: Vec2, p2: Vec2, p3: Vec2, t: f32) -> Vec2 {
+    let mt = 1.0 - t;
+    p0 * (mt * mt * mt) + (p1 * (mt * mt * 3.0) + (p2 * (mt * 3.0) + p3 * t) * t) * t
+}
+
+fn alloc_segment(config: &ConfigUniform, bump: &mut BumpAllocators) -> u32 {
+    bump.segments += 1;
+    let mut offset = bump.segments;
+    if offset + 1 > config.segments_size {
+        offset = 0;
+        bump.failed |= STAGE_PATH_COARSE;
+    }
+    offset
+}
+
+#[allow(clippy::too_many_arguments)]
+fn path_coarse_main(
+    n_wg: u32,
+    config: &ConfigUniform,
+    scene: &[u32],
+    cubics: &[Cubic],
+    paths: &[Path],
+    bump: &mut BumpAllocators,
+    tiles: &mut [Tile],
+    segments: &mut [PathSegment],
+) {
+    // Exit early if prior stages failed, as we can't run this stage.
+    if (bump.failed & (STAGE_BINNING | STAGE_TILE_ALLOC)) != 0 {
+        return;
+    }
+    for ix in 0..n_wg * WG_SIZE as u32 {
+        let is_segment = if ix < config.pattern_cubics_base {
+            let tag_word = scene[(config.layout.path_tag_base + (ix >> 2)) as usize];
+            let shift = (ix & 3) * 8;
+            let tag_byte = (tag_word >> shift) & 0xff;
+            // The cubics of template paths are only drawn through their instances.
+            (tag_byte & PATH_TAG_SEG_TYPE) != 0
+                && cubics[ix as usize].path_ix >= config.layout.n_template_paths
+        } else if (bump.failed & STAGE_PATTERN) == 0 {
+            // Pattern instances are allocated after the cubics of the scene. If the
+            // pattern stage failed, the slots it reserved may not have been written.
+            let n_instances = bump.pattern_cubic.min(config.pattern_cubics_size);
+            ix - config.pattern_cubics_base < n_instances
+        } else {
+            false
+        };
+        if !is_segment {
+            continue;
+        }
+        let Some(cubic) = cubics.get(ix as usize) else {
+            continue;
+        };
+        let Some(path) = paths.get(cubic.path_ix as usize) else {
+            continue;
+        };
+        let is_stroke = (cubic.flags & CUBIC_IS_STROKE) != 0;
+        let bbox = path.bbox.map(|x| x as i32);
+        let stroke = Vec2::from_array(cubic.stroke);
+        let p0 = Vec2::from_array(cubic.p0);
+        let p1 = Vec2::from_array(cubic.p1);
+        let p2 = Vec2::from_array(cubic.p2);
+        let p3 = Vec2::from_array(cubic.p3);
+        let err_v = (p2 - p1) * 3.0 + p0 - p3;
+        let err = err_v.dot(err_v);
+        const ACCURACY: f32 = 0.25;
+        const Q_ACCURACY: f32 = ACCURACY * 0.1;
+        const REM_ACCURACY: f32 = ACCURACY - Q_ACCURACY;
+        const MAX_HYPOT2: f32 = 432.0 * Q_ACCURACY * Q_ACCURACY;
+        let mut n_quads = ((err * (1.0 / MAX_HYPOT2)).powf(1.0 / 6.0).ceil() as u32).max(1);
+        n_quads = n_quads.min(MAX_QUADS as u32);
+        let mut keep_params = [SubdivResult::default(); MAX_QUADS];
+        let mut val = 0.0;
+        let mut qp0 = p0;
+        let step = 1.0 / n_quads as f32;
+        for i in 0..n_quads {
+            let t = (i + 1) as f32 * step;
+            let qp2 = eval_cubic(p0, p1, p2, p3, t);
+            let mut qp1 = eval_cubic(p0, p1, p2, p3, t - 0.5 * step);
+            qp1 = qp1 * 2.0 - (qp0 + qp2) * 0.5;
+            let params = estimate_subdiv(qp0, qp1, qp2, REM_ACCURACY.sqrt());
+            keep_params[i as usize] = params;
+            val += params.val;
+            qp0 = qp2;
+        }
+        let n = ((val * (0.5 / REM_ACCURACY.sqrt())).ceil() as u32).max(1);
+        let mut lp0 = p0;
+        qp0 = p0;
+        let v_step = val / n as f32;
+        let mut n_out = 1;
+        let mut val_sum = 0.0;
+        for i in 0..n_quads {
+            let t = (i + 1) as f32 * step;
+            let qp2 = eval_cubic(p0, p1, p2, p3, t);
+            let mut qp1 = eval_cubic(p0, p1, p2, p3, t - 0.5 * step);
+            qp1 = qp1 * 2.0 - (qp0 + qp2) * 0.5;
+            let params = keep_params[i as usize];
+            let u0 = approx_parabola_inv_integral(params.a0);
+            let u2 = approx_parabola_inv_integral(params.a2);
+            let uscale = 1.0 / (u2 - u0);
+            let mut val_target = n_out as f32 * v_step;
+            while n_out == n || val_target < val_sum + params.val {
+                let lp1 = if n_out == n {
+                    p3
+                } else {
+                    let u = (val_target - val_sum) / params.val;
+                    let a = mix(params.a0, params.a2, u);
+                    let au = approx_parabola_inv_integral(a);
+                    let t = (au - u0) * uscale;
+                    eval_quad(qp0, qp1, qp2, t)
+                };
+
+                // Output line segment lp0..lp1
+                let xymin = lp0.min(lp1) - stroke;
+                let xymax = lp0.max(lp1) + stroke;
+                let dp = lp1 - lp0;
+                let recip_dx = 1.0 / dp.x;
+                let invslope = if dp.y.abs() < 1.0e-9 {
+                    1.0e9
+                } else {
+                    dp.x / dp.y
+                };
+                let sx = 1.0 / TILE_WIDTH as f32;
+                let sy = 1.0 / TILE_HEIGHT as f32;
+                let c = (stroke.x + invslope.abs() * (0.5 * TILE_HEIGHT as f32 + stroke.y)) * sx;
+                let b = invslope;
+                let a = (lp0.x - (lp0.y - 0.5 * TILE_HEIGHT as f32) * b) * sx;
+                let mut x0 = (xymin.x * sx).floor() as i32;
+                let mut x1 = ((xymax.x * sx).floor() + 1.0) as i32;
+                let mut y0 = (xymin.y * sy).floor() as i32;
+                let mut y1 = ((xymax.y * sy).floor() + 1.0) as i32;
+                x0 = x0.clamp(bbox[0], bbox[2]);
+                x1 = x1.clamp(bbox[0], bbox[2]);
+                y0 = y0.clamp(bbox[1], bbox[3]);
+                y1 = y1.clamp(bbox[1], bbox[3]);
+                let mut xc = a + b * y0 as f32;
+                let stride = bbox[2] - bbox[0];
+                let mut base = path.tiles as i32 + (y0 - bbox[1]) * stride - bbox[0];
+                let mut xray = (lp0.x * sx).floor() as i32;
+                let mut last_xray = (lp1.x * sx).floor() as i32;
+                if dp.y < 0.0 {
+                    std::mem::swap(&mut xray, &mut last_xray);
+                }
+                for y in y0..y1 {
+                    let tile_y0 = y as f32 * TILE_HEIGHT as f32;
+                    let xbackdrop = (xray + 1).max(bbox[0]);
+                    if !is_stroke && xymin.y < tile_y0 && xbackdrop < bbox[2] {
+                        let backdrop = if dp.y < 0.0 { 1 } else { -1 };
+                        if let Some(tile) = tile_at(tiles, base + xbackdrop) {
+                            tile.backdrop += backdrop;
+                        }
+                    }
+                    let mut next_xray = last_xray;
+                    if y + 1 < y1 {
+                        let tile_y1 = (y + 1) as f32 * TILE_HEIGHT as f32;
+                        let x_edge = lp0.x + (tile_y1 - lp0.y) * invslope;
+                        next_xray = (x_edge * sx).floor() as i32;
+                    }
+                    let min_xray = xray.min(next_xray);
+                    let max_xray = xray.max(next_xray);
+                    let mut xx0 = ((xc - c).floor() as i32).min(min_xray);
+                    let mut xx1 = ((xc + c).ceil() as i32).max(max_xray + 1);
+                    xx0 = xx0.clamp(x0, x1);
+                    xx1 = xx1.clamp(x0, x1);
+                    for x in xx0..xx1 {
+                        let tile_x0 = x as f32 * TILE_WIDTH as f32;
+                        // allocate segment, insert linked list
+                        let seg_ix = alloc_segment(config, bump);
+                        let Some(tile) = tile_at(tiles, base + x) else {
+                            continue;
+                        };
+                        let old = std::mem::replace(&mut tile.segments, seg_ix);
+                        let mut origin = lp0;
+                        let mut delta = dp;
+                        let mut y_edge = 0.0;
+                        if !is_stroke {
+                            y_edge = mix(lp0.y, lp1.y, (tile_x0 - lp0.x) * recip_dx);
+                            if xymin.x < tile_x0 {
+                                let p = Vec2::new(tile_x0, y_edge);
+                                if dp.x < 0.0 {
+                                    delta = p - lp0;
+                                } else {
+                                    origin = p;
+                                    delta = lp1 - p;
+                                }
+                                if delta.x == 0.0 {
+                                    delta.x = sign(dp.x) * 1e-9;
+                                }
+                            }
+                            if x <= min_xray || max_xray < x {
+                                y_edge = 1e9;
+                            }
+                        }
+                        if let Some(segment) = segments.get_mut(seg_ix as usize) {
+                            *segment = PathSegment {
+                                origin: origin.to_array(),
+                                delta: delta.to_array(),
+                                y_edge,
+                                next: old,
+                            };
+                        }
+                    }
+                    xc += b;
+                    base += stride;
+                    xray = next_xray;
+                }
+                n_out += 1;
+                val_target += v_step;
+                lp0 = lp1;
+            }
+            val_sum += params.val;
+            qp0 = qp2;
+        }
+    }
+}
+
+fn tile_at(tiles: &mut [Tile], tile_ix: i32) -> Option<&mut Tile> {
+    usize::try_from(tile_ix)
+        .ok()
+        .and_then(|ix| tiles.get_mut(ix))
+}
+
+pub fn path_coarse(n_wg: (u32, u32, u32), resources: &[CpuBinding]) -> Result<(), Error> {
+    let config = resources[0].as_typed()?;
+    let scene = resources[1].as_slice()?;
+    let cubics = resources[2].as_slice()?;
+    let paths = resources[3].as_slice()?;
+    let mut bump = resources[4].as_typed_mut()?;
+    let mut tiles = resources[5].as_slice_mut()?;
+    let mut segments = resources[6].as_slice_mut()?;
+    path_coarse_main(
+        n_wg.0,
+        &config,
+        &scene,
+        &cubics,
+        &paths,
+        &mut bump,
+        &mut tiles,
+        &mut segments,
+    );
+    Ok(())
+}
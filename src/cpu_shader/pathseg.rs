@@ -0,0 +1,170 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use vello_encoding::{ConfigUniform, Cubic, Monoid, PathBbox, PathMonoid, PathTag};
+
+use super::util::{read_transform, transform_apply, Vec2, WG_SIZE};
+use crate::cpu_dispatch::CpuBinding;
+use crate::engine::Error;
+
+const PATH_TAG_SEG_TYPE: u8 = 3;
+const PATH_TAG_LINETO: u8 = 1;
+const PATH_TAG_QUADTO: u8 = 2;
+const PATH_TAG_CUBICTO: u8 = 3;
+const PATH_TAG_F32: u8 = 8;
+
+struct PathDataReader<'a> {
+    scene: &'a [u32],
+    pathdata_base: u32,
+}
+
+impl<'a> PathDataReader<'a> {
+    fn read_f32_point(&self, ix: u32) -> Vec2 {
+        let base = (self.pathdata_base + ix) as usize;
+        Vec2::new(
+            f32::from_bits(self.scene[base]),
+            f32::from_bits(self.scene[base + 1]),
+        )
+    }
+
+    fn read_i16_point(&self, ix: u32) -> Vec2 {
+        let raw = self.scene[(self.pathdata_base + ix) as usize];
+        let x = ((raw << 16) as i32 >> 16) as f32;
+        let y = (raw as i32 >> 16) as f32;
+        Vec2::new(x, y)
+    }
+}
+
+fn pathseg_main(
+    n_wg: u32,
+    config: &ConfigUniform,
+    scene: &[u32],
+    tag_monoids: &[PathMonoid],
+    path_bboxes: &mut [PathBbox],
+    cubics: &mut [Cubic],
+) {
+    let reader = PathDataReader {
+        scene,
+        pathdata_base: config.layout.path_data_base,
+    };
+    for ix in 0..n_wg * WG_SIZE as u32 {
+        let tag_word = scene[(config.layout.path_tag_base + (ix >> 2)) as usize];
+        let shift = (ix & 3) * 8;
+        let mut tm = PathMonoid::new(tag_word & ((1 << shift) - 1));
+        tm = tag_monoids[(ix >> 2) as usize].combine(&tm);
+        let tag_byte = ((tag_word >> shift) & 0xff) as u8;
+
+        let linewidth = scene
+            .get((config.layout.linewidth_base + tm.linewidth_ix) as usize)
+            .map(|bits| f32::from_bits(*bits))
+            .unwrap_or_default();
+        // The trailing padding tags can index one past the last path; the GPU
+        // drops those writes.
+        let Some(out) = path_bboxes.get_mut(tm.path_ix as usize) else {
+            continue;
+        };
+        if (tag_byte & PathTag::PATH.0) != 0 {
+            out.linewidth = linewidth;
+            out.trans_ix = tm.trans_ix;
+            out.last_tag_ix = ix;
+        }
+        // Decode path data
+        let seg_type = tag_byte & PATH_TAG_SEG_TYPE;
+        if seg_type != 0 {
+            let mut p0;
+            let mut p1;
+            let mut p2 = Vec2::default();
+            let mut p3 = Vec2::default();
+            if (tag_byte & PATH_TAG_F32) != 0 {
+                p0 = reader.read_f32_point(tm.pathseg_offset);
+                p1 = reader.read_f32_point(tm.pathseg_offset + 2);
+                if seg_type >= PATH_TAG_QUADTO {
+                    p2 = reader.read_f32_point(tm.pathseg_offset + 4);
+                    if seg_type == PATH_TAG_CUBICTO {
+                        p3 = reader.read_f32_point(tm.pathseg_offset + 6);
+                    }
+                }
+            } else {
+                p0 = reader.read_i16_point(tm.pathseg_offset);
+                p1 = reader.read_i16_point(tm.pathseg_offset + 1);
+                if seg_type >= PATH_TAG_QUADTO {
+                    p2 = reader.read_i16_point(tm.pathseg_offset + 2);
+                    if seg_type == PATH_TAG_CUBICTO {
+                        p3 = reader.read_i16_point(tm.pathseg_offset + 3);
+                    }
+                }
+            }
+            let transform = read_transform(scene, config.layout.transform_base, tm.trans_ix);
+            p0 = transform_apply(&transform, p0);
+            p1 = transform_apply(&transform, p1);
+            let mut bbox_min = p0.min(p1);
+            let mut bbox_max = p0.max(p1);
+            // Degree-raise
+            if seg_type == PATH_TAG_LINETO {
+                p3 = p1;
+                p2 = p3.mix(p0, 1.0 / 3.0);
+                p1 = p0.mix(p3, 1.0 / 3.0);
+            } else if seg_type >= PATH_TAG_QUADTO {
+                p2 = transform_apply(&transform, p2);
+                bbox_min = bbox_min.min(p2);
+                bbox_max = bbox_max.max(p2);
+                if seg_type == PATH_TAG_CUBICTO {
+                    p3 = transform_apply(&transform, p3);
+                    bbox_min = bbox_min.min(p3);
+                    bbox_max = bbox_max.max(p3);
+                } else {
+                    p3 = p2;
+                    p2 = p1.mix(p2, 1.0 / 3.0);
+                    p1 = p1.mix(p0, 1.0 / 3.0);
+                }
+            }
+            let mut stroke = Vec2::default();
+            if linewidth >= 0.0 {
+                let m = transform.matrix;
+                stroke = Vec2::new(
+                    Vec2::new(m[0], m[2]).length(),
+                    Vec2::new(m[1], m[3]).length(),
+                ) * (0.5 * linewidth);
+                bbox_min = bbox_min - stroke;
+                bbox_max = bbox_max + stroke;
+            }
+            let flags = (linewidth >= 0.0) as u32;
+            cubics[ix as usize] = Cubic {
+                p0: p0.to_array(),
+                p1: p1.to_array(),
+                p2: p2.to_array(),
+                p3: p3.to_array(),
+                stroke: stroke.to_array(),
+                path_ix: tm.path_ix,
+                flags,
+            };
+            // Template paths only hold the geometry of pattern instances, which
+            // the pattern stage places, so they keep an empty bounding box.
+            if (bbox_max.x > bbox_min.x || bbox_max.y > bbox_min.y)
+                && tm.path_ix >= config.layout.n_template_paths
+            {
+                out.x0 = out.x0.min(bbox_min.x.floor() as i32);
+                out.y0 = out.y0.min(bbox_min.y.floor() as i32);
+                out.x1 = out.x1.max(bbox_max.x.ceil() as i32);
+                out.y1 = out.y1.max(bbox_max.y.ceil() as i32);
+            }
+        }
+    }
+}
+
+pub fn pathseg(n_wg: (u32, u32, u32), resources: &[CpuBinding]) -> Result<(), Error> {
+    let config = resources[0].as_typed()?;
+    let scene = resources[1].as_slice()?;
+    let tag_monoids = resources[2].as_slice()?;
+    let mut path_bboxes = resources[3].as_slice_mut()?;
+    let mut cubics = resources[4].as_slice_mut()?;
+    pathseg_main(
+        n_wg.0,
+        &config,
+        &scene,
+        &tag_monoids,
+        &mut path_bboxes,
+        &mut cubics,
+    );
+    Ok(())
+}
@@ -0,0 +1,55 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use vello_encoding::{ConfigUniform, Monoid, PathMonoid};
+
+use super::util::WG_SIZE;
+use crate::cpu_dispatch::CpuBinding;
+use crate::engine::Error;
+
+fn pathtag_reduce_main(
+    n_wg: u32,
+    config: &ConfigUniform,
+    scene: &[u32],
+    reduced: &mut [PathMonoid],
+) {
+    let pathtag_base = config.layout.path_tag_base as usize;
+    for i in 0..n_wg as usize {
+        let mut m = PathMonoid::default();
+        for j in 0..WG_SIZE {
+            let tag_word = scene[pathtag_base + i * WG_SIZE + j];
+            m = m.combine(&PathMonoid::new(tag_word));
+        }
+        reduced[i] = m;
+    }
+}
+
+pub fn pathtag_reduce(n_wg: (u32, u32, u32), resources: &[CpuBinding]) -> Result<(), Error> {
+    let config = resources[0].as_typed()?;
+    let scene = resources[1].as_slice()?;
+    let mut reduced = resources[2].as_slice_mut()?;
+    pathtag_reduce_main(n_wg.0, &config, &scene, &mut reduced);
+    Ok(())
+}
+
+fn pathtag_reduce2_main(n_wg: u32, reduced_in: &[PathMonoid], reduced: &mut [PathMonoid]) {
+    for i in 0..n_wg as usize {
+        let mut m = PathMonoid::default();
+        for j in 0..WG_SIZE {
+            // Reads past the end of the input behave like the identity.
+            if let Some(other) = reduced_in.get(i * WG_SIZE + j) {
+                m = m.combine(other);
+            }
+        }
+        if let Some(out) = reduced.get_mut(i) {
+            *out = m;
+        }
+    }
+}
+
+pub fn pathtag_reduce2(n_wg: (u32, u32, u32), resources: &[CpuBinding]) -> Result<(), Error> {
+    let reduced_in = resources[0].as_slice()?;
+    let mut reduced = resources[1].as_slice_mut()?;
+    pathtag_reduce2_main(n_wg.0, &reduced_in, &mut reduced);
+    Ok(())
+}
@@ -0,0 +1,85 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use vello_encoding::{ConfigUniform, Monoid, PathMonoid};
+
+use super::util::WG_SIZE;
+use crate::cpu_dispatch::CpuBinding;
+use crate::engine::Error;
+
+fn pathtag_scan_main(
+    n_wg: u32,
+    config: &ConfigUniform,
+    scene: &[u32],
+    reduced: &[PathMonoid],
+    tag_monoids: &mut [PathMonoid],
+    small: bool,
+) {
+    let pathtag_base = config.layout.path_tag_base as usize;
+    let mut prefix = PathMonoid::default();
+    for (i, reduced) in reduced.iter().enumerate().take(n_wg as usize) {
+        // The large variant consumes an already scanned parent level.
+        let mut m = if small { prefix } else { *reduced };
+        for j in 0..WG_SIZE {
+            let ix = i * WG_SIZE + j;
+            tag_monoids[ix] = m;
+            m = m.combine(&PathMonoid::new(scene[pathtag_base + ix]));
+        }
+        if small {
+            prefix = prefix.combine(reduced);
+        }
+    }
+}
+
+/// Path tag scan for scenes that need a single level of reduction.
+pub fn pathtag_scan_small(n_wg: (u32, u32, u32), resources: &[CpuBinding]) -> Result<(), Error> {
+    let config = resources[0].as_typed()?;
+    let scene = resources[1].as_slice()?;
+    let reduced = resources[2].as_slice()?;
+    let mut tag_monoids = resources[3].as_slice_mut()?;
+    pathtag_scan_main(n_wg.0, &config, &scene, &reduced, &mut tag_monoids, true);
+    Ok(())
+}
+
+/// Path tag scan for scenes that use the two level reduction.
+pub fn pathtag_scan_large(n_wg: (u32, u32, u32), resources: &[CpuBinding]) -> Result<(), Error> {
+    let config = resources[0].as_typed()?;
+    let scene = resources[1].as_slice()?;
+    let reduced = resources[2].as_slice()?;
+    let mut tag_monoids = resources[3].as_slice_mut()?;
+    pathtag_scan_main(n_wg.0, &config, &scene, &reduced, &mut tag_monoids, false);
+    Ok(())
+}
+
+fn pathtag_scan1_main(
+    n_wg: u32,
+    reduced: &[PathMonoid],
+    reduced2: &[PathMonoid],
+    tag_monoids: &mut [PathMonoid],
+) {
+    let mut prefix = PathMonoid::default();
+    for i in 0..n_wg as usize {
+        let mut m = prefix;
+        for j in 0..WG_SIZE {
+            let ix = i * WG_SIZE + j;
+            let Some(out) = tag_monoids.get_mut(ix) else {
+                break;
+            };
+            *out = m;
+            if let Some(other) = reduced.get(ix) {
+                m = m.combine(other);
+            }
+        }
+        if let Some(other) = reduced2.get(i) {
+            prefix = prefix.combine(other);
+        }
+    }
+}
+
+pub fn pathtag_scan1(n_wg: (u32, u32, u32), resources: &[CpuBinding]) -> Result<(), Error> {
+    let reduced = resources[0].as_slice()?;
+    let reduced2 = resources[1].as_slice()?;
+    let mut tag_monoids = resources[2].as_slice_mut()?;
+    pathtag_scan1_main(n_wg.0, &reduced, &reduced2, &mut tag_monoids);
+    Ok(())
+}
@@ -0,0 +1,712 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use peniko::{Color, ColorStop};
+use vello_encoding::{
+    BumpAllocators, ConfigUniform, Cubic, DrawColor, DrawTag, PathBbox, PatternData,
+    PatternInstance, Transform,
+};
+
+use super::util::{read_transform, WG_SIZE};
+use crate::cpu_dispatch::CpuBinding;
+use crate::engine::Error;
+
+// Bitflag for this stage failing allocation.
+const STAGE_PATTERN: u32 = 0x10;
+
+const PATH_TAG_SEG_TYPE: u32 = 3;
+
+fn read_pattern(scene: &[u32], pattern_base: u32, ix: u32) -> PatternData {
+    const SIZE: usize = std::mem::size_of::<PatternData>() / 4;
+    let base = (pattern_base as usize) + ix as usize * SIZE;
+    bytemuck::pod_read_unaligned(bytemuck::cast_slice(&scene[base..base + SIZE]))
+}
+
+pub(super) fn read_instance(scene: &[u32], instance_base: u32, ix: u32) -> PatternInstance {
+    const SIZE: usize = std::mem::size_of::<PatternInstance>() / 4;
+    let base = (instance_base as usize) + ix as usize * SIZE;
+    bytemuck::pod_read_unaligned(bytemuck::cast_slice(&scene[base..base + SIZE]))
+}
+
+/// Returns the pattern of an instance, with its lattice mapped by the
+/// transform of the enclosing cell.
+fn instance_pattern(
+    config: &ConfigUniform,
+    scene: &[u32],
+    instance: &PatternInstance,
+) -> PatternData {
+    let pattern = read_pattern(scene, config.layout.pattern_base, instance.pattern);
+    PatternData {
+        transform: instance.transform * pattern.transform,
+        ..pattern
+    }
+}
+
+/// Returns the range of cells an instance is drawn in, in row-major order.
+fn instance_cells(config: &ConfigUniform, scene: &[u32], instance: &PatternInstance) -> [i32; 4] {
+    if instance.pattern == PatternInstance::NONE
+        || instance.flags & PatternInstance::SINGLE_CELL != 0
+    {
+        let [x, y] = instance.cell;
+        return [x, y, x.wrapping_add(1), y.wrapping_add(1)];
+    }
+    instance_pattern(config, scene, instance).cell_range(instance.bbox, instance.content_bbox)
+}
+
+/// Returns the number of draw objects an instance expands to, and the size
+/// of their draw info, saturating like `instance_sizes` in shared/pattern.wgsl.
+pub(super) fn instance_sizes(config: &ConfigUniform, scene: &[u32], ix: u32) -> [u32; 2] {
+    let instance = read_instance(scene, config.layout.pattern_instance_base, ix);
+    let [x0, y0, x1, y1] = instance_cells(config, scene, &instance);
+    let width = x1.wrapping_sub(x0).max(0) as u32;
+    let height = y1.wrapping_sub(y0).max(0) as u32;
+    let n_cells = width.saturating_mul(height);
+    let [start, end] = instance.objects;
+    let n_objects = end - start.min(end);
+    [
+        n_cells.saturating_mul(n_objects),
+        n_cells.saturating_mul(instance.info),
+    ]
+}
+
+pub(super) fn combine_instance_sizes(a: [u32; 2], b: [u32; 2]) -> [u32; 2] {
+    [a[0].saturating_add(b[0]), a[1].saturating_add(b[1])]
+}
+
+/// Returns the placement stream, which lies between the patterns and their
+/// colors in the scene.
+fn read_placements<'a>(config: &ConfigUniform, scene: &'a [u32]) -> &'a [Transform] {
+    let layout = &config.layout;
+    let words = &scene[layout.placement_base as usize..layout.pattern_color_base as usize];
+    bytemuck::cast_slice(&words[..words.len() / 6 * 6])
+}
+
+/// Returns the color stops of a pattern.
+fn read_color_stops(
+    config: &ConfigUniform,
+    scene: &[u32],
+    pattern: &PatternData,
+) -> Vec<ColorStop> {
+    let base = config.layout.pattern_color_base as usize;
+    (pattern.color_stops[0]..pattern.color_stops[1])
+        .map(|ix| {
+            let word = base + ix as usize * 2;
+            let [r, g, b, a] = scene[word + 1].to_be_bytes();
+            ColorStop {
+                offset: f32::from_bits(scene[word]),
+                color: Color::rgba8(r, g, b, a),
+            }
+        })
+        .collect()
+}
+
+fn is_segment(config: &ConfigUniform, scene: &[u32], tag_ix: u32) -> bool {
+    let tag_word = scene[(config.layout.path_tag_base + (tag_ix >> 2)) as usize];
+    let tag_byte = (tag_word >> ((tag_ix & 3) * 8)) & 0xff;
+    (tag_byte & PATH_TAG_SEG_TYPE) != 0
+}
+
+/// Writes the tag of the expanded draw object `ix`, and the index of its path
+/// and the offset of its draw data, which override those of the draw monoid.
+fn write_draw(
+    config: &ConfigUniform,
+    scene: &mut [u32],
+    ix: u32,
+    tag: u32,
+    path_ix: u32,
+    scene_offset: u32,
+) {
+    scene[(config.layout.draw_tag_base + ix) as usize] = tag;
+    let slot = (config.pattern_slot_base + ix * 3) as usize;
+    scene[slot] = path_ix;
+    scene[slot + 1] = scene_offset;
+}
+
+fn pattern_main(
+    n_wg: u32,
+    config: &ConfigUniform,
+    scene: &mut [u32],
+    offsets: &[[u32; 2]],
+    path_bboxes: &mut [PathBbox],
+    cubics: &mut [Cubic],
+    bump: &mut BumpAllocators,
+) {
+    let layout = &config.layout;
+    let n_instances = layout.n_pattern_instances as usize;
+    for ix in 0..n_wg * WG_SIZE as u32 {
+        if ix >= layout.n_draw_objects {
+            break;
+        }
+        // Draw objects past the end, or of instances that don't fit, are no-ops
+        // with an empty path.
+        let dst = config.pattern_path_base + ix;
+        // The instance that draws this draw object is the last one whose offset
+        // isn't past it.
+        let instance_ix = offsets[..n_instances]
+            .partition_point(|offset| offset[0] <= ix)
+            .saturating_sub(1);
+        let end = offsets[instance_ix + 1];
+        if ix >= end[0] || end[0] > layout.n_draw_objects || end[1] > layout.bin_data_start {
+            write_draw(config, scene, ix, DrawTag::NOP.0, dst, 0);
+            continue;
+        }
+        let instance = read_instance(scene, layout.pattern_instance_base, instance_ix as u32);
+        let n_objects = instance.objects[1] - instance.objects[0];
+        let local_ix = ix - offsets[instance_ix][0];
+        let cell_ix = local_ix / n_objects;
+        let source = instance.objects[0] + local_ix % n_objects;
+        let source_base = (layout.draw_source_base + source * 2) as usize;
+        let tag = scene[source_base];
+        let source_offset = scene[source_base + 1];
+        if instance.pattern == PatternInstance::NONE {
+            write_draw(config, scene, ix, tag, source, source_offset);
+            continue;
+        }
+
+        // Transform from the space of the contents to the enclosing layer, and
+        // the color of the innermost pattern that has one.
+        let pattern = instance_pattern(config, scene, &instance);
+        let [x, y] = if instance.flags & PatternInstance::SINGLE_CELL != 0 {
+            instance.cell
+        } else {
+            let [x0, y0, x1, _] = pattern.cell_range(instance.bbox, instance.content_bbox);
+            let width = (x1 - x0) as u32;
+            [x0 + (cell_ix % width) as i32, y0 + (cell_ix / width) as i32]
+        };
+        let cell_transform = pattern.cell_transform(x, y, read_placements(config, scene));
+        let stops = read_color_stops(config, scene, &pattern);
+        let color = pattern.cell_color(x, y, &stops).or_else(|| {
+            let [r, g, b, a] = instance.color.to_be_bytes();
+            (instance.flags & PatternInstance::HAS_COLOR != 0).then(|| Color::rgba8(r, g, b, a))
+        });
+
+        let src = path_bboxes[source as usize];
+        let trans_ix = config.pattern_transform_base + ix;
+        let transform = cell_transform * read_transform(scene, layout.transform_base, src.trans_ix);
+        let base = (layout.transform_base + trans_ix * 6) as usize;
+        scene[base..base + 6].copy_from_slice(bytemuck::cast_slice(&[transform]));
+        path_bboxes[dst as usize].trans_ix = trans_ix;
+        path_bboxes[dst as usize].linewidth = src.linewidth;
+        let mut scene_offset = source_offset;
+        if let (Some(color), true) = (color, tag == DrawTag::COLOR.0) {
+            // The color of a copy is stored after its slot.
+            let slot = config.pattern_slot_base + ix * 3;
+            let rgba = scene[(layout.draw_data_base + source_offset) as usize];
+            scene[slot as usize + 2] = DrawColor { rgba }.recolor(color).rgba;
+            scene_offset = slot + 2 - layout.draw_data_base;
+        }
+        write_draw(config, scene, ix, tag, dst, scene_offset);
+
+        // The cubics of a path are stored at the indices of its segment tags, which
+        // follow the tag of the previous path.
+        let tag_start = match source {
+            0 => 0,
+            source => path_bboxes[source as usize - 1].last_tag_ix + 1,
+        };
+        let tag_end = src.last_tag_ix;
+        let n_cubics = (tag_start..tag_end)
+            .filter(|tag_ix| is_segment(config, scene, *tag_ix))
+            .count() as u32;
+        if n_cubics == 0 {
+            continue;
+        }
+        let cubic_offset = bump.pattern_cubic;
+        bump.pattern_cubic = bump.pattern_cubic.wrapping_add(n_cubics);
+        if n_cubics > config.pattern_cubics_size
+            || cubic_offset > config.pattern_cubics_size - n_cubics
+        {
+            // Report the overflow and draw nothing rather than writing past the
+            // end of the buffer.
+            bump.failed |= STAGE_PATTERN;
+            continue;
+        }
+        // Mirrors the stroke expansion in pathseg.
+        let stroke = if src.linewidth >= 0.0 {
+            let m = transform.matrix;
+            let length = |x: f32, y: f32| (x * x + y * y).sqrt();
+            [length(m[0], m[2]), length(m[1], m[3])].map(|x| 0.5 * src.linewidth * x)
+        } else {
+            [0.0; 2]
+        };
+        let mut out_ix = (config.pattern_cubics_base + cubic_offset) as usize;
+        let mut bbox = [1e9f32, 1e9, -1e9, -1e9];
+        for tag_ix in tag_start..tag_end {
+            if !is_segment(config, scene, tag_ix) {
+                continue;
+            }
+            let mut c = cubics[tag_ix as usize];
+            c.p0 = cell_transform.apply(c.p0);
+            c.p1 = cell_transform.apply(c.p1);
+            c.p2 = cell_transform.apply(c.p2);
+            c.p3 = cell_transform.apply(c.p3);
+            c.stroke = stroke;
+            c.path_ix = dst;
+            cubics[out_ix] = c;
+            out_ix += 1;
+            for p in [c.p0, c.p1, c.p2, c.p3] {
+                bbox[0] = bbox[0].min(p[0]);
+                bbox[1] = bbox[1].min(p[1]);
+                bbox[2] = bbox[2].max(p[0]);
+                bbox[3] = bbox[3].max(p[1]);
+            }
+        }
+        let bbox = [
+            bbox[0] - stroke[0],
+            bbox[1] - stroke[1],
+            bbox[2] + stroke[0],
+            bbox[3] + stroke[1],
+        ];
+        if bbox[2] > bbox[0] || bbox[3] > bbox[1] {
+            let out = &mut path_bboxes[dst as usize];
+            out.x0 = bbox[0].floor() as i32;
+            out.y0 = bbox[1].floor() as i32;
+            out.x1 = bbox[2].ceil() as i32;
+            out.y1 = bbox[3].ceil() as i32;
+        }
+    }
+}
+
+pub fn pattern(n_wg: (u32, u32, u32), resources: &[CpuBinding]) -> Result<(), Error> {
+    let config = resources[0].as_typed()?;
+    let mut scene = resources[1].as_slice_mut()?;
+    let offsets = resources[2].as_slice()?;
+    let mut path_bboxes = resources[3].as_slice_mut()?;
+    let mut cubics = resources[4].as_slice_mut()?;
+    let mut bump = resources[5].as_typed_mut()?;
+    pattern_main(
+        n_wg.0,
+        &config,
+        &mut scene,
+        &offsets,
+        &mut path_bboxes,
+        &mut cubics,
+        &mut bump,
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use bytemuck::Pod;
+
+    use super::*;
+    use crate::cpu_shader::{pattern_leaf, pattern_reduce};
+
+    const STEP: f32 = 16.0;
+    const TARGET: [f32; 4] = [0.0, 0.0, 64.0, 64.0];
+
+    /// The contents of the pattern, a single cubic within `CONTENT_BBOX`.
+    const CONTENTS: Cubic = Cubic {
+        p0: [0.0, 0.0],
+        p1: [2.0, 6.0],
+        p2: [6.0, 2.0],
+        p3: [8.0, 8.0],
+        stroke: [0.0, 0.0],
+        path_ix: 0,
+        flags: 0,
+    };
+    const CONTENT_BBOX: [f32; 4] = [0.0, 0.0, 8.0, 8.0];
+
+    fn tiled(tiling: u32) -> PatternData {
+        PatternData {
+            transform: Transform::IDENTITY,
+            x_step: [STEP, 0.0],
+            y_step: [0.0, STEP],
+            extend: PatternData::EXTEND_REPEAT,
+            tiling,
+            ..bytemuck::Zeroable::zeroed()
+        }
+    }
+
+    /// Returns an instance of the template in every cell of `pattern` whose
+    /// contents reach `TARGET`.
+    fn instance(pattern: u32) -> PatternInstance {
+        PatternInstance {
+            pattern,
+            flags: 0,
+            objects: [0, 1],
+            info: DrawTag::COLOR.info_size(),
+            transform: Transform::IDENTITY,
+            color: 0,
+            cell: [0, 0],
+            bbox: TARGET,
+            content_bbox: CONTENT_BBOX,
+        }
+    }
+
+    fn buffer<T: Pod>(data: &[T]) -> RefCell<Vec<u32>> {
+        RefCell::new(bytemuck::cast_slice(data).to_vec())
+    }
+
+    fn read<T: Pod>(buffer: RefCell<Vec<u32>>) -> Vec<T> {
+        bytemuck::cast_slice(&buffer.into_inner()).to_vec()
+    }
+
+    /// The buffers written by the pattern stages.
+    struct Output {
+        bump: BumpAllocators,
+        scene: Vec<u32>,
+        config: ConfigUniform,
+        path_bboxes: Vec<PathBbox>,
+        cubics: Vec<Cubic>,
+    }
+
+    impl Output {
+        /// Returns the tag, path and draw data offset of the expanded draw
+        /// object `ix`.
+        fn draw(&self, ix: u32) -> (u32, u32, u32) {
+            let tag = self.scene[(self.config.layout.draw_tag_base + ix) as usize];
+            let slot = (self.config.pattern_slot_base + ix * 3) as usize;
+            (tag, self.scene[slot], self.scene[slot + 1])
+        }
+
+        fn transform(&self, path_ix: u32) -> Transform {
+            let trans_ix = self.path_bboxes[path_ix as usize].trans_ix;
+            read_transform(&self.scene, self.config.layout.transform_base, trans_ix)
+        }
+
+        fn color(&self, scene_offset: u32) -> u32 {
+            self.scene[(self.config.layout.draw_data_base + scene_offset) as usize]
+        }
+    }
+
+    /// Runs the pattern stages on `instances` of the template draw object 0,
+    /// an opaque white fill of path 0 that holds [`CONTENTS`], with room for
+    /// `n_draws` expanded draw objects and `n_cubics` copied cubics.
+    ///
+    /// Expanded draw object `i` draws path `i + 1`.
+    fn dispatch(
+        patterns: &[PatternData],
+        stops: &[ColorStop],
+        instances: &[PatternInstance],
+        n_draws: u32,
+        n_cubics: u32,
+    ) -> Output {
+        let mut layout = vello_encoding::Layout {
+            n_draw_objects: n_draws,
+            n_paths: 1 + n_draws,
+            n_pattern_instances: instances.len() as u32,
+            n_template_paths: 1,
+            bin_data_start: n_draws * DrawTag::COLOR.info_size(),
+            ..Default::default()
+        };
+        // A segment and the path tag of the template.
+        let mut scene = vec![0x1003];
+        layout.transform_base = scene.len() as u32;
+        scene.extend(bytemuck::cast_slice(&[Transform::IDENTITY]));
+        layout.pattern_base = scene.len() as u32;
+        scene.extend(bytemuck::cast_slice(patterns));
+        layout.placement_base = scene.len() as u32;
+        layout.pattern_color_base = scene.len() as u32;
+        for stop in stops {
+            let color = stop.color;
+            let rgba = u32::from_be_bytes([color.r, color.g, color.b, color.a]);
+            scene.extend([stop.offset.to_bits(), rgba]);
+        }
+        layout.pattern_instance_base = scene.len() as u32;
+        scene.extend(bytemuck::cast_slice(instances));
+        layout.draw_data_base = scene.len() as u32;
+        scene.push(0xffff_ffff);
+        layout.draw_source_base = scene.len() as u32;
+        scene.extend([DrawTag::COLOR.0, 0]);
+        // The expanded draw tags, followed by the slots and transforms.
+        layout.draw_tag_base = scene.len() as u32;
+        let pattern_slot_base = layout.draw_tag_base + n_draws;
+        let slots_end = pattern_slot_base + n_draws * 3;
+        let pattern_transform_base = (slots_end - layout.transform_base).div_ceil(6);
+        let scene_len = layout.transform_base + (pattern_transform_base + n_draws) * 6;
+        scene.resize(scene_len as usize, 0);
+        let config = ConfigUniform {
+            layout,
+            pattern_cubics_base: 1,
+            pattern_cubics_size: n_cubics,
+            pattern_slot_base,
+            pattern_transform_base,
+            pattern_path_base: 1,
+            ..Default::default()
+        };
+        let mut path_bboxes = vec![PathBbox::default(); 1 + n_draws as usize];
+        path_bboxes[0] = PathBbox {
+            linewidth: -1.0,
+            last_tag_ix: 1,
+            ..Default::default()
+        };
+        let n_instance_wgs = (instances.len() as u32).div_ceil(WG_SIZE as u32);
+        let config_buf = buffer(&[config]);
+        let scene_buf = RefCell::new(scene);
+        let reduced_buf = buffer(&vec![[0u32; 2]; n_instance_wgs as usize]);
+        let offsets_buf = buffer(&vec![[0u32; 2]; instances.len() + 1]);
+        let path_bbox_buf = buffer(&path_bboxes);
+        let cubic_buf = buffer(&vec![CONTENTS; 1 + n_cubics as usize]);
+        let bump_buf = buffer(&[BumpAllocators::default()]);
+        let n_wg = (n_instance_wgs, 1, 1);
+        pattern_reduce(
+            n_wg,
+            &[
+                CpuBinding::Buffer(&config_buf),
+                CpuBinding::Buffer(&scene_buf),
+                CpuBinding::Buffer(&reduced_buf),
+            ],
+        )
+        .unwrap();
+        pattern_leaf(
+            n_wg,
+            &[
+                CpuBinding::Buffer(&config_buf),
+                CpuBinding::Buffer(&scene_buf),
+                CpuBinding::Buffer(&reduced_buf),
+                CpuBinding::Buffer(&offsets_buf),
+                CpuBinding::Buffer(&bump_buf),
+            ],
+        )
+        .unwrap();
+        pattern(
+            (n_draws.div_ceil(WG_SIZE as u32), 1, 1),
+            &[
+                CpuBinding::Buffer(&config_buf),
+                CpuBinding::Buffer(&scene_buf),
+                CpuBinding::Buffer(&offsets_buf),
+                CpuBinding::Buffer(&path_bbox_buf),
+                CpuBinding::Buffer(&cubic_buf),
+                CpuBinding::Buffer(&bump_buf),
+            ],
+        )
+        .unwrap();
+        Output {
+            bump: read(bump_buf)[0],
+            scene: scene_buf.into_inner(),
+            config,
+            path_bboxes: read(path_bbox_buf),
+            cubics: read(cubic_buf),
+        }
+    }
+
+    /// Asserts that the pattern stage draws a copy in each of `cells`, with
+    /// the first point of the contents at the given position.
+    fn assert_tiling(tiling: u32, cells: &[([i32; 2], [f32; 2])]) {
+        let pattern = tiled(tiling);
+        // The cells are the ones the CPU enumerates for the same bounds.
+        let enumerated = pattern.cells(TARGET, CONTENT_BBOX);
+        let expected_cells: Vec<[i32; 2]> = cells.iter().map(|(cell, _)| *cell).collect();
+        assert_eq!(enumerated, expected_cells, "tiling {tiling}");
+        let out = dispatch(&[pattern], &[], &[instance(0)], 64, 64);
+        let n_cells = cells.len() as u32;
+        assert_eq!(out.bump.failed, 0);
+        assert_eq!(out.bump.pattern_draw, n_cells);
+        assert_eq!(out.bump.pattern_info, n_cells);
+        assert_eq!(out.bump.pattern_cubic, n_cells);
+        // Each copy is written after the contents, in the order of the cells.
+        let copies = &out.cubics[1..1 + cells.len()];
+        let starts: Vec<[f32; 2]> = copies.iter().map(|cubic| cubic.p0).collect();
+        let expected: Vec<[f32; 2]> = cells.iter().map(|(_, p0)| *p0).collect();
+        assert_eq!(starts, expected, "tiling {tiling}");
+        for (i, (copy, [x, y])) in copies.iter().zip(enumerated).enumerate() {
+            let path_ix = i as u32 + 1;
+            assert_eq!(out.draw(i as u32), (DrawTag::COLOR.0, path_ix, 0));
+            let transform = pattern.cell_transform(x, y, &[]);
+            assert_eq!(copy.path_ix, path_ix);
+            assert_eq!(copy.p1, transform.apply(CONTENTS.p1));
+            assert_eq!(copy.p2, transform.apply(CONTENTS.p2));
+            assert_eq!(copy.p3, transform.apply(CONTENTS.p3));
+            assert_eq!(out.transform(path_ix), transform);
+            let bbox = out.path_bboxes[path_ix as usize];
+            let [x0, y0] = transform.apply([0.0, 0.0]).map(|x| x.floor() as i32);
+            let [x1, y1] = transform.apply([8.0, 8.0]).map(|x| x.ceil() as i32);
+            let expected = [x0.min(x1), y0.min(y1), x0.max(x1), y0.max(y1)];
+            assert_eq!([bbox.x0, bbox.y0, bbox.x1, bbox.y1], expected);
+        }
+        // The remaining draw objects are no-ops.
+        for ix in n_cells..64 {
+            assert_eq!(out.draw(ix), (DrawTag::NOP.0, ix + 1, 0));
+        }
+        // The template keeps its geometry and color.
+        assert_eq!(out.cubics[0].p0, CONTENTS.p0);
+        assert_eq!(out.color(0), 0xffff_ffff);
+    }
+
+    /// Returns the cells in `[x0, x1)` by `[y0, y1)` in row-major order, with
+    /// the position of the origin of the contents of each.
+    fn cells(
+        [x0, y0, x1, y1]: [i32; 4],
+        origin: impl Fn(i32, i32) -> [f32; 2],
+    ) -> Vec<([i32; 2], [f32; 2])> {
+        (y0..y1)
+            .flat_map(|y| (x0..x1).map(move |x| [x, y]))
+            .map(|[x, y]| ([x, y], origin(x, y)))
+            .collect()
+    }
+
+    fn odd(x: i32) -> f32 {
+        (x & 1) as f32
+    }
+
+    #[test]
+    fn grid() {
+        // The contents of the cells left of and above the target reach into
+        // it, so they are conservatively included.
+        let cells = cells([-1, -1, 4, 4], |x, y| [x as f32 * STEP, y as f32 * STEP]);
+        assert_eq!(cells.len(), 25);
+        assert_tiling(PatternData::TILING_GRID, &cells);
+    }
+
+    #[test]
+    fn brick() {
+        let cells = cells([-1, -1, 4, 4], |x, y| {
+            [(x as f32 + 0.5 * odd(y)) * STEP, y as f32 * STEP]
+        });
+        assert_tiling(PatternData::TILING_BRICK, &cells);
+    }
+
+    #[test]
+    fn half_drop() {
+        let cells = cells([-1, -1, 4, 4], |x, y| {
+            [x as f32 * STEP, (y as f32 + 0.5 * odd(x)) * STEP]
+        });
+        assert_tiling(PatternData::TILING_HALF_DROP, &cells);
+    }
+
+    #[test]
+    fn mirror() {
+        // Odd columns and rows are flipped across the center of the cell,
+        // which moves the origin of the contents to the far side.
+        let cells = cells([-1, -1, 4, 4], |x, y| {
+            [(x as f32 + odd(x)) * STEP, (y as f32 + odd(y)) * STEP]
+        });
+        assert_tiling(PatternData::TILING_MIRROR, &cells);
+    }
+
+    #[test]
+    fn hex() {
+        // Rows are three quarters of a cell apart, so more rows cover the
+        // target.
+        let cells = cells([-1, -1, 4, 6], |x, y| {
+            [(x as f32 + 0.5 * odd(y)) * STEP, y as f32 * 0.75 * STEP]
+        });
+        assert_tiling(PatternData::TILING_HEX, &cells);
+    }
+
+    #[test]
+    fn nested() {
+        // The copy is placed by its own cell and then by the cell of the
+        // enclosing pattern, which is the transform of the instance.
+        let outer = PatternData {
+            transform: Transform {
+                matrix: [1.0, 0.0, 0.0, 1.0],
+                translation: [100.0, 0.0],
+            },
+            ..tiled(PatternData::TILING_GRID)
+        };
+        let instances = [PatternInstance {
+            flags: PatternInstance::SINGLE_CELL,
+            transform: outer.cell_transform(1, 0, &[]),
+            cell: [0, 2],
+            ..instance(1)
+        }];
+        let out = dispatch(
+            &[outer, tiled(PatternData::TILING_BRICK)],
+            &[],
+            &instances,
+            4,
+            4,
+        );
+        assert_eq!(out.bump.pattern_draw, 1);
+        assert_eq!(out.bump.pattern_cubic, 1);
+        assert_eq!(out.cubics[1].p0, [100.0 + STEP, 2.0 * STEP]);
+        assert_eq!(out.cubics[1].path_ix, 1);
+    }
+
+    #[test]
+    fn colors() {
+        // A single stop colors every cell, keeping the alpha of the contents.
+        let pattern = PatternData {
+            colors: PatternData::COLORS_LIST,
+            color_stops: [0, 1],
+            ..tiled(PatternData::TILING_GRID)
+        };
+        let stops = [ColorStop {
+            offset: 0.0,
+            color: Color::rgba8(255, 0, 0, 128),
+        }];
+        let single = |pattern, flags| PatternInstance {
+            flags: PatternInstance::SINGLE_CELL | flags,
+            ..instance(pattern)
+        };
+        let instances = [
+            single(0, 0),
+            // The color of the enclosing cell applies to a pattern without
+            // colors of its own.
+            PatternInstance {
+                color: 0x00ff_0080,
+                ..single(1, PatternInstance::HAS_COLOR)
+            },
+            single(1, 0),
+            // Draw objects outside of patterns keep their path and draw data.
+            PatternInstance {
+                pattern: PatternInstance::NONE,
+                ..instance(0)
+            },
+        ];
+        let out = dispatch(
+            &[pattern, tiled(PatternData::TILING_GRID)],
+            &stops,
+            &instances,
+            4,
+            16,
+        );
+        assert_eq!(out.bump.pattern_draw, 4);
+        let (_, _, red) = out.draw(0);
+        assert_eq!(out.color(red), 0x8000_0080);
+        let (_, _, green) = out.draw(1);
+        assert_eq!(out.color(green), 0x0080_0080);
+        assert_eq!(out.draw(2), (DrawTag::COLOR.0, 3, 0));
+        assert_eq!(out.draw(3), (DrawTag::COLOR.0, 0, 0));
+        assert_eq!(out.color(0), 0xffff_ffff);
+    }
+
+    #[test]
+    fn offsets_span_workgroups() {
+        // Each instance draws a single cell, so the draw objects follow the
+        // order of the instances across workgroups.
+        let n = WG_SIZE as u32 + 44;
+        let instances: Vec<_> = (0..n as i32)
+            .map(|x| PatternInstance {
+                flags: PatternInstance::SINGLE_CELL,
+                cell: [x, 0],
+                ..instance(0)
+            })
+            .collect();
+        let out = dispatch(&[tiled(PatternData::TILING_GRID)], &[], &instances, n, n);
+        assert_eq!(out.bump.failed, 0);
+        assert_eq!(out.bump.pattern_draw, n);
+        for ix in 0..n {
+            assert_eq!(out.draw(ix), (DrawTag::COLOR.0, ix + 1, 0));
+            let translation = out.transform(ix + 1).translation;
+            assert_eq!(translation, [ix as f32 * STEP, 0.0]);
+        }
+    }
+
+    #[test]
+    fn overflow() {
+        // The grid draws 25 copies, and only 16 fit, so none are drawn.
+        let pattern = tiled(PatternData::TILING_GRID);
+        let out = dispatch(&[pattern], &[], &[instance(0)], 16, 64);
+        assert_eq!(out.bump.failed, STAGE_PATTERN);
+        assert_eq!(out.bump.pattern_draw, 25);
+        assert_eq!(out.bump.pattern_cubic, 0);
+        for ix in 0..16 {
+            assert_eq!(out.draw(ix), (DrawTag::NOP.0, ix + 1, 0));
+        }
+
+        // The copies fit, and only 16 of their cubics do.
+        let out = dispatch(&[pattern], &[], &[instance(0)], 32, 16);
+        assert_eq!(out.bump.failed, STAGE_PATTERN);
+        assert_eq!(out.bump.pattern_cubic, 25);
+        // The copies that don't fit keep an empty bounding box, so that later
+        // stages skip them.
+        for bbox in &out.path_bboxes[17..] {
+            assert_eq!([bbox.x0, bbox.y0, bbox.x1, bbox.y1], [0; 4]);
+        }
+    }
+}
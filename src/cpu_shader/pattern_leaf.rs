@@ -0,0 +1,55 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use vello_encoding::{BumpAllocators, ConfigUniform};
+
+use super::pattern::{combine_instance_sizes, instance_sizes};
+use super::util::WG_SIZE;
+use crate::cpu_dispatch::CpuBinding;
+use crate::engine::Error;
+
+// Bitflag for the pattern stage failing allocation.
+const STAGE_PATTERN: u32 = 0x10;
+
+fn pattern_leaf_main(
+    n_wg: u32,
+    config: &ConfigUniform,
+    scene: &[u32],
+    reduced: &[[u32; 2]],
+    offsets: &mut [[u32; 2]],
+    bump: &mut BumpAllocators,
+) {
+    let n_instances = config.layout.n_pattern_instances;
+    let mut prefix = [0; 2];
+    for i in 0..n_wg {
+        let mut m = prefix;
+        for j in 0..WG_SIZE as u32 {
+            let ix = i * WG_SIZE as u32 + j;
+            if ix >= n_instances {
+                break;
+            }
+            offsets[ix as usize] = m;
+            m = combine_instance_sizes(m, instance_sizes(config, scene, ix));
+            if ix + 1 == n_instances {
+                offsets[n_instances as usize] = m;
+                bump.pattern_draw = m[0];
+                bump.pattern_info = m[1];
+                if m[0] > config.layout.n_draw_objects || m[1] > config.layout.bin_data_start {
+                    // The instances that don't fit aren't expanded.
+                    bump.failed |= STAGE_PATTERN;
+                }
+            }
+        }
+        prefix = combine_instance_sizes(prefix, reduced[i as usize]);
+    }
+}
+
+pub fn pattern_leaf(n_wg: (u32, u32, u32), resources: &[CpuBinding]) -> Result<(), Error> {
+    let config = resources[0].as_typed()?;
+    let scene = resources[1].as_slice()?;
+    let reduced = resources[2].as_slice()?;
+    let mut offsets = resources[3].as_slice_mut()?;
+    let mut bump = resources[4].as_typed_mut()?;
+    pattern_leaf_main(n_wg.0, &config, &scene, &reduced, &mut offsets, &mut bump);
+    Ok(())
+}
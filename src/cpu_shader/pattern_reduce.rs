@@ -0,0 +1,29 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use vello_encoding::ConfigUniform;
+
+use super::pattern::{combine_instance_sizes, instance_sizes};
+use super::util::WG_SIZE;
+use crate::cpu_dispatch::CpuBinding;
+use crate::engine::Error;
+
+fn pattern_reduce_main(n_wg: u32, config: &ConfigUniform, scene: &[u32], reduced: &mut [[u32; 2]]) {
+    let n_instances = config.layout.n_pattern_instances;
+    for i in 0..n_wg {
+        let m = (0..WG_SIZE as u32)
+            .map(|j| i * WG_SIZE as u32 + j)
+            .filter(|ix| *ix < n_instances)
+            .map(|ix| instance_sizes(config, scene, ix))
+            .fold([0; 2], combine_instance_sizes);
+        reduced[i as usize] = m;
+    }
+}
+
+pub fn pattern_reduce(n_wg: (u32, u32, u32), resources: &[CpuBinding]) -> Result<(), Error> {
+    let config = resources[0].as_typed()?;
+    let scene = resources[1].as_slice()?;
+    let mut reduced = resources[2].as_slice_mut()?;
+    pattern_reduce_main(n_wg.0, &config, &scene, &mut reduced);
+    Ok(())
+}
@@ -0,0 +1,106 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use bytemuck::Zeroable;
+use vello_encoding::{BumpAllocators, ConfigUniform, Path, Tile};
+
+use super::util::{TILE_HEIGHT, TILE_WIDTH, WG_SIZE};
+use crate::cpu_dispatch::CpuBinding;
+use crate::engine::Error;
+
+// Bitflags for each stage that can fail allocation.
+const STAGE_BINNING: u32 = 0x1;
+const STAGE_TILE_ALLOC: u32 = 0x2;
+
+fn tile_alloc_main(
+    n_wg: u32,
+    config: &ConfigUniform,
+    draw_bboxes: &[[f32; 4]],
+    bump: &mut BumpAllocators,
+    paths: &mut [Path],
+    tiles: &mut [Tile],
+) {
+    // Exit early if prior stages failed, as we can't run this stage.
+    if (bump.failed & STAGE_BINNING) != 0 {
+        return;
+    }
+    let sx = 1.0 / TILE_WIDTH as f32;
+    let sy = 1.0 / TILE_HEIGHT as f32;
+    let width_in_tiles = config.width_in_tiles as i32;
+    let height_in_tiles = config.height_in_tiles as i32;
+    for wg in 0..n_wg as usize {
+        let mut bboxes = [[0u32; 4]; WG_SIZE];
+        let mut tile_counts = [0u32; WG_SIZE];
+        for local_ix in 0..WG_SIZE {
+            // Pattern commands are draw objects without a path, so this runs over
+            // paths rather than draw objects.
+            let path_ix = (wg * WG_SIZE + local_ix) as u32;
+            let mut x0 = 0;
+            let mut y0 = 0;
+            let mut x1 = 0;
+            let mut y1 = 0;
+            if path_ix < config.layout.n_paths {
+                let bbox = draw_bboxes[path_ix as usize];
+                // Don't round up the bottom-right corner of the bbox if the area is zero and
+                // leave the coordinates at 0, so that `tile_count` is zero.
+                if bbox[0] < bbox[2] && bbox[1] < bbox[3] {
+                    x0 = (bbox[0] * sx).floor() as i32;
+                    y0 = (bbox[1] * sy).floor() as i32;
+                    x1 = (bbox[2] * sx).ceil() as i32;
+                    y1 = (bbox[3] * sy).ceil() as i32;
+                }
+            }
+            let ux0 = x0.clamp(0, width_in_tiles) as u32;
+            let uy0 = y0.clamp(0, height_in_tiles) as u32;
+            let ux1 = x1.clamp(0, width_in_tiles) as u32;
+            let uy1 = y1.clamp(0, height_in_tiles) as u32;
+            bboxes[local_ix] = [ux0, uy0, ux1, uy1];
+            tile_counts[local_ix] = (ux1 - ux0) * (uy1 - uy0);
+        }
+        let count: u32 = tile_counts.iter().sum();
+        let mut tile_offset = bump.tile;
+        bump.tile += count;
+        if tile_offset + count > config.tiles_size {
+            tile_offset = 0;
+            bump.failed |= STAGE_TILE_ALLOC;
+        }
+        // The shader broadcasts the offset through the last path of the workgroup.
+        if let Some(path) = paths.get_mut(wg * WG_SIZE + WG_SIZE - 1) {
+            path.tiles = tile_offset;
+        }
+        let mut tile_subix = 0;
+        for local_ix in 0..WG_SIZE {
+            let path_ix = wg * WG_SIZE + local_ix;
+            if path_ix < config.layout.n_paths as usize {
+                let mut path = Path::zeroed();
+                path.bbox = bboxes[local_ix];
+                path.tiles = tile_offset + tile_subix;
+                paths[path_ix] = path;
+            }
+            tile_subix += tile_counts[local_ix];
+        }
+        // zero allocated memory
+        for i in 0..count {
+            if let Some(tile) = tiles.get_mut((tile_offset + i) as usize) {
+                *tile = Tile::default();
+            }
+        }
+    }
+}
+
+pub fn tile_alloc(n_wg: (u32, u32, u32), resources: &[CpuBinding]) -> Result<(), Error> {
+    let config = resources[0].as_typed()?;
+    let draw_bboxes = resources[1].as_slice()?;
+    let mut bump = resources[2].as_typed_mut()?;
+    let mut paths = resources[3].as_slice_mut()?;
+    let mut tiles = resources[4].as_slice_mut()?;
+    tile_alloc_main(
+        n_wg.0,
+        &config,
+        &draw_bboxes,
+        &mut bump,
+        &mut paths,
+        &mut tiles,
+    );
+    Ok(())
+}
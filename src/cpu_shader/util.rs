@@ -0,0 +1,187 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Utility types and functions shared by the CPU shaders.
+
+use std::ops::{Add, Mul, Neg, Sub};
+
+use vello_encoding::{ConfigUniform, DrawTag, Transform};
+
+pub const WG_SIZE: usize = 256;
+
+pub const TILE_WIDTH: u32 = 16;
+pub const TILE_HEIGHT: u32 = 16;
+pub const N_TILE_X: usize = 16;
+pub const N_TILE_Y: usize = 16;
+pub const N_TILE: usize = N_TILE_X * N_TILE_Y;
+
+/// Two-component vector, standing in for `vec2<f32>`.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct Vec2 {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl Vec2 {
+    pub fn new(x: f32, y: f32) -> Self {
+        Vec2 { x, y }
+    }
+
+    pub fn splat(v: f32) -> Self {
+        Vec2 { x: v, y: v }
+    }
+
+    pub fn from_array(a: [f32; 2]) -> Self {
+        Vec2 { x: a[0], y: a[1] }
+    }
+
+    pub fn to_array(self) -> [f32; 2] {
+        [self.x, self.y]
+    }
+
+    pub fn dot(self, other: Vec2) -> f32 {
+        self.x * other.x + self.y * other.y
+    }
+
+    pub fn length(self) -> f32 {
+        self.dot(self).sqrt()
+    }
+
+    pub fn distance(self, other: Vec2) -> f32 {
+        (self - other).length()
+    }
+
+    pub fn min(self, other: Vec2) -> Vec2 {
+        Vec2::new(self.x.min(other.x), self.y.min(other.y))
+    }
+
+    pub fn max(self, other: Vec2) -> Vec2 {
+        Vec2::new(self.x.max(other.x), self.y.max(other.y))
+    }
+
+    pub fn floor(self) -> Vec2 {
+        Vec2::new(self.x.floor(), self.y.floor())
+    }
+
+    /// Linear interpolation, with the same operation order as WGSL `mix`.
+    pub fn mix(self, other: Vec2, t: f32) -> Vec2 {
+        self * (1.0 - t) + other * t
+    }
+}
+
+impl Add for Vec2 {
+    type Output = Vec2;
+
+    fn add(self, rhs: Vec2) -> Vec2 {
+        Vec2::new(self.x + rhs.x, self.y + rhs.y)
+    }
+}
+
+impl Sub for Vec2 {
+    type Output = Vec2;
+
+    fn sub(self, rhs: Vec2) -> Vec2 {
+        Vec2::new(self.x - rhs.x, self.y - rhs.y)
+    }
+}
+
+impl Mul<f32> for Vec2 {
+    type Output = Vec2;
+
+    fn mul(self, rhs: f32) -> Vec2 {
+        Vec2::new(self.x * rhs, self.y * rhs)
+    }
+}
+
+impl Mul for Vec2 {
+    type Output = Vec2;
+
+    fn mul(self, rhs: Vec2) -> Vec2 {
+        Vec2::new(self.x * rhs.x, self.y * rhs.y)
+    }
+}
+
+impl Neg for Vec2 {
+    type Output = Vec2;
+
+    fn neg(self) -> Vec2 {
+        Vec2::new(-self.x, -self.y)
+    }
+}
+
+pub fn read_transform(scene: &[u32], transform_base: u32, ix: u32) -> Transform {
+    let base = (transform_base + ix * 6) as usize;
+    let c = |i: usize| f32::from_bits(scene[base + i]);
+    Transform {
+        matrix: [c(0), c(1), c(2), c(3)],
+        translation: [c(4), c(5)],
+    }
+}
+
+pub fn transform_apply(transform: &Transform, p: Vec2) -> Vec2 {
+    let m = transform.matrix;
+    let t = transform.translation;
+    Vec2::new(
+        m[0] * p.x + m[2] * p.y + t[0],
+        m[1] * p.x + m[3] * p.y + t[1],
+    )
+}
+
+pub fn transform_inverse(transform: &Transform) -> Transform {
+    let m = transform.matrix;
+    let t = transform.translation;
+    let inv_det = 1.0 / (m[0] * m[3] - m[1] * m[2]);
+    let inv_mat = [
+        inv_det * m[3],
+        inv_det * -m[1],
+        inv_det * -m[2],
+        inv_det * m[0],
+    ];
+    let inv_tr = [
+        inv_mat[0] * -t[0] + inv_mat[2] * -t[1],
+        inv_mat[1] * -t[0] + inv_mat[3] * -t[1],
+    ];
+    Transform {
+        matrix: inv_mat,
+        translation: inv_tr,
+    }
+}
+
+/// Composes two transforms, with the same operation order as `transform_mul`
+/// in transform.wgsl.
+pub fn transform_mul(a: &Transform, b: &Transform) -> Transform {
+    let (am, bm) = (a.matrix, b.matrix);
+    Transform {
+        matrix: [
+            am[0] * bm[0] + am[2] * bm[1],
+            am[1] * bm[0] + am[3] * bm[1],
+            am[0] * bm[2] + am[2] * bm[3],
+            am[1] * bm[2] + am[3] * bm[3],
+        ],
+        translation: [
+            am[0] * b.translation[0] + am[2] * b.translation[1] + a.translation[0],
+            am[1] * b.translation[0] + am[3] * b.translation[1] + a.translation[1],
+        ],
+    }
+}
+
+/// Reads a draw tag, defaulting to `NOP` past the end of the draw object stream.
+pub fn read_draw_tag_from_scene(config: &ConfigUniform, scene: &[u32], ix: u32) -> DrawTag {
+    if ix < config.layout.n_draw_objects {
+        DrawTag(scene[(config.layout.draw_tag_base + ix) as usize])
+    } else {
+        DrawTag::NOP
+    }
+}
+
+/// Equivalent of WGSL `pack4x8unorm`.
+pub fn pack4x8unorm(v: [f32; 4]) -> u32 {
+    v.iter().enumerate().fold(0, |packed, (i, x)| {
+        packed | (((0.5 + 255.0 * x.clamp(0.0, 1.0)) as u32) << (i * 8))
+    })
+}
+
+/// Equivalent of WGSL `unpack4x8unorm`.
+pub fn unpack4x8unorm(x: u32) -> [f32; 4] {
+    [0, 1, 2, 3].map(|i| ((x >> (i * 8)) & 0xff) as f32 / 255.0)
+}
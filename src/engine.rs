@@ -29,7 +29,7 @@ use wgpu::{
 pub type Error = Box<dyn std::error::Error>;
 
 #[derive(Clone, Copy)]
-pub struct ShaderId(usize);
+pub struct ShaderId(pub(crate) usize);
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Id(NonZeroU64);
@@ -46,19 +46,20 @@ pub struct Engine {
 struct Shader {
     pipeline: ComputePipeline,
     bind_group_layout: BindGroupLayout,
+    bind_types: Vec<BindType>,
     label: &'static str,
 }
 
 #[derive(Default)]
 pub struct Recording {
-    commands: Vec<Command>,
+    pub(crate) commands: Vec<Command>,
 }
 
 #[derive(Clone, Copy)]
 pub struct BufProxy {
-    size: u64,
-    id: Id,
-    name: &'static str,
+    pub(crate) size: u64,
+    pub(crate) id: Id,
+    pub(crate) name: &'static str,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq)]
@@ -66,14 +67,17 @@ pub enum ImageFormat {
     Rgba8,
     #[allow(unused)]
     Bgra8,
+    Rgba16Float,
 }
 
 #[derive(Clone, Copy)]
 pub struct ImageProxy {
-    width: u32,
-    height: u32,
-    format: ImageFormat,
-    id: Id,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    /// Number of layers of an image that is bound as an array.
+    pub(crate) layers: Option<u32>,
+    pub(crate) format: ImageFormat,
+    pub(crate) id: Id,
 }
 
 #[derive(Clone, Copy)]
@@ -92,7 +96,8 @@ pub enum Command {
     Upload(BufProxy, Vec<u8>),
     UploadUniform(BufProxy, Vec<u8>),
     UploadImage(ImageProxy, Vec<u8>),
-    WriteImage(ImageProxy, [u32; 4], Vec<u8>),
+    /// Writes a rectangle of one layer of an image.
+    WriteImage(ImageProxy, u32, [u32; 4], Vec<u8>),
     // Discussion question: third argument is vec of resources?
     // Maybe use tricks to make more ergonomic?
     // Alternative: provide bufs & images as separate sequences
@@ -116,6 +121,8 @@ pub enum BindType {
     Image(ImageFormat),
     /// A storage image with read only access.
     ImageRead(ImageFormat),
+    /// An array of images with read only access.
+    ImageArrayRead(ImageFormat),
     // TODO: Uniform, Sampler, maybe others
 }
 
@@ -139,9 +146,75 @@ struct BufferProperties {
     name: &'static str,
 }
 
-#[derive(Default)]
-struct ResourcePool {
-    bufs: HashMap<BufferProperties, Vec<Buffer>>,
+#[derive(Hash, PartialEq, Eq)]
+struct TextureProperties {
+    size: wgpu::Extent3d,
+    format: wgpu::TextureFormat,
+    usages: TextureUsages,
+}
+
+/// How a texture is first used, which determines the usages it is created with.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum TextureUse {
+    /// Written by the host, then read by shaders.
+    Upload,
+    /// Bound read only before it is written, so its contents don't matter.
+    ReadOnly,
+    /// Written by a shader, and possibly read by a later one.
+    Storage,
+}
+
+impl TextureUse {
+    /// Returns the use of a texture that is first bound as `bind_type`.
+    fn binding(bind_type: BindType) -> Self {
+        match bind_type {
+            BindType::Image(_) => Self::Storage,
+            _ => Self::ReadOnly,
+        }
+    }
+}
+
+/// Resources that are kept for reuse after they are freed.
+///
+/// Each resource is tagged with a serial number when it is returned to the
+/// pool, so that the least recently used ones can be trimmed first. The
+/// resource types are only parameters so that the bookkeeping can be tested
+/// without a device.
+struct ResourcePool<B = Buffer, T = Texture> {
+    bufs: HashMap<BufferProperties, Vec<(B, u64)>>,
+    textures: HashMap<TextureProperties, Vec<(T, u64)>>,
+    serial: u64,
+    hits: u64,
+    misses: u64,
+}
+
+impl<B, T> Default for ResourcePool<B, T> {
+    fn default() -> Self {
+        Self {
+            bufs: Default::default(),
+            textures: Default::default(),
+            serial: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+}
+
+/// Statistics of the resources pooled by an engine.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct PoolStats {
+    /// Number of buffers available for reuse.
+    pub buffers: usize,
+    /// Total size of the buffers available for reuse, in bytes.
+    pub buffer_bytes: u64,
+    /// Number of textures available for reuse.
+    pub textures: usize,
+    /// Total size of the textures available for reuse, in bytes.
+    pub texture_bytes: u64,
+    /// Number of requests that reused a pooled resource.
+    pub hits: u64,
+    /// Number of requests that allocated a new resource.
+    pub misses: u64,
 }
 
 impl Engine {
@@ -218,6 +291,16 @@ impl Engine {
                         count: None,
                     }
                 }
+                BindType::ImageArrayRead(_) => wgpu::BindGroupLayoutEntry {
+                    binding: i as u32,
+                    visibility: wgpu::ShaderStages::COMPUTE,
+                    ty: wgpu::BindingType::Texture {
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
+                        multisampled: false,
+                    },
+                    count: None,
+                },
             })
             .collect::<Vec<_>>();
         let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
@@ -239,6 +322,7 @@ impl Engine {
         let shader = Shader {
             pipeline,
             bind_group_layout,
+            bind_types: layout.to_vec(),
             label,
         };
         let id = self.shaders.len();
@@ -289,30 +373,8 @@ impl Engine {
                     let block_size = format
                         .block_size(None)
                         .expect("ImageFormat must have a valid block size");
-                    let texture = device.create_texture(&wgpu::TextureDescriptor {
-                        label: None,
-                        size: wgpu::Extent3d {
-                            width: image_proxy.width,
-                            height: image_proxy.height,
-                            depth_or_array_layers: 1,
-                        },
-                        mip_level_count: 1,
-                        sample_count: 1,
-                        dimension: wgpu::TextureDimension::D2,
-                        usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
-                        format,
-                        view_formats: &[],
-                    });
-                    let texture_view = texture.create_view(&wgpu::TextureViewDescriptor {
-                        label: None,
-                        dimension: Some(TextureViewDimension::D2),
-                        aspect: TextureAspect::All,
-                        mip_level_count: None,
-                        base_mip_level: 0,
-                        base_array_layer: 0,
-                        array_layer_count: None,
-                        format: Some(format),
-                    });
+                    let (texture, texture_view) =
+                        self.pool.get_image(image_proxy, TextureUse::Upload, device);
                     queue.write_texture(
                         wgpu::ImageCopyTexture {
                             texture: &texture,
@@ -335,8 +397,11 @@ impl Engine {
                     self.bind_map
                         .insert_image(image_proxy.id, texture, texture_view)
                 }
-                Command::WriteImage(proxy, [x, y, width, height], data) => {
-                    if let Ok((texture, _)) = self.bind_map.get_or_create_image(*proxy, device) {
+                Command::WriteImage(proxy, layer, [x, y, width, height], data) => {
+                    if let Ok((texture, _)) =
+                        self.bind_map
+                            .get_or_create_image(*proxy, device, &mut self.pool)
+                    {
                         let format = proxy.format.to_wgpu();
                         let block_size = format
                             .block_size(None)
@@ -345,7 +410,11 @@ impl Engine {
                             wgpu::ImageCopyTexture {
                                 texture,
                                 mip_level: 0,
-                                origin: wgpu::Origin3d { x: *x, y: *y, z: 0 },
+                                origin: wgpu::Origin3d {
+                                    x: *x,
+                                    y: *y,
+                                    z: *layer,
+                                },
                                 aspect: TextureAspect::All,
                             },
                             &data[..],
@@ -368,6 +437,7 @@ impl Engine {
                     let bind_group = self.bind_map.create_bind_group(
                         device,
                         &shader.bind_group_layout,
+                        &shader.bind_types,
                         bindings,
                         external_resources,
                         &mut self.pool,
@@ -429,14 +499,12 @@ impl Engine {
                     #[cfg(feature = "buffer_labels")]
                     name: buf.label,
                 };
-                self.pool.bufs.entry(props).or_default().push(buf.buffer);
+                self.pool.put_buf(props, buf.buffer);
             }
         }
         for id in free_images {
-            if let Some((texture, view)) = self.bind_map.image_map.remove(&id) {
-                // TODO: have a pool to avoid needless re-allocation
-                drop(texture);
-                drop(view);
+            if let Some((texture, _view)) = self.bind_map.image_map.remove(&id) {
+                self.pool.put_image(texture);
             }
         }
         Ok(())
@@ -449,6 +517,20 @@ impl Engine {
     pub fn free_download(&mut self, buf: BufProxy) {
         self.downloads.remove(&buf.id);
     }
+
+    /// Returns the number and size of the resources kept for reuse.
+    pub fn pool_stats(&self) -> PoolStats {
+        self.pool.stats()
+    }
+
+    /// Releases pooled resources, least recently used first, until the pool
+    /// holds at most `max_bytes`.
+    ///
+    /// Resources are pooled by size, so after a resize the resources of the old
+    /// size are only released by trimming.
+    pub fn trim_pool(&mut self, max_bytes: u64) {
+        self.pool.trim(max_bytes);
+    }
 }
 
 impl Recording {
@@ -491,9 +573,29 @@ impl Recording {
         width: u32,
         height: u32,
         data: impl Into<Vec<u8>>,
+    ) {
+        self.write_image_layer(image, 0, x, y, width, height, data);
+    }
+
+    /// Writes a rectangle of one layer of an image array.
+    #[allow(clippy::too_many_arguments)]
+    pub fn write_image_layer(
+        &mut self,
+        image: ImageProxy,
+        layer: u32,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        data: impl Into<Vec<u8>>,
     ) {
         let data = data.into();
-        self.push(Command::WriteImage(image, [x, y, width, height], data));
+        self.push(Command::WriteImage(
+            image,
+            layer,
+            [x, y, width, height],
+            data,
+        ));
     }
 
     pub fn dispatch<R>(&mut self, shader: ShaderId, wg_size: (u32, u32, u32), resources: R)
@@ -552,6 +654,7 @@ impl ImageFormat {
         match self {
             Self::Rgba8 => wgpu::TextureFormat::Rgba8Unorm,
             Self::Bgra8 => wgpu::TextureFormat::Bgra8Unorm,
+            Self::Rgba16Float => wgpu::TextureFormat::Rgba16Float,
         }
     }
 }
@@ -562,10 +665,41 @@ impl ImageProxy {
         ImageProxy {
             width,
             height,
+            layers: None,
             format,
             id,
         }
     }
+
+    /// Creates a proxy for an array of images, to be bound as
+    /// [`BindType::ImageArrayRead`].
+    pub fn new_array(width: u32, height: u32, layers: u32, format: ImageFormat) -> Self {
+        ImageProxy {
+            layers: Some(layers),
+            ..Self::new(width, height, format)
+        }
+    }
+
+    /// Returns the number of layers, which is one for an image that isn't an array.
+    pub fn layer_count(&self) -> u32 {
+        self.layers.unwrap_or(1)
+    }
+
+    fn extent(&self) -> wgpu::Extent3d {
+        wgpu::Extent3d {
+            width: self.width,
+            height: self.height,
+            depth_or_array_layers: self.layer_count(),
+        }
+    }
+
+    fn view_dimension(&self) -> TextureViewDimension {
+        if self.layers.is_some() {
+            TextureViewDimension::D2Array
+        } else {
+            TextureViewDimension::D2
+        }
+    }
 }
 
 impl ResourceProxy {
@@ -631,6 +765,7 @@ impl BindMap {
         &mut self,
         device: &Device,
         layout: &BindGroupLayout,
+        bind_types: &[BindType],
         bindings: &[ResourceProxy],
         external_resources: &[ExternalResource],
         pool: &mut ResourcePool,
@@ -665,7 +800,7 @@ impl BindMap {
             }
             None
         }
-        for proxy in bindings {
+        for (proxy, bind_type) in bindings.iter().zip(bind_types) {
             match proxy {
                 ResourceProxy::Buf(proxy) => {
                     if find_buf(external_resources, proxy).is_some() {
@@ -686,32 +821,7 @@ impl BindMap {
                         continue;
                     }
                     if let Entry::Vacant(v) = self.image_map.entry(proxy.id) {
-                        let format = proxy.format.to_wgpu();
-                        let texture = device.create_texture(&wgpu::TextureDescriptor {
-                            label: None,
-                            size: wgpu::Extent3d {
-                                width: proxy.width,
-                                height: proxy.height,
-                                depth_or_array_layers: 1,
-                            },
-                            mip_level_count: 1,
-                            sample_count: 1,
-                            dimension: wgpu::TextureDimension::D2,
-                            usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
-                            format,
-                            view_formats: &[],
-                        });
-                        let texture_view = texture.create_view(&wgpu::TextureViewDescriptor {
-                            label: None,
-                            dimension: Some(TextureViewDimension::D2),
-                            aspect: TextureAspect::All,
-                            mip_level_count: None,
-                            base_mip_level: 0,
-                            base_array_layer: 0,
-                            array_layer_count: None,
-                            format: Some(proxy.format.to_wgpu()),
-                        });
-                        v.insert((texture, texture_view));
+                        v.insert(pool.get_image(proxy, TextureUse::binding(*bind_type), device));
                     }
                 }
             }
@@ -773,36 +883,12 @@ impl BindMap {
         &mut self,
         proxy: ImageProxy,
         device: &Device,
+        pool: &mut ResourcePool,
     ) -> Result<&(Texture, TextureView), Error> {
         match self.image_map.entry(proxy.id) {
             Entry::Occupied(occupied) => Ok(occupied.into_mut()),
             Entry::Vacant(vacant) => {
-                let format = proxy.format.to_wgpu();
-                let texture = device.create_texture(&wgpu::TextureDescriptor {
-                    label: None,
-                    size: wgpu::Extent3d {
-                        width: proxy.width,
-                        height: proxy.height,
-                        depth_or_array_layers: 1,
-                    },
-                    mip_level_count: 1,
-                    sample_count: 1,
-                    dimension: wgpu::TextureDimension::D2,
-                    usage: TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
-                    format,
-                    view_formats: &[],
-                });
-                let texture_view = texture.create_view(&wgpu::TextureViewDescriptor {
-                    label: None,
-                    dimension: Some(TextureViewDimension::D2),
-                    aspect: TextureAspect::All,
-                    mip_level_count: None,
-                    base_mip_level: 0,
-                    base_array_layer: 0,
-                    array_layer_count: None,
-                    format: Some(proxy.format.to_wgpu()),
-                });
-                Ok(vacant.insert((texture, texture_view)))
+                Ok(vacant.insert(pool.get_image(&proxy, TextureUse::Upload, device)))
             }
         }
     }
@@ -826,11 +912,11 @@ impl ResourcePool {
             #[cfg(feature = "buffer_labels")]
             name,
         };
-        if let Some(buf_vec) = self.bufs.get_mut(&props) {
-            if let Some(buf) = buf_vec.pop() {
-                return buf;
-            }
+        if let Some((buf, _)) = self.bufs.get_mut(&props).and_then(Vec::pop) {
+            self.hits += 1;
+            return buf;
         }
+        self.misses += 1;
         device.create_buffer(&wgpu::BufferDescriptor {
             #[cfg(feature = "buffer_labels")]
             label: Some(name),
@@ -842,6 +928,118 @@ impl ResourcePool {
         })
     }
 
+    /// Get a texture for the image from the pool or create one.
+    ///
+    /// The contents of a reused texture are undefined.
+    fn get_image(
+        &mut self,
+        proxy: &ImageProxy,
+        texture_use: TextureUse,
+        device: &Device,
+    ) -> (Texture, TextureView) {
+        let format = proxy.format.to_wgpu();
+        let props = TextureProperties::new(proxy, texture_use);
+        let texture = if let Some((texture, _)) = self.textures.get_mut(&props).and_then(Vec::pop) {
+            self.hits += 1;
+            texture
+        } else {
+            self.misses += 1;
+            device.create_texture(&wgpu::TextureDescriptor {
+                label: None,
+                size: props.size,
+                mip_level_count: 1,
+                sample_count: 1,
+                dimension: wgpu::TextureDimension::D2,
+                usage: props.usages,
+                format,
+                view_formats: &[],
+            })
+        };
+        let texture_view = texture.create_view(&wgpu::TextureViewDescriptor {
+            label: None,
+            dimension: Some(proxy.view_dimension()),
+            aspect: TextureAspect::All,
+            mip_level_count: None,
+            base_mip_level: 0,
+            base_array_layer: 0,
+            array_layer_count: None,
+            format: Some(format),
+        });
+        (texture, texture_view)
+    }
+
+    fn put_image(&mut self, texture: Texture) {
+        let props = TextureProperties {
+            size: texture.size(),
+            format: texture.format(),
+            usages: texture.usage(),
+        };
+        self.put_texture(props, texture);
+    }
+}
+
+impl<B, T> ResourcePool<B, T> {
+    fn put_buf(&mut self, props: BufferProperties, buf: B) {
+        self.serial += 1;
+        self.bufs.entry(props).or_default().push((buf, self.serial));
+    }
+
+    fn put_texture(&mut self, props: TextureProperties, texture: T) {
+        self.serial += 1;
+        self.textures
+            .entry(props)
+            .or_default()
+            .push((texture, self.serial));
+    }
+
+    fn stats(&self) -> PoolStats {
+        let mut stats = PoolStats {
+            hits: self.hits,
+            misses: self.misses,
+            ..Default::default()
+        };
+        for (props, bufs) in &self.bufs {
+            stats.buffers += bufs.len();
+            stats.buffer_bytes += props.size * bufs.len() as u64;
+        }
+        for (props, textures) in &self.textures {
+            stats.textures += textures.len();
+            stats.texture_bytes += props.size_in_bytes() * textures.len() as u64;
+        }
+        stats
+    }
+
+    fn trim(&mut self, max_bytes: u64) {
+        // Find the serial number of the oldest resource to keep.
+        let mut resources = self
+            .bufs
+            .iter()
+            .flat_map(|(props, bufs)| bufs.iter().map(|(_, serial)| (*serial, props.size)))
+            .chain(self.textures.iter().flat_map(|(props, textures)| {
+                let size = props.size_in_bytes();
+                textures.iter().map(move |(_, serial)| (*serial, size))
+            }))
+            .collect::<Vec<_>>();
+        resources.sort_unstable_by_key(|&(serial, _)| std::cmp::Reverse(serial));
+        let mut total = 0;
+        let mut min_serial = u64::MAX;
+        for (serial, size) in resources {
+            total += size;
+            if total > max_bytes {
+                break;
+            }
+            min_serial = serial;
+        }
+        for bufs in self.bufs.values_mut() {
+            bufs.retain(|(_, serial)| *serial >= min_serial);
+        }
+        self.bufs.retain(|_, bufs| !bufs.is_empty());
+        for textures in self.textures.values_mut() {
+            textures.retain(|(_, serial)| *serial >= min_serial);
+        }
+        self.textures.retain(|_, textures| !textures.is_empty());
+    }
+
     /// Quantize a size up to the nearest size class.
     fn size_class(x: u64, bits: u32) -> u64 {
         if x > 1 << bits {
@@ -853,3 +1051,109 @@ impl ResourcePool {
         }
     }
 }
+
+impl TextureProperties {
+    fn new(proxy: &ImageProxy, texture_use: TextureUse) -> Self {
+        let usages = match texture_use {
+            TextureUse::Upload => TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST,
+            TextureUse::ReadOnly => TextureUsages::TEXTURE_BINDING,
+            TextureUse::Storage => TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING,
+        };
+        Self {
+            size: proxy.extent(),
+            format: proxy.format.to_wgpu(),
+            usages,
+        }
+    }
+
+    fn size_in_bytes(&self) -> u64 {
+        let block_size = self
+            .format
+            .block_size(None)
+            .expect("ImageFormat must have a valid block size");
+        let size = self.size;
+        size.width as u64
+            * size.height as u64
+            * size.depth_or_array_layers as u64
+            * block_size as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buf_props(size: u64) -> BufferProperties {
+        BufferProperties {
+            size,
+            usages: BufferUsages::STORAGE,
+            #[cfg(feature = "buffer_labels")]
+            name: "test",
+        }
+    }
+
+    fn texture_props(width: u32, height: u32, texture_use: TextureUse) -> TextureProperties {
+        TextureProperties::new(
+            &ImageProxy::new(width, height, ImageFormat::Rgba8),
+            texture_use,
+        )
+    }
+
+    #[test]
+    fn texture_usages_follow_the_binding() {
+        let usages = |bind_type| texture_props(1, 1, TextureUse::binding(bind_type)).usages;
+        let format = ImageFormat::Rgba8;
+        assert_eq!(
+            usages(BindType::Image(format)),
+            TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING
+        );
+        assert_eq!(
+            usages(BindType::ImageRead(format)),
+            TextureUsages::TEXTURE_BINDING
+        );
+        assert_eq!(
+            usages(BindType::ImageArrayRead(format)),
+            TextureUsages::TEXTURE_BINDING
+        );
+        assert_eq!(
+            texture_props(1, 1, TextureUse::Upload).usages,
+            TextureUsages::TEXTURE_BINDING | TextureUsages::COPY_DST
+        );
+    }
+
+    #[test]
+    fn pool_stats_count_pooled_resources() {
+        let mut pool = ResourcePool::<(), ()>::default();
+        pool.put_buf(buf_props(256), ());
+        pool.put_buf(buf_props(256), ());
+        pool.put_buf(buf_props(64), ());
+        pool.put_texture(texture_props(4, 2, TextureUse::Storage), ());
+        let stats = pool.stats();
+        assert_eq!(stats.buffers, 3);
+        assert_eq!(stats.buffer_bytes, 576);
+        assert_eq!(stats.textures, 1);
+        assert_eq!(stats.texture_bytes, 32);
+    }
+
+    #[test]
+    fn trim_releases_least_recently_pooled() {
+        let mut pool = ResourcePool::<u32, u32>::default();
+        pool.put_buf(buf_props(64), 0);
+        pool.put_texture(texture_props(4, 4, TextureUse::ReadOnly), 1);
+        pool.put_buf(buf_props(64), 2);
+        pool.put_buf(buf_props(32), 3);
+        // The two most recent buffers fit, the texture before them doesn't.
+        pool.trim(100);
+        let stats = pool.stats();
+        assert_eq!((stats.buffers, stats.buffer_bytes), (2, 96));
+        assert_eq!(stats.textures, 0);
+        assert_eq!(pool.bufs[&buf_props(64)][0].0, 2);
+        // Resources pooled after a trim are more recent than the ones kept.
+        pool.put_buf(buf_props(64), 4);
+        pool.trim(64);
+        assert_eq!(pool.bufs.len(), 1);
+        assert_eq!(pool.bufs[&buf_props(64)][0].0, 4);
+        pool.trim(0);
+        assert!(pool.bufs.is_empty());
+    }
+}
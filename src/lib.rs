@@ -14,7 +14,9 @@
 //
 // Also licensed under MIT license, at your choice.
 
+mod cpu_dispatch;
 mod engine;
+mod readback;
 mod render;
 mod scene;
 mod shaders;
@@ -27,18 +29,32 @@ pub use peniko::kurbo;
 #[doc(hidden)]
 pub use fello;
 
+pub mod cpu_shader;
 pub mod glyph;
 pub mod util;
 
-use render::Render;
-pub use scene::{DrawGlyphs, Scene, SceneBuilder, SceneFragment};
+pub use readback::{AlphaMode, Readback};
+use render::{Render, RenderCache};
+pub use scene::{
+    DrawGlyphs, LayerEffect, Pattern, PatternColors, PatternOrientation, PatternOverflow,
+    PatternPlacement, PatternTiling, PatternUnits, Scene, SceneBuilder, SceneFragment,
+};
 pub use util::block_on_wgpu;
 
-use engine::{Engine, ExternalResource, Recording};
+pub use cpu_dispatch::{CpuBinding, CpuEngine, CpuExternalResource, CpuShaderType, CpuTexture};
+pub use engine::PoolStats;
+use engine::{Engine, ExternalResource, ImageFormat, Recording};
 use shaders::FullShaders;
 
+use std::borrow::Cow;
+pub use vello_encoding::AllocationFailed;
 /// Temporary export, used in with_winit for stats
 pub use vello_encoding::BumpAllocators;
+pub use vello_encoding::BumpSizes;
+pub use vello_encoding::ColorSpace;
+pub use vello_encoding::{CacheStats, ResolverStats};
+use vello_encoding::{EffectLayers, Encoding};
+pub use vello_encoding::{ImageQuality, ImageSampling};
 use wgpu::{Device, Queue, SurfaceTexture, TextureFormat, TextureView};
 #[cfg(feature = "wgpu-profiler")]
 use wgpu_profiler::GpuProfiler;
@@ -49,12 +65,62 @@ pub type Error = Box<dyn std::error::Error>;
 /// Specialization of `Result` for our catch-all error type.
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Error returned when a scene needs larger bump allocated buffers than allowed.
+///
+/// This is reported when the total size would exceed [`RendererOptions::memory_limit`], or
+/// when a single buffer would exceed the storage buffer binding limit of the device.
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryLimitExceeded {
+    /// The size in bytes that the render asked for.
+    pub requested: u64,
+    /// The limit in bytes that was exceeded.
+    pub limit: u64,
+}
+
+impl std::fmt::Display for MemoryLimitExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "scene requires {} bytes of dynamic memory, exceeding the limit of {} bytes",
+            self.requested, self.limit
+        )
+    }
+}
+
+impl std::error::Error for MemoryLimitExceeded {}
+
+/// Statistics of the last render of a renderer, which point out scenes that don't take the
+/// fast path.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct RenderStats {
+    /// Number of patterns that were expanded on the CPU rather than instanced in the pattern
+    /// stage.
+    ///
+    /// Patterns are expanded when [`RendererOptions::expand_patterns`] is set or the scene has
+    /// layers with effects, which encodes a copy of the contents for every cell on each frame.
+    /// Otherwise the contents are encoded once and the pattern stage draws them in each cell,
+    /// see [`vello_encoding::instance_patterns`]. Either can fail with
+    /// [`PatternError::TooManyCells`](vello_encoding::PatternError::TooManyCells).
+    pub expanded_patterns: u32,
+    /// Number of layers whose effect was not applied, because they don't fit in the image
+    /// that the layers with effects are rendered to, see [`vello_encoding::split_effects`].
+    pub dropped_effects: u32,
+}
+
 /// Renders a scene into a texture or surface.
 pub struct Renderer {
     engine: Engine,
     shaders: FullShaders,
     blit: Option<BlitPipeline>,
+    /// Copies the storage texture to a target whose format can't be written by the fine stage.
+    copy: Option<BlitPipeline>,
     target: Option<TargetTexture>,
+    target_format: TargetFormat,
+    cache: RenderCache,
+    bump_sizes: BumpSizes,
+    memory_limit: Option<u64>,
+    expand_patterns: bool,
+    stats: RenderStats,
     #[cfg(feature = "wgpu-profiler")]
     profiler: GpuProfiler,
     #[cfg(feature = "wgpu-profiler")]
@@ -70,6 +136,53 @@ pub struct RenderParams {
     /// Dimensions of the rasterization target
     pub width: u32,
     pub height: u32,
+
+    /// Color space of the pixels written to the target. Targets that expect linear values,
+    /// such as [`TargetFormat::Rgba16Float`] textures used for HDR compositing or surfaces
+    /// with an sRGB format, should use [`ColorSpace::LinearSrgb`].
+    pub target_color_space: ColorSpace,
+
+    /// Color space in which colors are blended and composited. Scene colors, gradient ramps
+    /// and images are converted to this color space before compositing, and the result is
+    /// converted to `target_color_space` on output.
+    ///
+    /// [`ColorSpace::LinearSrgb`] gives gamma correct results, matching tools that composite
+    /// in linear light. Gradients are then also interpolated in linear light.
+    pub blend_color_space: ColorSpace,
+}
+
+/// Format of the textures a [`Renderer`] renders to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TargetFormat {
+    /// [`TextureFormat::Rgba8Unorm`].
+    #[default]
+    Rgba8,
+    /// [`TextureFormat::Bgra8Unorm`].
+    ///
+    /// This can't be used for storage textures on all devices, so the scene is rendered to an
+    /// intermediate texture and copied with a render pass.
+    Bgra8,
+    /// [`TextureFormat::Rgba16Float`].
+    Rgba16Float,
+}
+
+impl TargetFormat {
+    /// Returns the format of the textures passed to [`Renderer::render_to_texture`].
+    pub fn texture_format(self) -> TextureFormat {
+        match self {
+            Self::Rgba8 => TextureFormat::Rgba8Unorm,
+            Self::Bgra8 => TextureFormat::Bgra8Unorm,
+            Self::Rgba16Float => TextureFormat::Rgba16Float,
+        }
+    }
+
+    /// Returns the format of the image written by the fine stage.
+    fn storage_format(self) -> ImageFormat {
+        match self {
+            Self::Rgba8 | Self::Bgra8 => ImageFormat::Rgba8,
+            Self::Rgba16Float => ImageFormat::Rgba16Float,
+        }
+    }
 }
 
 pub struct RendererOptions {
@@ -79,21 +192,44 @@ pub struct RendererOptions {
     /// The timestamp period from [`wgpu::Queue::get_timestamp_period`]
     /// Used when the wgpu-profiler feature is enabled
     pub timestamp_period: f32,
+    /// The maximum total size in bytes of the dynamically allocated buffers.
+    ///
+    /// The async render methods grow these buffers when a scene overflows them, and fail
+    /// with [`MemoryLimitExceeded`] rather than growing past this limit, or when the sizes
+    /// estimated from the scene already exceed it. If None, only the limits of the device
+    /// apply.
+    pub memory_limit: Option<u64>,
+    /// Expand patterns on the CPU with [`vello_encoding::expand_patterns`] instead of
+    /// instancing them in the pattern stage with [`vello_encoding::instance_patterns`].
+    pub expand_patterns: bool,
+    /// The format of the textures passed to [`Renderer::render_to_texture`], and of the
+    /// pixels returned by [`Renderer::render_to_buffer`].
+    pub target_format: TargetFormat,
 }
 
 impl Renderer {
     /// Creates a new renderer for the specified device.
     pub fn new(device: &Device, render_options: &RendererOptions) -> Result<Self> {
         let mut engine = Engine::new();
-        let shaders = shaders::full_shaders(device, &mut engine)?;
+        let target_format = render_options.target_format;
+        let shaders = shaders::full_shaders(device, &mut engine, target_format.storage_format())?;
         let blit = render_options
             .surface_format
-            .map(|surface_format| BlitPipeline::new(device, surface_format));
+            .map(|surface_format| BlitPipeline::new(device, surface_format, true));
+        let copy = (target_format == TargetFormat::Bgra8)
+            .then(|| BlitPipeline::new(device, target_format.texture_format(), false));
         Ok(Self {
             engine,
             shaders,
             blit,
+            copy,
             target: None,
+            target_format,
+            cache: RenderCache::default(),
+            bump_sizes: BumpSizes::default(),
+            memory_limit: render_options.memory_limit,
+            expand_patterns: render_options.expand_patterns,
+            stats: RenderStats::default(),
             // Use 3 pending frames
             #[cfg(feature = "wgpu-profiler")]
             profiler: GpuProfiler::new(3, render_options.timestamp_period, device.features()),
@@ -105,8 +241,13 @@ impl Renderer {
     /// Renders a scene to the target texture.
     ///
     /// The texture is assumed to be of the specified dimensions and have been created with
-    /// the [wgpu::TextureFormat::Rgba8Unorm] format and the [wgpu::TextureUsages::STORAGE_BINDING]
-    /// flag set.
+    /// the format of [`RendererOptions::target_format`] and the
+    /// [wgpu::TextureUsages::STORAGE_BINDING] flag set, or the
+    /// [wgpu::TextureUsages::RENDER_ATTACHMENT] flag for [`TargetFormat::Bgra8`].
+    ///
+    /// This does not check whether the dynamically allocated buffers overflowed. Their sizes
+    /// are estimated from the scene, and are at least the sizes found by previous async
+    /// renders, but complex scenes should be rendered with [`Self::render_to_texture_async`].
     pub fn render_to_texture(
         &mut self,
         device: &Device,
@@ -115,7 +256,36 @@ impl Renderer {
         texture: &TextureView,
         params: &RenderParams,
     ) -> Result<()> {
-        let (recording, target) = render::render_full(scene, &self.shaders, params);
+        if self.copy.is_none() {
+            return self.render_to_storage(device, queue, scene, texture, params);
+        }
+        let storage = self.storage_texture(device, params);
+        self.render_to_storage(device, queue, scene, &storage.view, params)?;
+        self.copy_to_target(device, queue, &storage.view, texture, params);
+        self.target = Some(storage);
+        Ok(())
+    }
+
+    /// Renders a scene to a texture in the storage format of the fine stage.
+    fn render_to_storage(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        scene: &Scene,
+        texture: &TextureView,
+        params: &RenderParams,
+    ) -> Result<()> {
+        let (encoding, effect_layers, stats) = scene_encoding(scene, params, self.expand_patterns)?;
+        self.stats = stats;
+        self.cache.advance();
+        let (recording, target) = render::render_encoding_full(
+            &encoding,
+            &effect_layers,
+            &mut self.cache,
+            &self.shaders,
+            params,
+            &self.bump_sizes,
+        );
         let external_resources = [ExternalResource::Image(
             *target.as_image().unwrap(),
             texture,
@@ -135,7 +305,9 @@ impl Renderer {
     /// Renders a scene to the target surface.
     ///
     /// This renders to an intermediate texture and then runs a render pass to blit to the
-    /// specified surface texture.
+    /// specified surface texture. The intermediate texture is kept across renders. It is
+    /// allocated in steps larger than the surface, so that it isn't replaced on every frame of
+    /// an interactive resize, and only shrinks after the surface has been smaller for a while.
     ///
     /// The surface is assumed to be of the specified dimensions and have been configured with
     /// the same format passed in the constructing [`RendererOptions`]' `surface_format`.
@@ -148,52 +320,18 @@ impl Renderer {
         surface: &SurfaceTexture,
         params: &RenderParams,
     ) -> Result<()> {
-        let width = params.width;
-        let height = params.height;
-        let mut target = self
-            .target
-            .take()
-            .unwrap_or_else(|| TargetTexture::new(device, width, height));
-        // TODO: implement clever resizing semantics here to avoid thrashing the memory allocator
-        // during resize, specifically on metal.
-        if target.width != width || target.height != height {
-            target = TargetTexture::new(device, width, height);
-        }
-        self.render_to_texture(device, queue, scene, &target.view, params)?;
+        let target = self.storage_texture(device, params);
+        self.render_to_storage(device, queue, scene, &target.view, params)?;
         let blit = self
             .blit
             .as_ref()
             .expect("renderer should have configured surface_format to use on a surface");
         let mut encoder =
             device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
-        {
-            let surface_view = surface
-                .texture
-                .create_view(&wgpu::TextureViewDescriptor::default());
-            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-                label: None,
-                layout: &blit.bind_layout,
-                entries: &[wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&target.view),
-                }],
-            });
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: None,
-                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &surface_view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color::default()),
-                        store: true,
-                    },
-                })],
-                depth_stencil_attachment: None,
-            });
-            render_pass.set_pipeline(&blit.pipeline);
-            render_pass.set_bind_group(0, &bind_group, &[]);
-            render_pass.draw(0..6, 0..1);
-        }
+        let surface_view = surface
+            .texture
+            .create_view(&wgpu::TextureViewDescriptor::default());
+        blit.draw(device, &mut encoder, &target.view, &surface_view, params);
         queue.submit(Some(encoder.finish()));
         self.target = Some(target);
         Ok(())
@@ -204,21 +342,28 @@ impl Renderer {
     pub async fn reload_shaders(&mut self, device: &Device) -> Result<()> {
         device.push_error_scope(wgpu::ErrorFilter::Validation);
         let mut engine = Engine::new();
-        let shaders = shaders::full_shaders(device, &mut engine)?;
+        let shaders =
+            shaders::full_shaders(device, &mut engine, self.target_format.storage_format())?;
         let error = device.pop_error_scope().await;
         if let Some(error) = error {
             return Err(error.into());
         }
         self.engine = engine;
         self.shaders = shaders;
+        // The retained textures belonged to the previous engine.
+        self.cache = RenderCache::default();
         Ok(())
     }
 
     /// Renders a scene to the target texture.
     ///
-    /// The texture is assumed to be of the specified dimensions and have been created with
-    /// the [wgpu::TextureFormat::Rgba8Unorm] format and the [wgpu::TextureUsages::STORAGE_BINDING]
-    /// flag set.
+    /// The texture is assumed to be of the specified dimensions and have been created as for
+    /// [`Self::render_to_texture`].
+    ///
+    /// The counters of the dynamically allocated buffers are read back after the coarse
+    /// phase. If any buffer overflowed, it is grown and the coarse phase is run again, so
+    /// that the scene renders correctly. The grown sizes are kept for subsequent renders.
+    /// Layers with effects are rendered the same way, each before the rest of the scene.
     ///
     /// The return value is the value of the `BumpAllocators` in this rendering, which is currently used
     /// for debug output.
@@ -233,40 +378,60 @@ impl Renderer {
         texture: &TextureView,
         params: &RenderParams,
     ) -> Result<Option<BumpAllocators>> {
-        let mut render = Render::new();
-        let encoding = scene.data();
-        let recording = render.render_encoding_coarse(encoding, &self.shaders, params, true);
-        let target = render.out_image();
-        let bump_buf = render.bump_buf();
-        self.engine.run_recording(
-            device,
-            queue,
-            &recording,
-            &[],
-            "t_async_coarse",
-            #[cfg(feature = "wgpu-profiler")]
-            &mut self.profiler,
-        )?;
+        if self.copy.is_none() {
+            return self
+                .render_to_storage_async(device, queue, scene, texture, params)
+                .await;
+        }
+        let storage = self.storage_texture(device, params);
+        let bump = self
+            .render_to_storage_async(device, queue, scene, &storage.view, params)
+            .await?;
+        self.copy_to_target(device, queue, &storage.view, texture, params);
+        self.target = Some(storage);
+        Ok(bump)
+    }
 
-        let mut bump: Option<BumpAllocators> = None;
-        if let Some(bump_buf) = self.engine.get_download(bump_buf) {
-            let buf_slice = bump_buf.slice(..);
-            let (sender, receiver) = futures_intrusive::channel::shared::oneshot_channel();
-            buf_slice.map_async(wgpu::MapMode::Read, move |v| sender.send(v).unwrap());
-            if let Some(recv_result) = receiver.receive().await {
-                recv_result?;
-            } else {
-                return Err("channel was closed".into());
-            }
-            let mapped = buf_slice.get_mapped_range();
-            bump = Some(bytemuck::pod_read_unaligned(&mapped));
+    /// Renders a scene to a texture in the storage format of the fine stage, growing the
+    /// dynamically allocated buffers as needed.
+    async fn render_to_storage_async(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        scene: &Scene,
+        texture: &TextureView,
+        params: &RenderParams,
+    ) -> Result<Option<BumpAllocators>> {
+        let (encoding, effect_layers, stats) = scene_encoding(scene, params, self.expand_patterns)?;
+        self.stats = stats;
+        self.cache.advance();
+        let layer_image = render::effect_layer_image(&effect_layers, &self.shaders);
+        for layer in &effect_layers.layers {
+            let layer_params = render::effect_layer_params(layer, params);
+            let (mut render, _) = self
+                .render_coarse_async(device, queue, &layer.encoding, &layer_params)
+                .await?;
+            let mut recording = Recording::default();
+            render.record_effect_layer(layer, layer_image, &self.shaders, &mut recording);
+            self.engine.run_recording(
+                device,
+                queue,
+                &recording,
+                &[],
+                "t_async_effect_layer",
+                #[cfg(feature = "wgpu-profiler")]
+                &mut self.profiler,
+            )?;
         }
-        // TODO: apply logic to determine whether we need to rerun coarse, and also
-        // allocate the blend stack as needed.
-        self.engine.free_download(bump_buf);
+        let (mut render, bump) = self
+            .render_coarse_async(device, queue, &encoding, params)
+            .await?;
+        let target = render.out_image();
         // Maybe clear to reuse allocation?
         let mut recording = Recording::default();
+        render.set_layer_image(layer_image);
         render.record_fine(&self.shaders, &mut recording);
+        recording.free_image(layer_image);
         let external_resources = [ExternalResource::Image(target, texture)];
         self.engine.run_recording(
             device,
@@ -280,6 +445,130 @@ impl Renderer {
         Ok(bump)
     }
 
+    /// Runs the coarse phase of rendering an encoding, growing the dynamically allocated
+    /// buffers and running it again until they don't overflow.
+    ///
+    /// Returns the render, ready for its fine phase, and the bump allocators of the last run.
+    /// Fails with [`MemoryLimitExceeded`] if the estimated or grown sizes exceed the limits,
+    /// and with [`AllocationFailed`] if a stage failed without overflowing a buffer.
+    async fn render_coarse_async(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        encoding: &Encoding,
+        params: &RenderParams,
+    ) -> Result<(Render, Option<BumpAllocators>)> {
+        loop {
+            let mut render = Render::new();
+            let recording = render.render_encoding_coarse(
+                encoding,
+                &mut self.cache,
+                &self.shaders,
+                params,
+                &self.bump_sizes,
+                true,
+            );
+            let bump_buf = render.bump_buf();
+            self.engine.run_recording(
+                device,
+                queue,
+                &recording,
+                &[],
+                "t_async_coarse",
+                #[cfg(feature = "wgpu-profiler")]
+                &mut self.profiler,
+            )?;
+
+            let mut bump: Option<BumpAllocators> = None;
+            if let Some(bump_buf) = self.engine.get_download(bump_buf) {
+                let buf_slice = bump_buf.slice(..);
+                let (sender, receiver) = futures_intrusive::channel::shared::oneshot_channel();
+                buf_slice.map_async(wgpu::MapMode::Read, move |v| sender.send(v).unwrap());
+                if let Some(recv_result) = receiver.receive().await {
+                    recv_result?;
+                } else {
+                    return Err("channel was closed".into());
+                }
+                let mapped = buf_slice.get_mapped_range();
+                bump = Some(bytemuck::pod_read_unaligned(&mapped));
+            }
+            // TODO: allocate the blend stack as needed.
+            self.engine.free_download(bump_buf);
+            let mut bump_sizes = *render.bump_sizes();
+            let binding_limit = device.limits().max_storage_buffer_binding_size as u64;
+            let grown = grow_bump_sizes(
+                &mut bump_sizes,
+                render.config(),
+                bump.as_ref(),
+                binding_limit,
+                self.memory_limit,
+            );
+            if let Ok(false) = grown {
+                return Ok((render, bump));
+            }
+            let mut recording = Recording::default();
+            render.discard_fine(&mut recording);
+            self.engine.run_recording(
+                device,
+                queue,
+                &recording,
+                &[],
+                "t_async_discard",
+                #[cfg(feature = "wgpu-profiler")]
+                &mut self.profiler,
+            )?;
+            grown?;
+            self.bump_sizes = bump_sizes;
+        }
+    }
+
+    /// Renders a scene and starts copying the pixels back to the CPU.
+    ///
+    /// The pixels are read from the returned [`Readback`], which allows several renders to be
+    /// in flight at once. As with [`Self::render_to_texture_async`], the dynamically allocated
+    /// buffers are grown and the coarse phase run again if they overflowed, so this blocks
+    /// until the counters of the coarse phase have been read back, polling the device with
+    /// [`block_on_wgpu`]. Only the copy of the pixels is left in flight.
+    ///
+    /// This can't block on the web, where [`Self::render_to_buffer_async`] should be used.
+    pub fn render_to_buffer(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        scene: &Scene,
+        params: &RenderParams,
+    ) -> Result<Readback> {
+        block_on_wgpu(
+            device,
+            self.render_to_buffer_async(device, queue, scene, params),
+        )
+    }
+
+    /// Renders a scene with [`Self::render_to_texture_async`] and starts copying the pixels
+    /// back to the CPU.
+    ///
+    /// See [`Self::render_to_buffer`].
+    pub async fn render_to_buffer_async(
+        &mut self,
+        device: &Device,
+        queue: &Queue,
+        scene: &Scene,
+        params: &RenderParams,
+    ) -> Result<Readback> {
+        let texture = readback_texture(device, params.width, params.height, self.target_format)?;
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        self.render_to_texture_async(device, queue, scene, &view, params)
+            .await?;
+        Ok(Readback::new(
+            device,
+            queue,
+            &texture,
+            params.width,
+            params.height,
+            self.target_format,
+        ))
+    }
+
     /// See [Self::render_to_surface]
     pub async fn render_to_surface_async(
         &mut self,
@@ -289,19 +578,9 @@ impl Renderer {
         surface: &SurfaceTexture,
         params: &RenderParams,
     ) -> Result<Option<BumpAllocators>> {
-        let width = params.width;
-        let height = params.height;
-        let mut target = self
-            .target
-            .take()
-            .unwrap_or_else(|| TargetTexture::new(device, width, height));
-        // TODO: implement clever resizing semantics here to avoid thrashing the memory allocator
-        // during resize, specifically on metal.
-        if target.width != width || target.height != height {
-            target = TargetTexture::new(device, width, height);
-        }
+        let target = self.storage_texture(device, params);
         let bump = self
-            .render_to_texture_async(device, queue, scene, &target.view, params)
+            .render_to_storage_async(device, queue, scene, &target.view, params)
             .await?;
         let blit = self
             .blit
@@ -309,34 +588,10 @@ impl Renderer {
             .expect("renderer should have configured surface_format to use on a surface");
         let mut encoder =
             device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
-        {
-            let surface_view = surface
-                .texture
-                .create_view(&wgpu::TextureViewDescriptor::default());
-            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-                label: None,
-                layout: &blit.bind_layout,
-                entries: &[wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&target.view),
-                }],
-            });
-            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
-                label: None,
-                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &surface_view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color::default()),
-                        store: true,
-                    },
-                })],
-                depth_stencil_attachment: None,
-            });
-            render_pass.set_pipeline(&blit.pipeline);
-            render_pass.set_bind_group(0, &bind_group, &[]);
-            render_pass.draw(0..6, 0..1);
-        }
+        let surface_view = surface
+            .texture
+            .create_view(&wgpu::TextureViewDescriptor::default());
+        blit.draw(device, &mut encoder, &target.view, &surface_view, params);
         #[cfg(feature = "wgpu-profiler")]
         self.profiler.resolve_queries(&mut encoder);
         queue.submit(Some(encoder.finish()));
@@ -349,46 +604,498 @@ impl Renderer {
         }
         Ok(bump)
     }
+
+    /// Returns the intermediate texture in the storage format of the fine stage for a render,
+    /// reusing the previous one if possible.
+    fn storage_texture(&mut self, device: &Device, params: &RenderParams) -> TargetTexture {
+        TargetTexture::reuse_or_new(
+            self.target.take(),
+            device,
+            params.width,
+            params.height,
+            self.target_format.storage_format().to_wgpu(),
+        )
+    }
+
+    /// Copies an intermediate texture to a target texture that the fine stage can't write.
+    fn copy_to_target(
+        &self,
+        device: &Device,
+        queue: &Queue,
+        source: &TextureView,
+        target: &TextureView,
+        params: &RenderParams,
+    ) {
+        let copy = self
+            .copy
+            .as_ref()
+            .expect("copy pipeline should exist for the target format");
+        let mut encoder =
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor { label: None });
+        copy.draw(device, &mut encoder, source, target, params);
+        queue.submit(Some(encoder.finish()));
+    }
+
+    /// Returns the hit and miss counts of the caches of late bound resources (glyph outlines,
+    /// gradient ramps and images), which are retained across renders.
+    pub fn cache_stats(&self) -> ResolverStats {
+        self.cache.stats()
+    }
+
+    /// Returns the statistics of the last render.
+    pub fn render_stats(&self) -> RenderStats {
+        self.stats
+    }
+
+    /// Returns the number and size of the GPU buffers and textures kept for reuse by later
+    /// renders.
+    pub fn pool_stats(&self) -> PoolStats {
+        self.engine.pool_stats()
+    }
+
+    /// Releases pooled GPU buffers and textures, least recently used first, until the pool
+    /// holds at most `max_bytes`.
+    ///
+    /// Resources are pooled by size, so this can be called after the render target is resized
+    /// to release the resources of the previous size.
+    pub fn trim_pool(&mut self, max_bytes: u64) {
+        self.engine.trim_pool(max_bytes);
+    }
 }
 
+/// Checks that the given sizes for the dynamically allocated buffers fit within
+/// `memory_limit` in total, and within `binding_limit` for each buffer.
+fn check_memory_limit(
+    bump_sizes: &BumpSizes,
+    binding_limit: u64,
+    memory_limit: Option<u64>,
+) -> Result<()> {
+    let max_buffer_size = bump_sizes.max_buffer_size_in_bytes();
+    if max_buffer_size > binding_limit {
+        return Err(MemoryLimitExceeded {
+            requested: max_buffer_size,
+            limit: binding_limit,
+        }
+        .into());
+    }
+    if let Some(limit) = memory_limit {
+        let size = bump_sizes.size_in_bytes();
+        if size > limit {
+            return Err(MemoryLimitExceeded {
+                requested: size,
+                limit,
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Grows the sizes of the dynamically allocated buffers of a render to fit the allocations
+/// recorded in `bump`, returning `true` if they grew.
+///
+/// The sizes are checked with [`check_memory_limit`] before and after growing them, so that
+/// sizes estimated from the scene that are already too large fail up front. Fails with
+/// [`AllocationFailed`] if a stage failed without overflowing a buffer.
+fn grow_bump_sizes(
+    bump_sizes: &mut BumpSizes,
+    config: &vello_encoding::ConfigUniform,
+    bump: Option<&BumpAllocators>,
+    binding_limit: u64,
+    memory_limit: Option<u64>,
+) -> Result<bool> {
+    check_memory_limit(bump_sizes, binding_limit, memory_limit)?;
+    let Some(bump) = bump else {
+        return Ok(false);
+    };
+    let grew = bump_sizes.grow(config, bump)?;
+    if grew {
+        check_memory_limit(bump_sizes, binding_limit, memory_limit)?;
+    }
+    Ok(grew)
+}
+
+/// Returns the encoding to render for `scene`, with its patterns expanded if
+/// `expand_patterns` is set, and instanced otherwise.
+///
+/// Layers with effects are split from the encoding, to be rendered first, which also
+/// expands the patterns. Fails with [`vello_encoding::PatternError`] if the patterns
+/// can't be expanded or instanced.
+fn scene_encoding<'a>(
+    scene: &'a Scene,
+    params: &RenderParams,
+    expand_patterns: bool,
+) -> Result<(Cow<'a, Encoding>, EffectLayers, RenderStats)> {
+    let encoding = scene.data();
+    let viewport = [0.0, 0.0, params.width as f32, params.height as f32];
+    // Patterns count their begin and end draw objects.
+    let stats = RenderStats {
+        expanded_patterns: encoding.n_patterns / 2,
+        ..Default::default()
+    };
+    if !encoding.effects.is_empty() {
+        let (encoding, effect_layers) = vello_encoding::split_effects(encoding, viewport)?;
+        let stats = RenderStats {
+            dropped_effects: effect_layers.dropped,
+            ..stats
+        };
+        return Ok((Cow::Owned(encoding), effect_layers, stats));
+    }
+    if encoding.n_patterns != 0 {
+        if expand_patterns {
+            let encoding = vello_encoding::expand_patterns(encoding, viewport)?;
+            return Ok((Cow::Owned(encoding), EffectLayers::default(), stats));
+        }
+        let encoding = vello_encoding::instance_patterns(encoding, viewport)?;
+        return Ok((
+            Cow::Owned(encoding),
+            EffectLayers::default(),
+            RenderStats::default(),
+        ));
+    }
+    Ok((
+        Cow::Borrowed(encoding),
+        EffectLayers::default(),
+        RenderStats::default(),
+    ))
+}
+
+/// Renders a scene into a CPU image, without requiring a GPU.
+///
+/// This runs the same recording as [`Renderer`], with each stage executed by
+/// the ports in [`cpu_shader`].
+pub struct CpuRenderer {
+    engine: CpuEngine,
+    shaders: FullShaders,
+    cache: RenderCache,
+    bump_sizes: BumpSizes,
+    memory_limit: Option<u64>,
+    expand_patterns: bool,
+    target_format: TargetFormat,
+    stats: RenderStats,
+}
+
+impl CpuRenderer {
+    /// Creates a new CPU renderer.
+    pub fn new() -> Self {
+        let mut engine = CpuEngine::new();
+        let shaders = shaders::full_shaders_cpu(&mut engine);
+        Self {
+            engine,
+            shaders,
+            cache: RenderCache::default(),
+            bump_sizes: BumpSizes::default(),
+            memory_limit: None,
+            expand_patterns: false,
+            target_format: TargetFormat::default(),
+            stats: RenderStats::default(),
+        }
+    }
+
+    /// Sets the format of the pixels returned by [`Self::render_to_buffer`], as with
+    /// [`RendererOptions::target_format`].
+    ///
+    /// The fine stage of the CPU renderer always writes RGBA8, which is converted to the
+    /// target format. [`TargetFormat::Rgba16Float`] pixels are therefore quantized to 8 bits
+    /// per channel, unlike those of the GPU renderer, and values outside of `0..=1` are
+    /// clamped.
+    pub fn with_target_format(mut self, target_format: TargetFormat) -> Self {
+        self.target_format = target_format;
+        self
+    }
+
+    /// Sets whether patterns are expanded on the CPU, as with
+    /// [`RendererOptions::expand_patterns`].
+    pub fn with_expand_patterns(mut self, expand_patterns: bool) -> Self {
+        self.expand_patterns = expand_patterns;
+        self
+    }
+
+    /// Sets the maximum total size in bytes of the dynamically allocated buffers.
+    ///
+    /// As with [`RendererOptions::memory_limit`], rendering fails with
+    /// [`MemoryLimitExceeded`] rather than growing the buffers past this limit. Each
+    /// buffer is also limited to the default storage buffer binding size of wgpu.
+    pub fn with_memory_limit(mut self, memory_limit: Option<u64>) -> Self {
+        self.memory_limit = memory_limit;
+        self
+    }
+
+    /// Renders a scene to the target texture.
+    ///
+    /// The texture is assumed to be of the specified dimensions. As with the GPU renderer,
+    /// the pixels are written as separated (not premultiplied) RGBA8.
+    ///
+    /// Like [`Renderer::render_to_texture_async`], the coarse phase is run again with grown
+    /// buffers if the dynamically allocated buffers overflowed, up to the memory limit set
+    /// with [`with_memory_limit`](Self::with_memory_limit).
+    pub fn render_to_texture(
+        &mut self,
+        scene: &Scene,
+        texture: &mut CpuTexture,
+        params: &RenderParams,
+    ) -> Result<()> {
+        let (encoding, effect_layers, stats) = scene_encoding(scene, params, self.expand_patterns)?;
+        self.stats = stats;
+        self.cache.advance();
+        let layer_image = render::effect_layer_image(&effect_layers, &self.shaders);
+        for layer in &effect_layers.layers {
+            let layer_params = render::effect_layer_params(layer, params);
+            let mut render = self.render_coarse(&layer.encoding, &layer_params)?;
+            let mut recording = Recording::default();
+            render.record_effect_layer(layer, layer_image, &self.shaders, &mut recording);
+            self.engine.run_recording(&recording, &[])?;
+        }
+        let mut render = self.render_coarse(&encoding, params)?;
+        let target = render.out_image();
+        let mut recording = Recording::default();
+        render.set_layer_image(layer_image);
+        render.record_fine(&self.shaders, &mut recording);
+        recording.free_image(layer_image);
+        let target_texture = std::cell::RefCell::new(std::mem::take(texture));
+        let external_resources = [CpuExternalResource::Image(target, &target_texture)];
+        let result = self.engine.run_recording(&recording, &external_resources);
+        *texture = target_texture.into_inner();
+        result?;
+        Ok(())
+    }
+
+    /// Renders a scene and returns its pixels as tightly packed rows in the
+    /// [target format](Self::with_target_format), as with [`Renderer::render_to_buffer`].
+    ///
+    /// The scene is rasterized to RGBA8 as with [`Self::render_to_texture`], so
+    /// [`TargetFormat::Rgba16Float`] pixels only have the precision of 8 bit channels, see
+    /// [`Self::with_target_format`].
+    pub fn render_to_buffer(
+        &mut self,
+        scene: &Scene,
+        params: &RenderParams,
+        alpha_mode: AlphaMode,
+    ) -> Result<Vec<u8>> {
+        let mut texture = CpuTexture::new(params.width, params.height);
+        self.render_to_texture(scene, &mut texture, params)?;
+        Ok(readback::convert_rgba8(
+            texture.as_bytes(),
+            params.width,
+            self.target_format,
+            alpha_mode,
+        ))
+    }
+
+    /// Runs the coarse phase of rendering an encoding, growing the dynamically allocated
+    /// buffers and running it again until they don't overflow.
+    ///
+    /// Fails as [`Renderer::render_coarse_async`] does.
+    fn render_coarse(&mut self, encoding: &Encoding, params: &RenderParams) -> Result<Render> {
+        loop {
+            let mut render = Render::new();
+            let recording = render.render_encoding_coarse(
+                encoding,
+                &mut self.cache,
+                &self.shaders,
+                params,
+                &self.bump_sizes,
+                true,
+            );
+            let bump_buf = render.bump_buf();
+            self.engine.run_recording(&recording, &[])?;
+            let bump: Option<BumpAllocators> = self
+                .engine
+                .get_download(bump_buf)
+                .map(bytemuck::pod_read_unaligned);
+            self.engine.free_download(bump_buf);
+            let mut bump_sizes = *render.bump_sizes();
+            let binding_limit = wgpu::Limits::default().max_storage_buffer_binding_size as u64;
+            let grown = grow_bump_sizes(
+                &mut bump_sizes,
+                render.config(),
+                bump.as_ref(),
+                binding_limit,
+                self.memory_limit,
+            );
+            if let Ok(false) = grown {
+                return Ok(render);
+            }
+            let mut recording = Recording::default();
+            render.discard_fine(&mut recording);
+            self.engine.run_recording(&recording, &[])?;
+            grown?;
+            self.bump_sizes = bump_sizes;
+        }
+    }
+
+    /// Returns the hit and miss counts of the caches of late bound resources.
+    ///
+    /// See [`Renderer::cache_stats`].
+    pub fn cache_stats(&self) -> ResolverStats {
+        self.cache.stats()
+    }
+
+    /// Returns the statistics of the last render.
+    pub fn render_stats(&self) -> RenderStats {
+        self.stats
+    }
+
+    /// Returns the sizes of the dynamically allocated buffers that later renders start from.
+    ///
+    /// Sizes are only retained once a render overflowed the sizes estimated for its scene,
+    /// and are zero until then.
+    pub fn bump_sizes(&self) -> BumpSizes {
+        self.bump_sizes
+    }
+}
+
+impl Default for CpuRenderer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Granularity of the size of the intermediate target of [`Renderer::render_to_surface`].
+///
+/// The target is over-allocated to a multiple of this size, so that resizing a window doesn't
+/// allocate a new texture every frame.
+const TARGET_SIZE_STEP: u32 = 256;
+
+/// Number of consecutive renders needing a smaller target before the target is shrunk.
+const TARGET_SHRINK_DELAY: u32 = 60;
+
 struct TargetTexture {
     view: TextureView,
+    size: TargetSize,
+}
+
+/// Size of a target texture, with the number of consecutive renders that needed a smaller
+/// target.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct TargetSize {
     width: u32,
     height: u32,
+    oversized_renders: u32,
+}
+
+impl TargetSize {
+    fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            oversized_renders: 0,
+        }
+    }
+
+    /// Returns the size of the target for a render of the specified size, given the size of
+    /// the previous target.
+    ///
+    /// The previous target is reused, with the same width and height, unless it is too small,
+    /// or has been larger than needed for [`TARGET_SHRINK_DELAY`] renders. New targets are
+    /// rounded up to [`TARGET_SIZE_STEP`], but not beyond `max_size`.
+    fn next(previous: Option<Self>, width: u32, height: u32, max_size: u32) -> Self {
+        let step_size = |size| {
+            wgpu::util::align_to(size, TARGET_SIZE_STEP)
+                .min(max_size)
+                .max(size)
+        };
+        let alloc = Self::new(step_size(width), step_size(height));
+        match previous {
+            Some(mut size) if size.width >= width && size.height >= height => {
+                if size.width > alloc.width || size.height > alloc.height {
+                    size.oversized_renders += 1;
+                    if size.oversized_renders > TARGET_SHRINK_DELAY {
+                        return alloc;
+                    }
+                } else {
+                    size.oversized_renders = 0;
+                }
+                size
+            }
+            _ => alloc,
+        }
+    }
 }
 
 impl TargetTexture {
-    pub fn new(device: &Device, width: u32, height: u32) -> Self {
+    pub fn new(device: &Device, size: TargetSize, format: TextureFormat) -> Self {
         let texture = device.create_texture(&wgpu::TextureDescriptor {
             label: None,
             size: wgpu::Extent3d {
-                width,
-                height,
+                width: size.width,
+                height: size.height,
                 depth_or_array_layers: 1,
             },
             mip_level_count: 1,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
             usage: wgpu::TextureUsages::STORAGE_BINDING | wgpu::TextureUsages::TEXTURE_BINDING,
-            format: wgpu::TextureFormat::Rgba8Unorm,
+            format,
             view_formats: &[],
         });
         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
-        Self {
-            view,
-            width,
-            height,
+        Self { view, size }
+    }
+
+    /// Returns a target for a render of the specified size, reusing the previous target if
+    /// [`TargetSize::next`] keeps its size.
+    fn reuse_or_new(
+        target: Option<Self>,
+        device: &Device,
+        width: u32,
+        height: u32,
+        format: TextureFormat,
+    ) -> Self {
+        let max_size = device.limits().max_texture_dimension_2d;
+        let size = TargetSize::next(target.as_ref().map(|t| t.size), width, height, max_size);
+        match target {
+            Some(mut target)
+                if (target.size.width, target.size.height) == (size.width, size.height) =>
+            {
+                target.size = size;
+                target
+            }
+            _ => Self::new(device, size, format),
         }
     }
 }
 
+/// Creates a texture that can be rendered to and copied to a [`Readback`] buffer.
+fn readback_texture(
+    device: &Device,
+    width: u32,
+    height: u32,
+    format: TargetFormat,
+) -> Result<wgpu::Texture> {
+    // Copies of empty textures are invalid, so report them before rendering.
+    if width == 0 || height == 0 {
+        return Err(format!("cannot read back a {width}x{height} render").into());
+    }
+    let usage = match format {
+        TargetFormat::Bgra8 => wgpu::TextureUsages::RENDER_ATTACHMENT,
+        _ => wgpu::TextureUsages::STORAGE_BINDING,
+    };
+    Ok(device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("readback texture"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        usage: usage | wgpu::TextureUsages::COPY_SRC,
+        format: format.texture_format(),
+        view_formats: &[],
+    }))
+}
+
 struct BlitPipeline {
     bind_layout: wgpu::BindGroupLayout,
     pipeline: wgpu::RenderPipeline,
 }
 
 impl BlitPipeline {
-    fn new(device: &Device, format: TextureFormat) -> Self {
+    /// Creates a pipeline that draws a texture of separated RGBA values to a target of the
+    /// specified format, premultiplying them by alpha if `premultiply` is set.
+    fn new(device: &Device, format: TextureFormat, premultiply: bool) -> Self {
         const SHADERS: &str = r#"
             @vertex
             fn vs_main(@builtin(vertex_index) ix: u32) -> @builtin(position) vec4<f32> {
@@ -417,6 +1124,11 @@ impl BlitPipeline {
                 let rgba_sep = textureLoad(fine_output, vec2<i32>(pos.xy), 0);
                 return vec4(rgba_sep.rgb * rgba_sep.a, rgba_sep.a);
             }
+
+            @fragment
+            fn fs_copy(@builtin(position) pos: vec4<f32>) -> @location(0) vec4<f32> {
+                return textureLoad(fine_output, vec2<i32>(pos.xy), 0);
+            }
         "#;
 
         let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
@@ -451,7 +1163,7 @@ impl BlitPipeline {
             },
             fragment: Some(wgpu::FragmentState {
                 module: &shader,
-                entry_point: "fs_main",
+                entry_point: if premultiply { "fs_main" } else { "fs_copy" },
                 targets: &[Some(wgpu::ColorTargetState {
                     format,
                     blend: None,
@@ -480,4 +1192,97 @@ impl BlitPipeline {
             pipeline,
         }
     }
+
+    /// Records a render pass that draws the top left corner of `source` to `target`, which
+    /// has the dimensions of the render.
+    fn draw(
+        &self,
+        device: &Device,
+        encoder: &mut wgpu::CommandEncoder,
+        source: &TextureView,
+        target: &TextureView,
+        params: &RenderParams,
+    ) {
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &self.bind_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(source),
+            }],
+        });
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: None,
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: target,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::default()),
+                    store: true,
+                },
+            })],
+            depth_stencil_attachment: None,
+        });
+        // The source may be larger than the target, and only its top left corner is
+        // rendered.
+        render_pass.set_viewport(
+            0.0,
+            0.0,
+            params.width as f32,
+            params.height as f32,
+            0.0,
+            1.0,
+        );
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &bind_group, &[]);
+        render_pass.draw(0..6, 0..1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn targets_grow_in_steps() {
+        let size = TargetSize::next(None, 300, 100, 8192);
+        assert_eq!(size, TargetSize::new(512, 256));
+        // Sizes beyond the last step are clamped to the limit, but never below the render.
+        assert_eq!(
+            TargetSize::next(Some(size), 8000, 100, 8000),
+            TargetSize::new(8000, 256)
+        );
+        assert_eq!(
+            TargetSize::next(Some(size), 600, 100, 512),
+            TargetSize::new(600, 256)
+        );
+    }
+
+    #[test]
+    fn targets_are_reused() {
+        let size = TargetSize::new(512, 256);
+        // A render within the same steps doesn't count as oversized.
+        assert_eq!(TargetSize::next(Some(size), 400, 200, 8192), size);
+        let oversized = TargetSize::next(Some(size), 100, 100, 8192);
+        assert_eq!(
+            oversized,
+            TargetSize {
+                oversized_renders: 1,
+                ..size
+            }
+        );
+        assert_eq!(TargetSize::next(Some(oversized), 512, 256, 8192), size);
+    }
+
+    #[test]
+    fn targets_shrink_after_a_delay() {
+        let mut size = TargetSize::new(1024, 1024);
+        for renders in 1..=TARGET_SHRINK_DELAY {
+            size = TargetSize::next(Some(size), 100, 300, 8192);
+            assert_eq!((size.width, size.height), (1024, 1024));
+            assert_eq!(size.oversized_renders, renders);
+        }
+        size = TargetSize::next(Some(size), 100, 300, 8192);
+        assert_eq!(size, TargetSize::new(256, 512));
+    }
 }
@@ -0,0 +1,387 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use futures_intrusive::channel::shared::{oneshot_channel, OneshotReceiver};
+use wgpu::{BufferAsyncError, Device, Queue};
+
+use crate::{Result, TargetFormat};
+
+/// Representation of the alpha channel of pixels read back from a render.
+#[derive(Copy, Clone, PartialEq, Eq, Default, Debug)]
+pub enum AlphaMode {
+    /// Color channels are not multiplied by alpha, as written by the renderer.
+    #[default]
+    Separated,
+    /// Color channels are multiplied by alpha.
+    Premultiplied,
+}
+
+/// Pixels of a render being copied back to the CPU.
+///
+/// This is returned by [`Renderer::render_to_buffer`]. The copy makes progress when the
+/// device is polled, so several renders can be started before waiting on any of them.
+///
+/// [`Renderer::render_to_buffer`]: crate::Renderer::render_to_buffer
+pub struct Readback {
+    buffer: wgpu::Buffer,
+    width: u32,
+    height: u32,
+    format: TargetFormat,
+    bytes_per_row: u32,
+    ready: Arc<AtomicBool>,
+    receiver: OneshotReceiver<std::result::Result<(), BufferAsyncError>>,
+}
+
+impl Readback {
+    /// Copies the specified texture into a new buffer and starts mapping it.
+    pub(crate) fn new(
+        device: &Device,
+        queue: &Queue,
+        texture: &wgpu::Texture,
+        width: u32,
+        height: u32,
+        format: TargetFormat,
+    ) -> Self {
+        // Rows of a texture copy must be aligned, and are trimmed when the pixels are read.
+        let bytes_per_row = wgpu::util::align_to(
+            width * bytes_per_pixel(format),
+            wgpu::COPY_BYTES_PER_ROW_ALIGNMENT,
+        );
+        let buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("readback buffer"),
+            size: bytes_per_row as u64 * height as u64,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("readback"),
+        });
+        encoder.copy_texture_to_buffer(
+            texture.as_image_copy(),
+            wgpu::ImageCopyBuffer {
+                buffer: &buffer,
+                layout: wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(bytes_per_row),
+                    rows_per_image: None,
+                },
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+        );
+        queue.submit(Some(encoder.finish()));
+        let ready = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = oneshot_channel();
+        let ready_in_callback = ready.clone();
+        buffer
+            .slice(..)
+            .map_async(wgpu::MapMode::Read, move |result| {
+                ready_in_callback.store(true, Ordering::Release);
+                // The receiver is gone if the readback was dropped before it completed.
+                let _ = sender.send(result);
+            });
+        Self {
+            buffer,
+            width,
+            height,
+            format,
+            bytes_per_row,
+            ready,
+            receiver,
+        }
+    }
+
+    /// Returns the width of the image in pixels.
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// Returns the height of the image in pixels.
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Returns the format of the pixels.
+    pub fn format(&self) -> TargetFormat {
+        self.format
+    }
+
+    /// Returns true if the pixels can be read without waiting on the GPU.
+    ///
+    /// This only changes when the device is polled, for example with
+    /// `device.poll(wgpu::Maintain::Poll)`.
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::Acquire)
+    }
+
+    /// Waits for the copy to complete and returns the pixels as tightly packed rows in the
+    /// [format](Self::format) of the render. [`TargetFormat::Rgba16Float`] channels are
+    /// native endian half precision floats.
+    ///
+    /// As with [`Renderer::render_to_texture_async`], the future only completes when the
+    /// device is polled, for example with [`block_on_wgpu`](crate::block_on_wgpu).
+    ///
+    /// [`Renderer::render_to_texture_async`]: crate::Renderer::render_to_texture_async
+    pub async fn pixels(self, alpha_mode: AlphaMode) -> Result<Vec<u8>> {
+        if let Some(recv_result) = self.receiver.receive().await {
+            recv_result?;
+        } else {
+            return Err("channel was closed".into());
+        }
+        let pixels = {
+            let mapped = self.buffer.slice(..).get_mapped_range();
+            read_pixels(
+                &mapped,
+                self.width,
+                self.bytes_per_row,
+                self.format,
+                alpha_mode,
+            )
+        };
+        self.buffer.unmap();
+        Ok(pixels)
+    }
+}
+
+/// Trims the aligned rows of `mapped` to tightly packed rows of `width` pixels, and
+/// converts them to `alpha_mode`.
+fn read_pixels(
+    mapped: &[u8],
+    width: u32,
+    bytes_per_row: u32,
+    format: TargetFormat,
+    alpha_mode: AlphaMode,
+) -> Vec<u8> {
+    let row_size = (width * bytes_per_pixel(format)) as usize;
+    let mut pixels = Vec::with_capacity(row_size * mapped.len() / bytes_per_row as usize);
+    for row in mapped.chunks_exact(bytes_per_row as usize) {
+        pixels.extend_from_slice(&row[..row_size]);
+    }
+    if alpha_mode == AlphaMode::Premultiplied {
+        match format {
+            TargetFormat::Rgba8 | TargetFormat::Bgra8 => {
+                for pixel in pixels.chunks_exact_mut(4) {
+                    let alpha = pixel[3] as u32;
+                    for channel in &mut pixel[..3] {
+                        *channel = ((*channel as u32 * alpha + 127) / 255) as u8;
+                    }
+                }
+            }
+            TargetFormat::Rgba16Float => {
+                for pixel in pixels.chunks_exact_mut(8) {
+                    let alpha = f16_to_f32(u16::from_ne_bytes([pixel[6], pixel[7]]));
+                    for channel in pixel[..6].chunks_exact_mut(2) {
+                        let value = f16_to_f32(u16::from_ne_bytes([channel[0], channel[1]]));
+                        channel.copy_from_slice(&f32_to_f16(value * alpha).to_ne_bytes());
+                    }
+                }
+            }
+        }
+    }
+    pixels
+}
+
+/// Converts tightly packed RGBA8 rows of `width` pixels, as written by the CPU renderer, to
+/// `format` and `alpha_mode`.
+///
+/// [`TargetFormat::Rgba16Float`] channels are widened from 8 bits, so they keep that precision.
+pub(crate) fn convert_rgba8(
+    rgba: &[u8],
+    width: u32,
+    format: TargetFormat,
+    alpha_mode: AlphaMode,
+) -> Vec<u8> {
+    let converted: Vec<u8> = match format {
+        TargetFormat::Rgba8 => rgba.to_vec(),
+        TargetFormat::Bgra8 => rgba
+            .chunks_exact(4)
+            .flat_map(|pixel| [pixel[2], pixel[1], pixel[0], pixel[3]])
+            .collect(),
+        TargetFormat::Rgba16Float => rgba
+            .iter()
+            .flat_map(|&channel| f32_to_f16(channel as f32 / 255.0).to_ne_bytes())
+            .collect(),
+    };
+    let bytes_per_row = width * bytes_per_pixel(format);
+    read_pixels(&converted, width, bytes_per_row, format, alpha_mode)
+}
+
+fn bytes_per_pixel(format: TargetFormat) -> u32 {
+    match format {
+        TargetFormat::Rgba8 | TargetFormat::Bgra8 => 4,
+        TargetFormat::Rgba16Float => 8,
+    }
+}
+
+fn f16_to_f32(half: u16) -> f32 {
+    let sign = ((half & 0x8000) as u32) << 16;
+    let exponent = ((half >> 10) & 0x1f) as u32;
+    let mantissa = (half & 0x3ff) as u32;
+    let bits = match exponent {
+        0 => {
+            // Zero or subnormal, which is normal as a single precision float.
+            let value = mantissa as f32 * (1.0 / (1 << 24) as f32);
+            return if sign != 0 { -value } else { value };
+        }
+        0x1f => sign | 0x7f80_0000 | (mantissa << 13),
+        _ => sign | ((exponent + 127 - 15) << 23) | (mantissa << 13),
+    };
+    f32::from_bits(bits)
+}
+
+fn f32_to_f16(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32 - 127 + 15;
+    let mantissa = bits & 0x7f_ffff;
+    if value.is_nan() {
+        sign | 0x7e00
+    } else if exponent >= 0x1f {
+        sign | 0x7c00
+    } else if exponent <= 0 {
+        if exponent < -10 {
+            return sign;
+        }
+        // Subnormal, rounded to nearest.
+        let mantissa = mantissa | 0x80_0000;
+        let shift = (14 - exponent) as u32;
+        let half = (mantissa >> shift) + ((mantissa >> (shift - 1)) & 1);
+        sign | half as u16
+    } else {
+        // Rounded to nearest, carrying into the exponent if needed.
+        let half = ((exponent as u32) << 10) | (mantissa >> 13);
+        sign | (half + ((mantissa >> 12) & 1)) as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use peniko::kurbo::{Affine, Rect};
+    use peniko::{Color, Fill};
+
+    use super::*;
+    use crate::{ColorSpace, CpuRenderer, RenderParams, Scene, SceneBuilder};
+
+    fn halves(values: &[f32]) -> Vec<u8> {
+        values
+            .iter()
+            .flat_map(|&value| f32_to_f16(value).to_ne_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn rows_are_trimmed() {
+        // Two rows of one pixel, each padded to 8 bytes.
+        let mapped = [1, 2, 3, 4, 0, 0, 0, 0, 5, 6, 7, 8, 0, 0, 0, 0];
+        for format in [TargetFormat::Rgba8, TargetFormat::Bgra8] {
+            let pixels = read_pixels(&mapped, 1, 8, format, AlphaMode::Separated);
+            assert_eq!(pixels, [1, 2, 3, 4, 5, 6, 7, 8]);
+        }
+        let mut mapped = halves(&[0.25, 0.5, 0.75, 1.0]);
+        mapped.extend_from_slice(&[9; 8]);
+        let pixels = read_pixels(
+            &mapped,
+            1,
+            16,
+            TargetFormat::Rgba16Float,
+            AlphaMode::Separated,
+        );
+        assert_eq!(pixels, halves(&[0.25, 0.5, 0.75, 1.0]));
+    }
+
+    #[test]
+    fn premultiplies_alpha() {
+        let mapped = [255, 128, 0, 128, 200, 100, 50, 0, 10, 20, 30, 255];
+        for format in [TargetFormat::Rgba8, TargetFormat::Bgra8] {
+            let pixels = read_pixels(&mapped, 3, 12, format, AlphaMode::Premultiplied);
+            assert_eq!(pixels, [128, 64, 0, 128, 0, 0, 0, 0, 10, 20, 30, 255]);
+        }
+        let mapped = halves(&[1.0, 0.5, 0.25, 0.5, 0.75, 0.5, 1.0, 0.0]);
+        let pixels = read_pixels(
+            &mapped,
+            2,
+            16,
+            TargetFormat::Rgba16Float,
+            AlphaMode::Premultiplied,
+        );
+        assert_eq!(pixels, halves(&[0.5, 0.25, 0.125, 0.5, 0.0, 0.0, 0.0, 0.0]));
+    }
+
+    #[test]
+    fn half_floats_round_trip() {
+        for value in [0.0, -0.0, 1.0, -2.5, 65504.0, 6.1035156e-5, 5.9604645e-8] {
+            assert_eq!(f16_to_f32(f32_to_f16(value)), value);
+        }
+        assert_eq!(f32_to_f16(1e6), 0x7c00);
+        assert!(f16_to_f32(f32_to_f16(f32::NAN)).is_nan());
+    }
+
+    /// Renders a 16x16 scene filled with `color` over a transparent base, returning the
+    /// first pixel.
+    fn render_pixel(
+        color: Color,
+        format: TargetFormat,
+        alpha_mode: AlphaMode,
+        target_color_space: ColorSpace,
+    ) -> Vec<u8> {
+        let mut scene = Scene::new();
+        let rect = Rect::new(0.0, 0.0, 16.0, 16.0);
+        SceneBuilder::for_scene(&mut scene).fill(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            color,
+            None,
+            &rect,
+        );
+        let params = RenderParams {
+            base_color: Color::TRANSPARENT,
+            width: 16,
+            height: 16,
+            target_color_space,
+            blend_color_space: Default::default(),
+        };
+        let pixels = CpuRenderer::new()
+            .with_target_format(format)
+            .render_to_buffer(&scene, &params, alpha_mode)
+            .unwrap();
+        assert_eq!(pixels.len(), 16 * 16 * bytes_per_pixel(format) as usize);
+        pixels[..bytes_per_pixel(format) as usize].to_vec()
+    }
+
+    #[test]
+    fn cpu_bgra8_output() {
+        let color = Color::rgba8(255, 128, 0, 255);
+        let pixel = |format| render_pixel(color, format, AlphaMode::Separated, ColorSpace::Srgb);
+        assert_eq!(pixel(TargetFormat::Rgba8), [255, 128, 0, 255]);
+        assert_eq!(pixel(TargetFormat::Bgra8), [0, 128, 255, 255]);
+    }
+
+    #[test]
+    fn cpu_rgba16float_output() {
+        let color = Color::rgba8(255, 0, 0, 102);
+        let format = TargetFormat::Rgba16Float;
+        let separated = render_pixel(color, format, AlphaMode::Separated, ColorSpace::Srgb);
+        assert_eq!(separated, halves(&[1.0, 0.0, 0.0, 0.4]));
+        let premultiplied = render_pixel(color, format, AlphaMode::Premultiplied, ColorSpace::Srgb);
+        assert_eq!(premultiplied, halves(&[0.4, 0.0, 0.0, 0.4]));
+    }
+
+    #[test]
+    fn cpu_linear_srgb_output() {
+        // sRGB 188 is about 0.5 in linear light, and alpha is not converted. Separated
+        // channels of translucent pixels are rounded once more.
+        let color = Color::rgba8(188, 188, 188, 128);
+        for (space, expected) in [(ColorSpace::Srgb, 188), (ColorSpace::LinearSrgb, 128)] {
+            let pixel = render_pixel(color, TargetFormat::Rgba8, AlphaMode::Separated, space);
+            for (channel, expected) in pixel.iter().zip([expected, expected, expected, 128]) {
+                assert!(channel.abs_diff(expected) <= 1, "{pixel:?} in {space:?}");
+            }
+        }
+    }
+}
@@ -3,14 +3,21 @@
 use crate::{
     engine::{BufProxy, ImageFormat, ImageProxy, Recording, ResourceProxy},
     shaders::FullShaders,
-    RenderParams, Scene,
+    RenderParams,
+};
+use peniko::{Color, Image};
+use vello_encoding::{
+    BlurConfig, BumpSizes, ColorSpace, ConfigUniform, EffectLayer, EffectLayers, Encoding, Images,
+    Ramps, Resolver, ResolverStats, WorkgroupSize,
 };
-use vello_encoding::{Encoding, WorkgroupSize};
 
 /// State for a render in progress.
 pub struct Render {
     fine_wg_count: Option<WorkgroupSize>,
     fine_resources: Option<FineResources>,
+    config: Option<ConfigUniform>,
+    bump_sizes: Option<BumpSizes>,
+    layer_image: Option<ImageProxy>,
 }
 
 /// Resources produced by pipeline, needed for fine rasterization.
@@ -27,87 +34,283 @@ struct FineResources {
     out_image: ImageProxy,
 }
 
-pub fn render_full(
-    scene: &Scene,
-    shaders: &FullShaders,
-    params: &RenderParams,
-) -> (Recording, ResourceProxy) {
-    render_encoding_full(scene.data(), shaders, params)
+/// Late bound resources retained across renders.
+///
+/// The resolver keeps its caches of glyphs, gradient ramps and images, and the ramp and
+/// image atlas textures are kept by the engine, so that only the parts that changed are
+/// uploaded. The textures belong to the engine that ran the recordings, so this must be
+/// reset when the engine is replaced.
+#[derive(Default)]
+pub struct RenderCache {
+    resolver: Resolver,
+    images: RetainedImages,
+}
+
+/// Textures that late bound resources are uploaded to.
+#[derive(Default)]
+struct RetainedImages {
+    ramps: Option<ImageProxy>,
+    atlas: Option<ImageProxy>,
+}
+
+impl RenderCache {
+    /// Advances the caches to the next frame. This should be called once per frame.
+    pub fn advance(&mut self) {
+        self.resolver.advance();
+    }
+
+    /// Returns the counters of the resolver's caches.
+    pub fn stats(&self) -> ResolverStats {
+        self.resolver.stats()
+    }
+}
+
+impl RetainedImages {
+    /// Returns the gradient ramp texture, writing the rows that changed.
+    fn ramp_image(&mut self, ramps: &Ramps, recording: &mut Recording) -> ImageProxy {
+        match self.ramps {
+            // Without gradients, the contents of the texture don't matter.
+            Some(image) if ramps.height == 0 => image,
+            Some(image) if image.width == ramps.width && image.height == ramps.height => {
+                let rows = ramps.dirty_rows.clone();
+                if !rows.is_empty() {
+                    let start = (rows.start * ramps.width) as usize;
+                    let end = (rows.end * ramps.width) as usize;
+                    recording.write_image(
+                        image,
+                        0,
+                        rows.start,
+                        ramps.width,
+                        rows.end - rows.start,
+                        bytemuck::cast_slice::<u32, u8>(&ramps.data[start..end]),
+                    );
+                }
+                image
+            }
+            _ => {
+                if let Some(image) = self.ramps.take() {
+                    recording.free_image(image);
+                }
+                let image = if ramps.height == 0 {
+                    ImageProxy::new(1, 1, ImageFormat::Rgba8)
+                } else {
+                    let image = ImageProxy::new(ramps.width, ramps.height, ImageFormat::Rgba8);
+                    let data: &[u8] = bytemuck::cast_slice(ramps.data);
+                    recording.write_image(image, 0, 0, ramps.width, ramps.height, data);
+                    image
+                };
+                self.ramps = Some(image);
+                image
+            }
+        }
+    }
+
+    /// Returns the image atlas texture, writing the images that were added to it.
+    ///
+    /// Images keep their locations when the atlas grows or gains pages, but the texture is
+    /// then recreated and all images are written again.
+    fn atlas_image(&mut self, images: &Images, recording: &mut Recording) -> ImageProxy {
+        match self.atlas {
+            // Without images, the contents of the texture don't matter.
+            Some(atlas) if images.images.is_empty() => atlas,
+            Some(atlas)
+                if atlas.width == images.width
+                    && atlas.height == images.height
+                    && atlas.layer_count() == images.pages =>
+            {
+                write_atlas_images(atlas, images.dirty, recording);
+                atlas
+            }
+            _ => {
+                if let Some(atlas) = self.atlas.take() {
+                    recording.free_image(atlas);
+                }
+                let atlas = if images.images.is_empty() {
+                    ImageProxy::new_array(1, 1, 1, ImageFormat::Rgba8)
+                } else {
+                    let atlas = ImageProxy::new_array(
+                        images.width,
+                        images.height,
+                        images.pages,
+                        ImageFormat::Rgba8,
+                    );
+                    write_atlas_images(atlas, images.images, recording);
+                    atlas
+                };
+                self.atlas = Some(atlas);
+                atlas
+            }
+        }
+    }
+}
+
+fn write_atlas_images(
+    atlas: ImageProxy,
+    images: &[(Image, u32, u32, u32)],
+    recording: &mut Recording,
+) {
+    for (image, x, y, page) in images {
+        recording.write_image_layer(
+            atlas,
+            *page,
+            *x,
+            *y,
+            image.width,
+            image.height,
+            image.data.data(),
+        );
+    }
 }
 
 /// Create a single recording with both coarse and fine render stages.
 ///
+/// The layers of layer effects split from the encoding are rendered first.
+///
 /// This function is not recommended when the scene can be complex, as it does not
 /// implement robust dynamic memory.
 pub fn render_encoding_full(
     encoding: &Encoding,
+    effect_layers: &EffectLayers,
+    cache: &mut RenderCache,
     shaders: &FullShaders,
     params: &RenderParams,
+    bump_sizes: &BumpSizes,
 ) -> (Recording, ResourceProxy) {
+    let (mut recording, layer_image) =
+        render_effect_layers(effect_layers, cache, shaders, params, bump_sizes);
     let mut render = Render::new();
-    let mut recording = render.render_encoding_coarse(encoding, shaders, params, false);
+    let coarse = render.render_encoding_coarse(encoding, cache, shaders, params, bump_sizes, false);
+    recording.commands.extend(coarse.commands);
     let out_image = render.out_image();
+    render.set_layer_image(layer_image);
     render.record_fine(shaders, &mut recording);
+    recording.free_image(layer_image);
     (recording, out_image.into())
 }
 
+/// Create a recording that renders the layers of layer effects to a layer image.
+///
+/// Each layer is rasterized to an image of its own, which is then blurred into its
+/// region of the layer image. The layer image must be set on the [`Render`] of the
+/// scene with [`Render::set_layer_image`], and freed after its fine stage.
+///
+/// As with [`render_encoding_full`], this does not implement robust dynamic memory.
+/// Robust renders instead run the coarse stage of each layer with the parameters of
+/// [`effect_layer_params`] until it fits, and then record its fine stage and blur with
+/// [`Render::record_effect_layer`].
+pub fn render_effect_layers(
+    effect_layers: &EffectLayers,
+    cache: &mut RenderCache,
+    shaders: &FullShaders,
+    params: &RenderParams,
+    bump_sizes: &BumpSizes,
+) -> (Recording, ImageProxy) {
+    let mut recording = Recording::default();
+    let layer_image = effect_layer_image(effect_layers, shaders);
+    for layer in &effect_layers.layers {
+        let mut render = Render::new();
+        let coarse = render.render_encoding_coarse(
+            &layer.encoding,
+            cache,
+            shaders,
+            &effect_layer_params(layer, params),
+            bump_sizes,
+            false,
+        );
+        recording.commands.extend(coarse.commands);
+        render.record_effect_layer(layer, layer_image, shaders, &mut recording);
+    }
+    (recording, layer_image)
+}
+
+/// Create the image that the layers of layer effects are rendered to.
+pub fn effect_layer_image(effect_layers: &EffectLayers, shaders: &FullShaders) -> ImageProxy {
+    ImageProxy::new(
+        effect_layers.width.max(1),
+        effect_layers.height.max(1),
+        shaders.target_format,
+    )
+}
+
+/// Returns the parameters for rendering the contents of an effect layer of a scene
+/// rendered with `params`.
+pub fn effect_layer_params(layer: &EffectLayer, params: &RenderParams) -> RenderParams {
+    // Layers are drawn like images, so they are stored in the same color space.
+    RenderParams {
+        base_color: Color::TRANSPARENT,
+        width: layer.width,
+        height: layer.height,
+        target_color_space: ColorSpace::Srgb,
+        blend_color_space: params.blend_color_space,
+    }
+}
+
 impl Render {
     pub fn new() -> Self {
         Render {
             fine_wg_count: None,
             fine_resources: None,
+            config: None,
+            bump_sizes: None,
+            layer_image: None,
         }
     }
 
+    /// Set the image that the layers of layer effects were rendered to, which is read by
+    /// the fine stage.
+    ///
+    /// The image is not freed with the other resources of the fine stage. Without it, the
+    /// fine stage reads a placeholder.
+    pub fn set_layer_image(&mut self, image: ImageProxy) {
+        self.layer_image = Some(image);
+    }
+
     /// Prepare a recording for the coarse rasterization phase.
     ///
     /// The `robust` parameter controls whether we're preparing for readback
-    /// of the atomic bump buffer, for robust dynamic memory.
+    /// of the atomic bump buffer, for robust dynamic memory. The sizes of the bump
+    /// allocated buffers are estimated from the scene, and are at least those in
+    /// `min_bump_sizes`.
+    ///
+    /// Late bound resources are resolved with the caches in `cache`, which also holds the
+    /// gradient ramp and image atlas textures.
     pub fn render_encoding_coarse(
         &mut self,
         encoding: &Encoding,
+        cache: &mut RenderCache,
         shaders: &FullShaders,
         params: &RenderParams,
+        min_bump_sizes: &BumpSizes,
         robust: bool,
     ) -> Recording {
-        use vello_encoding::{RenderConfig, Resolver};
+        use vello_encoding::RenderConfig;
 
         let mut recording = Recording::default();
-        let mut resolver = Resolver::new();
         let mut packed = vec![];
-        let (layout, ramps, images) = resolver.resolve(encoding, &mut packed);
-        let gradient_image = if ramps.height == 0 {
-            ResourceProxy::new_image(1, 1, ImageFormat::Rgba8)
-        } else {
-            let data: &[u8] = bytemuck::cast_slice(ramps.data);
-            ResourceProxy::Image(recording.upload_image(
-                ramps.width,
-                ramps.height,
-                ImageFormat::Rgba8,
-                data,
-            ))
-        };
-        let image_atlas = if images.images.is_empty() {
-            ImageProxy::new(1, 1, ImageFormat::Rgba8)
-        } else {
-            ImageProxy::new(images.width, images.height, ImageFormat::Rgba8)
-        };
-        for image in images.images {
-            recording.write_image(
-                image_atlas,
-                image.1,
-                image.2,
-                image.0.width,
-                image.0.height,
-                image.0.data.data(),
-            );
-        }
+        cache
+            .resolver
+            .set_ramp_color_space(params.blend_color_space);
+        let (layout, ramps, images) = cache.resolver.resolve(encoding, &mut packed);
+        let retained = &mut cache.images;
+        let gradient_image = ResourceProxy::Image(retained.ramp_image(&ramps, &mut recording));
+        let image_atlas = retained.atlas_image(&images, &mut recording);
 
-        let cpu_config =
-            RenderConfig::new(&layout, params.width, params.height, &params.base_color);
+        let bump_sizes =
+            BumpSizes::estimate(&layout, params.width, params.height).max(min_bump_sizes);
+        let mut cpu_config = RenderConfig::with_bump_sizes(
+            &layout,
+            params.width,
+            params.height,
+            &params.base_color,
+            &bump_sizes,
+        );
+        cpu_config.gpu.target_color_space = params.target_color_space as u32;
+        cpu_config.gpu.blend_color_space = params.blend_color_space as u32;
         let buffer_sizes = &cpu_config.buffer_sizes;
         let wg_counts = &cpu_config.workgroup_counts;
 
+        // The pattern stage writes the draw objects it expands after the packed scene.
+        packed.resize(buffer_sizes.scene.size_in_bytes() as usize, 0);
         let scene_buf = ResourceProxy::Buf(recording.upload("scene", packed));
         let config_buf = ResourceProxy::Buf(
             recording.upload_uniform("config", bytemuck::bytes_of(&cpu_config.gpu)),
@@ -197,6 +400,52 @@ impl Render {
                 cubic_buf,
             ],
         );
+        // Pattern instances are expanded into draw objects, with copies of the
+        // transformed geometry of their templates, before draw_reduce reads
+        // their tags.
+        let bump_buf = BufProxy::new(buffer_sizes.bump_alloc.size_in_bytes().into(), "bump_buf");
+        recording.clear_all(bump_buf);
+        let bump_buf = ResourceProxy::Buf(bump_buf);
+        if wg_counts.pattern.0 > 0 {
+            let pattern_reduced_buf = ResourceProxy::new_buf(
+                buffer_sizes.pattern_reduced.size_in_bytes().into(),
+                "pattern_reduced_buf",
+            );
+            recording.dispatch(
+                shaders.pattern_reduce,
+                wg_counts.pattern_reduce,
+                [config_buf, scene_buf, pattern_reduced_buf],
+            );
+            let pattern_offsets_buf = ResourceProxy::new_buf(
+                buffer_sizes.pattern_offsets.size_in_bytes().into(),
+                "pattern_offsets_buf",
+            );
+            recording.dispatch(
+                shaders.pattern_leaf,
+                wg_counts.pattern_leaf,
+                [
+                    config_buf,
+                    scene_buf,
+                    pattern_reduced_buf,
+                    pattern_offsets_buf,
+                    bump_buf,
+                ],
+            );
+            recording.free_resource(pattern_reduced_buf);
+            recording.dispatch(
+                shaders.pattern,
+                wg_counts.pattern,
+                [
+                    config_buf,
+                    scene_buf,
+                    pattern_offsets_buf,
+                    path_bbox_buf,
+                    cubic_buf,
+                    bump_buf,
+                ],
+            );
+            recording.free_resource(pattern_offsets_buf);
+        }
         let draw_reduced_buf = ResourceProxy::new_buf(
             buffer_sizes.draw_reduced.size_in_bytes().into(),
             "draw_reduced_buf",
@@ -210,11 +459,16 @@ impl Render {
             buffer_sizes.draw_monoids.size_in_bytes().into(),
             "draw_monoid_buf",
         );
-        let clip_inp_buf = ResourceProxy::new_buf(
+        let clip_inp_buf = BufProxy::new(
             buffer_sizes.clip_inps.size_in_bytes().into(),
             "clip_inp_buf",
         );
-        let pattern_inp_buf = ResourceProxy::new_buf(buffer_sizes.pattern_inps.size_in_bytes().into(), "pattern_inp_buf");
+        if wg_counts.pattern.0 > 0 {
+            // Clips are counted for every expanded draw object, and clip_leaf
+            // reads the entries that no clip writes.
+            recording.clear_all(clip_inp_buf);
+        }
+        let clip_inp_buf = ResourceProxy::Buf(clip_inp_buf);
         recording.dispatch(
             shaders.draw_leaf,
             wg_counts.draw_leaf,
@@ -226,7 +480,6 @@ impl Render {
                 draw_monoid_buf,
                 info_bin_data_buf,
                 clip_inp_buf,
-                pattern_inp_buf,
             ],
         );
         recording.free_resource(draw_reduced_buf);
@@ -272,32 +525,14 @@ impl Render {
         recording.free_resource(clip_bic_buf);
         recording.free_resource(clip_el_buf);
 
-        //pattern_instancing
-        let bump_buf = BufProxy::new(buffer_sizes.bump_alloc.size_in_bytes().into(), "bump_buf");
-        let debug = ResourceProxy::new_buf(1024, "debug");
-        recording.clear_all(bump_buf);
-        let bump_buf = ResourceProxy::Buf(bump_buf);
-        if wg_counts.pattern.0 > 0 {
-            recording.dispatch(
-                shaders.pattern,
-                wg_counts.pattern,
-                [
-                    config_buf,
-                    scene_buf,
-                    pattern_inp_buf,
-                    clip_bbox_buf,
-                    path_bbox_buf,
-                    cubic_buf,
-                    bump_buf,
-                    debug,
-                ],
-            );
-        }
-        
-        let draw_bbox_buf = ResourceProxy::new_buf(
+        // Binning writes the bounding boxes by path, which leaves the dummy
+        // paths of clip ends empty.
+        let draw_bbox_buf = BufProxy::new(
             buffer_sizes.draw_bboxes.size_in_bytes().into(),
             "draw_bbox_buf",
         );
+        recording.clear_all(draw_bbox_buf);
+        let draw_bbox_buf = ResourceProxy::Buf(draw_bbox_buf);
         let bin_header_buf = ResourceProxy::new_buf(
             buffer_sizes.bin_headers.size_in_bytes().into(),
             "bin_header_buf",
@@ -326,14 +561,7 @@ impl Render {
         recording.dispatch(
             shaders.tile_alloc,
             wg_counts.tile_alloc,
-            [
-                config_buf,
-                scene_buf,
-                draw_bbox_buf,
-                bump_buf,
-                path_buf,
-                tile_buf,
-            ],
+            [config_buf, draw_bbox_buf, bump_buf, path_buf, tile_buf],
         );
         recording.free_resource(draw_bbox_buf);
         recording.dispatch(
@@ -376,7 +604,9 @@ impl Render {
         recording.free_resource(draw_monoid_buf);
         recording.free_resource(bin_header_buf);
         recording.free_resource(path_buf);
-        let out_image = ImageProxy::new(params.width, params.height, ImageFormat::Rgba8);
+        let out_image = ImageProxy::new(params.width, params.height, shaders.target_format);
+        self.config = Some(cpu_config.gpu);
+        self.bump_sizes = Some(bump_sizes);
         self.fine_wg_count = Some(wg_counts.fine);
         self.fine_resources = Some(FineResources {
             config_buf,
@@ -400,6 +630,9 @@ impl Render {
     pub fn record_fine(&mut self, shaders: &FullShaders, recording: &mut Recording) {
         let fine_wg_count = self.fine_wg_count.take().unwrap();
         let fine = self.fine_resources.take().unwrap();
+        let layer_image = self
+            .layer_image
+            .unwrap_or_else(|| ImageProxy::new(1, 1, shaders.target_format));
         recording.dispatch(
             shaders.fine,
             fine_wg_count,
@@ -412,17 +645,90 @@ impl Render {
                 fine.gradient_image,
                 fine.info_bin_data_buf,
                 fine.image_atlas,
+                ResourceProxy::Image(layer_image),
             ],
         );
+        if self.layer_image.is_none() {
+            recording.free_image(layer_image);
+        }
+        recording.free_resource(fine.config_buf);
+        recording.free_resource(fine.tile_buf);
+        recording.free_resource(fine.segments_buf);
+        recording.free_resource(fine.ptcl_buf);
+        recording.free_resource(fine.info_bin_data_buf);
+    }
+
+    /// Run fine rasterization of an effect layer, assuming the coarse phase of its
+    /// encoding succeeded, and blur the result into its region of `layer_image`.
+    pub fn record_effect_layer(
+        &mut self,
+        layer: &EffectLayer,
+        layer_image: ImageProxy,
+        shaders: &FullShaders,
+        recording: &mut Recording,
+    ) {
+        let contents = self.out_image();
+        // Nested layers are drawn from the layer image.
+        self.set_layer_image(layer_image);
+        self.record_fine(shaders, recording);
+        let blurred = ImageProxy::new(layer.width, layer.height, shaders.target_format);
+        let effect = layer.effect;
+        let mut config = BlurConfig {
+            width: layer.width,
+            height: layer.height,
+            x: layer.x,
+            y: layer.y,
+            kind: effect.kind,
+            std_dev: effect.std_dev,
+            color: effect.color,
+            offset: effect.offset.map(|offset| offset.round() as i32),
+            ..Default::default()
+        };
+        let wg_count = (layer.width.div_ceil(16), layer.height.div_ceil(16), 1);
+        for (vertical, input, output) in [(0, contents, blurred), (1, blurred, layer_image)] {
+            config.vertical = vertical;
+            let config_buf = ResourceProxy::Buf(
+                recording.upload_uniform("blur_config", bytemuck::bytes_of(&config)),
+            );
+            recording.dispatch(
+                shaders.blur,
+                wg_count,
+                [
+                    config_buf,
+                    ResourceProxy::Image(contents),
+                    ResourceProxy::Image(input),
+                    ResourceProxy::Image(output),
+                ],
+            );
+            recording.free_resource(config_buf);
+        }
+        recording.free_image(contents);
+        recording.free_image(blurred);
+    }
+
+    /// Release the resources held for fine rasterization without running it.
+    ///
+    /// This is used when the coarse phase failed and needs to be run again.
+    pub fn discard_fine(&mut self, recording: &mut Recording) {
+        self.fine_wg_count = None;
+        let fine = self.fine_resources.take().unwrap();
         recording.free_resource(fine.config_buf);
         recording.free_resource(fine.tile_buf);
         recording.free_resource(fine.segments_buf);
         recording.free_resource(fine.ptcl_buf);
-        recording.free_resource(fine.gradient_image);
-        recording.free_resource(fine.image_atlas);
         recording.free_resource(fine.info_bin_data_buf);
     }
 
+    /// Get the configuration used for the coarse phase.
+    pub fn config(&self) -> &ConfigUniform {
+        self.config.as_ref().unwrap()
+    }
+
+    /// Get the sizes of the bump allocated buffers used for the coarse phase.
+    pub fn bump_sizes(&self) -> &BumpSizes {
+        self.bump_sizes.as_ref().unwrap()
+    }
+
     /// Get the output image.
     ///
     /// This is going away, as the caller will add the output image to the bind
@@ -441,3 +747,74 @@ impl Render {
             .unwrap()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use peniko::kurbo::{Affine, Rect};
+    use peniko::{Blob, ColorStop, Fill, Format, Gradient};
+
+    use super::*;
+    use crate::cpu_dispatch::CpuEngine;
+    use crate::engine::Command;
+    use crate::shaders::full_shaders_cpu;
+    use crate::{Scene, SceneBuilder};
+
+    /// A scene with a gradient and one image per entry of `images`.
+    fn scene(images: &[Image]) -> Scene {
+        let mut scene = Scene::new();
+        let mut sb = SceneBuilder::for_scene(&mut scene);
+        let rect = Rect::new(0.0, 0.0, 16.0, 16.0);
+        let stops = [(0.0, Color::rgb8(255, 0, 0)), (1.0, Color::rgb8(0, 0, 255))]
+            .map(|(offset, color)| ColorStop { offset, color });
+        let gradient = Gradient::new_linear((0.0, 0.0), (16.0, 0.0)).with_stops(stops);
+        sb.fill(Fill::NonZero, Affine::IDENTITY, &gradient, None, &rect);
+        for image in images {
+            sb.fill(Fill::NonZero, Affine::IDENTITY, image, None, &rect);
+        }
+        scene
+    }
+
+    fn image(color: [u8; 4]) -> Image {
+        Image::new(Blob::from(color.repeat(4)), Format::Rgba8, 2, 2)
+    }
+
+    /// Records the coarse phase of `scene`, returning the number of image writes.
+    fn image_writes(scene: &Scene, cache: &mut RenderCache) -> usize {
+        let shaders = full_shaders_cpu(&mut CpuEngine::new());
+        let params = RenderParams {
+            base_color: Color::WHITE,
+            width: 64,
+            height: 64,
+            target_color_space: Default::default(),
+            blend_color_space: Default::default(),
+        };
+        let recording = Render::new().render_encoding_coarse(
+            scene.data(),
+            cache,
+            &shaders,
+            &params,
+            &BumpSizes::default(),
+            false,
+        );
+        recording
+            .commands
+            .iter()
+            .filter(|command| matches!(command, Command::WriteImage(..)))
+            .count()
+    }
+
+    #[test]
+    fn unchanged_frames_write_no_images() {
+        let mut cache = RenderCache::default();
+        let first = image([255, 0, 0, 255]);
+        let frame = scene(std::slice::from_ref(&first));
+        // The ramp and the image are written in the first frame.
+        assert_eq!(image_writes(&frame, &mut cache), 2);
+        cache.advance();
+        assert_eq!(image_writes(&frame, &mut cache), 0);
+        cache.advance();
+        // Only the added image is written.
+        let second = image([0, 0, 255, 255]);
+        assert_eq!(image_writes(&scene(&[first, second]), &mut cache), 1);
+    }
+}
@@ -14,10 +14,18 @@
 //
 // Also licensed under MIT license, at your choice.
 
+use std::io::{self, Read, Write};
+
 use fello::NormalizedCoord;
-use peniko::kurbo::{Affine, Rect, Shape, Vec2};
-use peniko::{BlendMode, BrushRef, Color, Fill, Font, Image, Stroke, StyleRef};
-use vello_encoding::{Encoding, Glyph, GlyphRun, Patch, Transform};
+use peniko::kurbo::{
+    Affine, BezPath, CubicBez, Line, ParamCurve, ParamCurveArclen, ParamCurveDeriv, PathEl,
+    PathSeg, Point, QuadBez, Rect, Shape, Vec2,
+};
+use peniko::{BlendMode, BrushRef, Color, ColorStop, Extend, Fill, Font, Image, Stroke, StyleRef};
+use vello_encoding::{
+    stroke_to_fill, BlobStorage, EffectData, Encoding, Glyph, GlyphRun, ImageSampling, Patch,
+    PatternData, Transform,
+};
 
 /// Encoded definition of a scene and associated resources.
 #[derive(Default)]
@@ -35,6 +43,19 @@ impl Scene {
     pub fn data(&self) -> &Encoding {
         &self.data
     }
+
+    /// Writes the scene with its fonts and images in the binary format of
+    /// [`Encoding::save`].
+    pub fn save(&self, mut writer: impl Write) -> io::Result<()> {
+        self.data.save(&mut writer, BlobStorage::Embed)
+    }
+
+    /// Reads a scene written by [`save`](Self::save).
+    pub fn load(mut reader: impl Read) -> io::Result<Self> {
+        Ok(Self {
+            data: Encoding::load(&mut reader)?,
+        })
+    }
 }
 
 /// Encoded definition of a scene fragment and associated resources.
@@ -54,6 +75,12 @@ impl SceneFragment {
         self.data.is_empty()
     }
 
+    /// Returns true if the fragment draws anything. Unlike [`is_empty`](Self::is_empty),
+    /// this includes glyph runs, whose paths are only added when resolving.
+    fn has_draws(&self) -> bool {
+        !self.data.draw_tags.is_empty()
+    }
+
     /// Returns the the entire sequence of points in the scene fragment.
     pub fn points(&self) -> &[[f32; 2]] {
         if self.is_empty() {
@@ -88,11 +115,45 @@ impl<'a> SceneBuilder<'a> {
         Self { scene }
     }
 
-    pub fn start_pattern(&mut self, start: Vec2, box_scale:Vec2, rotation: f32){
-        self.scene.encode_begin_pattern( start, box_scale, rotation);
+    /// Begins a pattern that repeats the following draws across the current layer.
+    ///
+    /// Cells of size `box_scale` are placed on a lattice with its origin at `start`,
+    /// rotated by `rotation` radians. The pattern ends with [`end_pattern`](Self::end_pattern).
+    ///
+    /// Any draw may be made inside a pattern, including layers. The pattern stage copies
+    /// each draw object of the contents into the visible cells, whatever its brush. The
+    /// renderer only expands the patterns on the CPU, encoding a copy of the contents for
+    /// every cell on each frame, when the scene has layers with effects or
+    /// [`RendererOptions::expand_patterns`](crate::RendererOptions::expand_patterns) is set.
+    /// [`RenderStats::expanded_patterns`](crate::RenderStats::expanded_patterns) counts the
+    /// patterns of such renders, and is 0 when they are instanced. Either way, the number of
+    /// cells is limited, see
+    /// [`PatternError::TooManyCells`](vello_encoding::PatternError::TooManyCells).
+    pub fn start_pattern(&mut self, start: Vec2, box_scale: Vec2, rotation: f32) {
+        let transform = Affine::rotate(rotation as f64) * Affine::translate(start);
+        self.scene.encode_begin_pattern(
+            PatternData {
+                transform: Transform::from_kurbo(&transform),
+                x_step: [box_scale.x as f32, 0.0],
+                y_step: [0.0, box_scale.y as f32],
+                extend: PatternData::EXTEND_REPEAT,
+                tiling: PatternData::TILING_GRID,
+                seed: 0,
+                offset_jitter: [0.0; 2],
+                rotation_jitter: 0.0,
+                scale_jitter: 0.0,
+                placements: [0; 2],
+                colors: PatternData::COLORS_NONE,
+                color_stops: [0; 2],
+                overflow: PatternData::OVERFLOW_VISIBLE,
+            },
+            std::iter::empty(),
+            std::iter::empty(),
+        );
     }
 
-    pub fn end_pattern(&mut self){
+    /// Ends the current pattern.
+    pub fn end_pattern(&mut self) {
         self.scene.encode_end_pattern();
     }
 
@@ -118,6 +179,44 @@ impl<'a> SceneBuilder<'a> {
         self.scene.encode_begin_clip(blend, alpha.clamp(0.0, 1.0));
     }
 
+    /// Pushes a new layer, as with [`push_layer`](Self::push_layer), whose contents are
+    /// filtered by `effect` before they are composed with previous layers.
+    ///
+    /// The contents are clipped to the shape before they are filtered, and the result
+    /// may extend beyond it. Each layer with an effect is rendered to an image of its own,
+    /// so effects are much more expensive than plain layers. Layers whose filtered region
+    /// is larger than 8192 pixels on a side are drawn without their effect, which is
+    /// reported by [`RenderStats::dropped_effects`](crate::RenderStats::dropped_effects).
+    pub fn push_layer_with_effect(
+        &mut self,
+        blend: impl Into<BlendMode>,
+        alpha: f32,
+        transform: Affine,
+        shape: &impl Shape,
+        effect: LayerEffect,
+    ) {
+        self.push_layer(blend, alpha, transform, shape);
+        let effect = match effect {
+            LayerEffect::Blur { std_dev } => EffectData {
+                kind: EffectData::KIND_BLUR,
+                std_dev: std_dev as f32,
+                ..bytemuck::Zeroable::zeroed()
+            },
+            LayerEffect::DropShadow {
+                offset,
+                std_dev,
+                color,
+            } => EffectData {
+                kind: EffectData::KIND_DROP_SHADOW,
+                std_dev: std_dev as f32,
+                offset: [offset.x as f32, offset.y as f32],
+                color: color.to_premul_u32(),
+                ..bytemuck::Zeroable::zeroed()
+            },
+        };
+        self.scene.encode_layer_effect(effect);
+    }
+
     /// Pops the current layer.
     pub fn pop_layer(&mut self) {
         self.scene.encode_end_clip();
@@ -132,26 +231,65 @@ impl<'a> SceneBuilder<'a> {
         brush_transform: Option<Affine>,
         shape: &impl Shape,
     ) {
+        if self.encode_fill_shape(style, transform, brush_transform, shape) {
+            self.scene.encode_brush(brush, 1.0);
+        }
+    }
+
+    /// Fills a shape with an image, using the specified alpha multiplier and
+    /// sampling parameters.
+    ///
+    /// Filling with an image brush instead samples bilinearly and extends the
+    /// image in both directions with its extend mode.
+    #[allow(clippy::too_many_arguments)]
+    pub fn fill_image(
+        &mut self,
+        style: Fill,
+        transform: Affine,
+        image: &Image,
+        alpha: f32,
+        sampling: ImageSampling,
+        brush_transform: Option<Affine>,
+        shape: &impl Shape,
+    ) {
+        if self.encode_fill_shape(style, transform, brush_transform, shape) {
+            self.scene.encode_image_sampled(image, alpha, sampling);
+        }
+    }
+
+    /// Encodes the path of a fill ahead of its brush. Returns false if the
+    /// shape is empty, in which case the brush must not be encoded.
+    fn encode_fill_shape(
+        &mut self,
+        style: Fill,
+        transform: Affine,
+        brush_transform: Option<Affine>,
+        shape: &impl Shape,
+    ) -> bool {
         self.scene
             .encode_transform(Transform::from_kurbo(&transform));
         self.scene.encode_linewidth(match style {
             Fill::NonZero => -1.0,
             Fill::EvenOdd => -2.0,
         });
-        if self.scene.encode_shape(shape, true) {
-            if let Some(brush_transform) = brush_transform {
-                if self
-                    .scene
-                    .encode_transform(Transform::from_kurbo(&(transform * brush_transform)))
-                {
-                    self.scene.swap_last_path_tags();
-                }
+        if !self.scene.encode_shape(shape, true) {
+            return false;
+        }
+        if let Some(brush_transform) = brush_transform {
+            if self
+                .scene
+                .encode_transform(Transform::from_kurbo(&(transform * brush_transform)))
+            {
+                self.scene.swap_last_path_tags();
             }
-            self.scene.encode_brush(brush, 1.0);
         }
+        true
     }
 
     /// Strokes a shape using the specified style and brush.
+    ///
+    /// The stroke is expanded into a filled outline, applying the joins, caps, miter limit
+    /// and dashes of the style.
     pub fn stroke<'b>(
         &mut self,
         style: &Stroke,
@@ -160,20 +298,222 @@ impl<'a> SceneBuilder<'a> {
         brush_transform: Option<Affine>,
         shape: &impl Shape,
     ) {
-        self.scene
-            .encode_transform(Transform::from_kurbo(&transform));
-        self.scene.encode_linewidth(style.width);
-        if self.scene.encode_shape(shape, false) {
-            if let Some(brush_transform) = brush_transform {
-                if self
-                    .scene
-                    .encode_transform(Transform::from_kurbo(&(transform * brush_transform)))
-                {
-                    self.scene.swap_last_path_tags();
-                }
+        // Flatten in local coordinates, at a tolerance that holds after the transform.
+        let Some(tolerance) = local_tolerance(transform) else {
+            return;
+        };
+        if style.scale {
+            let outline = stroke_to_fill(shape.path_elements(tolerance), style, tolerance);
+            self.fill(Fill::NonZero, transform, brush, brush_transform, &outline);
+        } else {
+            // The width is in device space, so expand the transformed shape. The brush keeps
+            // the coordinate space of the shape.
+            let path = transform * shape.path_elements(tolerance).collect::<BezPath>();
+            let outline = stroke_to_fill(path, style, DEVICE_TOLERANCE);
+            let brush_transform = transform * brush_transform.unwrap_or(Affine::IDENTITY);
+            self.fill(
+                Fill::NonZero,
+                Affine::IDENTITY,
+                brush,
+                Some(brush_transform),
+                &outline,
+            );
+        }
+    }
+
+    /// Fills a shape with a pattern.
+    ///
+    /// The shape is filled using the non-zero rule.
+    pub fn fill_pattern(&mut self, transform: Affine, pattern: &Pattern, shape: &impl Shape) {
+        self.encode_pattern(transform, shape, transform, shape.bounding_box(), pattern);
+    }
+
+    /// Strokes a shape with a pattern.
+    ///
+    /// As in SVG, bounding box units refer to the bounds of the shape without the stroke.
+    pub fn stroke_pattern(
+        &mut self,
+        style: &Stroke,
+        transform: Affine,
+        pattern: &Pattern,
+        shape: &impl Shape,
+    ) {
+        let bbox = shape.bounding_box();
+        let Some(tolerance) = local_tolerance(transform) else {
+            return;
+        };
+        if style.scale {
+            let outline = stroke_to_fill(shape.path_elements(tolerance), style, tolerance);
+            self.encode_pattern(transform, &outline, transform, bbox, pattern);
+        } else {
+            let path = transform * shape.path_elements(tolerance).collect::<BezPath>();
+            let outline = stroke_to_fill(path, style, DEVICE_TOLERANCE);
+            self.encode_pattern(Affine::IDENTITY, &outline, transform, bbox, pattern);
+        }
+    }
+
+    /// Draws a pattern in the current layer, without a shape to fill.
+    ///
+    /// Cells on a lattice cover the current layer, so this is mostly useful for patterns
+    /// placed along a path. Bounding box units refer to the bounds of the guide path of
+    /// such patterns, and are the same as user space units otherwise.
+    pub fn draw_pattern(&mut self, transform: Affine, pattern: &Pattern) {
+        if !pattern.content.has_draws() {
+            return;
+        }
+        let bbox = match pattern.placement {
+            PatternPlacement::Lattice => Rect::new(0.0, 0.0, 1.0, 1.0),
+            PatternPlacement::Path { guide, .. } => guide.bounding_box(),
+        };
+        self.encode_pattern_cells(transform, bbox, pattern);
+    }
+
+    /// Encodes a layer bound by `clip` that contains the repeated contents of
+    /// `pattern`, in the coordinate space given by `transform`.
+    fn encode_pattern(
+        &mut self,
+        clip_transform: Affine,
+        clip: &impl Shape,
+        transform: Affine,
+        bbox: Rect,
+        pattern: &Pattern,
+    ) {
+        if !pattern.content.has_draws() {
+            return;
+        }
+        self.push_layer(BlendMode::default(), 1.0, clip_transform, clip);
+        self.encode_pattern_cells(transform, bbox, pattern);
+        self.pop_layer();
+    }
+
+    /// Encodes the cells of `pattern` in the current layer, in the coordinate space
+    /// given by `transform`. `bbox` is the bounding box that bounding box units refer to.
+    fn encode_pattern_cells(&mut self, transform: Affine, bbox: Rect, pattern: &Pattern) {
+        let bbox_units = Affine::new([bbox.width(), 0.0, 0.0, bbox.height(), bbox.x0, bbox.y0]);
+        let units = match pattern.units {
+            PatternUnits::UserSpaceOnUse => Affine::IDENTITY,
+            PatternUnits::ObjectBoundingBox => bbox_units,
+        };
+        let content_units = match pattern.content_units {
+            PatternUnits::UserSpaceOnUse => Affine::IDENTITY,
+            PatternUnits::ObjectBoundingBox => {
+                Affine::scale_non_uniform(bbox.width(), bbox.height())
             }
-            self.scene.encode_brush(brush, 1.0);
+        };
+        // Steps are offsets, so only the scale of the units applies.
+        let [sx, _, _, sy, _, _] = units.as_coeffs();
+        let step = |v: Vec2| [(v.x * sx) as f32, (v.y * sy) as f32];
+        let (lattice, x_step, y_step, tiling, placements) = match pattern.placement {
+            PatternPlacement::Lattice => {
+                let origin = units * pattern.tile.origin();
+                let (x_step, y_step) = pattern.spacing.unwrap_or((
+                    Vec2::new(pattern.tile.width(), 0.0),
+                    Vec2::new(0.0, pattern.tile.height()),
+                ));
+                let tiling = match pattern.tiling {
+                    PatternTiling::Grid => PatternData::TILING_GRID,
+                    PatternTiling::Brick => PatternData::TILING_BRICK,
+                    PatternTiling::HalfDrop => PatternData::TILING_HALF_DROP,
+                    PatternTiling::Mirror => PatternData::TILING_MIRROR,
+                    PatternTiling::Hex => PatternData::TILING_HEX,
+                };
+                (
+                    transform * pattern.transform * Affine::translate(origin.to_vec2()),
+                    step(x_step),
+                    step(y_step),
+                    tiling,
+                    vec![],
+                )
+            }
+            // Cells have no extent, so that jitter is about the origin of the contents.
+            PatternPlacement::Path {
+                guide,
+                spacing,
+                offset,
+                orientation,
+            } => {
+                let Some(placements) = placements_along_path(guide, spacing, offset, orientation)
+                else {
+                    return;
+                };
+                (
+                    transform * pattern.transform,
+                    [0.0; 2],
+                    [0.0; 2],
+                    PatternData::TILING_PLACEMENTS,
+                    placements,
+                )
+            }
+        };
+        let extend = match pattern.extend {
+            Extend::Pad => PatternData::EXTEND_PAD,
+            Extend::Repeat => PatternData::EXTEND_REPEAT,
+            Extend::Reflect => PatternData::EXTEND_REFLECT,
+        };
+        let list_stops: Vec<ColorStop>;
+        let (colors, color_stops) = match pattern.colors {
+            PatternColors::None => (PatternData::COLORS_NONE, &[][..]),
+            PatternColors::List(colors) => {
+                list_stops = colors
+                    .iter()
+                    .map(|&color| ColorStop { offset: 0.0, color })
+                    .collect();
+                (PatternData::COLORS_LIST, &list_stops[..])
+            }
+            PatternColors::Gradient(stops) => (PatternData::COLORS_GRADIENT, stops),
+        };
+        let is_clipped = match (pattern.overflow, pattern.placement) {
+            (PatternOverflow::Visible, _)
+            | (PatternOverflow::Hidden, PatternPlacement::Path { .. }) => false,
+            (PatternOverflow::Hidden, PatternPlacement::Lattice)
+            | (PatternOverflow::Clip(_), _) => true,
+        };
+        // Flags the layer below, which every cell draws a copy of, as the clip of the cells.
+        let overflow = if is_clipped {
+            PatternData::OVERFLOW_CLIP
+        } else {
+            PatternData::OVERFLOW_VISIBLE
+        };
+        self.scene.encode_begin_pattern(
+            PatternData {
+                transform: Transform::from_kurbo(&lattice),
+                x_step,
+                y_step,
+                extend,
+                tiling,
+                seed: pattern.seed,
+                offset_jitter: step(pattern.offset_jitter),
+                rotation_jitter: pattern.rotation_jitter as f32,
+                scale_jitter: pattern.scale_jitter as f32,
+                placements: [0; 2],
+                colors,
+                color_stops: [0; 2],
+                overflow,
+            },
+            color_stops.iter().copied(),
+            placements.into_iter(),
+        );
+        // The layer that clips each cell is in pattern space, where the tile starts at
+        // the origin.
+        match pattern.overflow {
+            PatternOverflow::Hidden if is_clipped => {
+                let [w, h] = [pattern.tile.width() * sx, pattern.tile.height() * sy];
+                let tile = Rect::new(0.0, 0.0, w, h);
+                self.push_layer(BlendMode::default(), 1.0, Affine::IDENTITY, &tile);
+            }
+            PatternOverflow::Clip(shape) => {
+                self.push_layer(BlendMode::default(), 1.0, content_units, shape);
+            }
+            _ => {}
         }
+        self.scene.append(
+            &pattern.content.data,
+            &Some(Transform::from_kurbo(&content_units)),
+        );
+        if is_clipped {
+            self.pop_layer();
+        }
+        self.scene.encode_end_pattern();
     }
 
     /// Draws an image at its natural size with the given transform.
@@ -187,20 +527,499 @@ impl<'a> SceneBuilder<'a> {
         );
     }
 
+    /// Draws a rounded rectangle blurred with a gaussian filter, as used for box shadows.
+    ///
+    /// The blur is computed analytically, which is much cheaper than blurring a layer.
+    /// `std_dev` is the standard deviation of the filter, in the coordinates of the
+    /// rectangle.
+    pub fn draw_blurred_rounded_rect(
+        &mut self,
+        transform: Affine,
+        rect: Rect,
+        color: Color,
+        radius: f64,
+        std_dev: f64,
+    ) {
+        // The filter is negligible beyond three standard deviations.
+        let kernel_size = 3.0 * std_dev;
+        let shape = rect.inflate(kernel_size, kernel_size);
+        self.draw_blurred_rounded_rect_in(&shape, transform, rect, color, radius, std_dev);
+    }
+
+    /// Draws a blurred rounded rectangle, as with
+    /// [`draw_blurred_rounded_rect`](Self::draw_blurred_rounded_rect), limited to `shape`.
+    ///
+    /// This is useful to draw only the part of a shadow that is outside of an opaque box.
+    pub fn draw_blurred_rounded_rect_in(
+        &mut self,
+        shape: &impl Shape,
+        transform: Affine,
+        rect: Rect,
+        color: Color,
+        radius: f64,
+        std_dev: f64,
+    ) {
+        let brush_transform = Affine::translate(rect.center().to_vec2());
+        if self.encode_fill_shape(Fill::NonZero, transform, Some(brush_transform), shape) {
+            self.scene.encode_blurred_rounded_rect(
+                color,
+                rect.width() as f32,
+                rect.height() as f32,
+                radius as f32,
+                std_dev as f32,
+            );
+        }
+    }
+
     /// Returns a builder for encoding a glyph run.
     pub fn draw_glyphs(&mut self, font: &Font) -> DrawGlyphs {
         DrawGlyphs::new(self.scene, font)
     }
 
     /// Appends a fragment to the scene.
+    ///
+    /// The transform also applies to the lattices of the patterns of the fragment,
+    /// whose contents stay in pattern space.
+    ///
+    /// Strokes of the fragment were expanded into outlines when they were built, at a
+    /// tolerance for the transforms of the fragment alone. They are not expanded again,
+    /// so a transform that scales them up also scales up their flattening error, and the
+    /// widths of strokes without [`Stroke::scale`] are scaled like any other geometry.
     pub fn append(&mut self, fragment: &SceneFragment, transform: Option<Affine>) {
-        //todo we ignore transform now for it is not important
-        //let transform = Some(Affine::IDENTITY);
         self.scene.append(
             &fragment.data,
             &transform.map(|xform| Transform::from_kurbo(&xform)),
         );
     }
+
+    /// Appends a fragment once for each of the given transforms.
+    ///
+    /// The geometry of the fragment is encoded only once and copied by the pattern
+    /// stage, which makes this much cheaper than appending the fragment for each
+    /// transform when drawing many copies. Each copy of each draw is drawn as its own
+    /// path, in the order of the transforms, so this renders the same as appending the
+    /// fragment for each transform.
+    ///
+    /// The transforms are placements of a pattern, so each of them counts as a cell, see
+    /// [`PatternError::TooManyCells`](vello_encoding::PatternError::TooManyCells). Only one
+    /// pattern instance is encoded for all of them, and the pattern stage computes the copies
+    /// on the GPU.
+    pub fn draw_instances(&mut self, fragment: &SceneFragment, transforms: &[Affine]) {
+        if !fragment.has_draws() || transforms.is_empty() {
+            return;
+        }
+        let placements = transforms.iter().map(Transform::from_kurbo);
+        self.scene.encode_begin_pattern(
+            PatternData {
+                transform: Transform::IDENTITY,
+                x_step: [0.0; 2],
+                y_step: [0.0; 2],
+                extend: PatternData::EXTEND_REPEAT,
+                tiling: PatternData::TILING_PLACEMENTS,
+                seed: 0,
+                offset_jitter: [0.0; 2],
+                rotation_jitter: 0.0,
+                scale_jitter: 0.0,
+                placements: [0; 2],
+                colors: PatternData::COLORS_NONE,
+                color_stops: [0; 2],
+                overflow: PatternData::OVERFLOW_VISIBLE,
+            },
+            std::iter::empty(),
+            placements,
+        );
+        self.scene.append(&fragment.data, &None);
+        self.scene.encode_end_pattern();
+    }
+}
+
+/// Filter applied to the contents of a layer, pushed with
+/// [`SceneBuilder::push_layer_with_effect`].
+///
+/// Lengths are in the coordinates of the shape of the layer.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum LayerEffect {
+    /// Blurs the contents with a gaussian filter of the given standard deviation.
+    Blur { std_dev: f64 },
+    /// Draws a copy of the contents below them, filled with `color`, moved by
+    /// `offset` and blurred with a gaussian filter of the given standard deviation.
+    DropShadow {
+        offset: Vec2,
+        std_dev: f64,
+        color: Color,
+    },
+}
+
+/// Coordinate system for the geometry of a [`Pattern`], after the SVG
+/// `patternUnits` and `patternContentUnits` attributes.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum PatternUnits {
+    /// Coordinates are in the user space of the filled shape.
+    #[default]
+    UserSpaceOnUse,
+    /// Coordinates are fractions of the bounding box of the filled shape.
+    ObjectBoundingBox,
+}
+
+/// Arrangement of the cells of a [`Pattern`].
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum PatternTiling {
+    /// Cells are placed on a regular lattice.
+    #[default]
+    Grid,
+    /// Odd rows are offset by half a cell, like courses of bricks.
+    Brick,
+    /// Odd columns are offset by half a cell.
+    HalfDrop,
+    /// Odd columns and rows are mirrored, the same as [`Extend::Reflect`].
+    ///
+    /// Combined with [`Extend::Reflect`], cells are still mirrored only once rather
+    /// than flipped back, and combined with [`Extend::Pad`], only the origin cell is
+    /// drawn, which is never mirrored.
+    Mirror,
+    /// Odd rows are offset by half a cell and rows are three quarters of a
+    /// cell apart, so that hexagonal cells interlock.
+    Hex,
+}
+
+/// Colors that replace the solid colors of the contents of each cell of a
+/// [`Pattern`].
+///
+/// The pattern stage writes the color of each cell into the draw data of the
+/// copies of the solid fills of the contents, keeping their alpha. Gradients
+/// and images keep their colors.
+#[derive(Copy, Clone, Debug, Default)]
+pub enum PatternColors<'a> {
+    /// The contents keep their colors.
+    #[default]
+    None,
+    /// Each cell picks one of the colors at random.
+    List(&'a [Color]),
+    /// Each cell samples the gradient at a random offset.
+    Gradient(&'a [ColorStop]),
+}
+
+/// Where the cells of a [`Pattern`] are placed.
+#[derive(Copy, Clone, Debug, Default)]
+pub enum PatternPlacement<'a> {
+    /// Cells are placed on the lattice spanned by the tile size or spacing of the
+    /// pattern.
+    #[default]
+    Lattice,
+    /// Cells are placed along `guide`, `spacing` apart, starting `offset` from the start
+    /// of each subpath. The origin of the contents is placed on the path, and the tile
+    /// and spacing of the pattern don't apply.
+    ///
+    /// The guide is in the coordinates of the pattern, before its transform, and
+    /// distances are measured along it. Placements before the start of a subpath are
+    /// skipped, and a spacing that isn't positive places a single cell on each subpath.
+    /// The end of a closed subpath coincides with its start and gets no cell of its own.
+    /// Nothing is drawn if the spacing is so small compared to the length of the guide
+    /// that it places more cells than a pattern may have, see
+    /// [`PatternError::TooManyCells`](vello_encoding::PatternError::TooManyCells).
+    Path {
+        guide: &'a BezPath,
+        spacing: f64,
+        offset: f64,
+        orientation: PatternOrientation,
+    },
+}
+
+/// Orientation of the cells of a [`Pattern`] placed along a path.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum PatternOrientation {
+    /// The x axis of the contents follows the direction of the path.
+    #[default]
+    Tangent,
+    /// The contents keep the orientation of the coordinates of the pattern.
+    Fixed,
+}
+
+/// How the contents of each cell of a [`Pattern`] are bounded, like the
+/// `overflow` property of an SVG `<pattern>`.
+///
+/// Each cell draws its own copy of the layer that clips it, like the other draw
+/// objects of the contents.
+#[derive(Copy, Clone, Debug, Default)]
+pub enum PatternOverflow<'a> {
+    /// The contents may overlap neighboring cells and are only bounded by the
+    /// shape that is filled or stroked.
+    #[default]
+    Visible,
+    /// The contents are clipped to the tile of their cell, which is the default
+    /// of SVG. Cells placed along a path have no tile and aren't clipped.
+    Hidden,
+    /// The contents are clipped to a shape, in the coordinates of the contents.
+    Clip(&'a BezPath),
+}
+
+/// Scene fragment repeated across a shape when used to fill or stroke it.
+///
+/// This follows the SVG `<pattern>` element. The contents are drawn relative to
+/// the origin of `tile`, and copies are placed along a lattice spanned by the
+/// tile size, or by `spacing` when set. `transform` corresponds to the
+/// `patternTransform` attribute. Copies can also be placed along a path, as set
+/// by `placement`.
+///
+/// The geometry of the contents is encoded once, and the pattern stage computes
+/// the visible cells and places a copy of each draw object in every one of them
+/// on the GPU, whatever its brush. The number of cells is limited, see
+/// [`PatternError::TooManyCells`](vello_encoding::PatternError::TooManyCells).
+#[derive(Copy, Clone)]
+pub struct Pattern<'a> {
+    /// Contents of a cell.
+    pub content: &'a SceneFragment,
+    /// Rectangle of the origin cell, in `units`.
+    pub tile: Rect,
+    /// Offsets between horizontally and vertically adjacent cells, in `units`.
+    /// Skewed lattices can be described with non-axis aligned offsets.
+    pub spacing: Option<(Vec2, Vec2)>,
+    /// Coordinate system of `tile` and `spacing`.
+    pub units: PatternUnits,
+    /// Coordinate system of `content`.
+    pub content_units: PatternUnits,
+    /// Transform applied to the lattice and its contents.
+    pub transform: Affine,
+    /// Whether cells are repeated, mirrored or only drawn once.
+    pub extend: Extend,
+    /// Arrangement of the cells when repeated.
+    pub tiling: PatternTiling,
+    /// Where the cells are placed.
+    pub placement: PatternPlacement<'a>,
+    /// Seed of the random variation between cells.
+    pub seed: u32,
+    /// Largest random offset of the contents of a cell, in `units`.
+    pub offset_jitter: Vec2,
+    /// Largest random rotation of the contents of a cell about its center, in radians.
+    pub rotation_jitter: f64,
+    /// Largest random relative change of the scale of the contents of a cell.
+    pub scale_jitter: f64,
+    /// Per-cell colors of the contents.
+    pub colors: PatternColors<'a>,
+    /// How the contents are bounded by their cell.
+    pub overflow: PatternOverflow<'a>,
+}
+
+impl<'a> Pattern<'a> {
+    /// Creates a pattern repeating `content` in cells of the given rectangle.
+    pub fn new(content: &'a SceneFragment, tile: Rect) -> Self {
+        Self {
+            content,
+            tile,
+            spacing: None,
+            units: PatternUnits::UserSpaceOnUse,
+            content_units: PatternUnits::UserSpaceOnUse,
+            transform: Affine::IDENTITY,
+            extend: Extend::Repeat,
+            tiling: PatternTiling::Grid,
+            placement: PatternPlacement::Lattice,
+            seed: 0,
+            offset_jitter: Vec2::ZERO,
+            rotation_jitter: 0.0,
+            scale_jitter: 0.0,
+            colors: PatternColors::None,
+            overflow: PatternOverflow::Visible,
+        }
+    }
+
+    /// Builder method for setting the offsets between adjacent cells.
+    pub fn with_spacing(mut self, x: Vec2, y: Vec2) -> Self {
+        self.spacing = Some((x, y));
+        self
+    }
+
+    /// Builder method for setting the coordinate system of the tile and spacing.
+    pub fn with_units(mut self, units: PatternUnits) -> Self {
+        self.units = units;
+        self
+    }
+
+    /// Builder method for setting the coordinate system of the contents.
+    pub fn with_content_units(mut self, units: PatternUnits) -> Self {
+        self.content_units = units;
+        self
+    }
+
+    /// Builder method for setting the pattern transform.
+    pub fn with_transform(mut self, transform: Affine) -> Self {
+        self.transform = transform;
+        self
+    }
+
+    /// Builder method for setting the extend mode.
+    pub fn with_extend(mut self, extend: Extend) -> Self {
+        self.extend = extend;
+        self
+    }
+
+    /// Builder method for setting the arrangement of the cells.
+    pub fn with_tiling(mut self, tiling: PatternTiling) -> Self {
+        self.tiling = tiling;
+        self
+    }
+
+    /// Builder method for setting where the cells are placed.
+    pub fn with_placement(mut self, placement: PatternPlacement<'a>) -> Self {
+        self.placement = placement;
+        self
+    }
+
+    /// Builder method for setting the seed of the variation between cells.
+    pub fn with_seed(mut self, seed: u32) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// Builder method for setting the largest random offset of the contents of a cell.
+    pub fn with_offset_jitter(mut self, jitter: Vec2) -> Self {
+        self.offset_jitter = jitter;
+        self
+    }
+
+    /// Builder method for setting the largest random rotation of the contents of a cell.
+    pub fn with_rotation_jitter(mut self, jitter: f64) -> Self {
+        self.rotation_jitter = jitter;
+        self
+    }
+
+    /// Builder method for setting the largest random change of the scale of the contents
+    /// of a cell.
+    pub fn with_scale_jitter(mut self, jitter: f64) -> Self {
+        self.scale_jitter = jitter;
+        self
+    }
+
+    /// Builder method for setting the per-cell colors, see [`PatternColors`].
+    pub fn with_colors(mut self, colors: PatternColors<'a>) -> Self {
+        self.colors = colors;
+        self
+    }
+
+    /// Builder method for setting how the contents are bounded by their cell, see
+    /// [`PatternOverflow`].
+    pub fn with_overflow(mut self, overflow: PatternOverflow<'a>) -> Self {
+        self.overflow = overflow;
+        self
+    }
+}
+
+/// Flattening tolerance of strokes, in device space.
+const DEVICE_TOLERANCE: f64 = 0.1;
+
+/// Returns the tolerance in the local coordinates of `transform` that is within
+/// [`DEVICE_TOLERANCE`] after the transform, or `None` if it collapses the plane.
+fn local_tolerance(transform: Affine) -> Option<f64> {
+    // The largest singular value of the linear part is the most a distance is stretched.
+    let [a, b, c, d, _, _] = transform.as_coeffs();
+    let sum = a * a + b * b + c * c + d * d;
+    let det = a * d - b * c;
+    let max_scale = (0.5 * (sum + (sum * sum - 4.0 * det * det).max(0.0).sqrt())).sqrt();
+    (max_scale > 0.0).then(|| DEVICE_TOLERANCE / max_scale)
+}
+
+/// Returns the transforms that place cells along `guide`, as described by
+/// [`PatternPlacement::Path`], or `None` if they would be more than
+/// [`MAX_PATTERN_CELLS`](vello_encoding::MAX_PATTERN_CELLS).
+fn placements_along_path(
+    guide: &BezPath,
+    spacing: f64,
+    offset: f64,
+    orientation: PatternOrientation,
+) -> Option<Vec<Transform>> {
+    const ACCURACY: f64 = 1e-3;
+    let first = if offset >= 0.0 {
+        offset
+    } else if spacing > 0.0 {
+        offset + (-offset / spacing).ceil() * spacing
+    } else {
+        f64::INFINITY
+    };
+    // Split the guide into subpaths, which each restart the distance.
+    let mut subpaths: Vec<(Vec<PathSeg>, bool)> = vec![];
+    let (mut start, mut last) = (Point::ZERO, Point::ZERO);
+    for el in guide.elements() {
+        let segment = match *el {
+            PathEl::MoveTo(p) => {
+                (start, last) = (p, p);
+                subpaths.push((vec![], false));
+                continue;
+            }
+            PathEl::LineTo(p) => PathSeg::Line(Line::new(last, p)),
+            PathEl::QuadTo(p1, p2) => PathSeg::Quad(QuadBez::new(last, p1, p2)),
+            PathEl::CurveTo(p1, p2, p3) => PathSeg::Cubic(CubicBez::new(last, p1, p2, p3)),
+            PathEl::ClosePath => PathSeg::Line(Line::new(last, start)),
+        };
+        last = segment.end();
+        // Paths start with a move, so there is always a current subpath.
+        if let Some((segments, closed)) = subpaths.last_mut() {
+            segments.push(segment);
+            *closed = matches!(el, PathEl::ClosePath);
+        }
+    }
+    // Count the placements of each subpath before placing any, so that a spacing that
+    // is tiny compared to the length of the guide is rejected up front.
+    let mut n_total = 0.0;
+    let subpaths: Vec<_> = subpaths
+        .into_iter()
+        .map(|(segments, closed)| {
+            let lengths: Vec<f64> = segments.iter().map(|seg| seg.arclen(ACCURACY)).collect();
+            // The end of a closed subpath is its start, so it isn't placed twice.
+            let mut end: f64 = lengths.iter().sum();
+            if closed {
+                end -= ACCURACY;
+            }
+            let count = if segments.is_empty() || first > end {
+                0.0
+            } else if spacing > 0.0 {
+                ((end - first) / spacing).floor() + 1.0
+            } else {
+                1.0
+            };
+            n_total += count;
+            (segments, lengths, end, count as u64)
+        })
+        .collect();
+    if n_total.is_nan() || n_total > vello_encoding::MAX_PATTERN_CELLS as f64 {
+        return None;
+    }
+    let mut placements = Vec::with_capacity(n_total as usize);
+    for (segments, lengths, end, count) in subpaths {
+        // Distance along the subpath of the start of the segment, and index of the next
+        // placement.
+        let (mut distance, mut i) = (0.0, 0);
+        for (segment, length) in segments.iter().zip(lengths) {
+            while i < count {
+                // Rounding must not move the last placement past the end.
+                let next = (first + i as f64 * spacing.max(0.0)).min(end);
+                if next > distance + length {
+                    break;
+                }
+                // A zero length segment has no parameter for a distance along it.
+                let t = if length > 0.0 {
+                    segment.inv_arclen(next - distance, ACCURACY)
+                } else {
+                    0.0
+                };
+                let mut placement = Affine::translate(segment.eval(t).to_vec2());
+                if orientation == PatternOrientation::Tangent {
+                    let mut tangent = match segment {
+                        PathSeg::Line(line) => line.p1 - line.p0,
+                        PathSeg::Quad(quad) => quad.deriv().eval(t).to_vec2(),
+                        PathSeg::Cubic(cubic) => cubic.deriv().eval(t).to_vec2(),
+                    };
+                    // The derivative vanishes at the cusps of degenerate curves.
+                    if tangent.hypot2() < 1e-12 {
+                        tangent = segment.end() - segment.start();
+                    }
+                    placement *= Affine::rotate(tangent.atan2());
+                }
+                placements.push(Transform::from_kurbo(&placement));
+                i += 1;
+            }
+            distance += length;
+        }
+    }
+    Some(placements)
 }
 
 /// Builder for encoding a glyph run.
@@ -322,3 +1141,202 @@ impl<'a> DrawGlyphs<'a> {
         self.encoding.encode_brush(self.brush, self.brush_alpha);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_tolerance_follows_largest_scale() {
+        let tolerance = |transform| local_tolerance(transform).unwrap() / DEVICE_TOLERANCE;
+        let near = |a: f64, b: f64| (a - b).abs() < 1e-9;
+        assert!(near(tolerance(Affine::translate((5.0, 7.0))), 1.0));
+        assert!(near(tolerance(Affine::scale(4.0)), 0.25));
+        assert!(near(tolerance(Affine::scale_non_uniform(0.5, 8.0)), 0.125));
+        assert!(near(
+            tolerance(Affine::rotate(0.7) * Affine::scale_non_uniform(-3.0, 2.0)),
+            1.0 / 3.0
+        ));
+        // A shear stretches the diagonal more than either axis.
+        let shear = Affine::new([1.0, 0.0, 2.0, 1.0, 0.0, 0.0]);
+        assert!(near(tolerance(shear), 1.0 / (1.0 + 2f64.sqrt())));
+        // A transform that collapses the plane to a line still stretches it.
+        assert!(near(tolerance(Affine::scale_non_uniform(2.0, 0.0)), 0.5));
+        assert_eq!(local_tolerance(Affine::scale(0.0)), None);
+    }
+
+    fn line(p0: (f64, f64), p1: (f64, f64)) -> BezPath {
+        Line::new(p0, p1).to_path(0.1)
+    }
+
+    /// Places cells with a fixed orientation, returning their positions.
+    fn positions(guide: &BezPath, spacing: f64, offset: f64) -> Vec<[f32; 2]> {
+        placements_along_path(guide, spacing, offset, PatternOrientation::Fixed)
+            .unwrap()
+            .iter()
+            .map(|placement| {
+                assert_eq!(placement.matrix, Transform::IDENTITY.matrix);
+                placement.translation.map(|x| (x * 1e3).round() / 1e3)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn placements_follow_spacing() {
+        let guide = line((0.0, 0.0), (30.0, 0.0));
+        assert_eq!(
+            positions(&guide, 10.0, 0.0),
+            [[0.0, 0.0], [10.0, 0.0], [20.0, 0.0], [30.0, 0.0]]
+        );
+        assert_eq!(
+            positions(&guide, 12.5, 0.0),
+            [[0.0, 0.0], [12.5, 0.0], [25.0, 0.0]]
+        );
+        // Without spacing, a single cell is placed.
+        assert_eq!(positions(&guide, 0.0, 7.0), [[7.0, 0.0]]);
+        // Distances continue across segments, and restart with each subpath.
+        let mut guide = line((0.0, 0.0), (15.0, 0.0));
+        guide.line_to((15.0, 15.0));
+        guide.move_to((0.0, 40.0));
+        guide.line_to((10.0, 40.0));
+        assert_eq!(
+            positions(&guide, 10.0, 0.0),
+            [
+                [0.0, 0.0],
+                [10.0, 0.0],
+                [15.0, 5.0],
+                [15.0, 15.0],
+                [0.0, 40.0],
+                [10.0, 40.0]
+            ]
+        );
+    }
+
+    #[test]
+    fn placements_start_at_offset() {
+        let guide = line((0.0, 0.0), (30.0, 0.0));
+        assert_eq!(
+            positions(&guide, 10.0, 5.0),
+            [[5.0, 0.0], [15.0, 0.0], [25.0, 0.0]]
+        );
+        // A negative offset starts the spacing before the guide.
+        assert_eq!(
+            positions(&guide, 10.0, -12.0),
+            [[8.0, 0.0], [18.0, 0.0], [28.0, 0.0]]
+        );
+        assert!(positions(&guide, 10.0, 31.0).is_empty());
+        assert!(positions(&guide, 0.0, -1.0).is_empty());
+    }
+
+    #[test]
+    fn placements_follow_orientation() {
+        let mut guide = line((0.0, 0.0), (10.0, 0.0));
+        guide.line_to((10.0, 10.0));
+        let tangent = placements_along_path(&guide, 5.0, 2.5, PatternOrientation::Tangent).unwrap();
+        let matrices = tangent
+            .iter()
+            .map(|placement| placement.matrix.map(|x| x.round()))
+            .collect::<Vec<_>>();
+        // Along x, then rotated a quarter turn along y.
+        assert_eq!(
+            matrices,
+            [
+                [1.0, 0.0, -0.0, 1.0],
+                [1.0, 0.0, -0.0, 1.0],
+                [0.0, 1.0, -1.0, 0.0],
+                [0.0, 1.0, -1.0, 0.0]
+            ]
+        );
+        let fixed = placements_along_path(&guide, 5.0, 2.5, PatternOrientation::Fixed).unwrap();
+        for (tangent, fixed) in tangent.iter().zip(&fixed) {
+            assert_eq!(fixed.matrix, Transform::IDENTITY.matrix);
+            assert_eq!(tangent.translation, fixed.translation);
+        }
+        // The tangent of a curve is its derivative.
+        let mut arc = BezPath::new();
+        arc.move_to((0.0, 0.0));
+        arc.quad_to((10.0, 0.0), (10.0, 10.0));
+        let placements =
+            placements_along_path(&arc, 0.0, 0.0, PatternOrientation::Tangent).unwrap();
+        assert_eq!(
+            placements[0].matrix.map(|x| x.round()),
+            [1.0, 0.0, -0.0, 1.0]
+        );
+    }
+
+    #[test]
+    fn closed_guides_place_their_start_once() {
+        let guide = Rect::new(0.0, 0.0, 10.0, 10.0).to_path(0.1);
+        assert_eq!(
+            positions(&guide, 10.0, 0.0),
+            [[0.0, 0.0], [10.0, 0.0], [10.0, 10.0], [0.0, 10.0]]
+        );
+        // The closing segment is part of the guide.
+        assert_eq!(
+            positions(&guide, 15.0, 0.0),
+            [[0.0, 0.0], [10.0, 5.0], [0.0, 10.0]]
+        );
+        // An open guide along the same points places its end.
+        let mut open = line((0.0, 0.0), (10.0, 0.0));
+        open.line_to((10.0, 10.0));
+        open.line_to((0.0, 10.0));
+        open.line_to((0.0, 0.0));
+        assert_eq!(positions(&open, 10.0, 0.0).len(), 5);
+    }
+
+    #[test]
+    fn zero_length_guides() {
+        // A zero length segment places a single cell, with an arbitrary but finite
+        // orientation.
+        let point = line((5.0, 5.0), (5.0, 5.0));
+        assert_eq!(positions(&point, 10.0, 0.0), [[5.0, 5.0]]);
+        let tangent =
+            placements_along_path(&point, 10.0, 0.0, PatternOrientation::Tangent).unwrap();
+        assert!(tangent[0].matrix.iter().all(|x| x.is_finite()));
+        // Without segments, nothing is placed.
+        let mut moves = BezPath::new();
+        moves.move_to((5.0, 5.0));
+        assert!(positions(&moves, 10.0, 0.0).is_empty());
+        assert!(positions(&BezPath::new(), 10.0, 0.0).is_empty());
+    }
+
+    #[test]
+    fn tiny_spacing_is_rejected() {
+        let guide = line((0.0, 0.0), (30.0, 0.0));
+        let place =
+            |spacing| placements_along_path(&guide, spacing, 0.0, PatternOrientation::Fixed);
+        assert!(place(1e-30).is_none());
+        let max = vello_encoding::MAX_PATTERN_CELLS as f64;
+        assert!(place(15.0 / max).is_none());
+        // The limit is on the count, so a spacing above it still places every cell.
+        assert_eq!(place(60.0 / max).unwrap().len() as f64, max / 2.0 + 1.0);
+        // Offsets far before the guide don't place cells one spacing at a time.
+        let far = placements_along_path(&guide, 1e-30, -1e10, PatternOrientation::Fixed);
+        assert!(far.is_none());
+    }
+
+    #[test]
+    fn instances_are_encoded_once() {
+        let mut fragment = SceneFragment::new();
+        let mut builder = SceneBuilder::for_fragment(&mut fragment);
+        let square = Rect::new(0.0, 0.0, 4.0, 4.0);
+        builder.fill(Fill::NonZero, Affine::IDENTITY, Color::BLUE, None, &square);
+        // More copies than patterns may have cells when expanded on the CPU.
+        let n = vello_encoding::MAX_PATTERN_CELLS as usize + 1;
+        let transforms = (0..n)
+            .map(|i| Affine::translate(((i % 1000) as f64, (i / 1000) as f64)))
+            .collect::<Vec<_>>();
+        let mut scene = Scene::new();
+        let mut builder = SceneBuilder::for_scene(&mut scene);
+        builder.draw_instances(&fragment, &transforms);
+        let viewport = [0.0, 0.0, 1000.0, 1000.0];
+        let instanced = vello_encoding::instance_patterns(scene.data(), viewport).unwrap();
+        // A single record with a cell for every placement, over one template path.
+        let [instance] = instanced.pattern_instances[..] else {
+            panic!("expected one pattern instance");
+        };
+        assert_eq!(instance.n_cells(&instanced.pattern_data), n as u64);
+        assert_eq!(instanced.n_paths, 1);
+        assert_eq!(instanced.draw_tags.len(), 1);
+    }
+}
@@ -22,7 +22,11 @@ use std::collections::HashSet;
 
 use wgpu::Device;
 
-use crate::engine::{BindType, Engine, Error, ImageFormat, ShaderId};
+use crate::{
+    cpu_dispatch::CpuEngine,
+    cpu_shader,
+    engine::{BindType, Engine, Error, ImageFormat, ShaderId},
+};
 
 macro_rules! shader {
     ($name:expr) => {&{
@@ -63,6 +67,8 @@ pub struct FullShaders {
     pub draw_leaf: ShaderId,
     pub clip_reduce: ShaderId,
     pub clip_leaf: ShaderId,
+    pub pattern_reduce: ShaderId,
+    pub pattern_leaf: ShaderId,
     pub pattern: ShaderId,
     pub binning: ShaderId,
     pub tile_alloc: ShaderId,
@@ -70,9 +76,16 @@ pub struct FullShaders {
     pub backdrop: ShaderId,
     pub coarse: ShaderId,
     pub fine: ShaderId,
+    pub blur: ShaderId,
+    /// Format of the image written by the fine stage.
+    pub target_format: ImageFormat,
 }
 
-pub fn full_shaders(device: &Device, engine: &mut Engine) -> Result<FullShaders, Error> {
+pub fn full_shaders(
+    device: &Device,
+    engine: &mut Engine,
+    target_format: ImageFormat,
+) -> Result<FullShaders, Error> {
     let imports = SHARED_SHADERS
         .iter()
         .copied()
@@ -163,7 +176,6 @@ pub fn full_shaders(device: &Device, engine: &mut Engine) -> Result<FullShaders,
             BindType::Buffer,
             BindType::Buffer,
             BindType::Buffer,
-            BindType::Buffer,
         ],
     )?;
     let clip_reduce = engine.add_shader(
@@ -192,16 +204,32 @@ pub fn full_shaders(device: &Device, engine: &mut Engine) -> Result<FullShaders,
             BindType::Buffer,
         ],
     )?;
+    let pattern_reduce = engine.add_shader(
+        device,
+        "pattern_reduce",
+        preprocess::preprocess(shader!("pattern_reduce"), &empty, &imports).into(),
+        &[BindType::Uniform, BindType::BufReadOnly, BindType::Buffer],
+    )?;
+    let pattern_leaf = engine.add_shader(
+        device,
+        "pattern_leaf",
+        preprocess::preprocess(shader!("pattern_leaf"), &empty, &imports).into(),
+        &[
+            BindType::Uniform,
+            BindType::BufReadOnly,
+            BindType::BufReadOnly,
+            BindType::Buffer,
+            BindType::Buffer,
+        ],
+    )?;
     let pattern = engine.add_shader(
         device,
         "pattern",
         preprocess::preprocess(shader!("pattern"), &empty, &imports).into(),
         &[
             BindType::Uniform,
-            BindType::BufReadOnly,
-            BindType::BufReadOnly,
-            BindType::BufReadOnly,
             BindType::Buffer,
+            BindType::BufReadOnly,
             BindType::Buffer,
             BindType::Buffer,
             BindType::Buffer,
@@ -229,7 +257,6 @@ pub fn full_shaders(device: &Device, engine: &mut Engine) -> Result<FullShaders,
         &[
             BindType::Uniform,
             BindType::BufReadOnly,
-            BindType::BufReadOnly,
             BindType::Buffer,
             BindType::Buffer,
             BindType::Buffer,
@@ -273,19 +300,39 @@ pub fn full_shaders(device: &Device, engine: &mut Engine) -> Result<FullShaders,
             BindType::Buffer,
         ],
     )?;
+    let mut fine_config = full_config;
+    if target_format == ImageFormat::Rgba16Float {
+        fine_config.insert("rgba16float".into());
+    }
     let fine = engine.add_shader(
         device,
         "fine",
-        preprocess::preprocess(shader!("fine"), &full_config, &imports).into(),
+        preprocess::preprocess(shader!("fine"), &fine_config, &imports).into(),
         &[
             BindType::Uniform,
             BindType::BufReadOnly,
             BindType::BufReadOnly,
-            BindType::Image(ImageFormat::Rgba8),
+            BindType::Image(target_format),
             BindType::BufReadOnly,
             BindType::ImageRead(ImageFormat::Rgba8),
             BindType::BufReadOnly,
-            BindType::ImageRead(ImageFormat::Rgba8),
+            BindType::ImageArrayRead(ImageFormat::Rgba8),
+            BindType::ImageRead(target_format),
+        ],
+    )?;
+    let mut blur_config = HashSet::new();
+    if target_format == ImageFormat::Rgba16Float {
+        blur_config.insert("rgba16float".into());
+    }
+    let blur = engine.add_shader(
+        device,
+        "blur",
+        preprocess::preprocess(shader!("blur"), &blur_config, &imports).into(),
+        &[
+            BindType::Uniform,
+            BindType::ImageRead(target_format),
+            BindType::ImageRead(target_format),
+            BindType::Image(target_format),
         ],
     )?;
     Ok(FullShaders {
@@ -300,6 +347,8 @@ pub fn full_shaders(device: &Device, engine: &mut Engine) -> Result<FullShaders,
         draw_leaf,
         clip_reduce,
         clip_leaf,
+        pattern_reduce,
+        pattern_leaf,
         pattern,
         binning,
         tile_alloc,
@@ -307,9 +356,39 @@ pub fn full_shaders(device: &Device, engine: &mut Engine) -> Result<FullShaders,
         backdrop,
         coarse,
         fine,
+        blur,
+        target_format,
     })
 }
 
+/// Registers the CPU implementations of the full pipeline with a [`CpuEngine`].
+pub fn full_shaders_cpu(engine: &mut CpuEngine) -> FullShaders {
+    FullShaders {
+        pathtag_reduce: engine.add_shader("pathtag_reduce", cpu_shader::pathtag_reduce),
+        pathtag_reduce2: engine.add_shader("pathtag_reduce2", cpu_shader::pathtag_reduce2),
+        pathtag_scan1: engine.add_shader("pathtag_scan1", cpu_shader::pathtag_scan1),
+        pathtag_scan: engine.add_shader("pathtag_scan", cpu_shader::pathtag_scan_small),
+        pathtag_scan_large: engine.add_shader("pathtag_scan_large", cpu_shader::pathtag_scan_large),
+        bbox_clear: engine.add_shader("bbox_clear", cpu_shader::bbox_clear),
+        pathseg: engine.add_shader("pathseg", cpu_shader::pathseg),
+        draw_reduce: engine.add_shader("draw_reduce", cpu_shader::draw_reduce),
+        draw_leaf: engine.add_shader("draw_leaf", cpu_shader::draw_leaf),
+        clip_reduce: engine.add_shader("clip_reduce", cpu_shader::clip_reduce),
+        clip_leaf: engine.add_shader("clip_leaf", cpu_shader::clip_leaf),
+        pattern_reduce: engine.add_shader("pattern_reduce", cpu_shader::pattern_reduce),
+        pattern_leaf: engine.add_shader("pattern_leaf", cpu_shader::pattern_leaf),
+        pattern: engine.add_shader("pattern", cpu_shader::pattern),
+        binning: engine.add_shader("binning", cpu_shader::binning),
+        tile_alloc: engine.add_shader("tile_alloc", cpu_shader::tile_alloc),
+        path_coarse: engine.add_shader("path_coarse", cpu_shader::path_coarse),
+        backdrop: engine.add_shader("backdrop", cpu_shader::backdrop),
+        coarse: engine.add_shader("coarse", cpu_shader::coarse),
+        fine: engine.add_shader("fine", cpu_shader::fine),
+        blur: engine.add_shader("blur", cpu_shader::blur),
+        target_format: ImageFormat::Rgba8,
+    }
+}
+
 macro_rules! shared_shader {
     ($name:expr) => {
         (
@@ -328,6 +407,7 @@ const SHARED_SHADERS: &[(&str, &str)] = &[
     shared_shader!("cubic"),
     shared_shader!("drawtag"),
     shared_shader!("pathtag"),
+    shared_shader!("pattern"),
     shared_shader!("ptcl"),
     shared_shader!("segment"),
     shared_shader!("tile"),
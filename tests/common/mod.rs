@@ -0,0 +1,64 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Helpers shared by the tests that render with the CPU renderer.
+
+use vello::peniko::Color;
+use vello::{CpuRenderer, CpuTexture, RenderParams, Scene};
+
+/// Parameters of a render of the given size over a white background.
+pub fn params(width: u32, height: u32) -> RenderParams {
+    RenderParams {
+        base_color: Color::WHITE,
+        width,
+        height,
+        target_color_space: Default::default(),
+        blend_color_space: Default::default(),
+    }
+}
+
+/// Renders a scene of the given size with a new CPU renderer.
+pub fn render(scene: &Scene, width: u32, height: u32) -> CpuTexture {
+    render_with(&mut CpuRenderer::new(), scene, &params(width, height))
+}
+
+/// Renders a scene with `renderer`, panicking if the render fails.
+pub fn render_with(renderer: &mut CpuRenderer, scene: &Scene, params: &RenderParams) -> CpuTexture {
+    let mut texture = CpuTexture::new(params.width, params.height);
+    renderer
+        .render_to_texture(scene, &mut texture, params)
+        .unwrap();
+    texture
+}
+
+/// Returns the RGBA8 pixel at `(x, y)`.
+pub fn pixel(texture: &CpuTexture, x: usize, y: usize) -> [u8; 4] {
+    texture.pixels[y * texture.width as usize + x].to_le_bytes()
+}
+
+/// Asserts that each channel of an RGBA8 pixel is within `tolerance` of `expected`.
+pub fn assert_near(actual: [u8; 4], expected: [u8; 4], tolerance: u8) {
+    assert!(
+        near(actual, expected, tolerance),
+        "{actual:?} != {expected:?}"
+    );
+}
+
+/// Asserts that two renders differ by at most 1 in each channel of each pixel.
+pub fn assert_pixels_match(texture: &CpuTexture, expected: &CpuTexture) {
+    assert_eq!(
+        (texture.width, texture.height),
+        (expected.width, expected.height)
+    );
+    for (ix, (p, e)) in texture.pixels.iter().zip(&expected.pixels).enumerate() {
+        let (p, e) = (p.to_le_bytes(), e.to_le_bytes());
+        assert!(near(p, e, 1), "{p:?} != {e:?} at {ix}");
+    }
+}
+
+fn near(actual: [u8; 4], expected: [u8; 4], tolerance: u8) -> bool {
+    actual
+        .iter()
+        .zip(expected)
+        .all(|(a, b)| a.abs_diff(b) <= tolerance)
+}
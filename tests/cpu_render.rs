@@ -0,0 +1,895 @@
+// Copyright 2023 The Vello authors
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Scenes rendered with the CPU renderer, grouped by feature.
+
+mod common;
+
+use vello::kurbo::{Affine, Circle, Rect};
+use vello::peniko::{Color, Fill, Mix};
+use vello::{LayerEffect, Scene, SceneBuilder};
+
+const RED: [u8; 4] = [255, 0, 0, 255];
+const WHITE: [u8; 4] = [255, 255, 255, 255];
+
+/// Overlapping paths that each cover the viewport, which overflow the estimated tile and
+/// segment buffers.
+fn overlapping_circles(n: usize) -> Scene {
+    let mut scene = Scene::new();
+    let mut sb = SceneBuilder::for_scene(&mut scene);
+    draw_overlapping_circles(&mut sb, n);
+    scene
+}
+
+fn draw_overlapping_circles(sb: &mut SceneBuilder, n: usize) {
+    for i in 0..n {
+        let color = Color::rgba8(0, 0, (i * 5) as u8, 40);
+        let circle = Circle::new((128.0, 128.0), 200.0 - i as f64);
+        sb.fill(Fill::NonZero, Affine::IDENTITY, color, None, &circle);
+    }
+}
+
+/// Overlapping circles in a layer over the 256 by 256 target, blurred if `std_dev` is set.
+fn blurred_circles(n: usize, std_dev: Option<f64>) -> Scene {
+    let mut scene = Scene::new();
+    let mut sb = SceneBuilder::for_scene(&mut scene);
+    let shape = Rect::new(0.0, 0.0, 256.0, 256.0);
+    match std_dev {
+        Some(std_dev) => sb.push_layer_with_effect(
+            Mix::Normal,
+            1.0,
+            Affine::IDENTITY,
+            &shape,
+            LayerEffect::Blur { std_dev },
+        ),
+        None => sb.push_layer(Mix::Normal, 1.0, Affine::IDENTITY, &shape),
+    }
+    draw_overlapping_circles(&mut sb, n);
+    sb.pop_layer();
+    scene
+}
+
+mod memory {
+    use vello::kurbo::{BezPath, Point, RoundedRect};
+    use vello::peniko::Stroke;
+    use vello::{CpuRenderer, CpuTexture, MemoryLimitExceeded};
+
+    use super::common::{params, render_with};
+    use super::*;
+
+    /// Renders a scene with the CPU renderer, asserting that the estimated buffer sizes
+    /// fit it without growing.
+    fn assert_estimate_fits(scene: &Scene, width: u32, height: u32) {
+        let mut renderer = CpuRenderer::new();
+        render_with(&mut renderer, scene, &params(width, height));
+        // Sizes are only retained by the renderer when a render overflowed.
+        assert_eq!(renderer.bump_sizes().size_in_bytes(), 0);
+    }
+
+    #[test]
+    fn estimate_fits_ui_scene() {
+        let mut scene = Scene::new();
+        let mut sb = SceneBuilder::for_scene(&mut scene);
+        let stroke = Stroke::new(1.5);
+        for row in 0..20 {
+            for column in 0..6 {
+                let (x, y) = (20.0 + column as f64 * 130.0, 10.0 + row as f64 * 29.0);
+                let button = RoundedRect::new(x, y, x + 120.0, y + 24.0, 6.0);
+                let color = Color::rgb8(40 * column as u8, 10 * row as u8, 200);
+                sb.fill(Fill::NonZero, Affine::IDENTITY, color, None, &button);
+                sb.stroke(&stroke, Affine::IDENTITY, Color::BLACK, None, &button);
+            }
+        }
+        assert_estimate_fits(&scene, 800, 600);
+    }
+
+    /// A five pointed star with the given outer and inner radii.
+    fn star(center: Point, outer: f64, inner: f64) -> BezPath {
+        let mut path = BezPath::new();
+        for i in 0..10 {
+            let radius = if i % 2 == 0 { outer } else { inner };
+            let angle = i as f64 * std::f64::consts::PI / 5.0;
+            let point = center + radius * vello::kurbo::Vec2::from_angle(angle);
+            if i == 0 {
+                path.move_to(point);
+            } else {
+                path.line_to(point);
+            }
+        }
+        path.close_path();
+        path
+    }
+
+    #[test]
+    fn estimate_fits_many_paths() {
+        let mut scene = Scene::new();
+        let mut sb = SceneBuilder::for_scene(&mut scene);
+        for i in 0..4096 {
+            let center = ((i % 64) as f64 * 16.0 + 8.0, (i / 64) as f64 * 16.0 + 8.0);
+            let star = star(center.into(), 7.5, 3.0);
+            let color = Color::rgba8((i % 256) as u8, (i / 16) as u8, 128, 200);
+            sb.fill(Fill::NonZero, Affine::IDENTITY, color, None, &star);
+        }
+        assert_estimate_fits(&scene, 1024, 1024);
+    }
+
+    #[test]
+    fn cpu_renderer_grows_within_memory_limit() {
+        let scene = overlapping_circles(40);
+        let mut renderer = CpuRenderer::new();
+        let mut texture = render_with(&mut renderer, &scene, &params(256, 256));
+        let limit = renderer.bump_sizes().size_in_bytes();
+        let mut limited = CpuRenderer::new().with_memory_limit(Some(limit / 2));
+        let error = limited
+            .render_to_texture(&scene, &mut texture, &params(256, 256))
+            .unwrap_err();
+        let error = error.downcast_ref::<MemoryLimitExceeded>().unwrap();
+        assert_eq!(error.limit, limit / 2);
+        assert!(error.requested > error.limit);
+    }
+
+    #[test]
+    fn cpu_renderer_checks_estimate_against_memory_limit() {
+        let scene = overlapping_circles(1);
+        let mut texture = CpuTexture::new(256, 256);
+        let mut limited = CpuRenderer::new().with_memory_limit(Some(1024));
+        let error = limited
+            .render_to_texture(&scene, &mut texture, &params(256, 256))
+            .unwrap_err();
+        let error = error.downcast_ref::<MemoryLimitExceeded>().unwrap();
+        assert_eq!(error.limit, 1024);
+        // The estimate didn't overflow, so no sizes were retained.
+        assert_eq!(limited.bump_sizes().size_in_bytes(), 0);
+    }
+}
+
+mod effects {
+    use vello::CpuRenderer;
+
+    use super::common::{assert_near, params, pixel, render, render_with};
+    use super::*;
+
+    #[test]
+    fn cpu_renderer_grows_for_effect_layers() {
+        let mut renderer = CpuRenderer::new();
+        let blurred = render_with(
+            &mut renderer,
+            &blurred_circles(40, Some(2.0)),
+            &params(256, 256),
+        );
+        // Only the contents of the layer overflow, as the scene just draws its region.
+        assert!(renderer.bump_sizes().size_in_bytes() > 0);
+        let plain = render(&blurred_circles(40, None), 256, 256);
+        // The circles are uniform around the center, where the blur has no effect.
+        assert_near(pixel(&blurred, 128, 128), pixel(&plain, 128, 128), 1);
+    }
+
+    #[test]
+    fn render_stats_count_dropped_effects() {
+        let (width, height) = (9000, 16);
+        let mut scene = Scene::new();
+        let mut sb = SceneBuilder::for_scene(&mut scene);
+        let blur = LayerEffect::Blur { std_dev: 1.0 };
+        let color = Color::rgb8(0, 0, 255);
+        for shape in [
+            Rect::new(0.0, 0.0, width as f64, 8.0),
+            Rect::new(0.0, 8.0, 64.0, 16.0),
+        ] {
+            sb.push_layer_with_effect(Mix::Normal, 1.0, Affine::IDENTITY, &shape, blur);
+            sb.fill(Fill::NonZero, Affine::IDENTITY, color, None, &shape);
+            sb.pop_layer();
+        }
+        let mut renderer = CpuRenderer::new();
+        let texture = render_with(&mut renderer, &scene, &params(width, height));
+        // The wide layer doesn't fit in the layer image, and is drawn without its blur.
+        assert_eq!(renderer.render_stats().dropped_effects, 1);
+        assert_eq!(pixel(&texture, 0, 4), [0, 0, 255, 255]);
+    }
+}
+
+mod blending {
+    use vello::peniko::{ColorStop, Gradient};
+    use vello::{ColorSpace, CpuRenderer, RenderParams};
+
+    use super::common::{assert_near, params, pixel, render_with};
+    use super::*;
+
+    /// Renders a scene with the given blend color space.
+    fn render_blended(scene: &Scene, width: u32, height: u32, space: ColorSpace) -> Vec<[u8; 4]> {
+        let params = RenderParams {
+            blend_color_space: space,
+            ..params(width, height)
+        };
+        let texture = render_with(&mut CpuRenderer::new(), scene, &params);
+        (0..width as usize)
+            .map(|x| pixel(&texture, x, height as usize / 2))
+            .collect()
+    }
+
+    #[test]
+    fn blend_translucent_overlap() {
+        // Half transparent red and blue rects over white, overlapping in the middle.
+        let mut scene = Scene::new();
+        let mut sb = SceneBuilder::for_scene(&mut scene);
+        let red = Color::rgba8(255, 0, 0, 128);
+        let blue = Color::rgba8(0, 0, 255, 128);
+        let rect = Rect::new(0.0, 0.0, 32.0, 16.0);
+        sb.fill(Fill::NonZero, Affine::IDENTITY, red, None, &rect);
+        sb.fill(
+            Fill::NonZero,
+            Affine::translate((16.0, 0.0)),
+            blue,
+            None,
+            &rect,
+        );
+        // Blending the encoded values halves them, while blending linear values
+        // halves the light, which encodes to 187.
+        let expected = [
+            (
+                ColorSpace::Srgb,
+                [[255, 127, 127], [127, 63, 191], [127, 127, 255]],
+            ),
+            (
+                ColorSpace::LinearSrgb,
+                [[255, 187, 187], [187, 136, 225], [187, 187, 255]],
+            ),
+        ];
+        for (space, pixels) in expected {
+            let row = render_blended(&scene, 48, 16, space);
+            for (x, [r, g, b]) in [8, 24, 40].into_iter().zip(pixels) {
+                assert_near(row[x], [r, g, b, 255], 1);
+            }
+        }
+    }
+
+    #[test]
+    fn blend_gradient() {
+        // A gradient from black to white, sampled at the centers of pixels.
+        let mut scene = Scene::new();
+        let mut sb = SceneBuilder::for_scene(&mut scene);
+        let gradient =
+            Gradient::new_linear((0.0, 0.0), (256.0, 0.0)).with_stops([0, 255].map(|v| {
+                ColorStop {
+                    offset: v as f32 / 255.0,
+                    color: Color::rgb8(v, v, v),
+                }
+            }));
+        let rect = Rect::new(0.0, 0.0, 256.0, 4.0);
+        sb.fill(Fill::NonZero, Affine::IDENTITY, &gradient, None, &rect);
+        // Ramps are interpolated in the blend color space, so the linear midpoint is
+        // half as bright, which encodes to 187.
+        let expected = [
+            (ColorSpace::Srgb, [0, 63, 127, 191, 254]),
+            (ColorSpace::LinearSrgb, [0, 137, 187, 224, 254]),
+        ];
+        for (space, values) in expected {
+            let row = render_blended(&scene, 256, 4, space);
+            for (x, v) in [0, 64, 128, 192, 255].into_iter().zip(values) {
+                assert_near(row[x], [v, v, v, 255], 1);
+            }
+        }
+    }
+}
+
+mod patterns {
+    use vello::kurbo::{BezPath, Point, Vec2};
+    use vello::peniko::{Blob, ColorStop, Extend, Format, Gradient, Image, Stroke};
+    use vello::{
+        CpuRenderer, CpuTexture, Pattern, PatternColors, PatternOverflow, PatternTiling,
+        PatternUnits, SceneFragment,
+    };
+
+    use super::common::{assert_pixels_match, params, pixel, render, render_with};
+    use super::*;
+
+    /// A pattern of small squares over a 64 by 64 target, with the given per-cell colors.
+    fn square_pattern(colors: PatternColors) -> Scene {
+        let mut fragment = SceneFragment::new();
+        let mut sb = SceneBuilder::for_fragment(&mut fragment);
+        let square = Rect::new(0.0, 0.0, 4.0, 4.0);
+        sb.fill(Fill::NonZero, Affine::IDENTITY, Color::BLACK, None, &square);
+        let mut scene = Scene::new();
+        let mut sb = SceneBuilder::for_scene(&mut scene);
+        let tile = Rect::new(0.0, 0.0, 8.0, 8.0);
+        let pattern = Pattern::new(&fragment, tile).with_colors(colors);
+        sb.draw_pattern(Affine::IDENTITY, &pattern);
+        scene
+    }
+
+    /// Renders a 64 by 64 scene with its patterns instanced and expanded on the CPU,
+    /// asserting that they render the same and that `n_patterns` patterns were expanded.
+    /// Returns the instanced render.
+    fn assert_expanded_patterns_match(scene: &Scene, n_patterns: u32) -> CpuTexture {
+        let [instanced, expanded] = [false, true].map(|expand_patterns| {
+            let mut renderer = CpuRenderer::new().with_expand_patterns(expand_patterns);
+            let texture = render_with(&mut renderer, scene, &params(64, 64));
+            let expected = if expand_patterns { n_patterns } else { 0 };
+            assert_eq!(renderer.render_stats().expanded_patterns, expected);
+            texture
+        });
+        assert_pixels_match(&instanced, &expanded);
+        instanced
+    }
+
+    /// Returns the number of pixels of a render that aren't white.
+    fn count_drawn(texture: &CpuTexture) -> usize {
+        let white = u32::from_le_bytes(WHITE);
+        texture.pixels.iter().filter(|&&p| p != white).count()
+    }
+
+    #[test]
+    fn render_stats_count_expanded_patterns() {
+        let mut renderer = CpuRenderer::new();
+        let scene = square_pattern(PatternColors::None);
+        render_with(&mut renderer, &scene, &params(64, 64));
+        assert_eq!(renderer.render_stats().expanded_patterns, 0);
+        // Per-cell colors are written by the pattern stage.
+        let colors = [Color::rgb8(255, 0, 0), Color::rgb8(0, 0, 255)];
+        let scene = square_pattern(PatternColors::List(&colors));
+        render_with(&mut renderer, &scene, &params(64, 64));
+        assert_eq!(renderer.render_stats().expanded_patterns, 0);
+        let mut renderer = CpuRenderer::new().with_expand_patterns(true);
+        render_with(&mut renderer, &scene, &params(64, 64));
+        assert_eq!(renderer.render_stats().expanded_patterns, 1);
+    }
+
+    #[test]
+    fn per_cell_colors_are_instanced() {
+        let stops = [
+            ColorStop {
+                offset: 0.0,
+                color: Color::rgb8(255, 0, 0),
+            },
+            ColorStop {
+                offset: 1.0,
+                color: Color::rgba8(0, 0, 255, 128),
+            },
+        ];
+        let scene = square_pattern(PatternColors::Gradient(&stops));
+        let instanced = assert_expanded_patterns_match(&scene, 1);
+        // The squares of the cells sample the gradient at different offsets.
+        let mut colors: Vec<[u8; 4]> = (0..8)
+            .map(|i| pixel(&instanced, i * 8 + 2, i * 8 + 2))
+            .collect();
+        colors.sort_unstable();
+        colors.dedup();
+        assert!(colors.len() > 2, "{colors:?}");
+    }
+
+    #[test]
+    fn clipped_patterns_are_instanced() {
+        // Cells 16 apart, whose contents overflow their 8 by 8 tile to the right.
+        let mut fragment = SceneFragment::new();
+        let bar = Rect::new(0.0, 0.0, 12.0, 8.0);
+        SceneBuilder::for_fragment(&mut fragment).fill(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            Color::BLACK,
+            None,
+            &bar,
+        );
+        let tile = Rect::new(0.0, 0.0, 8.0, 8.0);
+        let spacing = (Vec2::new(16.0, 0.0), Vec2::new(0.0, 16.0));
+        // Each instance draws its own copy of the layer that clips it, which renders the
+        // same as expanding the pattern.
+        let mut triangle = BezPath::new();
+        triangle.move_to((0.0, 0.0));
+        triangle.line_to((12.0, 0.0));
+        triangle.line_to((0.0, 12.0));
+        triangle.close_path();
+        let overflows = [
+            PatternOverflow::Visible,
+            PatternOverflow::Hidden,
+            PatternOverflow::Clip(&triangle),
+        ];
+        let [visible, hidden, clipped] = overflows.map(|overflow| {
+            let pattern = Pattern::new(&fragment, tile)
+                .with_spacing(spacing.0, spacing.1)
+                .with_transform(Affine::rotate(0.1))
+                .with_overflow(overflow);
+            let mut scene = Scene::new();
+            SceneBuilder::for_scene(&mut scene).draw_pattern(Affine::IDENTITY, &pattern);
+            assert_expanded_patterns_match(&scene, 1)
+        });
+        // The cell at (16, 16), within its tile and past its right edge.
+        let cell_pixel = |texture: &CpuTexture, x: f64, y: f64| {
+            let p = Affine::rotate(0.1) * Point::new(16.0 + x, 16.0 + y);
+            pixel(texture, p.x as usize, p.y as usize)
+        };
+        let black = [0, 0, 0, 255];
+        assert_eq!(cell_pixel(&visible, 4.0, 4.0), black);
+        assert_eq!(cell_pixel(&visible, 10.0, 4.0), black);
+        assert_eq!(cell_pixel(&hidden, 4.0, 4.0), black);
+        assert_eq!(cell_pixel(&hidden, 10.0, 4.0), WHITE);
+        assert_eq!(cell_pixel(&hidden, 7.0, 7.0), black);
+        assert_eq!(cell_pixel(&clipped, 2.0, 2.0), black);
+        assert_eq!(cell_pixel(&clipped, 7.0, 7.0), WHITE);
+    }
+
+    #[test]
+    fn expanded_patterns_match_instanced_patterns() {
+        // Overlapping copies with per-cell colors, and a nested pattern of strokes.
+        let mut fragment = SceneFragment::new();
+        let mut sb = SceneBuilder::for_fragment(&mut fragment);
+        let color = Color::rgba8(200, 40, 0, 180);
+        sb.fill(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            color,
+            None,
+            &Circle::new((4.0, 4.0), 7.0),
+        );
+        let mut scene = Scene::new();
+        let mut sb = SceneBuilder::for_scene(&mut scene);
+        let colors = [Color::rgb8(0, 120, 0), Color::rgba8(0, 0, 200, 100)];
+        let pattern = Pattern::new(&fragment, Rect::new(0.0, 0.0, 12.0, 12.0))
+            .with_tiling(PatternTiling::Brick)
+            .with_transform(Affine::rotate(0.3) * Affine::skew(0.2, 0.0))
+            .with_colors(PatternColors::List(&colors));
+        sb.draw_pattern(Affine::IDENTITY, &pattern);
+        sb.start_pattern(Vec2::new(3.0, 5.0), Vec2::new(10.0, 10.0), -0.4);
+        let stroke = Stroke::new(1.0);
+        let square = Rect::new(0.0, 0.0, 4.0, 4.0);
+        sb.stroke(
+            &stroke,
+            Affine::IDENTITY,
+            Color::rgb8(0, 0, 160),
+            None,
+            &square,
+        );
+        sb.end_pattern();
+        let instanced = assert_expanded_patterns_match(&scene, 2);
+        assert!(count_drawn(&instanced) > 1000);
+    }
+
+    #[test]
+    fn patterns_instance_every_draw_kind() {
+        let stops = |a: Color, b: Color| {
+            [(0.0, a), (1.0, b)].map(|(offset, color)| ColorStop { offset, color })
+        };
+        let mut fragment = SceneFragment::new();
+        let mut sb = SceneBuilder::for_fragment(&mut fragment);
+        let linear = Gradient::new_linear((0.0, 0.0), (10.0, 0.0))
+            .with_stops(stops(Color::rgb8(255, 0, 0), Color::rgb8(0, 0, 255)));
+        sb.fill(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            &linear,
+            None,
+            &Rect::new(0.0, 0.0, 10.0, 6.0),
+        );
+        let radial = Gradient::new_radial((15.0, 4.0), 4.0).with_stops(stops(
+            Color::rgb8(255, 255, 0),
+            Color::rgba8(0, 128, 0, 128),
+        ));
+        sb.fill(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            &radial,
+            None,
+            &Circle::new((15.0, 4.0), 4.0),
+        );
+        sb.stroke(
+            &Stroke::new(1.5),
+            Affine::IDENTITY,
+            Color::rgb8(0, 0, 100),
+            None,
+            &Rect::new(2.0, 8.0, 12.0, 14.0),
+        );
+        let checker: Vec<u8> = (0..16)
+            .flat_map(|i| match (i + i / 4) % 2 {
+                0 => [255, 0, 255, 255],
+                _ => [0, 160, 160, 200],
+            })
+            .collect();
+        let image = Image::new(Blob::new(std::sync::Arc::new(checker)), Format::Rgba8, 4, 4);
+        sb.draw_image(&image, Affine::translate((14.0, 10.0)));
+        let circle = Circle::new((8.0, 11.0), 5.0);
+        sb.push_layer(Mix::Multiply, 0.8, Affine::IDENTITY, &circle);
+        sb.fill(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            Color::rgb8(0, 200, 255),
+            None,
+            &Rect::new(0.0, 6.0, 16.0, 16.0),
+        );
+        sb.pop_layer();
+        let mut scene = Scene::new();
+        let pattern = Pattern::new(&fragment, Rect::new(0.0, 0.0, 20.0, 18.0))
+            .with_transform(Affine::rotate(0.2));
+        SceneBuilder::for_scene(&mut scene).draw_pattern(Affine::IDENTITY, &pattern);
+        let instanced = assert_expanded_patterns_match(&scene, 1);
+        assert!(count_drawn(&instanced) > 2000);
+    }
+
+    /// Fills the whole 256x256 target with `pattern`, whose contents are a red square
+    /// covering `square`.
+    fn render_pattern(square: Rect, pattern: impl Fn(&SceneFragment) -> Pattern) -> CpuTexture {
+        let mut fragment = SceneFragment::new();
+        SceneBuilder::for_fragment(&mut fragment).fill(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            Color::rgb8(255, 0, 0),
+            None,
+            &square,
+        );
+        let mut scene = Scene::new();
+        let shape = Rect::new(40.0, 40.0, 240.0, 140.0);
+        SceneBuilder::for_scene(&mut scene).fill_pattern(
+            Affine::IDENTITY,
+            &pattern(&fragment),
+            &shape,
+        );
+        render(&scene, 256, 256)
+    }
+
+    #[test]
+    fn pattern_units_follow_the_bounding_box() {
+        let square = Rect::new(0.0, 0.0, 10.0, 10.0);
+        // A 0.1x0.2 tile of the 200x100 shape is 20x20, anchored at the shape's corner.
+        let texture = render_pattern(square, |fragment| {
+            Pattern::new(fragment, Rect::new(0.0, 0.0, 0.1, 0.2))
+                .with_units(PatternUnits::ObjectBoundingBox)
+        });
+        for (x, y) in [(45, 45), (65, 45), (45, 65), (225, 125)] {
+            assert_eq!(pixel(&texture, x, y), RED, "({x}, {y})");
+        }
+        for (x, y) in [(55, 45), (45, 55), (35, 45), (45, 145)] {
+            assert_eq!(pixel(&texture, x, y), WHITE, "({x}, {y})");
+        }
+        // The equivalent user space tile starts at the shape's corner.
+        let user = render_pattern(square, |fragment| {
+            Pattern::new(fragment, Rect::new(40.0, 40.0, 60.0, 60.0))
+        });
+        assert!(texture.pixels == user.pixels);
+    }
+
+    #[test]
+    fn pattern_content_units_scale_the_contents() {
+        // Bounding box contents are scaled by the 200x100 shape, so this is a 10x10 square.
+        let bbox = render_pattern(Rect::new(0.0, 0.0, 0.05, 0.1), |fragment| {
+            Pattern::new(fragment, Rect::new(0.0, 0.0, 20.0, 20.0))
+                .with_content_units(PatternUnits::ObjectBoundingBox)
+        });
+        let user = render_pattern(Rect::new(0.0, 0.0, 10.0, 10.0), |fragment| {
+            Pattern::new(fragment, Rect::new(0.0, 0.0, 20.0, 20.0))
+        });
+        assert_eq!(pixel(&bbox, 45, 45), RED);
+        assert_eq!(pixel(&bbox, 55, 45), WHITE);
+        assert_eq!(pixel(&bbox, 45, 55), WHITE);
+        assert!(bbox.pixels == user.pixels);
+    }
+
+    #[test]
+    fn pattern_transform_moves_the_lattice() {
+        let square = Rect::new(0.0, 0.0, 10.0, 10.0);
+        let translated = render_pattern(square, |fragment| {
+            Pattern::new(fragment, Rect::new(0.0, 0.0, 20.0, 20.0))
+                .with_transform(Affine::translate((5.0, 0.0)))
+        });
+        assert_eq!(pixel(&translated, 43, 45), WHITE);
+        assert_eq!(pixel(&translated, 47, 45), RED);
+        assert_eq!(pixel(&translated, 63, 45), WHITE);
+        // Scaling the pattern scales both the contents and the steps.
+        let scaled = render_pattern(square, |fragment| {
+            Pattern::new(fragment, Rect::new(0.0, 0.0, 20.0, 20.0))
+                .with_transform(Affine::scale(2.0))
+        });
+        assert_eq!(pixel(&scaled, 45, 45), RED);
+        assert_eq!(pixel(&scaled, 65, 45), WHITE);
+        assert_eq!(pixel(&scaled, 85, 45), RED);
+        assert_eq!(pixel(&scaled, 85, 65), WHITE);
+    }
+
+    #[test]
+    fn pattern_extend_modes() {
+        let square = Rect::new(0.0, 0.0, 10.0, 10.0);
+        let with_extend = |extend| {
+            render_pattern(square, move |fragment| {
+                Pattern::new(fragment, Rect::new(60.0, 60.0, 80.0, 80.0)).with_extend(extend)
+            })
+        };
+        // Pad draws the origin cell only.
+        let pad = with_extend(Extend::Pad);
+        assert_eq!(pixel(&pad, 65, 65), RED);
+        for (x, y) in [(85, 65), (65, 85), (45, 45), (75, 65)] {
+            assert_eq!(pixel(&pad, x, y), WHITE, "({x}, {y})");
+        }
+        let repeat = with_extend(Extend::Repeat);
+        for (x, y) in [(65, 65), (85, 65), (65, 85), (45, 45)] {
+            assert_eq!(pixel(&repeat, x, y), RED, "({x}, {y})");
+        }
+        assert_eq!(pixel(&repeat, 95, 65), WHITE);
+        // Reflect mirrors odd columns horizontally and odd rows vertically.
+        let reflect = with_extend(Extend::Reflect);
+        for (x, y) in [(65, 65), (95, 65), (65, 95), (95, 95), (105, 65)] {
+            assert_eq!(pixel(&reflect, x, y), RED, "({x}, {y})");
+        }
+        for (x, y) in [(85, 65), (65, 85), (85, 85)] {
+            assert_eq!(pixel(&reflect, x, y), WHITE, "({x}, {y})");
+        }
+    }
+
+    #[test]
+    fn pattern_mirror_tiling_combines_with_extend() {
+        let square = Rect::new(0.0, 0.0, 10.0, 10.0);
+        let with_modes = |tiling, extend| {
+            render_pattern(square, move |fragment| {
+                Pattern::new(fragment, Rect::new(60.0, 60.0, 80.0, 80.0))
+                    .with_tiling(tiling)
+                    .with_extend(extend)
+            })
+            .pixels
+        };
+        // Mirrored cells are flipped once, whether by the tiling, the extend mode or both.
+        let reflect = with_modes(PatternTiling::Grid, Extend::Reflect);
+        assert_eq!(reflect[65 * 256 + 95].to_le_bytes(), RED);
+        assert!(with_modes(PatternTiling::Mirror, Extend::Repeat) == reflect);
+        assert!(with_modes(PatternTiling::Mirror, Extend::Reflect) == reflect);
+        // Pad draws only the origin cell, which is never mirrored.
+        let pad = with_modes(PatternTiling::Grid, Extend::Pad);
+        assert!(with_modes(PatternTiling::Mirror, Extend::Pad) == pad);
+    }
+}
+
+mod instances {
+    use vello::peniko::{ColorStop, Gradient};
+    use vello::{CpuTexture, SceneFragment};
+
+    use super::common::{assert_pixels_match, pixel, render};
+    use super::*;
+
+    /// Draws `fragment` at each transform with `draw_instances`, and by appending it for
+    /// each transform, asserting that they render the same. Returns the instanced render.
+    fn assert_instances_match_copies(
+        fragment: &SceneFragment,
+        transforms: &[Affine],
+    ) -> CpuTexture {
+        let mut instanced = Scene::new();
+        SceneBuilder::for_scene(&mut instanced).draw_instances(fragment, transforms);
+        assert_ne!(instanced.data().n_patterns, 0);
+        let mut appended = Scene::new();
+        let mut sb = SceneBuilder::for_scene(&mut appended);
+        for transform in transforms {
+            sb.append(fragment, Some(*transform));
+        }
+        let [instanced, appended] = [&instanced, &appended].map(|scene| render(scene, 256, 256));
+        assert_pixels_match(&instanced, &appended);
+        instanced
+    }
+
+    /// A circle of radius 40 at the origin.
+    fn circle_fragment(color: Color) -> SceneFragment {
+        let mut fragment = SceneFragment::new();
+        let circle = Circle::new((0.0, 0.0), 40.0);
+        SceneBuilder::for_fragment(&mut fragment).fill(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            color,
+            None,
+            &circle,
+        );
+        fragment
+    }
+
+    #[test]
+    fn draw_instances_composites_translucent_overlaps() {
+        // The copies are at 100 and 150, and overlap between 110 and 140.
+        let transforms = [100.0, 150.0].map(|x| Affine::translate((x, 128.0)));
+        let fragment = circle_fragment(Color::rgba8(0, 0, 255, 128));
+        let texture = assert_instances_match_copies(&fragment, &transforms);
+        assert!(pixel(&texture, 125, 128)[0] < pixel(&texture, 80, 128)[0]);
+    }
+
+    #[test]
+    fn draw_instances_fills_mirrored_overlaps() {
+        let transforms = [
+            Affine::translate((100.0, 128.0)),
+            Affine::translate((150.0, 128.0)) * Affine::scale_non_uniform(-1.0, 1.0),
+        ];
+        let fragment = circle_fragment(Color::rgb8(0, 0, 255));
+        let texture = assert_instances_match_copies(&fragment, &transforms);
+        assert_eq!(pixel(&texture, 125, 128), [0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn draw_instances_keeps_copy_order() {
+        // An opaque square and a translucent gradient circle overlapping its right side.
+        let mut fragment = SceneFragment::new();
+        let mut sb = SceneBuilder::for_fragment(&mut fragment);
+        let square = Rect::new(0.0, 0.0, 40.0, 40.0);
+        sb.fill(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            Color::rgb8(255, 0, 0),
+            None,
+            &square,
+        );
+        let gradient = Gradient::new_linear((20.0, 0.0), (60.0, 0.0)).with_stops([
+            ColorStop {
+                offset: 0.0,
+                color: Color::rgba8(0, 0, 255, 160),
+            },
+            ColorStop {
+                offset: 1.0,
+                color: Color::rgba8(0, 255, 0, 160),
+            },
+        ]);
+        let circle = Circle::new((40.0, 20.0), 20.0);
+        sb.fill(Fill::NonZero, Affine::IDENTITY, &gradient, None, &circle);
+        // The square of the second copy covers the circle of the first, which covers the
+        // square of the first.
+        let transforms = [50.0, 80.0].map(|x| Affine::translate((x, 100.0)));
+        let texture = assert_instances_match_copies(&fragment, &transforms);
+        assert_eq!(pixel(&texture, 95, 120), RED);
+        assert_ne!(pixel(&texture, 75, 120), RED);
+    }
+}
+
+mod sweep_gradients {
+    use std::f32::consts::PI;
+
+    use vello::peniko::{ColorStop, Extend, Gradient};
+    use vello::CpuTexture;
+
+    use super::common::{assert_near, pixel, render};
+    use super::*;
+
+    /// Fills the 256x256 target with a red to blue sweep gradient around its center.
+    fn render_sweep(start_angle: f32, end_angle: f32, extend: Extend) -> CpuTexture {
+        let stops = [(0.0, Color::rgb8(255, 0, 0)), (1.0, Color::rgb8(0, 0, 255))]
+            .map(|(offset, color)| ColorStop { offset, color });
+        let gradient = Gradient::new_sweep((128.0, 128.0), start_angle, end_angle)
+            .with_stops(stops)
+            .with_extend(extend);
+        let mut scene = Scene::new();
+        let rect = Rect::new(0.0, 0.0, 256.0, 256.0);
+        SceneBuilder::for_scene(&mut scene).fill(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            &gradient,
+            None,
+            &rect,
+        );
+        render(&scene, 256, 256)
+    }
+
+    /// Asserts that the pixel at `(x, y)` is within a ramp sample of `t` between red and
+    /// blue.
+    fn assert_ramp(texture: &CpuTexture, x: usize, y: usize, t: f32) {
+        let expected = [255.0 * (1.0 - t), 0.0, 255.0 * t, 255.0].map(|c| c.round() as u8);
+        assert_near(pixel(texture, x, y), expected, 2);
+    }
+
+    #[test]
+    fn sweep_gradient_angles() {
+        // The angle increases clockwise from the positive x axis, as y points down.
+        let texture = render_sweep(0.0, PI, Extend::Pad);
+        assert_ramp(&texture, 200, 128, 0.0);
+        assert_ramp(&texture, 128, 200, 0.5);
+        assert_ramp(&texture, 78, 178, 0.75);
+        let texture = render_sweep(PI / 2.0, 3.0 * PI / 2.0, Extend::Pad);
+        assert_ramp(&texture, 128, 200, 0.0);
+        assert_ramp(&texture, 50, 128, 0.5);
+        assert_ramp(&texture, 128, 50, 1.0);
+    }
+
+    #[test]
+    fn sweep_gradient_extends() {
+        // (78, 78) is at 5/4 pi, and (178, 78) at 7/4 pi, so past the end of the ramp.
+        let pad = render_sweep(0.0, PI, Extend::Pad);
+        assert_ramp(&pad, 78, 78, 1.0);
+        assert_ramp(&pad, 178, 78, 1.0);
+        let repeat = render_sweep(0.0, PI, Extend::Repeat);
+        assert_ramp(&repeat, 78, 78, 0.25);
+        assert_ramp(&repeat, 178, 78, 0.75);
+        let reflect = render_sweep(0.0, PI, Extend::Reflect);
+        assert_ramp(&reflect, 78, 78, 0.75);
+        assert_ramp(&reflect, 178, 78, 0.25);
+        // Before the start angle, pad uses the first stop.
+        let pad = render_sweep(PI / 2.0, PI, Extend::Pad);
+        assert_ramp(&pad, 200, 128, 0.0);
+    }
+}
+
+mod images {
+    use vello::peniko::{Blob, Extend, Format, Image};
+    use vello::{CpuTexture, ImageQuality, ImageSampling};
+
+    use super::common::{assert_near, pixel, render};
+    use super::*;
+
+    const BLUE: [u8; 4] = [0, 0, 255, 255];
+
+    /// Fills the top 256x64 of the target with `image` scaled by `scale`.
+    fn render_image(image: &Image, alpha: f32, sampling: ImageSampling, scale: f64) -> CpuTexture {
+        let mut scene = Scene::new();
+        let rect = Rect::new(0.0, 0.0, 256.0, 64.0);
+        SceneBuilder::for_scene(&mut scene).fill_image(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            image,
+            alpha,
+            sampling,
+            Some(Affine::scale(scale)),
+            &rect,
+        );
+        render(&scene, 256, 256)
+    }
+
+    /// A red texel next to a blue one.
+    fn red_blue_image() -> Image {
+        let data = vec![255, 0, 0, 255, 0, 0, 255, 255];
+        Image::new(Blob::from(data), Format::Rgba8, 2, 1)
+    }
+
+    #[test]
+    fn image_extend_modes() {
+        let image = red_blue_image();
+        let sampling = |extend| ImageSampling::new(extend).with_quality(ImageQuality::Nearest);
+        // Texels are 32 pixels wide, so x = 80 is in texel 2 and x = 112 in texel 3.
+        let pad = render_image(&image, 1.0, sampling(Extend::Pad), 32.0);
+        assert_eq!(pixel(&pad, 16, 16), RED);
+        assert_eq!(pixel(&pad, 48, 16), BLUE);
+        assert_eq!(pixel(&pad, 80, 16), BLUE);
+        assert_eq!(pixel(&pad, 112, 16), BLUE);
+        // Pad also extends rows, while only the filled rect is drawn.
+        assert_eq!(pixel(&pad, 16, 48), RED);
+        assert_eq!(pixel(&pad, 16, 80), WHITE);
+        let repeat = render_image(&image, 1.0, sampling(Extend::Repeat), 32.0);
+        assert_eq!(pixel(&repeat, 80, 16), RED);
+        assert_eq!(pixel(&repeat, 112, 16), BLUE);
+        let reflect = render_image(&image, 1.0, sampling(Extend::Reflect), 32.0);
+        assert_eq!(pixel(&reflect, 80, 16), BLUE);
+        assert_eq!(pixel(&reflect, 112, 16), RED);
+        // The extend modes of each direction are independent.
+        let mixed = ImageSampling::new(Extend::Repeat)
+            .with_y_extend(Extend::Pad)
+            .with_quality(ImageQuality::Nearest);
+        let mixed = render_image(&image, 1.0, mixed, 32.0);
+        assert_eq!(pixel(&mixed, 80, 48), RED);
+    }
+
+    #[test]
+    fn image_sampling_quality() {
+        let image = red_blue_image();
+        let sampling = |quality| ImageSampling::new(Extend::Pad).with_quality(quality);
+        // Texels are 64 pixels wide, so (32, 16) is at the center of the red texel and
+        // (64, 16) on the edge between the texels.
+        let nearest = render_image(&image, 1.0, sampling(ImageQuality::Nearest), 64.0);
+        assert_eq!(pixel(&nearest, 32, 16), RED);
+        assert_eq!(pixel(&nearest, 63, 16), RED);
+        assert_eq!(pixel(&nearest, 64, 16), BLUE);
+        let bilinear = render_image(&image, 1.0, sampling(ImageQuality::Bilinear), 64.0);
+        assert_near(pixel(&bilinear, 32, 16), RED, 2);
+        assert_near(pixel(&bilinear, 64, 16), [126, 0, 129, 255], 2);
+        // A quarter texel from the red center is a quarter blue.
+        assert_near(pixel(&bilinear, 48, 16), [191, 0, 64, 255], 2);
+        // The bicubic filter blurs texel centers with their neighbors.
+        let bicubic = render_image(&image, 1.0, sampling(ImageQuality::Bicubic), 64.0);
+        assert_near(pixel(&bicubic, 32, 16), [241, 0, 14, 255], 2);
+        assert_near(pixel(&bicubic, 64, 16), [126, 0, 129, 255], 2);
+    }
+
+    #[test]
+    fn image_alpha() {
+        // Half transparent red, drawn at half alpha over white.
+        let data = vec![255, 0, 0, 128];
+        let image = Image::new(Blob::from(data), Format::Rgba8, 1, 1);
+        let sampling = ImageSampling::new(Extend::Pad).with_quality(ImageQuality::Nearest);
+        let texture = render_image(&image, 0.5, sampling, 1.0);
+        assert_near(pixel(&texture, 16, 16), [255, 191, 191, 255], 2);
+        // An image brush is drawn with the alpha of the image only.
+        let mut scene = Scene::new();
+        let rect = Rect::new(0.0, 0.0, 256.0, 64.0);
+        SceneBuilder::for_scene(&mut scene).fill(
+            Fill::NonZero,
+            Affine::IDENTITY,
+            &image.clone().with_extend(Extend::Pad),
+            None,
+            &rect,
+        );
+        let texture = render(&scene, 256, 256);
+        assert_near(pixel(&texture, 16, 16), [255, 128, 128, 255], 2);
+    }
+}